@@ -1,4 +1,10 @@
 fn main() {
+    // The linker scripts only exist for the chip; a host build, e.g. of the
+    // unit tests, links like any other program.
+    if std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() != Ok("xtensa") {
+        return;
+    }
+
     linker_be_nice();
     println!("cargo:rustc-link-arg=-Tdefmt.x");
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
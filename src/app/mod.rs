@@ -1,19 +1,31 @@
 pub mod screens;
+#[cfg(target_arch = "xtensa")]
 pub mod terminal;
 
-use esp_storage::FlashStorage;
-use heapless::String;
+use embassy_time::{Duration, Instant};
+use embedded_storage::nor_flash::NorFlash;
 use ratatui::Frame;
 use ratatui::widgets::ListState;
 
-use defmt::{Format, warn};
+use defmt::{Format, info, warn};
 pub use screens::select_group::ITEMS as MENU_ITEMS;
+#[cfg(target_arch = "xtensa")]
 pub use terminal::{init_terminal, init_terminal_with_flush};
 
 use screens::Screen;
+use screens::pin_entry::PinPurpose;
 
-use crate::keepass::{Entry, Group, KeePassDb};
-use crate::usb_hid_queue::try_queue_type_text;
+use crate::encryption::{DbKey, DeviceHmac, derive_sw_key};
+use crate::keepass::{Entry, Group, KDBError, KeePassDb};
+use crate::secret::SecretString;
+use crate::storage::boot;
+use crate::storage::diagnostics::Diagnostics;
+use crate::storage::layout::StorageLayout;
+use crate::storage::region::DataRegion;
+use crate::storage::settings::Settings;
+use crate::storage::user_config::{MAX_PIN_LEN, UnlockMode, UserConfig};
+use crate::usb_hid_queue::{self, try_queue_type_text};
+use crate::usb_transfer::{self, ExportRequest, Reply, Request, TransferError};
 
 #[derive(Debug, Format)]
 pub enum Screens {
@@ -27,6 +39,12 @@ pub enum Screens {
     BootSplash(screens::boot_splash::BootSplashScreen),
     PinEntry(screens::pin_entry::PinEntryScreen),
     ViewPassword(screens::view_password::ViewPasswordScreen),
+    ViewNotes(screens::view_notes::ViewNotesScreen),
+    Settings(screens::settings::SettingsScreen),
+    FactoryReset(screens::factory_reset::FactoryResetScreen),
+    Diagnostics(screens::diagnostics::DiagnosticsScreen),
+    ConfirmTransfer(screens::confirm_transfer::ConfirmTransferScreen),
+    WipeFailed(screens::wipe_failed::WipeFailedScreen),
 }
 
 impl Screens {
@@ -74,10 +92,50 @@ impl Screens {
         Self::PinEntry(screens::pin_entry::PinEntryScreen::new())
     }
 
+    /// PIN or passphrase prompt for a database sealed with `unlock_mode`.
+    pub fn unlock(unlock_mode: UnlockMode) -> Self {
+        Self::PinEntry(screens::pin_entry::PinEntryScreen::unlock(unlock_mode))
+    }
+
     pub fn view_password(entry_index: usize) -> Self {
         Self::ViewPassword(screens::view_password::ViewPasswordScreen::new(entry_index))
     }
 
+    pub fn view_notes(entry_index: usize) -> Self {
+        Self::ViewNotes(screens::view_notes::ViewNotesScreen::new(entry_index))
+    }
+
+    pub fn settings(settings: Settings) -> Self {
+        Self::Settings(screens::settings::SettingsScreen::with_settings(settings))
+    }
+
+    pub fn factory_reset() -> Self {
+        Self::FactoryReset(screens::factory_reset::FactoryResetScreen::new())
+    }
+
+    pub fn diagnostics(diagnostics: &Diagnostics) -> Self {
+        Self::Diagnostics(screens::diagnostics::DiagnosticsScreen::new(diagnostics))
+    }
+
+    pub fn confirm_transfer(request: &Request) -> Self {
+        Self::ConfirmTransfer(
+            screens::confirm_transfer::ConfirmTransferScreen::for_request(request),
+        )
+    }
+
+    pub fn wipe_failed() -> Self {
+        Self::WipeFailed(screens::wipe_failed::WipeFailedScreen::new())
+    }
+
+    /// Asks for the current secret before it gets changed.
+    pub fn current_secret(unlock_mode: UnlockMode) -> Self {
+        Self::PinEntry(screens::pin_entry::PinEntryScreen::current(unlock_mode))
+    }
+
+    pub fn new_secret() -> Self {
+        Self::PinEntry(screens::pin_entry::PinEntryScreen::new_secret())
+    }
+
     pub fn item_count(&self, kpdb: &KeePassDb) -> usize {
         match self {
             Screens::SelectGroup(screen) => screen.item_count(kpdb),
@@ -90,6 +148,12 @@ impl Screens {
             Screens::BootSplash(_) => 0,
             Screens::PinEntry(screen) => screen.item_count(),
             Screens::ViewPassword(_) => 0,
+            Screens::ViewNotes(screen) => screen.item_count(),
+            Screens::Settings(_) => screens::settings::ITEMS,
+            Screens::FactoryReset(_) => screens::factory_reset::ITEMS,
+            Screens::Diagnostics(screen) => screen.item_count(),
+            Screens::ConfirmTransfer(_) => screens::confirm_transfer::ITEMS,
+            Screens::WipeFailed(_) => screens::wipe_failed::ITEMS,
         }
     }
 }
@@ -111,6 +175,12 @@ impl Screen for Screens {
             Screens::BootSplash(screen) => screen.draw(frame, selected, keepass),
             Screens::PinEntry(screen) => screen.draw(frame, selected, keepass),
             Screens::ViewPassword(screen) => screen.draw(frame, selected, keepass),
+            Screens::ViewNotes(screen) => screen.draw(frame, selected, keepass),
+            Screens::Settings(screen) => screen.draw(frame, selected, keepass),
+            Screens::FactoryReset(screen) => screen.draw(frame, selected, keepass),
+            Screens::Diagnostics(screen) => screen.draw(frame, selected, keepass),
+            Screens::ConfirmTransfer(screen) => screen.draw(frame, selected, keepass),
+            Screens::WipeFailed(screen) => screen.draw(frame, selected, keepass),
         }
     }
 
@@ -126,6 +196,12 @@ impl Screen for Screens {
             Screens::BootSplash(screen) => screen.on_select(selected),
             Screens::PinEntry(screen) => screen.on_select(selected),
             Screens::ViewPassword(screen) => screen.on_select(selected),
+            Screens::ViewNotes(screen) => screen.on_select(selected),
+            Screens::Settings(screen) => screen.on_select(selected),
+            Screens::FactoryReset(screen) => screen.on_select(selected),
+            Screens::Diagnostics(screen) => screen.on_select(selected),
+            Screens::ConfirmTransfer(screen) => screen.on_select(selected),
+            Screens::WipeFailed(screen) => screen.on_select(selected),
         }
     }
 
@@ -141,6 +217,12 @@ impl Screen for Screens {
             Screens::BootSplash(screen) => screen.on_tick(),
             Screens::PinEntry(screen) => screen.on_tick(),
             Screens::ViewPassword(screen) => screen.on_tick(),
+            Screens::ViewNotes(screen) => screen.on_tick(),
+            Screens::Settings(screen) => screen.on_tick(),
+            Screens::FactoryReset(screen) => screen.on_tick(),
+            Screens::Diagnostics(screen) => screen.on_tick(),
+            Screens::ConfirmTransfer(screen) => screen.on_tick(),
+            Screens::WipeFailed(screen) => screen.on_tick(),
         }
     }
 }
@@ -152,10 +234,46 @@ pub enum ScreenAction {
     Pop,
     CreateGroup(Group),
     CreateEntry(Entry),
-    TextEntrySubmit(String<{ screens::text_entry_form::MAX_TEXT_LEN }>),
+    TextEntrySubmit(SecretString<{ screens::text_entry_form::MAX_TEXT_LEN }>),
+    SubmitPin(SecretString<{ MAX_PIN_LEN }>),
+    SubmitPassphrase(SecretString<{ screens::text_entry_form::MAX_TEXT_LEN }>),
+    ChangePin,
     ToggleEntryAutotype(usize),
+    /// Opens the keyboard on a field of the entry at this index.
+    EditEntryField(usize, screens::entry_options::EntryField),
     TypeEntryPassword(usize),
     DeleteEntry(usize),
+    /// Erases the whole storage and starts over from a blank device.
+    FactoryReset,
+    /// Reads the storage's usage and health into the diagnostics screen.
+    ShowDiagnostics,
+    /// Opens the settings screen on the stored settings.
+    ShowSettings,
+    /// Stores the settings the settings screen changed.
+    SaveSettings(Settings),
+    /// Carries out the request the host sent over USB.
+    AcceptTransfer,
+    /// Turns the host's request down.
+    RejectTransfer,
+    /// Runs the auto-wipe again after it failed.
+    RetryWipe,
+}
+
+impl ScreenAction {
+    /// Whether the action changes the database, the PIN or the settings,
+    /// which read-only mode refuses.
+    fn writes_storage(&self) -> bool {
+        matches!(
+            self,
+            ScreenAction::CreateGroup(_)
+                | ScreenAction::CreateEntry(_)
+                | ScreenAction::ChangePin
+                | ScreenAction::ToggleEntryAutotype(_)
+                | ScreenAction::DeleteEntry(_)
+                | ScreenAction::FactoryReset
+                | ScreenAction::SaveSettings(_)
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -163,6 +281,19 @@ pub struct AppState {
     pub screen_stack: [Option<Screens>; 8],
     pub selected: ListState,
     pub kpdb: Option<KeePassDb>,
+    pub user_config: Option<UserConfig>,
+    pub layout: Option<StorageLayout>,
+    /// Set when the storage failed its checks and was opened read-only.
+    read_only: bool,
+    /// Last encoder turn or button press, for the idle auto-lock.
+    last_activity: Instant,
+    /// A request from the host, waiting on the confirmation screen.
+    transfer: Option<Request>,
+    /// An export the user accepted, for [`AppState::run_export`].
+    export: Option<ExportRequest>,
+    /// End of the backoff after the last failed PIN attempt. The PIN screen
+    /// shows it, but [`AppState::submit_secret`] is what enforces it.
+    locked_until: Option<Instant>,
 }
 
 impl AppState {
@@ -178,6 +309,13 @@ impl AppState {
             screen_stack,
             selected,
             kpdb: None,
+            user_config: None,
+            layout: None,
+            read_only: false,
+            last_activity: Instant::now(),
+            transfer: None,
+            export: None,
+            locked_until: None,
         }
     }
     pub fn with_kpdb(mut self, kpdb: KeePassDb) -> Self {
@@ -185,6 +323,41 @@ impl AppState {
         self
     }
 
+    pub fn with_layout(mut self, layout: StorageLayout) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    /// Refuses every change to the database; see [`WriteProtect`](crate::storage::write_protect::WriteProtect).
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Attaches the persisted PIN state. Any PIN screen already on the stack
+    /// switches to the enrolled unlock mode and picks up the failed-attempt
+    /// count and the lockout that goes with it, so rebooting doesn't skip a
+    /// cooldown.
+    pub fn with_user_config(mut self, user_config: UserConfig) -> Self {
+        let sealed = self.kpdb.as_ref().is_some_and(|kpdb| kpdb.is_sealed());
+        for screen in self.screen_stack.iter_mut().flatten() {
+            if sealed && matches!(screen, Screens::PinEntry(_)) {
+                *screen = Screens::unlock(user_config.unlock_mode());
+            }
+            if let Screens::PinEntry(screen) = screen {
+                screen.set_policy(user_config.settings().pin_policy);
+                screen.set_attempts(
+                    user_config.failed_attempts(),
+                    user_config.remaining_attempts(),
+                    user_config.lockout(),
+                );
+            }
+        }
+        self.locked_until = lockout_end(user_config.lockout());
+        self.user_config = Some(user_config);
+        self
+    }
+
     /// Applies a rotary navigation delta to the current menu selection.
     ///
     /// The selection is clamped to the valid item range for the current screen.
@@ -263,24 +436,96 @@ impl AppState {
         screen.draw(frame, &mut self.selected, kpdb);
     }
 
-    pub fn on_select(&mut self, storage: &mut FlashStorage) {
+    pub fn on_select<S: NorFlash, H: DeviceHmac>(&mut self, storage: &mut S, hmac: &mut H) {
         // Ensure the selection is valid for the current screen.
         self.apply_navigation(0);
         let selected = self.selected();
         let action = self.get_current_screen_mut().on_select(selected);
-        self.handle_screen_action(action, storage);
+        self.handle_screen_action(action, storage, hmac);
+    }
+
+    /// Restarts the idle auto-lock countdown. Called on every user input.
+    pub fn note_activity(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    /// Locks an unlocked database: decrypted records and the key are wiped
+    /// from RAM, typing over USB HID stops and the PIN screen is the only thing left on the stack.
+    pub fn lock(&mut self) {
+        let (Some(kpdb), Some(user_config)) = (self.kpdb.as_mut(), self.user_config.as_ref())
+        else {
+            return;
+        };
+        if !kpdb.is_unlocked() {
+            return;
+        }
+
+        kpdb.lock();
+        // A password still waiting to be typed goes with the key
+        usb_hid_queue::clear();
+        let mut screen = screens::pin_entry::PinEntryScreen::unlock(user_config.unlock_mode());
+        screen.set_policy(user_config.settings().pin_policy);
+        screen.set_attempts(
+            user_config.failed_attempts(),
+            user_config.remaining_attempts(),
+            user_config.lockout(),
+        );
+        self.locked_until = lockout_end(user_config.lockout());
+        self.reset_screen_stack(Screens::PinEntry(screen));
+        self.cancel_transfer(TransferError::Locked);
+        info!("Locked");
     }
 
-    pub fn on_tick(&mut self, storage: &mut FlashStorage) {
+    pub fn on_tick<S: NorFlash, H: DeviceHmac>(&mut self, storage: &mut S, hmac: &mut H) {
+        let idle_timeout = self.user_config.as_ref().and_then(UserConfig::auto_lock);
+        if idle_timeout.is_some_and(|timeout| self.last_activity.elapsed() >= timeout) {
+            self.lock();
+        }
+
         let action = self.get_current_screen_mut().on_tick();
-        self.handle_screen_action(action, storage);
+        self.handle_screen_action(action, storage, hmac);
     }
 
-    fn handle_screen_action(&mut self, action: ScreenAction, storage: &mut FlashStorage) {
+    fn handle_screen_action<S: NorFlash, H: DeviceHmac>(
+        &mut self,
+        action: ScreenAction,
+        storage: &mut S,
+        hmac: &mut H,
+    ) {
+        if self.read_only && action.writes_storage() {
+            self.push_screen(Screens::action_completed("Read-only mode"));
+            return;
+        }
+
         match action {
             ScreenAction::None => {}
             ScreenAction::Pop => self.pop_screen(),
             ScreenAction::Push(screen) => self.push_screen(screen),
+            ScreenAction::SubmitPin(pin) => {
+                let unlock_mode = UnlockMode::Pin {
+                    len: Some(pin.len() as u8),
+                };
+                self.submit_secret(pin.as_bytes(), unlock_mode, storage, hmac)
+            }
+            ScreenAction::ChangePin => {
+                let Some(user_config) = self.user_config.as_ref() else {
+                    return;
+                };
+                let (failed, remaining, lockout) = (
+                    user_config.failed_attempts(),
+                    user_config.remaining_attempts(),
+                    user_config.lockout(),
+                );
+                let policy = user_config.settings().pin_policy;
+                self.push_screen(Screens::current_secret(user_config.unlock_mode()));
+                if let Screens::PinEntry(screen) = self.get_current_screen_mut() {
+                    screen.set_policy(policy);
+                    screen.set_attempts(failed, remaining, lockout);
+                }
+            }
+            ScreenAction::SubmitPassphrase(passphrase) => {
+                self.submit_secret(passphrase.as_bytes(), UnlockMode::Passphrase, storage, hmac)
+            }
             ScreenAction::TextEntrySubmit(text) => {
                 self.pop_screen();
                 match self.get_current_screen_mut() {
@@ -291,6 +536,10 @@ impl AppState {
                             return;
                         };
                         let entry_index = screen.entry_index();
+                        if self.read_only {
+                            self.push_screen(Screens::action_completed("Read-only mode"));
+                            return;
+                        }
                         if let Some(kpdb) = self.kpdb.as_mut() {
                             let Some(existing) = kpdb
                                 .entries
@@ -299,23 +548,52 @@ impl AppState {
                             else {
                                 return;
                             };
-                            let mut entry = *existing;
-                            match field {
+                            let mut entry = existing.clone();
+                            let set = match field {
                                 screens::entry_options::EntryField::Title => {
-                                    fill_fixed(&mut entry.title, text.as_str());
+                                    entry.set_title(text.as_bytes())
                                 }
                                 screens::entry_options::EntryField::Username => {
-                                    fill_fixed(&mut entry.username, text.as_str());
+                                    entry.set_username(text.as_bytes())
                                 }
-                            }
-                            if let Err(err) = kpdb.update_entry(entry_index, entry, storage) {
-                                warn!("update_entry failed: {}", err);
+                                screens::entry_options::EntryField::Url => {
+                                    entry.set_url(text.as_bytes())
+                                }
+                                screens::entry_options::EntryField::Notes => {
+                                    entry.set_notes(text.as_bytes())
+                                }
+                            };
+                            match set.and_then(|()| kpdb.update_entry(entry_index, entry, storage))
+                            {
+                                Ok(()) => {}
+                                Err(KDBError::FieldTooLong) => {
+                                    self.push_screen(Screens::action_completed("Text too long"));
+                                }
+                                Err(err) => warn!("update_entry failed: {}", err),
                             }
                         }
                     }
                     _ => {}
                 };
             }
+            ScreenAction::EditEntryField(entry_index, field) => {
+                let Some(entry) = self
+                    .kpdb
+                    .as_ref()
+                    .and_then(|kpdb| kpdb.entries.get(entry_index))
+                    .and_then(|entry| entry.as_ref())
+                else {
+                    return;
+                };
+                let text = match field {
+                    screens::entry_options::EntryField::Title => entry.title(),
+                    screens::entry_options::EntryField::Username => entry.username(),
+                    screens::entry_options::EntryField::Url => entry.url(),
+                    screens::entry_options::EntryField::Notes => entry.notes(),
+                };
+                let screen = Screens::text_entry_form(core::str::from_utf8(text).unwrap_or(""));
+                self.push_screen(screen);
+            }
             ScreenAction::ToggleEntryAutotype(entry_index) => {
                 let on_entry_options =
                     matches!(self.get_current_screen(), Screens::EntryOptions(_));
@@ -327,7 +605,7 @@ impl AppState {
                     else {
                         return;
                     };
-                    let mut entry = *existing;
+                    let mut entry = existing.clone();
                     entry.autotype = !entry.autotype;
                     if let Err(err) = kpdb.update_entry(entry_index, entry, storage) {
                         warn!("update_entry failed: {}", err);
@@ -339,7 +617,10 @@ impl AppState {
                             .get(entry_index)
                             .and_then(|entry| entry.as_ref())
                         {
-                            let autotype_row = if updated.autotype { 4 } else { 3 };
+                            let autotype_row =
+                                screens::entry_options::EntryOptionsScreen::autotype_row(
+                                    updated.autotype,
+                                );
                             self.selected.select(Some(autotype_row));
                             *self.selected.offset_mut() = 0;
                         }
@@ -382,17 +663,16 @@ impl AppState {
             ScreenAction::TypeEntryPassword(entry_index) => {
                 if let Some(kpdb) = self.kpdb.as_mut() {
                     if let Some(entry) = kpdb.entries.get(entry_index).unwrap() {
-                        let pass_bytes = entry.password;
-
                         // Convert the pass bytes to str
-                        let pass_end = pass_bytes
-                            .iter()
-                            .position(|&b| b == 0)
-                            .unwrap_or(pass_bytes.len());
-                        let Ok(pass) = core::str::from_utf8(&pass_bytes[..pass_end]) else {
+                        let Ok(pass) = core::str::from_utf8(entry.password().expose()) else {
                             return;
                         };
-                        try_queue_type_text(pass).unwrap_or(());
+                        let settings = self
+                            .user_config
+                            .as_ref()
+                            .map(|user_config| *user_config.settings())
+                            .unwrap_or_default();
+                        try_queue_type_text(pass, &settings).unwrap_or(());
                     }
                 }
             }
@@ -410,9 +690,373 @@ impl AppState {
                     self.push_screen(Screens::action_completed("Entry deleted"));
                 }
             }
+            ScreenAction::FactoryReset => self.factory_reset(storage),
+            ScreenAction::ShowSettings => {
+                let settings = self
+                    .user_config
+                    .as_ref()
+                    .map(|user_config| *user_config.settings())
+                    .unwrap_or_default();
+                self.push_screen(Screens::settings(settings));
+            }
+            ScreenAction::SaveSettings(settings) => {
+                let Some(user_config) = self.user_config.as_mut() else {
+                    return;
+                };
+                if let Err(err) = user_config.save_settings(settings, storage) {
+                    warn!("save_settings failed: {}", err);
+                    self.push_screen(Screens::action_completed("Saving failed"));
+                }
+            }
+            ScreenAction::AcceptTransfer => {
+                let request = match self.transfer.take() {
+                    Some(Request::Import(request)) => request,
+                    Some(Request::Export(request)) => {
+                        self.export = Some(request);
+                        self.pop_screen();
+                        return;
+                    }
+                    None => return,
+                };
+                let Some(kpdb) = self.kpdb.as_mut() else {
+                    return;
+                };
+                let reply = usb_transfer::import(kpdb, request, storage);
+                let imported = matches!(reply, Reply::Imported { .. });
+                usb_transfer::reply(reply);
+                self.pop_screen();
+                if imported {
+                    // The screens below may show groups and entries that are gone
+                    self.screen_stack[1..].fill_with(|| None);
+                    self.push_screen(Screens::action_completed("Import done"));
+                } else {
+                    self.push_screen(Screens::action_completed("Import failed"));
+                }
+            }
+            ScreenAction::RetryWipe => self.wipe_device(storage),
+            ScreenAction::RejectTransfer => {
+                self.cancel_transfer(TransferError::Refused);
+                self.pop_screen();
+            }
+            ScreenAction::ShowDiagnostics => {
+                let (Some(layout), Some(kpdb)) = (self.layout.as_ref(), self.kpdb.as_ref()) else {
+                    return;
+                };
+                match Diagnostics::collect(storage, layout, kpdb) {
+                    Ok(diagnostics) => self.push_screen(Screens::diagnostics(&diagnostics)),
+                    Err(err) => {
+                        warn!("Collecting diagnostics failed: {}", err);
+                        self.push_screen(Screens::action_completed("Storage unreadable"));
+                    }
+                }
+            }
         }
     }
 
+    /// Handles a PIN or passphrase from the PIN screen: unlocks the database
+    /// (enrolling the secret and its `unlock_mode` on a fresh database), or
+    /// drives the change-PIN flow.
+    fn submit_secret<S: NorFlash, H: DeviceHmac>(
+        &mut self,
+        secret: &[u8],
+        unlock_mode: UnlockMode,
+        storage: &mut S,
+        hmac: &mut H,
+    ) {
+        let purpose = match self.get_current_screen() {
+            Screens::PinEntry(screen) => screen.purpose(),
+            _ => return,
+        };
+        if purpose == PinPurpose::New {
+            self.change_secret(secret, unlock_mode, storage, hmac);
+            return;
+        }
+
+        let (Some(kpdb), Some(user_config)) = (self.kpdb.as_mut(), self.user_config.as_mut())
+        else {
+            return;
+        };
+
+        // A wipe that is due but didn't go through refuses every attempt
+        if user_config.wipe_due() {
+            self.wipe_device(storage);
+            return;
+        }
+        if self
+            .locked_until
+            .is_some_and(|locked_until| Instant::now() < locked_until)
+        {
+            warn!("PIN attempt refused during the lockout");
+            return;
+        }
+
+        // 1. Count the attempt up front; it is cleared again on success.
+        if let Err(err) = user_config.record_failed_attempt(storage) {
+            warn!("record_failed_attempt failed: {}", err);
+            return;
+        }
+
+        // 2. Check the secret against the verifier, then open the database
+        // (or, when confirming it for a change, just the header tag).
+        let sw_key = derive_sw_key(
+            hmac,
+            secret,
+            &kpdb.header.transform_seed,
+            kpdb.header.transform_rounds,
+        );
+        let key = kpdb.derive_key(&sw_key);
+        drop(sw_key);
+        // Without a verifier (first PIN, or one lost mid-rewrite) the header tag decides.
+        // A fresh database has nothing to check against; the first PIN seals it.
+        let fresh = !kpdb.is_sealed();
+        // A key the verifier turns down never gets to decrypt anything.
+        let verified = fresh || user_config.check_verifier(&key).unwrap_or(true);
+        let accepted = verified && {
+            let result = match purpose {
+                PinPurpose::Current => kpdb.check_key(&key, storage),
+                _ => kpdb.unlock(key.clone(), storage),
+            };
+            match result {
+                Ok(()) => true,
+                Err(err) => {
+                    warn!("unlock failed: {}", err);
+                    false
+                }
+            }
+        };
+
+        if accepted {
+            let result = if fresh {
+                user_config.set_verifier(&key, unlock_mode, storage)
+            } else {
+                user_config.record_success(&key, storage)
+            };
+            if let Err(err) = result {
+                warn!("storing the PIN state failed: {}", err);
+            }
+            self.locked_until = None;
+            let settings = *user_config.settings();
+            // The default group only opens once there is something in it
+            let default_group = settings
+                .default_group
+                .filter(|&group| kpdb.groups.get(group as usize).is_some_and(Option::is_some));
+            self.pop_screen();
+            match purpose {
+                PinPurpose::Current => {
+                    self.push_screen(Screens::new_secret());
+                    if let Screens::PinEntry(screen) = self.get_current_screen_mut() {
+                        screen.set_policy(settings.pin_policy);
+                    }
+                }
+                PinPurpose::Unlock => {
+                    if let Some(group) = default_group {
+                        self.push_screen(Screens::select_entry(u32::from(group)));
+                    }
+                }
+                PinPurpose::New => {}
+            }
+            return;
+        }
+
+        // 3. Too many consecutive failures: wipe the device.
+        if user_config.wipe_due() {
+            self.wipe_device(storage);
+            return;
+        }
+
+        let (failed, remaining, lockout) = (
+            user_config.failed_attempts(),
+            user_config.remaining_attempts(),
+            user_config.lockout(),
+        );
+        self.locked_until = lockout_end(lockout);
+        if let Screens::PinEntry(screen) = self.get_current_screen_mut() {
+            screen.reject(failed, remaining, lockout);
+        }
+    }
+
+    /// Re-encrypts the database under a new PIN or passphrase, drawing fresh
+    /// seeds so the new key shares nothing with the old one.
+    fn change_secret<S: NorFlash, H: DeviceHmac>(
+        &mut self,
+        secret: &[u8],
+        unlock_mode: UnlockMode,
+        storage: &mut S,
+        hmac: &mut H,
+    ) {
+        let (Some(kpdb), Some(user_config)) = (self.kpdb.as_mut(), self.user_config.as_mut())
+        else {
+            return;
+        };
+
+        // 1. Derive the new key from fresh seeds
+        let new_header = kpdb.rekey_header();
+        let sw_key = derive_sw_key(
+            hmac,
+            secret,
+            &new_header.transform_seed,
+            new_header.transform_rounds,
+        );
+        let new_key = DbKey::derive(&sw_key, &new_header.master_seed);
+        drop(sw_key);
+
+        // 2. Re-encrypt every record through the scratch region
+        let success = match kpdb.rekey(new_header, new_key, unlock_mode, user_config, storage) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("rekey failed: {}", err);
+                false
+            }
+        };
+
+        self.pop_screen();
+        if success {
+            self.push_screen(Screens::action_completed("PIN changed"));
+        }
+    }
+
+    /// Erases the database and the PIN state, then starts over from a fresh
+    /// database waiting for a new PIN. If that fails, the wipe-failed screen
+    /// is all that is left and no PIN is taken until a retry goes through.
+    fn wipe_device<S: NorFlash>(&mut self, storage: &mut S) {
+        warn!("Too many failed PIN attempts; wiping the device");
+        let Some(layout) = self.layout else {
+            warn!("No storage layout to wipe");
+            self.show_wipe_failed();
+            return;
+        };
+        if let Err(err) = Self::wipe_storage(storage, &layout) {
+            warn!("Wiping the device failed: {}", err);
+            self.show_wipe_failed();
+            return;
+        }
+        self.start_over(storage, layout, "Device wiped");
+    }
+
+    /// Erases the secrets and recreates an empty database.
+    fn wipe_storage<S: NorFlash>(storage: &mut S, layout: &StorageLayout) -> Result<(), KDBError> {
+        layout.wipe_secrets(storage)?;
+        let keepass_region = layout.region_handle(DataRegion::KeePassDb)?;
+        KeePassDb::initialize_db(storage, keepass_region)
+    }
+
+    /// Drops every screen and whatever is left of the database in RAM, and
+    /// leaves the wipe-failed screen alone on the stack.
+    fn show_wipe_failed(&mut self) {
+        if let Some(kpdb) = self.kpdb.as_mut() {
+            kpdb.lock();
+        }
+        self.cancel_transfer(TransferError::Locked);
+        self.screen_stack = core::array::from_fn(|_| None);
+        self.screen_stack[0] = Some(Screens::wipe_failed());
+        self.selected.select_first();
+        *self.selected.offset_mut() = 0;
+    }
+
+    /// Erases every region and the layout, checks the flash reads back
+    /// erased, and starts over from a blank device waiting for a first PIN.
+    fn factory_reset<S: NorFlash>(&mut self, storage: &mut S) {
+        // Whatever happens to the flash, the unlocked database goes
+        self.lock();
+        if let Err(err) = boot::factory_reset(storage) {
+            warn!("factory_reset failed: {}", err);
+            self.push_screen(Screens::action_completed("Reset failed"));
+            return;
+        }
+
+        match StorageLayout::new(storage) {
+            Ok(layout) => self.start_over(storage, layout, "Device reset"),
+            Err(err) => warn!("Reading the new layout failed: {}", err),
+        }
+    }
+
+    /// Reloads the empty database and PIN state left by a wipe or a factory
+    /// reset, and returns to the PIN screen, which asks for a new PIN.
+    fn start_over<S: NorFlash>(&mut self, storage: &mut S, layout: StorageLayout, message: &str) {
+        // 1. Reload the now empty database and PIN state
+        self.cancel_transfer(TransferError::Locked);
+        self.locked_until = None;
+        self.layout = Some(layout);
+        self.kpdb = KeePassDb::new(storage, &layout).ok();
+        self.user_config = match layout.region_handle(DataRegion::UserConfig) {
+            Ok(region) => UserConfig::load(storage, region).ok(),
+            Err(err) => {
+                warn!("No UserConfig region: {}", err);
+                None
+            }
+        };
+
+        // 2. Back to the PIN screen
+        let mut screen = screens::pin_entry::PinEntryScreen::new();
+        if let Some(user_config) = self.user_config.as_ref() {
+            screen.set_policy(user_config.settings().pin_policy);
+        }
+        self.reset_screen_stack(Screens::PinEntry(screen));
+        self.push_screen(Screens::action_completed(message));
+    }
+
+    /// Takes a request the host sent over USB. The user confirms it on the
+    /// device, and only while the database is unlocked. Imports also need it
+    /// writable, which is why [`ScreenAction::AcceptTransfer`] isn't checked
+    /// against read-only mode.
+    pub fn on_transfer_request(&mut self, request: Request) {
+        let unlocked = self.kpdb.as_ref().is_some_and(KeePassDb::is_unlocked);
+        let import = matches!(request, Request::Import(_));
+        let refusal = if self.read_only && import {
+            Some(TransferError::ReadOnly)
+        } else if !unlocked {
+            Some(TransferError::Locked)
+        } else if self.transfer.is_some() || self.export.is_some() {
+            Some(TransferError::Refused)
+        } else {
+            None
+        };
+        if let Some(err) = refusal {
+            usb_transfer::reply(Reply::Failed(err));
+            return;
+        }
+
+        self.push_screen(Screens::confirm_transfer(&request));
+        self.transfer = Some(request);
+    }
+
+    /// Sends the database for an export the user accepted. The main loop
+    /// runs it after drawing, since it waits on the USB task to take each
+    /// piece of the file.
+    pub async fn run_export(&mut self) {
+        let Some(request) = self.export.take() else {
+            return;
+        };
+        let reply = match self.kpdb.as_ref() {
+            Some(kpdb) => usb_transfer::export(kpdb, request, usb_transfer::send_reply).await,
+            None => Reply::Failed(TransferError::Locked),
+        };
+        let message = match reply {
+            Reply::Exported { .. } => "Export done",
+            _ => "Export failed",
+        };
+        usb_transfer::send_reply(reply).await;
+        self.push_screen(Screens::action_completed(message));
+    }
+
+    /// Answers a request still waiting on the user, or an export not yet
+    /// started, with `err`.
+    fn cancel_transfer(&mut self, err: TransferError) {
+        if self.transfer.take().is_some() || self.export.take().is_some() {
+            usb_transfer::reply(Reply::Failed(err));
+        }
+    }
+
+    /// Drops every screen (and whatever plaintext they cached) and leaves the
+    /// group list with `pin_screen` on top of it.
+    fn reset_screen_stack(&mut self, pin_screen: Screens) {
+        self.screen_stack = core::array::from_fn(|_| None);
+        self.screen_stack[0] = Some(Screens::select_group());
+        self.screen_stack[1] = Some(pin_screen);
+        self.selected.select_first();
+        *self.selected.offset_mut() = 0;
+    }
+
     fn push_screen(&mut self, screen: Screens) {
         // Find the next empty slot
         for i in 0..self.screen_stack.len() {
@@ -437,15 +1081,13 @@ impl AppState {
     }
 }
 
+/// When a lockout of `lockout` starting now ends, or `None` for no lockout.
+fn lockout_end(lockout: Duration) -> Option<Instant> {
+    (lockout.as_ticks() > 0).then(|| Instant::now() + lockout)
+}
+
 impl Default for AppState {
     fn default() -> Self {
         Self::new()
     }
 }
-
-fn fill_fixed(dst: &mut [u8], value: &str) {
-    dst.fill(0);
-    let bytes = value.as_bytes();
-    let len = bytes.len().min(dst.len());
-    dst[..len].copy_from_slice(&bytes[..len]);
-}
@@ -0,0 +1,94 @@
+use defmt::Format;
+use ratatui::Frame;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, List, ListState, Paragraph};
+
+use crate::app::ScreenAction;
+use crate::app::screens::Screen;
+use crate::keepass::KeePassDb;
+use crate::usb_transfer::{FileFormat, Request};
+
+pub const ITEMS: usize = 2;
+/// "Cancel" comes first, so a stray click doesn't replace or send the database.
+pub const IMPORT_LABELS: [&str; ITEMS] = ["Cancel", "Import"];
+pub const EXPORT_LABELS: [&str; ITEMS] = ["Cancel", "Export"];
+
+/// Asks before a file sent over USB replaces the database, or before the
+/// database is sent to the host.
+#[derive(Debug, Format)]
+pub struct ConfirmTransferScreen {
+    export: bool,
+    format: FileFormat,
+}
+
+impl ConfirmTransferScreen {
+    pub fn for_request(request: &Request) -> Self {
+        Self {
+            export: matches!(request, Request::Export(_)),
+            format: request.format(),
+        }
+    }
+}
+
+impl Screen for ConfirmTransferScreen {
+    fn new() -> Self {
+        Self {
+            export: false,
+            format: FileFormat::Kdbx,
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame, selected: &mut ListState, _: &KeePassDb) {
+        let area = frame.area();
+        if area.is_empty() {
+            return;
+        }
+
+        let title = if self.export {
+            " USB export "
+        } else {
+            " USB import "
+        };
+        let outer_block = Block::bordered()
+            .border_style(Style::new().bold().red())
+            .title(title);
+        let inner = outer_block.inner(area);
+        frame.render_widget(outer_block, area);
+
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(2), Constraint::Min(0)])
+            .split(inner);
+
+        let message = match (self.export, self.format) {
+            (false, FileFormat::Kdb) => "Replace all passwords\nwith the .kdb file?",
+            (false, FileFormat::Kdbx) => "Replace all passwords\nwith the .kdbx file?",
+            (true, FileFormat::Kdb) => "Send all passwords\nas a .kdb file?",
+            (true, FileFormat::Kdbx) => "Send all passwords\nas a .kdbx file?",
+        };
+        frame.render_widget(
+            Paragraph::new(message).style(Style::new().bold()),
+            chunks[0],
+        );
+
+        let labels = if self.export {
+            EXPORT_LABELS
+        } else {
+            IMPORT_LABELS
+        };
+        let list = List::new(labels)
+            .style(Style::new())
+            .highlight_style(Style::new().bold().bg(Color::White).fg(Color::Black))
+            .highlight_symbol(">> ");
+        frame.render_stateful_widget(list, chunks[1], selected);
+    }
+
+    fn on_select(&mut self, selected: Option<usize>) -> ScreenAction {
+        match selected {
+            Some(0) => ScreenAction::RejectTransfer,
+            Some(1) => ScreenAction::AcceptTransfer,
+            _ => ScreenAction::None,
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use core::fmt::Write;
+
+use defmt::Format;
+use heapless::{String, Vec};
+use ratatui::Frame;
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, List, ListState};
+
+use crate::app::ScreenAction;
+use crate::app::screens::Screen;
+use crate::keepass::KeePassDb;
+use crate::storage::diagnostics::{Diagnostics, RegionUsage};
+use crate::storage::layout::REGION_COUNT;
+use crate::storage::region::DataRegion;
+
+const LINE_CAP: usize = 24;
+/// Firmware, layout, groups, entries, log space, wear, one line per region and "Back".
+pub const MAX_LINES: usize = 7 + REGION_COUNT;
+
+/// Read-only report of how full the storage is and whether its regions pass
+/// their CRC check. Selecting any line goes back.
+#[derive(Debug, Format)]
+pub struct DiagnosticsScreen {
+    lines: Vec<String<LINE_CAP>, MAX_LINES>,
+}
+
+impl DiagnosticsScreen {
+    pub fn new(diagnostics: &Diagnostics) -> Self {
+        let mut lines = Vec::new();
+        let mut line = |args: core::fmt::Arguments| {
+            let mut text: String<LINE_CAP> = String::new();
+            let _ = text.write_fmt(args);
+            let _ = lines.push(text);
+        };
+
+        line(format_args!("Firmware {}", diagnostics.firmware_version));
+        line(format_args!("Layout v{}", diagnostics.layout_version));
+        line(format_args!(
+            "Groups {}/{}{}",
+            diagnostics.groups,
+            diagnostics.max_groups,
+            near_limit_mark(diagnostics.groups, diagnostics.max_groups)
+        ));
+        line(format_args!(
+            "Entries {}/{}{}",
+            diagnostics.entries,
+            diagnostics.max_entries,
+            near_limit_mark(diagnostics.entries, diagnostics.max_entries)
+        ));
+        line(format_args!("Log free {}", Size(diagnostics.log_free)));
+        let (min_erases, max_erases) = diagnostics.erase_counts;
+        line(format_args!("Erases {}-{}", min_erases, max_erases));
+        for usage in &diagnostics.regions {
+            line(format_args!(
+                "{} {}/{}{} {}",
+                region_label(usage.region),
+                Size(usage.used),
+                Size(usage.capacity),
+                near_limit_mark(usage.used, usage.capacity),
+                crc_label(usage)
+            ));
+        }
+        line(format_args!("Back"));
+
+        Self { lines }
+    }
+
+    pub fn item_count(&self) -> usize {
+        self.lines.len()
+    }
+}
+
+impl Screen for DiagnosticsScreen {
+    fn new() -> Self {
+        Self { lines: Vec::new() }
+    }
+
+    fn draw(&mut self, frame: &mut Frame, selected: &mut ListState, _: &KeePassDb) {
+        let outer_block = Block::bordered()
+            .border_style(Style::new().bold().green())
+            .title(" Diagnostics ");
+
+        let list = List::new(self.lines.iter().map(String::as_str))
+            .block(outer_block)
+            .style(Style::new())
+            .highlight_style(Style::new().bold().bg(Color::White).fg(Color::Black))
+            .highlight_symbol(">> ");
+        frame.render_stateful_widget(list, frame.area(), selected);
+    }
+
+    fn on_select(&mut self, _selected: Option<usize>) -> ScreenAction {
+        ScreenAction::Pop
+    }
+}
+
+/// Byte count shown in bytes below 1 KiB and in whole KiB, rounded up, above.
+struct Size(u32);
+
+impl core::fmt::Display for Size {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.0 < 1024 {
+            write!(f, "{}B", self.0)
+        } else {
+            write!(f, "{}K", self.0.div_ceil(1024))
+        }
+    }
+}
+
+/// "!" once at least 90% of `limit` is used.
+fn near_limit_mark(used: u32, limit: u32) -> &'static str {
+    if limit > 0 && u64::from(used) * 10 >= u64::from(limit) * 9 {
+        "!"
+    } else {
+        ""
+    }
+}
+
+fn region_label(region: DataRegion) -> &'static str {
+    match region {
+        DataRegion::ProjectConfig => "Proj",
+        DataRegion::UserConfig => "User",
+        DataRegion::KeePassDb => "DB",
+        DataRegion::Scratch => "Scratch",
+    }
+}
+
+fn crc_label(usage: &RegionUsage) -> &'static str {
+    if usage.crc_ok { "ok" } else { "CRC!" }
+}
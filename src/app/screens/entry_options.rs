@@ -6,17 +6,19 @@ use ratatui::style::{Color, Style};
 use ratatui::widgets::{Block, List, ListState};
 
 use crate::app::screens::Screen;
-use crate::app::screens::text_entry_form::MAX_TEXT_LEN;
 use crate::app::{ScreenAction, Screens};
 use crate::keepass::KeePassDb;
+use crate::keepass::entry::MAX_TITLE_LEN;
 
-pub const ITEMS: usize = 7;
+pub const ITEMS: usize = 10;
 const AUTOTYPE_LABEL_CAP: usize = 20;
 
 #[derive(Clone, Copy, Debug, Format, Eq, PartialEq)]
 pub enum EntryField {
     Title,
     Username,
+    Url,
+    Notes,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -24,7 +26,10 @@ enum EntryOption {
     TypePassword,
     ChangeName,
     ChangeUsername,
+    ChangeUrl,
+    ChangeNotes,
     ViewPassword,
+    ViewNotes,
     ToggleAutotype,
     Back,
     DeleteEntry,
@@ -34,8 +39,7 @@ enum EntryOption {
 pub struct EntryOptionsScreen {
     entry_index: usize,
     autotype: bool,
-    title: String<MAX_TEXT_LEN>,
-    username: String<MAX_TEXT_LEN>,
+    title: String<MAX_TITLE_LEN>,
     autotype_label: String<AUTOTYPE_LABEL_CAP>,
     pending_field: Option<EntryField>,
     entry_present: bool,
@@ -47,7 +51,6 @@ impl EntryOptionsScreen {
             entry_index,
             autotype: false,
             title: String::new(),
-            username: String::new(),
             autotype_label: String::new(),
             pending_field: None,
             entry_present: false,
@@ -63,7 +66,8 @@ impl EntryOptionsScreen {
             return 1;
         };
 
-        let mut count = 6usize; // name, username, view, autotype, back, delete
+        // name, username, URL, notes, view password, view notes, autotype, back, delete
+        let mut count = 9usize;
         if entry.autotype {
             count = count.saturating_add(1);
         }
@@ -75,6 +79,11 @@ impl EntryOptionsScreen {
         self.entry_index
     }
 
+    /// Row of the autotype toggle, which moves down when "Type password" shows.
+    pub fn autotype_row(autotype: bool) -> usize {
+        if autotype { 7 } else { 6 }
+    }
+
     pub fn take_pending_field(&mut self) -> Option<EntryField> {
         self.pending_field.take()
     }
@@ -92,18 +101,26 @@ impl EntryOptionsScreen {
         match idx {
             0 => Some(EntryOption::ChangeName),
             1 => Some(EntryOption::ChangeUsername),
-            2 => Some(EntryOption::ViewPassword),
-            3 => Some(EntryOption::ToggleAutotype),
-            4 => Some(EntryOption::Back),
-            5 => Some(EntryOption::DeleteEntry),
+            2 => Some(EntryOption::ChangeUrl),
+            3 => Some(EntryOption::ChangeNotes),
+            4 => Some(EntryOption::ViewPassword),
+            5 => Some(EntryOption::ViewNotes),
+            6 => Some(EntryOption::ToggleAutotype),
+            7 => Some(EntryOption::Back),
+            8 => Some(EntryOption::DeleteEntry),
             _ => None,
         }
     }
 
-    fn sync_text(dst: &mut String<MAX_TEXT_LEN>, src: &[u8]) {
+    /// Opens the keyboard on `field`; the submitted text goes back to it.
+    fn edit(&mut self, field: EntryField) -> ScreenAction {
+        self.pending_field = Some(field);
+        ScreenAction::EditEntryField(self.entry_index, field)
+    }
+
+    fn sync_text(dst: &mut String<MAX_TITLE_LEN>, src: &[u8]) {
         dst.clear();
-        let end = src.iter().position(|&b| b == 0).unwrap_or(src.len());
-        let Ok(text) = core::str::from_utf8(&src[..end]) else {
+        let Ok(text) = core::str::from_utf8(src) else {
             return;
         };
         let _ = dst.push_str(text);
@@ -118,15 +135,13 @@ impl EntryOptionsScreen {
             self.entry_present = false;
             self.autotype = false;
             self.title.clear();
-            self.username.clear();
             self.autotype_label.clear();
             return;
         };
 
         self.entry_present = true;
         self.autotype = entry.autotype;
-        Self::sync_text(&mut self.title, &entry.title);
-        Self::sync_text(&mut self.username, &entry.username);
+        Self::sync_text(&mut self.title, entry.title());
 
         self.autotype_label.clear();
         let _ = self.autotype_label.push_str("Autotype: ");
@@ -144,7 +159,7 @@ impl Screen for EntryOptionsScreen {
     fn draw(&mut self, frame: &mut Frame, selected: &mut ListState, kpdb: &KeePassDb) {
         self.sync_from_entry(kpdb);
 
-        let mut title_padded: String<{ MAX_TEXT_LEN + 2 }> = String::new();
+        let mut title_padded: String<{ MAX_TITLE_LEN + 2 }> = String::new();
         if self.entry_present && !self.title.is_empty() {
             let _ = title_padded.push(' ');
             let _ = title_padded.push_str(self.title.as_str());
@@ -164,7 +179,10 @@ impl Screen for EntryOptionsScreen {
             }
             let _ = items.push("Change name");
             let _ = items.push("Change username");
+            let _ = items.push("Change URL");
+            let _ = items.push("Change notes");
             let _ = items.push("View password");
+            let _ = items.push("View notes");
             let _ = items.push(self.autotype_label.as_str());
         }
         let _ = items.push("Back");
@@ -193,17 +211,16 @@ impl Screen for EntryOptionsScreen {
 
         match self.option_at(selected) {
             Some(EntryOption::TypePassword) => ScreenAction::TypeEntryPassword(self.entry_index),
-            Some(EntryOption::ChangeName) => {
-                self.pending_field = Some(EntryField::Title);
-                ScreenAction::Push(Screens::text_entry_form(self.title.as_str()))
-            }
-            Some(EntryOption::ChangeUsername) => {
-                self.pending_field = Some(EntryField::Username);
-                ScreenAction::Push(Screens::text_entry_form(self.username.as_str()))
-            }
+            Some(EntryOption::ChangeName) => self.edit(EntryField::Title),
+            Some(EntryOption::ChangeUsername) => self.edit(EntryField::Username),
+            Some(EntryOption::ChangeUrl) => self.edit(EntryField::Url),
+            Some(EntryOption::ChangeNotes) => self.edit(EntryField::Notes),
             Some(EntryOption::ViewPassword) => {
                 ScreenAction::Push(Screens::view_password(self.entry_index))
             }
+            Some(EntryOption::ViewNotes) => {
+                ScreenAction::Push(Screens::view_notes(self.entry_index))
+            }
             Some(EntryOption::ToggleAutotype) => {
                 ScreenAction::ToggleEntryAutotype(self.entry_index)
             }
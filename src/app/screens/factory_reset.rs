@@ -0,0 +1,59 @@
+use defmt::Format;
+use ratatui::Frame;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, List, ListState, Paragraph};
+
+use crate::app::ScreenAction;
+use crate::app::screens::Screen;
+use crate::keepass::KeePassDb;
+
+pub const ITEMS: usize = 2;
+/// "Cancel" comes first, so it is what is selected when the dialog opens.
+pub const LABELS: [&str; ITEMS] = ["Cancel", "Erase everything"];
+
+/// Confirmation asked before a factory reset from the settings menu.
+#[derive(Debug, Format)]
+pub struct FactoryResetScreen {}
+
+impl Screen for FactoryResetScreen {
+    fn new() -> Self {
+        Self {}
+    }
+
+    fn draw(&mut self, frame: &mut Frame, selected: &mut ListState, _: &KeePassDb) {
+        let area = frame.area();
+        if area.is_empty() {
+            return;
+        }
+
+        let outer_block = Block::bordered()
+            .border_style(Style::new().bold().red())
+            .title(" Factory reset ");
+        let inner = outer_block.inner(area);
+        frame.render_widget(outer_block, area);
+
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(2), Constraint::Min(0)])
+            .split(inner);
+
+        let message = Paragraph::new("Erase all passwords,\nthe PIN and settings?")
+            .style(Style::new().bold());
+        frame.render_widget(message, chunks[0]);
+
+        let list = List::new(LABELS)
+            .style(Style::new())
+            .highlight_style(Style::new().bold().bg(Color::White).fg(Color::Black))
+            .highlight_symbol(">> ");
+        frame.render_stateful_widget(list, chunks[1], selected);
+    }
+
+    fn on_select(&mut self, selected: Option<usize>) -> ScreenAction {
+        match selected {
+            Some(0) => ScreenAction::Pop,
+            Some(1) => ScreenAction::FactoryReset,
+            _ => ScreenAction::None,
+        }
+    }
+}
@@ -1,13 +1,20 @@
 pub mod action_completed;
 pub mod boot_splash;
+pub mod confirm_transfer;
+pub mod diagnostics;
 pub mod entry_options;
+pub mod factory_reset;
 pub mod new_entry_form;
 pub mod new_group_form;
 pub mod pin_entry;
+pub mod recovery;
 pub mod select_entry;
 pub mod select_group;
+pub mod settings;
 pub mod text_entry_form;
+pub mod view_notes;
 pub mod view_password;
+pub mod wipe_failed;
 use ratatui::{Frame, widgets::ListState};
 
 use crate::{app::ScreenAction, keepass::KeePassDb};
@@ -1,5 +1,6 @@
+use core::fmt;
+
 use defmt::Format;
-use esp_hal::rng::Rng;
 use heapless::String;
 use ratatui::Frame;
 use ratatui::style::{Color, Style};
@@ -8,7 +9,9 @@ use ratatui::widgets::{Block, List, ListState};
 use crate::app::screens::Screen;
 use crate::app::screens::text_entry_form::MAX_TEXT_LEN;
 use crate::app::{ScreenAction, Screens};
-use crate::keepass::{Entry, KeePassDb};
+use crate::encryption;
+use crate::keepass::{Entry, KDBError, KeePassDb};
+use crate::secret::{Redacted, SecretBytes};
 
 pub const ITEMS: usize = 4;
 pub const LABELS: [&str; ITEMS] = ["Title", "Username", "Create", "Back"];
@@ -21,7 +24,6 @@ enum EntryField {
     Username,
 }
 
-#[derive(Debug, Format)]
 pub struct NewEntryFormScreen {
     pub group_id: Option<u32>,
     title: String<MAX_TEXT_LEN>,
@@ -29,6 +31,30 @@ pub struct NewEntryFormScreen {
     pending_field: Option<EntryField>,
 }
 
+impl fmt::Debug for NewEntryFormScreen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NewEntryFormScreen")
+            .field("group_id", &self.group_id)
+            .field("title", &Redacted(self.title.as_bytes()))
+            .field("username", &Redacted(self.username.as_bytes()))
+            .field("pending_field", &self.pending_field)
+            .finish()
+    }
+}
+
+impl Format for NewEntryFormScreen {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "NewEntryFormScreen {{ group_id: {}, title: {}, username: {}, pending_field: {} }}",
+            self.group_id,
+            Redacted(self.title.as_bytes()),
+            Redacted(self.username.as_bytes()),
+            self.pending_field,
+        );
+    }
+}
+
 impl NewEntryFormScreen {
     pub fn new(group_id: Option<u32>) -> Self {
         Self {
@@ -53,40 +79,34 @@ impl NewEntryFormScreen {
         }
     }
 
-    fn entry_from_form(&self) -> Entry {
+    /// Fails with [`KDBError::FieldTooLong`] if a field is longer than an
+    /// entry holds.
+    fn entry_from_form(&self) -> Result<Entry, KDBError> {
         let mut entry = Entry::default_with_group_id(self.group_id.unwrap_or(0));
-        Self::fill_random_password(&mut entry.password);
+        let mut password = SecretBytes::<PASSWORD_LEN>::zeroed();
+        Self::fill_random_password(password.expose_mut());
+        entry.set_password(password.expose())?;
 
         if !self.title.is_empty() {
-            entry.title.fill(0);
-            let bytes = self.title.as_bytes();
-            let len = bytes.len().min(entry.title.len());
-            entry.title[..len].copy_from_slice(&bytes[..len]);
+            entry.set_title(self.title.as_bytes())?;
         }
 
         if !self.username.is_empty() {
-            entry.username.fill(0);
-            let bytes = self.username.as_bytes();
-            let len = bytes.len().min(entry.username.len());
-            entry.username[..len].copy_from_slice(&bytes[..len]);
+            entry.set_username(self.username.as_bytes())?;
         }
 
-        entry
+        Ok(entry)
     }
 
-    fn fill_random_password(dst: &mut [u8; 64]) {
-        dst.fill(0);
-
-        let rng = Rng::new();
+    fn fill_random_password(dst: &mut [u8; PASSWORD_LEN]) {
         let charset = PASSWORD_CHARSET;
         let m = charset.len() as u16;
         let zone = 256u16 - (256u16 % m);
 
-        let out_len = PASSWORD_LEN.min(dst.len().saturating_sub(1));
-        for byte in dst.iter_mut().take(out_len) {
+        for byte in dst.iter_mut() {
             let mut raw = [0u8; 1];
             loop {
-                rng.read(&mut raw);
+                encryption::fill_random(&mut raw);
                 if (raw[0] as u16) < zone {
                     break;
                 }
@@ -125,7 +145,10 @@ impl Screen for NewEntryFormScreen {
                 self.pending_field = Some(EntryField::Username);
                 ScreenAction::Push(Screens::text_entry_form(self.username.as_str()))
             }
-            Some(2) => ScreenAction::CreateEntry(self.entry_from_form()),
+            Some(2) => match self.entry_from_form() {
+                Ok(entry) => ScreenAction::CreateEntry(entry),
+                Err(_) => ScreenAction::Push(Screens::action_completed("Text too long")),
+            },
             Some(3) => ScreenAction::Pop,
             _ => ScreenAction::None,
         }
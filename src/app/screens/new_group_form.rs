@@ -1,3 +1,5 @@
+use core::fmt;
+
 use defmt::Format;
 use heapless::String;
 use ratatui::Frame;
@@ -8,32 +10,53 @@ use crate::app::screens::Screen;
 use crate::app::screens::text_entry_form::MAX_TEXT_LEN;
 use crate::app::{ScreenAction, Screens};
 use crate::keepass::{Group, KeePassDb};
+use crate::secret::Redacted;
 
 pub const ITEMS: usize = 3;
 pub const LABELS: [&str; ITEMS] = ["Name", "Create", "Back"];
 
-#[derive(Debug, Format)]
 pub struct NewGroupForm {
     name: String<MAX_TEXT_LEN>,
 }
 
+impl fmt::Debug for NewGroupForm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NewGroupForm")
+            .field("name", &Redacted(self.name.as_bytes()))
+            .finish()
+    }
+}
+
+impl Format for NewGroupForm {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "NewGroupForm {{ name: {} }}",
+            Redacted(self.name.as_bytes())
+        );
+    }
+}
+
 impl NewGroupForm {
     pub fn set_name(&mut self, value: &str) {
         self.name.clear();
         let _ = self.name.push_str(value);
     }
 
-    fn group_from_form(&self) -> Group {
+    /// `None` if the name is longer than a group holds.
+    fn group_from_form(&self) -> Option<Group> {
         let mut group = Group::random();
         if self.name.is_empty() {
-            return group;
+            return Some(group);
         }
 
-        group.name.fill(0);
         let bytes = self.name.as_bytes();
-        let len = bytes.len().min(group.name.len());
-        group.name[..len].copy_from_slice(&bytes[..len]);
-        group
+        if bytes.len() > group.name.len() {
+            return None;
+        }
+        group.name.fill(0);
+        group.name[..bytes.len()].copy_from_slice(bytes);
+        Some(group)
     }
 }
 
@@ -61,7 +84,10 @@ impl Screen for NewGroupForm {
     fn on_select(&mut self, selected: Option<usize>) -> ScreenAction {
         match selected {
             Some(0) => ScreenAction::Push(Screens::text_entry_form(self.name.as_str())),
-            Some(1) => ScreenAction::CreateGroup(self.group_from_form()),
+            Some(1) => match self.group_from_form() {
+                Some(group) => ScreenAction::CreateGroup(group),
+                None => ScreenAction::Push(Screens::action_completed("Name too long")),
+            },
             Some(2) => ScreenAction::Pop,
             _ => ScreenAction::None,
         }
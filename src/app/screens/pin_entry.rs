@@ -1,5 +1,7 @@
+use core::fmt::Write;
+
 use defmt::Format;
-use esp_hal::rng::Rng;
+use embassy_time::{Duration, Instant};
 use heapless::{String, Vec};
 use ratatui::Frame;
 use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
@@ -8,37 +10,225 @@ use ratatui::widgets::{Block, ListState, Paragraph};
 
 use crate::app::ScreenAction;
 use crate::app::screens::Screen;
+use crate::app::screens::text_entry_form::TextEntryFormScreen;
+use crate::encryption;
 use crate::keepass::KeePassDb;
+use crate::secret::SecretString;
+use crate::storage::settings::PinPolicy;
+use crate::storage::user_config::{MAX_PIN_LEN, UnlockMode};
 
-const PIN_LEN: usize = 4;
 const DIGIT_COUNT: usize = 10;
-const DIGIT_LINE_CAP: usize = 32;
+/// Digits plus "OK", "Del" and, while setting a new secret, "Aa".
+const KEY_CAP: usize = DIGIT_COUNT + 3;
+const KEY_LINE_CAP: usize = 40;
 const BLINK_PERIOD_FRAMES: usize = 20;
+const STATUS_LINE_CAP: usize = 24;
+/// Longest masked line drawn; the panel is narrower than this.
+const MASK_CAP: usize = 32;
+
+/// What the secret typed on the screen is used for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Format)]
+pub enum PinPurpose {
+    /// Unlock the database, or seal a fresh one with its first secret.
+    Unlock,
+    /// Confirm the current secret before changing it.
+    Current,
+    /// Choose the secret the database gets re-encrypted with.
+    New,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum PinKey {
+    Digit(u8),
+    Ok,
+    Delete,
+    /// Switches to setting a passphrase instead of a PIN.
+    Passphrase,
+}
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-struct DigitSpan {
+struct KeySpan {
     start: usize,
     width: usize,
 }
 
 #[derive(Debug, Format)]
 pub struct PinEntryScreen {
-    pin: String<PIN_LEN>,
+    pin: SecretString<MAX_PIN_LEN>,
+    purpose: PinPurpose,
+    /// `None` while setting a new secret, otherwise how the database unlocks.
+    unlock_mode: Option<UnlockMode>,
+    /// Keyboard used in passphrase mode instead of the digit row.
+    passphrase: Option<TextEntryFormScreen>,
     digit_order: [u8; DIGIT_COUNT],
     last_rendered_selected: Option<usize>,
+    rejected: bool,
+    remaining_attempts: Option<u32>,
+    locked_until: Option<Instant>,
+    /// Shortest PIN and passphrase accepted when setting one.
+    policy: PinPolicy,
 }
 
 impl PinEntryScreen {
+    /// Screen for setting the first secret of a fresh database.
     pub fn new() -> Self {
         Self {
-            pin: String::new(),
+            pin: SecretString::new(),
+            purpose: PinPurpose::Unlock,
+            unlock_mode: None,
+            passphrase: None,
             digit_order: Self::shuffled_digits(),
             last_rendered_selected: None,
+            rejected: false,
+            remaining_attempts: None,
+            locked_until: None,
+            policy: PinPolicy::default(),
         }
     }
 
+    /// Screen for unlocking a database sealed with `unlock_mode`.
+    pub fn unlock(unlock_mode: UnlockMode) -> Self {
+        let mut screen = Self::new();
+        screen.unlock_mode = Some(unlock_mode);
+        if unlock_mode == UnlockMode::Passphrase {
+            screen.passphrase = Some(TextEntryFormScreen::new_with_text(""));
+        }
+        screen
+    }
+
+    /// Screen asking for the current secret before it gets changed.
+    pub fn current(unlock_mode: UnlockMode) -> Self {
+        let mut screen = Self::unlock(unlock_mode);
+        screen.purpose = PinPurpose::Current;
+        screen
+    }
+
+    /// Screen for choosing a new secret for an already sealed database.
+    pub fn new_secret() -> Self {
+        let mut screen = Self::new();
+        screen.purpose = PinPurpose::New;
+        screen
+    }
+
+    pub fn purpose(&self) -> PinPurpose {
+        self.purpose
+    }
+
+    pub fn set_policy(&mut self, policy: PinPolicy) {
+        self.policy = policy;
+    }
+
+    /// Shows the persisted attempt budget and blocks input for `lockout`.
+    ///
+    /// `remaining_attempts` is only displayed once at least one attempt failed.
+    pub fn set_attempts(
+        &mut self,
+        failed_attempts: u32,
+        remaining_attempts: u32,
+        lockout: Duration,
+    ) {
+        self.remaining_attempts = (failed_attempts > 0).then_some(remaining_attempts);
+        self.locked_until = (lockout.as_ticks() > 0).then(|| Instant::now() + lockout);
+    }
+
+    /// Clears the typed secret after a failed unlock and reshuffles the digits.
+    pub fn reject(&mut self, failed_attempts: u32, remaining_attempts: u32, lockout: Duration) {
+        self.pin.clear();
+        if let Some(passphrase) = self.passphrase.as_mut() {
+            passphrase.clear();
+        }
+        self.digit_order = Self::shuffled_digits();
+        self.rejected = true;
+        self.set_attempts(failed_attempts, remaining_attempts, lockout);
+    }
+
+    fn lockout_remaining_secs(&self) -> Option<u64> {
+        let locked_until = self.locked_until?;
+        let now = Instant::now();
+        if now >= locked_until {
+            return None;
+        }
+        // Round up so the countdown never shows "0s" while still locked.
+        let millis = (locked_until - now).as_millis();
+        Some(millis.div_ceil(1000))
+    }
+
+    fn status_line(&self) -> String<STATUS_LINE_CAP> {
+        let mut out: String<STATUS_LINE_CAP> = String::new();
+        if let Some(secs) = self.lockout_remaining_secs() {
+            let _ = write!(out, "Wait {}s", secs);
+        } else if let Some(remaining) = self.remaining_attempts {
+            let _ = write!(out, "{} tries left", remaining);
+        }
+        out
+    }
+
     pub fn item_count(&self) -> usize {
-        DIGIT_COUNT
+        match self.passphrase.as_ref() {
+            Some(passphrase) => passphrase.item_count(),
+            None => self.key_count(),
+        }
+    }
+
+    fn enrolling(&self) -> bool {
+        self.unlock_mode.is_none()
+    }
+
+    fn key_count(&self) -> usize {
+        let passphrase = if self.enrolling() { 1 } else { 0 };
+        DIGIT_COUNT + 2 + passphrase
+    }
+
+    fn key_at(&self, index: usize) -> Option<PinKey> {
+        if let Some(digit) = self.digit_order.get(index) {
+            return Some(PinKey::Digit(*digit));
+        }
+
+        match index - DIGIT_COUNT {
+            0 => Some(PinKey::Ok),
+            1 => Some(PinKey::Delete),
+            2 if self.enrolling() => Some(PinKey::Passphrase),
+            _ => None,
+        }
+    }
+
+    fn label_for_key(key: PinKey) -> &'static str {
+        const DIGITS: [&str; DIGIT_COUNT] = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
+        match key {
+            PinKey::Digit(digit) => DIGITS.get(digit as usize).copied().unwrap_or("?"),
+            PinKey::Ok => "OK",
+            PinKey::Delete => "Del",
+            PinKey::Passphrase => "Aa",
+        }
+    }
+
+    /// Whether "OK" may submit the PIN typed so far. Unlocking needs the
+    /// recorded length when there is one, setting a PIN the policy's minimum.
+    fn pin_complete(&self) -> bool {
+        let len = self.pin.len();
+        match self.unlock_mode {
+            Some(UnlockMode::Pin {
+                len: Some(expected),
+            }) => len == expected as usize,
+            _ => (usize::from(self.policy.min_pin_len)..=MAX_PIN_LEN).contains(&len),
+        }
+    }
+
+    /// Upper bound for the PIN being typed, used for the blinking cursor.
+    fn pin_limit(&self) -> usize {
+        match self.unlock_mode {
+            Some(UnlockMode::Pin {
+                len: Some(expected),
+            }) => expected as usize,
+            _ => MAX_PIN_LEN,
+        }
+    }
+
+    fn typed_len(&self) -> usize {
+        match self.passphrase.as_ref() {
+            Some(passphrase) => passphrase.text().len(),
+            None => self.pin.len(),
+        }
     }
 
     fn shuffled_digits() -> [u8; DIGIT_COUNT] {
@@ -47,16 +237,15 @@ impl PinEntryScreen {
             *digit = idx as u8;
         }
 
-        let rng = Rng::new();
         for i in (1..DIGIT_COUNT).rev() {
-            let j = Self::random_index(&rng, (i as u8).saturating_add(1));
+            let j = Self::random_index((i as u8).saturating_add(1));
             digits.swap(i, j);
         }
 
         digits
     }
 
-    fn random_index(rng: &Rng, upper_exclusive: u8) -> usize {
+    fn random_index(upper_exclusive: u8) -> usize {
         if upper_exclusive <= 1 {
             return 0;
         }
@@ -66,19 +255,24 @@ impl PinEntryScreen {
         let mut raw = [0u8; 1];
 
         loop {
-            rng.read(&mut raw);
+            encryption::fill_random(&mut raw);
             if (raw[0] as u16) < zone {
                 return (raw[0] as u16 % m) as usize;
             }
         }
     }
 
-    fn build_digit_line(&self) -> (String<DIGIT_LINE_CAP>, Vec<DigitSpan, DIGIT_COUNT>) {
-        let mut line: String<DIGIT_LINE_CAP> = String::new();
-        let mut spans: Vec<DigitSpan, DIGIT_COUNT> = Vec::new();
+    fn build_key_line(&self) -> (String<KEY_LINE_CAP>, Vec<KeySpan, KEY_CAP>) {
+        let mut line: String<KEY_LINE_CAP> = String::new();
+        let mut spans: Vec<KeySpan, KEY_CAP> = Vec::new();
         let mut cursor: usize = 0;
 
-        for (idx, digit) in self.digit_order.iter().enumerate() {
+        for idx in 0..self.key_count() {
+            let Some(key) = self.key_at(idx) else {
+                continue;
+            };
+            let label = Self::label_for_key(key);
+
             if idx > 0 {
                 if line.push(' ').is_err() {
                     break;
@@ -87,32 +281,37 @@ impl PinEntryScreen {
             }
 
             if spans
-                .push(DigitSpan {
+                .push(KeySpan {
                     start: cursor,
-                    width: 1,
+                    width: label.len(),
                 })
                 .is_err()
             {
                 break;
             }
 
-            let ch = char::from(b'0' + *digit);
-            if line.push(ch).is_err() {
+            if line.push_str(label).is_err() {
                 break;
             }
-            cursor = cursor.saturating_add(1);
+            cursor = cursor.saturating_add(label.len());
         }
 
         (line, spans)
     }
 
-    fn masked_display(&self, frame: &Frame) -> String<{ PIN_LEN + 1 }> {
-        let mut out: String<{ PIN_LEN + 1 }> = String::new();
-        for _ in 0..self.pin.len() {
+    fn masked_display(&self, frame: &Frame, width: u16) -> String<{ MASK_CAP }> {
+        let mut out: String<{ MASK_CAP }> = String::new();
+        let typed = self.typed_len();
+        // Long passphrases are capped to the panel width so the cursor stays visible.
+        let shown = typed
+            .min((width as usize).saturating_sub(1))
+            .min(MASK_CAP - 1);
+        for _ in 0..shown {
             let _ = out.push('*');
         }
 
-        if self.pin.len() < PIN_LEN && (frame.count() / BLINK_PERIOD_FRAMES) % 2 == 0 {
+        let has_room = self.passphrase.is_some() || self.pin.len() < self.pin_limit();
+        if has_room && (frame.count() / BLINK_PERIOD_FRAMES) % 2 == 0 {
             let _ = out.push('_');
         }
 
@@ -120,7 +319,7 @@ impl PinEntryScreen {
     }
 
     fn push_digit(&mut self, digit: u8) {
-        if self.pin.len() >= PIN_LEN {
+        if self.pin.len() >= self.pin_limit() {
             return;
         }
         let ch = char::from(b'0' + digit);
@@ -133,19 +332,33 @@ impl Screen for PinEntryScreen {
         Self::new()
     }
 
-    fn draw(&mut self, frame: &mut Frame, selected: &mut ListState, _: &KeePassDb) {
+    fn draw(&mut self, frame: &mut Frame, selected: &mut ListState, kpdb: &KeePassDb) {
         let chunks = Layout::default()
             .direction(Direction::Vertical)
             .constraints([Constraint::Min(3), Constraint::Length(3)])
             .split(frame.area());
 
+        let passphrase = self.passphrase.is_some();
+        let title = match (self.rejected, self.purpose, kpdb.is_sealed()) {
+            (true, _, _) if passphrase => " Wrong passphrase ",
+            (true, _, _) => " Wrong PIN ",
+            (false, PinPurpose::Current, _) if passphrase => " Current passphrase ",
+            (false, PinPurpose::Current, _) => " Current PIN ",
+            (false, PinPurpose::New, _) if passphrase => " New passphrase ",
+            (false, PinPurpose::New, _) => " New PIN ",
+            (false, PinPurpose::Unlock, false) if passphrase => " Set passphrase ",
+            (false, PinPurpose::Unlock, false) => " Set PIN ",
+            (false, PinPurpose::Unlock, true) if passphrase => " Enter passphrase ",
+            (false, PinPurpose::Unlock, true) => " Enter PIN ",
+        };
         let top_block = Block::bordered()
             .border_style(Style::new().bold().green())
-            .title(" Enter PIN ");
+            .title(title);
         let top_inner = top_block.inner(chunks[0]);
         frame.render_widget(top_block, chunks[0]);
 
-        let display_text = self.masked_display(frame);
+        let display_text = self.masked_display(frame, top_inner.width);
+        let status = self.status_line();
         if top_inner.height > 0 && top_inner.width > 0 {
             let y = top_inner.y + top_inner.height / 2;
             let text_area = Rect {
@@ -159,6 +372,21 @@ impl Screen for PinEntryScreen {
                 .alignment(Alignment::Center)
                 .style(Style::new().bold());
             frame.render_widget(paragraph, text_area);
+
+            let status_y = y + 1;
+            if !status.is_empty() && status_y < top_inner.y + top_inner.height {
+                let status_area = Rect {
+                    y: status_y,
+                    ..text_area
+                };
+                let paragraph = Paragraph::new(status.as_str()).alignment(Alignment::Center);
+                frame.render_widget(paragraph, status_area);
+            }
+        }
+
+        if let Some(passphrase) = self.passphrase.as_mut() {
+            passphrase.draw_keyboard(frame, chunks[1], selected);
+            return;
         }
 
         let bottom_block = Block::bordered().border_style(Style::new().bold().green());
@@ -170,7 +398,7 @@ impl Screen for PinEntryScreen {
             return;
         }
 
-        let (digit_line, spans) = self.build_digit_line();
+        let (key_line, spans) = self.build_key_line();
         if spans.is_empty() {
             self.last_rendered_selected = Some(0);
             return;
@@ -183,12 +411,18 @@ impl Screen for PinEntryScreen {
         let selected_idx = selected_raw.min(spans.len().saturating_sub(1));
         self.last_rendered_selected = Some(selected_idx);
 
-        let paragraph = Paragraph::new(digit_line.as_str());
+        // The row is wider than the panel once "OK"/"Del" are added; scroll just
+        // enough to keep the selected key in view.
+        let scroll_x = spans
+            .get(selected_idx)
+            .map(|span| (span.start + span.width).saturating_sub(bottom_inner.width as usize))
+            .unwrap_or(0);
+        let paragraph = Paragraph::new(key_line.as_str()).scroll((0, scroll_x as u16));
         frame.render_widget(paragraph, bottom_inner);
 
         if let Some(span) = spans.get(selected_idx) {
             let highlight = Rect {
-                x: bottom_inner.x + span.start as u16,
+                x: bottom_inner.x + span.start.saturating_sub(scroll_x) as u16,
                 y: bottom_inner.y,
                 width: span.width as u16,
                 height: 1,
@@ -201,19 +435,64 @@ impl Screen for PinEntryScreen {
     }
 
     fn on_select(&mut self, selected: Option<usize>) -> ScreenAction {
+        if self.lockout_remaining_secs().is_some() {
+            return ScreenAction::None;
+        }
+
+        if let Some(passphrase) = self.passphrase.as_mut() {
+            return match passphrase.on_select(selected) {
+                ScreenAction::TextEntrySubmit(text) => {
+                    if self.unlock_mode.is_none()
+                        && text.len() < usize::from(self.policy.min_passphrase_len)
+                    {
+                        return ScreenAction::None;
+                    }
+                    self.rejected = false;
+                    ScreenAction::SubmitPassphrase(text)
+                }
+                // "Back" returns to the digits while the secret is being chosen,
+                // and cancels when confirming the current one.
+                ScreenAction::Pop if self.enrolling() => {
+                    self.passphrase = None;
+                    self.last_rendered_selected = None;
+                    ScreenAction::None
+                }
+                ScreenAction::Pop if self.purpose == PinPurpose::Current => ScreenAction::Pop,
+                _ => {
+                    self.rejected = false;
+                    ScreenAction::None
+                }
+            };
+        }
+
         let selected = self.last_rendered_selected.or(selected);
         let Some(selected) = selected else {
             return ScreenAction::None;
         };
 
-        if let Some(digit) = self.digit_order.get(selected).copied() {
-            self.push_digit(digit);
-        }
-
-        if self.pin.len() >= PIN_LEN {
-            ScreenAction::Pop
-        } else {
-            ScreenAction::None
+        match self.key_at(selected) {
+            Some(PinKey::Digit(digit)) => {
+                self.rejected = false;
+                self.push_digit(digit);
+                ScreenAction::None
+            }
+            Some(PinKey::Ok) if self.pin_complete() => ScreenAction::SubmitPin(self.pin.clone()),
+            // Deleting past the first digit cancels a PIN change. The unlock
+            // screen can't be left this way.
+            Some(PinKey::Delete) if self.pin.is_empty() && self.purpose != PinPurpose::Unlock => {
+                ScreenAction::Pop
+            }
+            Some(PinKey::Delete) => {
+                let _ = self.pin.pop();
+                ScreenAction::None
+            }
+            Some(PinKey::Passphrase) => {
+                self.pin.clear();
+                self.passphrase = Some(TextEntryFormScreen::new_with_text(""));
+                self.last_rendered_selected = None;
+                ScreenAction::None
+            }
+            _ => ScreenAction::None,
         }
     }
 }
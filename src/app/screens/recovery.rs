@@ -0,0 +1,120 @@
+use defmt::Format;
+use ratatui::Frame;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, List, ListState, Paragraph};
+
+use crate::keepass::KDBError;
+use crate::storage::boot::BootError;
+use crate::storage::layout::StorageError;
+
+pub const ITEMS: usize = 3;
+pub const LABELS: [&str; ITEMS] = ["Retry", "Factory reset", "Read-only"];
+const CONFIRM_RESET_LABEL: &str = "Really reset?";
+
+/// What to do about storage that failed to open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RecoveryChoice {
+    Retry,
+    FactoryReset,
+    ReadOnly,
+}
+
+/// Shown at boot instead of the app when the storage can't be opened. It
+/// runs before there is a database, so it is driven directly by `main`
+/// rather than through the screen stack.
+#[derive(Debug, Format)]
+pub struct RecoveryScreen {
+    error: BootError,
+    /// The factory reset needs a second press to go through.
+    confirm_reset: bool,
+}
+
+impl RecoveryScreen {
+    pub fn new(error: BootError) -> Self {
+        Self {
+            error,
+            confirm_reset: false,
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self.error {
+            BootError::Storage(StorageError::Io) | BootError::Database(KDBError::Io) => {
+                "Flash read failed"
+            }
+            BootError::Storage(StorageError::CorruptRegions(_)) => "Storage corrupted",
+            BootError::Storage(StorageError::NotErased(_)) => "Flash erase failed",
+            BootError::Storage(StorageError::NotBlank) => "Storage metadata bad",
+            BootError::Storage(StorageError::PartitionNotFound) => "No data partition",
+            BootError::Storage(StorageError::PartitionTooSmall(_)) => "Partition too small",
+            BootError::Storage(StorageError::InvalidPartition) => "Bad partition table",
+            BootError::Storage(StorageError::UnsupportedLayout(_))
+            | BootError::Database(KDBError::UnsupportedFormat(_)) => "Unknown data format",
+            BootError::Storage(_) => "Storage layout bad",
+            BootError::Database(_) => "Database corrupted",
+            BootError::WipePending => "Wipe pending",
+        }
+    }
+
+    /// Without a usable data partition there is no storage to retry, reset or
+    /// read, so the screen only reports the error; it needs a reflash.
+    pub fn recoverable(&self) -> bool {
+        !matches!(
+            self.error,
+            BootError::Storage(
+                StorageError::PartitionNotFound
+                    | StorageError::PartitionTooSmall(_)
+                    | StorageError::InvalidPartition
+            )
+        )
+    }
+
+    pub fn draw(&mut self, frame: &mut Frame, selected: &mut ListState) {
+        let area = frame.area();
+        if area.is_empty() {
+            return;
+        }
+
+        let outer_block = Block::bordered()
+            .border_style(Style::new().bold().red())
+            .title(" Storage error ");
+        let inner = outer_block.inner(area);
+        frame.render_widget(outer_block, area);
+
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(1), Constraint::Min(0)])
+            .split(inner);
+
+        let message = Paragraph::new(self.message()).style(Style::new().bold());
+        frame.render_widget(message, chunks[0]);
+        if !self.recoverable() {
+            return;
+        }
+
+        let mut labels = LABELS;
+        if self.confirm_reset {
+            labels[1] = CONFIRM_RESET_LABEL;
+        }
+        let list = List::new(labels)
+            .style(Style::new())
+            .highlight_style(Style::new().bold().bg(Color::White).fg(Color::Black))
+            .highlight_symbol(">> ");
+        frame.render_stateful_widget(list, chunks[1], selected);
+    }
+
+    pub fn on_select(&mut self, selected: Option<usize>) -> Option<RecoveryChoice> {
+        let confirm_reset = core::mem::take(&mut self.confirm_reset);
+        match selected {
+            Some(0) => Some(RecoveryChoice::Retry),
+            Some(1) if confirm_reset => Some(RecoveryChoice::FactoryReset),
+            Some(1) => {
+                self.confirm_reset = true;
+                None
+            }
+            Some(2) => Some(RecoveryChoice::ReadOnly),
+            _ => None,
+        }
+    }
+}
@@ -90,9 +90,7 @@ impl Screen for SelectEntryScreen {
                     break;
                 }
 
-                let title = &entry.title;
-                let end = title.iter().position(|&b| b == 0).unwrap_or(title.len());
-                let label = match core::str::from_utf8(&title[..end]) {
+                let label = match core::str::from_utf8(entry.title()) {
                     Ok("") => "<untitled>",
                     Ok(label) => label,
                     Err(_) => "<invalid utf8>",
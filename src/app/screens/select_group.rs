@@ -13,6 +13,7 @@ pub const ITEMS: usize = 4;
 #[derive(Debug, Format)]
 pub struct SelectGroupScreen {
     new_group_position: Option<usize>,
+    settings_position: Option<usize>,
 }
 
 impl SelectGroupScreen {
@@ -37,10 +38,11 @@ impl SelectGroupScreen {
             count = count.saturating_add(1);
         }
 
+        // "New group" while there is room, then "Settings".
         if count < ITEMS {
-            count.saturating_add(1)
+            count.saturating_add(2)
         } else {
-            count
+            count.saturating_add(1)
         }
     }
 }
@@ -49,6 +51,7 @@ impl Screen for SelectGroupScreen {
     fn new() -> Self {
         Self {
             new_group_position: None,
+            settings_position: None,
         }
     }
 
@@ -60,7 +63,7 @@ impl Screen for SelectGroupScreen {
         self.new_group_position = None;
 
         let num_groups = (keepass.header.num_groups as usize).min(ITEMS);
-        let mut items: Vec<&str, ITEMS> = Vec::new();
+        let mut items: Vec<&str, { ITEMS + 1 }> = Vec::new();
         for i in 0..num_groups {
             let Some(group) = keepass.groups[i].as_ref() else {
                 break;
@@ -82,6 +85,8 @@ impl Screen for SelectGroupScreen {
             self.new_group_position = Some(items.len());
             let _ = items.push("New group");
         }
+        self.settings_position = Some(items.len());
+        let _ = items.push("Settings");
 
         let list = List::new(items)
             .block(outer_block)
@@ -94,6 +99,8 @@ impl Screen for SelectGroupScreen {
     fn on_select(&mut self, selected: Option<usize>) -> ScreenAction {
         if selected.is_some() && selected == self.new_group_position {
             ScreenAction::Push(Screens::new_group_form())
+        } else if selected.is_some() && selected == self.settings_position {
+            ScreenAction::ShowSettings
         } else {
             ScreenAction::Push(Screens::select_entry(selected.unwrap_or(0) as u32))
         }
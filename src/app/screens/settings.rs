@@ -0,0 +1,125 @@
+use core::fmt::Write;
+
+use defmt::Format;
+use heapless::String;
+use ratatui::Frame;
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, List, ListState};
+
+use crate::app::screens::Screen;
+use crate::app::{ScreenAction, Screens};
+use crate::keepass::KeePassDb;
+use crate::storage::settings::{
+    DEFAULT_AUTO_LOCK_SECS, DEFAULT_WIPE_AFTER_ATTEMPTS, MAX_WIPE_AFTER_ATTEMPTS,
+    MIN_WIPE_AFTER_ATTEMPTS, Settings,
+};
+
+pub const ITEMS: usize = 7;
+const LABEL_CAP: usize = 24;
+/// Timeouts the "Auto-lock" row steps through, in seconds; 0 never locks.
+const AUTO_LOCK_SECS: [u16; 6] = [30, 60, DEFAULT_AUTO_LOCK_SECS, 300, 600, 0];
+/// Typing delays the "Typing delay" row steps through, in milliseconds.
+const TYPING_DELAYS_MS: [u16; 5] = [2, 5, 10, 20, 50];
+/// Failed PIN attempts the "Wipe after" row steps through, all within
+/// `MIN_WIPE_AFTER_ATTEMPTS..=MAX_WIPE_AFTER_ATTEMPTS`.
+const WIPE_AFTER_ATTEMPTS: [u8; 5] = [
+    MIN_WIPE_AFTER_ATTEMPTS,
+    5,
+    DEFAULT_WIPE_AFTER_ATTEMPTS,
+    20,
+    MAX_WIPE_AFTER_ATTEMPTS,
+];
+
+#[derive(Debug, Format)]
+pub struct SettingsScreen {
+    settings: Settings,
+}
+
+impl SettingsScreen {
+    pub fn with_settings(settings: Settings) -> Self {
+        Self { settings }
+    }
+
+    fn labels(&self) -> [String<LABEL_CAP>; ITEMS] {
+        let label = |args: core::fmt::Arguments| {
+            let mut text: String<LABEL_CAP> = String::new();
+            let _ = text.write_fmt(args);
+            text
+        };
+        [
+            label(format_args!("Change PIN")),
+            match self.settings.auto_lock_secs {
+                0 => label(format_args!("Auto-lock never")),
+                secs => label(format_args!("Auto-lock {}s", secs)),
+            },
+            label(format_args!(
+                "Typing delay {}ms",
+                self.settings.typing_delay_ms
+            )),
+            label(format_args!(
+                "Wipe after {} tries",
+                self.settings.pin_policy.wipe_after_attempts
+            )),
+            label(format_args!("Diagnostics")),
+            label(format_args!("Factory reset")),
+            label(format_args!("Back")),
+        ]
+    }
+}
+
+/// The step after `current`, wrapping around; a value off the list goes to
+/// the first step.
+fn next_step<T: Copy + PartialEq>(steps: &[T], current: T) -> T {
+    let next = steps
+        .iter()
+        .position(|&step| step == current)
+        .map_or(0, |idx| (idx + 1) % steps.len());
+    steps[next]
+}
+
+impl Screen for SettingsScreen {
+    fn new() -> Self {
+        Self::with_settings(Settings::default())
+    }
+
+    fn draw(&mut self, frame: &mut Frame, selected: &mut ListState, _: &KeePassDb) {
+        let outer_block = Block::bordered()
+            .border_style(Style::new().bold().green())
+            .title(" Settings ");
+
+        let labels = self.labels();
+        let list = List::new(labels.iter().map(String::as_str))
+            .block(outer_block)
+            .style(Style::new())
+            .highlight_style(Style::new().bold().bg(Color::White).fg(Color::Black))
+            .highlight_symbol(">> ");
+
+        frame.render_stateful_widget(list, frame.area(), selected);
+    }
+
+    fn on_select(&mut self, selected: Option<usize>) -> ScreenAction {
+        match selected {
+            Some(0) => ScreenAction::ChangePin,
+            Some(1) => {
+                self.settings.auto_lock_secs =
+                    next_step(&AUTO_LOCK_SECS, self.settings.auto_lock_secs);
+                ScreenAction::SaveSettings(self.settings)
+            }
+            Some(2) => {
+                self.settings.typing_delay_ms =
+                    next_step(&TYPING_DELAYS_MS, self.settings.typing_delay_ms);
+                ScreenAction::SaveSettings(self.settings)
+            }
+            Some(3) => {
+                let policy = &mut self.settings.pin_policy;
+                policy.wipe_after_attempts =
+                    next_step(&WIPE_AFTER_ATTEMPTS, policy.wipe_after_attempts);
+                ScreenAction::SaveSettings(self.settings)
+            }
+            Some(4) => ScreenAction::ShowDiagnostics,
+            Some(5) => ScreenAction::Push(Screens::factory_reset()),
+            Some(6) => ScreenAction::Pop,
+            _ => ScreenAction::None,
+        }
+    }
+}
@@ -9,19 +9,29 @@ use ratatui::widgets::{Block, ListState, Paragraph};
 use crate::app::ScreenAction;
 use crate::app::screens::Screen;
 use crate::keepass::KeePassDb;
-
-pub const MAX_TEXT_LEN: usize = 64;
+use crate::keepass::entry::{MAX_NOTES_LEN, MAX_TITLE_LEN, MAX_URL_LEN, MAX_USERNAME_LEN};
+use crate::secret::SecretString;
+
+/// Longest text the keyboard takes, enough for any entry field it edits.
+pub const MAX_TEXT_LEN: usize = max(
+    max(MAX_TITLE_LEN, MAX_USERNAME_LEN),
+    max(MAX_URL_LEN, MAX_NOTES_LEN),
+);
 const KEYBOARD_LINE_CAP: usize = 128;
 const BLINK_PERIOD_FRAMES: usize = 20;
 const KEYBOARD_SCROLL_MARGIN_KEYS: usize = 2;
 
-const LETTERS: [&str; 39] = [
+const LETTERS: [&str; 42] = [
     "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S",
     "T", "U", "V", "W", "X", "Y", "Z", "1", "2", "3", "4", "5", "6", "7", "8", "9", "0", "_", "@",
-    ".",
+    ".", "-", ":", "/",
 ];
 const KEYBOARD_POS_CAP: usize = LETTERS.len() + 4;
 
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum KeyboardKey {
     Submit,
@@ -39,7 +49,8 @@ struct KeySpan {
 
 #[derive(Debug, Format)]
 pub struct TextEntryFormScreen {
-    text: String<MAX_TEXT_LEN>,
+    /// Zeroized on drop: the keyboard is also used to type passphrases.
+    text: SecretString<MAX_TEXT_LEN>,
     keyboard_scroll_x: u16,
     last_rendered_selected: Option<usize>,
 }
@@ -47,7 +58,7 @@ pub struct TextEntryFormScreen {
 impl TextEntryFormScreen {
     pub fn new_with_text(initial_text: &str) -> Self {
         let mut screen = Self {
-            text: String::new(),
+            text: SecretString::new(),
             keyboard_scroll_x: 0,
             last_rendered_selected: None,
         };
@@ -72,6 +83,111 @@ impl TextEntryFormScreen {
         }
     }
 
+    /// Draws the scrolling keyboard row into `area`. Shared with screens that
+    /// embed the keyboard, such as the passphrase unlock.
+    pub(crate) fn draw_keyboard(
+        &mut self,
+        frame: &mut Frame,
+        area: Rect,
+        selected: &mut ListState,
+    ) {
+        let bottom_block = Block::bordered().border_style(Style::new().bold().green());
+        let bottom_inner = bottom_block.inner(area);
+        frame.render_widget(bottom_block, area);
+
+        let key_count = self.key_count();
+        if key_count == 0 || bottom_inner.is_empty() || bottom_inner.height == 0 {
+            self.keyboard_scroll_x = 0;
+            self.last_rendered_selected = Some(0);
+            return;
+        }
+
+        let selected_raw = selected
+            .selected()
+            .or(self.last_rendered_selected)
+            .unwrap_or(0);
+        let selected_idx = selected_raw.min(key_count - 1);
+        let previous_selected_idx = self.last_rendered_selected.unwrap_or(selected_idx);
+        self.last_rendered_selected = Some(selected_idx);
+
+        let (keyboard_line, spans, total_width) = self.build_keyboard_line();
+        let Some(selected_span) = spans.get(selected_idx) else {
+            self.keyboard_scroll_x = 0;
+            return;
+        };
+        let selected_start = selected_span.start;
+        let selected_width = selected_span.width;
+
+        let view_width = bottom_inner.width as usize;
+        let mut scroll_x = self.keyboard_scroll_x as usize;
+        if view_width == 0 {
+            scroll_x = 0;
+        } else {
+            let max_scroll = total_width.saturating_sub(view_width);
+            scroll_x = scroll_x.min(max_scroll);
+
+            let visible_left = scroll_x;
+            let visible_right = visible_left.saturating_add(view_width);
+            let selected_right = selected_start.saturating_add(selected_width);
+
+            if selected_start < visible_left {
+                scroll_x = selected_start;
+            } else if selected_right > visible_right {
+                scroll_x = selected_right.saturating_sub(view_width);
+            }
+
+            if selected_idx > previous_selected_idx {
+                if let Some(target_idx) = selected_idx.checked_add(KEYBOARD_SCROLL_MARGIN_KEYS) {
+                    let target_idx = target_idx.min(spans.len().saturating_sub(1));
+                    if let Some(target_span) = spans.get(target_idx) {
+                        let target_right = target_span.start.saturating_add(target_span.width);
+                        let visible_right = scroll_x.saturating_add(view_width);
+                        if target_right > visible_right {
+                            scroll_x = target_right.saturating_sub(view_width);
+                        }
+                    }
+                }
+            } else if selected_idx < previous_selected_idx {
+                let target_idx = selected_idx.saturating_sub(KEYBOARD_SCROLL_MARGIN_KEYS);
+                if let Some(target_span) = spans.get(target_idx) {
+                    let target_left = target_span.start;
+                    if target_left < scroll_x {
+                        scroll_x = target_left;
+                    }
+                }
+            }
+
+            let max_scroll = total_width.saturating_sub(view_width);
+            scroll_x = scroll_x.min(max_scroll);
+        }
+        self.keyboard_scroll_x = scroll_x as u16;
+
+        let paragraph = Paragraph::new(keyboard_line.as_str()).scroll((0, self.keyboard_scroll_x));
+        frame.render_widget(paragraph, bottom_inner);
+
+        let visible_left = scroll_x;
+        let visible_right = visible_left.saturating_add(view_width);
+        let selected_left = selected_start;
+        let selected_right = selected_start.saturating_add(selected_width);
+
+        let highlight_left = selected_left.max(visible_left);
+        let highlight_right = selected_right.min(visible_right);
+        if highlight_left < highlight_right && selected_width > 0 {
+            let x = bottom_inner.x + (highlight_left.saturating_sub(visible_left) as u16);
+            let width = (highlight_right - highlight_left) as u16;
+            let highlight = Rect {
+                x,
+                y: bottom_inner.y,
+                width,
+                height: 1,
+            };
+            frame.buffer_mut().set_style(
+                highlight,
+                Style::new().bold().bg(Color::White).fg(Color::Black),
+            );
+        }
+    }
+
     fn has_submit(&self) -> bool {
         !self.text.is_empty()
     }
@@ -166,7 +282,7 @@ impl TextEntryFormScreen {
 impl Screen for TextEntryFormScreen {
     fn new() -> Self {
         Self {
-            text: String::new(),
+            text: SecretString::new(),
             keyboard_scroll_x: 0,
             last_rendered_selected: None,
         }
@@ -206,101 +322,7 @@ impl Screen for TextEntryFormScreen {
             frame.render_widget(paragraph, text_area);
         }
 
-        let bottom_block = Block::bordered().border_style(Style::new().bold().green());
-        let bottom_inner = bottom_block.inner(chunks[1]);
-        frame.render_widget(bottom_block, chunks[1]);
-
-        let key_count = self.key_count();
-        if key_count == 0 || bottom_inner.is_empty() || bottom_inner.height == 0 {
-            self.keyboard_scroll_x = 0;
-            self.last_rendered_selected = Some(0);
-            return;
-        }
-
-        let selected_raw = selected
-            .selected()
-            .or(self.last_rendered_selected)
-            .unwrap_or(0);
-        let selected_idx = selected_raw.min(key_count - 1);
-        let previous_selected_idx = self.last_rendered_selected.unwrap_or(selected_idx);
-        self.last_rendered_selected = Some(selected_idx);
-
-        let (keyboard_line, spans, total_width) = self.build_keyboard_line();
-        let Some(selected_span) = spans.get(selected_idx) else {
-            self.keyboard_scroll_x = 0;
-            return;
-        };
-        let selected_start = selected_span.start;
-        let selected_width = selected_span.width;
-
-        let view_width = bottom_inner.width as usize;
-        let mut scroll_x = self.keyboard_scroll_x as usize;
-        if view_width == 0 {
-            scroll_x = 0;
-        } else {
-            let max_scroll = total_width.saturating_sub(view_width);
-            scroll_x = scroll_x.min(max_scroll);
-
-            let visible_left = scroll_x;
-            let visible_right = visible_left.saturating_add(view_width);
-            let selected_right = selected_start.saturating_add(selected_width);
-
-            if selected_start < visible_left {
-                scroll_x = selected_start;
-            } else if selected_right > visible_right {
-                scroll_x = selected_right.saturating_sub(view_width);
-            }
-
-            if selected_idx > previous_selected_idx {
-                if let Some(target_idx) = selected_idx.checked_add(KEYBOARD_SCROLL_MARGIN_KEYS) {
-                    let target_idx = target_idx.min(spans.len().saturating_sub(1));
-                    if let Some(target_span) = spans.get(target_idx) {
-                        let target_right = target_span.start.saturating_add(target_span.width);
-                        let visible_right = scroll_x.saturating_add(view_width);
-                        if target_right > visible_right {
-                            scroll_x = target_right.saturating_sub(view_width);
-                        }
-                    }
-                }
-            } else if selected_idx < previous_selected_idx {
-                let target_idx = selected_idx.saturating_sub(KEYBOARD_SCROLL_MARGIN_KEYS);
-                if let Some(target_span) = spans.get(target_idx) {
-                    let target_left = target_span.start;
-                    if target_left < scroll_x {
-                        scroll_x = target_left;
-                    }
-                }
-            }
-
-            let max_scroll = total_width.saturating_sub(view_width);
-            scroll_x = scroll_x.min(max_scroll);
-        }
-        self.keyboard_scroll_x = scroll_x as u16;
-
-        let paragraph = Paragraph::new(keyboard_line.as_str()).scroll((0, self.keyboard_scroll_x));
-        frame.render_widget(paragraph, bottom_inner);
-
-        let visible_left = scroll_x;
-        let visible_right = visible_left.saturating_add(view_width);
-        let selected_left = selected_start;
-        let selected_right = selected_start.saturating_add(selected_width);
-
-        let highlight_left = selected_left.max(visible_left);
-        let highlight_right = selected_right.min(visible_right);
-        if highlight_left < highlight_right && selected_width > 0 {
-            let x = bottom_inner.x + (highlight_left.saturating_sub(visible_left) as u16);
-            let width = (highlight_right - highlight_left) as u16;
-            let highlight = Rect {
-                x,
-                y: bottom_inner.y,
-                width,
-                height: 1,
-            };
-            frame.buffer_mut().set_style(
-                highlight,
-                Style::new().bold().bg(Color::White).fg(Color::Black),
-            );
-        }
+        self.draw_keyboard(frame, chunks[1], selected);
     }
 
     fn on_select(&mut self, selected: Option<usize>) -> ScreenAction {
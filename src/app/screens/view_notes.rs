@@ -0,0 +1,96 @@
+use defmt::Format;
+use ratatui::Frame;
+use ratatui::layout::Rect;
+use ratatui::style::Style;
+use ratatui::widgets::{Block, ListState, Paragraph};
+
+use crate::app::ScreenAction;
+use crate::app::screens::Screen;
+use crate::keepass::KeePassDb;
+
+/// An entry's notes, cut into rows as wide as the screen. Turning the encoder
+/// scrolls a row at a time; selecting goes back.
+#[derive(Debug, Format)]
+pub struct ViewNotesScreen {
+    entry_index: usize,
+    /// Rows the notes can scroll by, as of the last draw.
+    scroll_rows: usize,
+}
+
+impl ViewNotesScreen {
+    pub fn new(entry_index: usize) -> Self {
+        Self {
+            entry_index,
+            scroll_rows: 0,
+        }
+    }
+
+    /// One position per row the notes can scroll by, plus the top.
+    pub fn item_count(&self) -> usize {
+        self.scroll_rows + 1
+    }
+}
+
+impl Screen for ViewNotesScreen {
+    fn new() -> Self {
+        Self::new(0)
+    }
+
+    fn draw(&mut self, frame: &mut Frame, selected: &mut ListState, kpdb: &KeePassDb) {
+        let notes = match kpdb
+            .entries
+            .get(self.entry_index)
+            .and_then(|entry| entry.as_ref())
+        {
+            Some(entry) if entry.notes().is_empty() => "<empty>",
+            Some(entry) => core::str::from_utf8(entry.notes()).unwrap_or("<invalid>"),
+            None => "<missing>",
+        };
+
+        let block = Block::bordered()
+            .border_style(Style::new().bold().green())
+            .title(" Notes ");
+        let inner = block.inner(frame.area());
+        frame.render_widget(block, frame.area());
+
+        if inner.is_empty() {
+            return;
+        }
+
+        let width = inner.width as usize;
+        let height = inner.height as usize;
+        self.scroll_rows = rows(notes, width).count().saturating_sub(height);
+        let top = selected.selected().unwrap_or(0).min(self.scroll_rows);
+
+        for (y, row) in (inner.y..).zip(rows(notes, width).skip(top).take(height)) {
+            let area = Rect {
+                x: inner.x,
+                y,
+                width: inner.width,
+                height: 1,
+            };
+            frame.render_widget(Paragraph::new(row), area);
+        }
+    }
+
+    fn on_select(&mut self, _: Option<usize>) -> ScreenAction {
+        ScreenAction::Pop
+    }
+}
+
+/// The lines of `text`, each cut into rows of at most `width` characters.
+fn rows(text: &str, width: usize) -> impl Iterator<Item = &str> {
+    text.split('\n').flat_map(move |line| {
+        let mut rest = Some(line.strip_suffix('\r').unwrap_or(line));
+        core::iter::from_fn(move || {
+            let line = rest?;
+            let end = line
+                .char_indices()
+                .nth(width.max(1))
+                .map_or(line.len(), |(index, _)| index);
+            let (row, tail) = line.split_at(end);
+            rest = (!tail.is_empty()).then_some(tail);
+            Some(row)
+        })
+    })
+}
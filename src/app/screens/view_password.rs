@@ -8,9 +8,9 @@ use ratatui::widgets::{Block, ListState, Paragraph, Wrap};
 use crate::app::ScreenAction;
 use crate::app::screens::Screen;
 use crate::keepass::KeePassDb;
+use crate::keepass::entry::MAX_PASSWORD_LEN;
 
 const MAX_TITLE_LEN: usize = 32;
-const MAX_PASSWORD_LEN: usize = 64;
 
 #[derive(Debug, Format)]
 pub struct ViewPasswordScreen {
@@ -59,8 +59,8 @@ impl Screen for ViewPasswordScreen {
 
         let (title, password) = match entry {
             Some(entry) => (
-                Self::bytes_to_string::<MAX_TITLE_LEN>(&entry.title),
-                Self::bytes_to_string::<MAX_PASSWORD_LEN>(&entry.password),
+                Self::bytes_to_string::<MAX_TITLE_LEN>(entry.title()),
+                Self::bytes_to_string::<MAX_PASSWORD_LEN>(entry.password().expose()),
             ),
             None => {
                 let mut title: String<MAX_TITLE_LEN> = String::new();
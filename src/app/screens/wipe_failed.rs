@@ -0,0 +1,58 @@
+use defmt::Format;
+use ratatui::Frame;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, List, ListState, Paragraph};
+
+use crate::app::ScreenAction;
+use crate::app::screens::Screen;
+use crate::keepass::KeePassDb;
+
+pub const ITEMS: usize = 1;
+pub const LABELS: [&str; ITEMS] = ["Retry wipe"];
+
+/// Shown alone on the stack after the auto-wipe failed. There is no way back
+/// to the PIN screen from here: the only thing left to do is to retry the
+/// wipe until it goes through.
+#[derive(Debug, Format)]
+pub struct WipeFailedScreen;
+
+impl Screen for WipeFailedScreen {
+    fn new() -> Self {
+        Self
+    }
+
+    fn draw(&mut self, frame: &mut Frame, selected: &mut ListState, _: &KeePassDb) {
+        let area = frame.area();
+        if area.is_empty() {
+            return;
+        }
+
+        let outer_block = Block::bordered()
+            .border_style(Style::new().bold().red())
+            .title(" Wipe failed ");
+        let inner = outer_block.inner(area);
+        frame.render_widget(outer_block, area);
+
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(2), Constraint::Min(0)])
+            .split(inner);
+
+        frame.render_widget(
+            Paragraph::new("Too many wrong PINs;\nthe wipe didn't finish")
+                .style(Style::new().bold()),
+            chunks[0],
+        );
+
+        let list = List::new(LABELS)
+            .style(Style::new())
+            .highlight_style(Style::new().bold().bg(Color::White).fg(Color::Black))
+            .highlight_symbol(">> ");
+        frame.render_stateful_widget(list, chunks[1], selected);
+    }
+
+    fn on_select(&mut self, _: Option<usize>) -> ScreenAction {
+        ScreenAction::RetryWipe
+    }
+}
@@ -10,7 +10,7 @@ extern crate alloc;
 
 mod usb_hid;
 
-use defmt::info;
+use defmt::{error, info, warn};
 use embassy_executor::Spawner;
 use embassy_time::{Duration, Timer};
 use embedded_hal_bus::spi::ExclusiveDevice;
@@ -23,13 +23,16 @@ use esp_hal::timer::timg::TimerGroup;
 use esp_hal::{clock::CpuClock, hmac::Hmac};
 use esp_storage::FlashStorage;
 use passbuddy::app::AppState;
-use passbuddy::keepass::KeePassDb;
-use passbuddy::storage::layout::StorageLayout;
-use passbuddy::storage::region::DataRegion;
+use passbuddy::app::screens::recovery::{self, RecoveryChoice, RecoveryScreen};
+use passbuddy::storage::boot;
+use passbuddy::storage::partition::{self, Partition};
+use passbuddy::storage::settings::{DisplayRotation, Settings};
+use passbuddy::storage::write_protect::WriteProtect;
+use ratatui::widgets::ListState;
 use {esp_backtrace as _, esp_println as _};
 
 use passbuddy::input::Inputs;
-use passbuddy::{app, display};
+use passbuddy::{app, display, usb_hid_queue, usb_transfer};
 
 // This creates a default app-descriptor required by the esp-idf bootloader.
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
@@ -51,7 +54,7 @@ async fn main(spawner: Spawner) -> ! {
 
     // 1. Get the peripherals declared
     info!("Declared peripherals");
-    let mut _hmac = Hmac::new(peripherals.HMAC);
+    let mut hmac = Hmac::new(peripherals.HMAC);
     let spi = Spi::new(
         peripherals.SPI2,
         SpiConfig::default()
@@ -63,7 +66,17 @@ async fn main(spawner: Spawner) -> ! {
     .with_mosi(peripherals.GPIO7);
     let usb = Usb::new(peripherals.USB0, peripherals.GPIO20, peripherals.GPIO19);
 
-    // 2. Let's initialize the display
+    // 2. Read the settings the display needs before anything is drawn. The
+    // storage is only opened further down; until then a missing partition or
+    // unreadable settings leave the defaults.
+    let mut flash = FlashStorage::new(peripherals.FLASH);
+    let data_partition = partition::find_data_partition(&mut flash);
+    let settings = match data_partition.as_ref() {
+        Ok(entry) => Settings::load_or_default(&mut Partition::new(&mut flash, entry)),
+        Err(_) => Settings::default(),
+    };
+
+    // 3. Let's initialize the display
     info!("Initializing display");
     let cs = Output::new(peripherals.GPIO10, Level::High, OutputConfig::default());
     let dc = Output::new(peripherals.GPIO4, Level::Low, OutputConfig::default());
@@ -71,7 +84,16 @@ async fn main(spawner: Spawner) -> ! {
 
     let spi_dev = ExclusiveDevice::new_no_delay(spi, cs).unwrap();
     let interface = display::ssd1309::SpiInterface::new(spi_dev, dc);
-    let mut display = display::ssd1309::Ssd1309::new(interface, Some(reset));
+    let display_config = display::ssd1309::Config {
+        rotation: match settings.rotation {
+            DisplayRotation::Normal => display::ssd1309::Rotation::Rotate0,
+            DisplayRotation::Flipped => display::ssd1309::Rotation::Rotate180,
+        },
+        contrast: settings.contrast,
+        ..Default::default()
+    };
+    let mut display =
+        display::ssd1309::Ssd1309::new(interface, Some(reset)).with_config(display_config);
 
     display
         .init(&mut Delay::new())
@@ -84,58 +106,100 @@ async fn main(spawner: Spawner) -> ! {
             .unwrap_or_else(|_| panic!("SSD1309 flush failed"));
     });
 
-    // 4. Let's initialize the storage
-    info!("Initializing storage");
-    let mut storage = FlashStorage::new(peripherals.FLASH);
+    const INPUT_TICK_MS: u64 = 2;
+    const UI_TICK_MS: u64 = 50;
 
-    // Debug helper: wipe the storage layout sector. If enabled, keep it *before*
-    // `run_healthcheck()` so the layout gets bootstrapped again.
-    // StorageLayout::wipe_layout(&mut storage).unwrap();
+    // 4. Let's initialize the input devices
+    info!("Setting the inputs");
+    let mut inputs = Inputs::new(peripherals.GPIO17, peripherals.GPIO15, peripherals.GPIO16);
 
-    match StorageLayout::run_healthcheck(&mut storage) {
-        Ok(_) => {
-            info!("Storage found; good to read");
-        }
-        Err(_) => {
-            // The decive needs to be writen
-            info!("Storage not found; initializing");
-            StorageLayout::bootstrap_storage_write(&mut storage)
-                .expect("initial storage bootstraping error");
+    // 5. Let's initialize the storage. If it can't be opened, the recovery
+    // screen lets the user retry, factory reset or go on read-only.
+    info!("Initializing storage");
+    let data_partition = match data_partition {
+        Ok(data_partition) => data_partition,
+        Err(err) => {
+            // Nothing to recover from without a partition; it needs a reflash
+            // with `partitions.csv`
+            error!("No usable data partition: {}", err);
+            let mut screen = RecoveryScreen::new(err.into());
+            let mut selected = ListState::default();
+            loop {
+                terminal
+                    .draw(|frame| screen.draw(frame, &mut selected))
+                    .expect("to draw");
+                Timer::after(Duration::from_millis(UI_TICK_MS)).await;
+            }
         }
-    }
+    };
+    info!(
+        "Data partition at {=u32:#x}, {=u32} bytes",
+        data_partition.offset, data_partition.size
+    );
+    let mut storage = WriteProtect::new(Partition::new(flash, &data_partition));
+
+    let mut choice = RecoveryChoice::Retry;
+    let booted = loop {
+        let result = match choice {
+            RecoveryChoice::Retry => boot::open(&mut storage),
+            RecoveryChoice::FactoryReset => {
+                boot::factory_reset(&mut storage).and_then(|()| boot::open(&mut storage))
+            }
+            RecoveryChoice::ReadOnly => boot::open_read_only(&mut storage),
+        };
+        let error = match result {
+            Ok(booted) => break booted,
+            Err(error) => error,
+        };
+        warn!("Opening the storage failed: {}", error);
+
+        let mut screen = RecoveryScreen::new(error);
+        let mut selected = ListState::default();
+        selected.select_first();
+        let mut ui_elapsed_ms = 0u64;
+        choice = loop {
+            Timer::after(Duration::from_millis(INPUT_TICK_MS)).await;
+            let delta = inputs.poll_encoder_delta();
+            if delta != 0 {
+                let next = (selected.selected().unwrap_or(0) as i16).saturating_add(delta);
+                selected.select(Some(next.clamp(0, recovery::ITEMS as i16 - 1) as usize));
+            }
 
-    let layout = StorageLayout::new(&mut storage);
-    let magic_str = core::str::from_utf8(&layout.header.magic).unwrap_or("<invalid utf8>");
-    info!("magic: {=str}", magic_str);
+            ui_elapsed_ms = ui_elapsed_ms.saturating_add(INPUT_TICK_MS);
+            if ui_elapsed_ms < UI_TICK_MS {
+                continue;
+            }
+            ui_elapsed_ms = 0;
 
-    // 5. Let's initialize the input devices
-    info!("Setting the inputs");
-    let mut inputs = Inputs::new(peripherals.GPIO17, peripherals.GPIO15, peripherals.GPIO16);
-    //
-    // 6. Get the key to decrypt the storage
-    //
-    // 7. Get the keepass groups
-    let keepass_region = layout.region_handle(DataRegion::KeePassDb).unwrap();
-    if !KeePassDb::check_if_exists(&mut storage, keepass_region).unwrap() {
-        info!("Creating a new keepass");
-        KeePassDb::initialize_db(&mut storage, keepass_region).unwrap();
-    }
+            if inputs.poll_button_pressed() {
+                if let Some(choice) = screen.on_select(selected.selected()) {
+                    break choice;
+                }
+            }
+            terminal
+                .draw(|frame| screen.draw(frame, &mut selected))
+                .expect("to draw");
+        };
+        info!("Recovery: {}", choice);
+        storage.set_protected(choice == RecoveryChoice::ReadOnly);
+    };
 
-    info!("Indexing the keepass database");
-    let kpdb = KeePassDb::new(&mut storage, keepass_region).unwrap();
-    let mut app_state = app_state.with_kpdb(kpdb);
+    let mut app_state = app_state
+        .with_kpdb(booted.kpdb)
+        .with_user_config(booted.user_config)
+        .with_layout(booted.layout)
+        .with_read_only(storage.is_protected());
 
     usb_hid::spawn(&spawner, usb);
 
     info!("Starting the loop");
-    const INPUT_TICK_MS: u64 = 2;
-    const UI_TICK_MS: u64 = 50;
     let mut ui_elapsed_ms = 0u64;
 
     loop {
         Timer::after(Duration::from_millis(INPUT_TICK_MS)).await;
         let delta = inputs.poll_encoder_delta();
         if delta != 0 {
+            app_state.note_activity();
             app_state.apply_navigation(delta);
         }
 
@@ -146,14 +210,29 @@ async fn main(spawner: Spawner) -> ! {
 
             if inputs.poll_button_pressed() {
                 info!("Action button pressed");
-                app_state.on_select(&mut storage);
+                app_state.note_activity();
+                app_state.on_select(&mut storage, &mut hmac);
+            }
+
+            // Pulling the device out of the host locks it right away
+            if usb_hid_queue::take_usb_disconnected() {
+                info!("USB disconnected");
+                app_state.lock();
             }
 
-            app_state.on_tick(&mut storage);
+            if let Some(request) = usb_transfer::try_take_request() {
+                info!("USB import request");
+                app_state.on_transfer_request(request);
+            }
+
+            app_state.on_tick(&mut storage, &mut hmac);
 
             terminal
                 .draw(|frame| app_state.draw_current_screen(frame))
                 .expect("to draw");
+
+            // After the draw, so the confirmation is gone while the file goes out
+            app_state.run_export().await;
         }
     }
 }
@@ -1,19 +1,23 @@
+mod serial;
+
 use alloc::boxed::Box;
 use defmt::{info, warn};
 use embassy_executor::Spawner;
 use embassy_time::{Duration, Timer};
 use embassy_usb::class::hid::{HidReaderWriter, HidWriter, State};
-use embassy_usb::{Builder, UsbDevice};
+use embassy_usb::{Builder, Handler, UsbDevice};
 use esp_hal::otg_fs::Usb;
 use esp_hal::otg_fs::asynch::{Config, Driver as OtgDriver};
 use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
 
+use passbuddy::storage::settings::KeyboardLayout;
 use passbuddy::usb_hid_queue;
 use passbuddy::usb_hid_queue::UsbHidCommand;
 
 pub fn spawn(spawner: &Spawner, usb: Usb<'static>) {
-    // Creating the driver from the hal
-    let ep_out_buffer = Box::leak(Box::new([0u8; 124]));
+    // Creating the driver from the hal. The OUT endpoints of the control
+    // pipe, the keyboard and the serial port share this buffer.
+    let ep_out_buffer = Box::leak(Box::new([0u8; 256]));
     let config = Config::default();
     let otg_driver = OtgDriver::new(usb, ep_out_buffer, config);
 
@@ -23,6 +27,11 @@ pub fn spawn(spawner: &Spawner, usb: Usb<'static>) {
     usb_config.serial_number = Some("1234567890");
     usb_config.max_power = 100;
     usb_config.max_packet_size_0 = 64;
+    // A keyboard and a serial port in one device
+    usb_config.device_class = 0xEF;
+    usb_config.device_sub_class = 0x02;
+    usb_config.device_protocol = 0x01;
+    usb_config.composite_with_iads = true;
 
     let config_descriptor_buffer = Box::leak(Box::new([0; 256]));
     let bos_descriptor_buffer = Box::leak(Box::new([0; 256]));
@@ -30,6 +39,7 @@ pub fn spawn(spawner: &Spawner, usb: Usb<'static>) {
     let control_buffer = Box::leak(Box::new([0; 64]));
 
     let usb_state = Box::leak(Box::new(State::new()));
+    let connection_handler = Box::leak(Box::new(ConnectionHandler));
 
     let mut usb_builder = Builder::new(
         otg_driver,
@@ -40,6 +50,8 @@ pub fn spawn(spawner: &Spawner, usb: Usb<'static>) {
         control_buffer,
     );
 
+    usb_builder.handler(connection_handler);
+
     let hid_config = embassy_usb::class::hid::Config {
         report_descriptor: KeyboardReport::desc(),
         request_handler: None,
@@ -47,11 +59,31 @@ pub fn spawn(spawner: &Spawner, usb: Usb<'static>) {
         max_packet_size: 8,
     };
     let hid = HidReaderWriter::<_, 1, 8>::new(&mut usb_builder, usb_state, hid_config);
+    let serial = serial::add(&mut usb_builder);
     let usb = usb_builder.build();
     let (_, writer) = hid.split();
 
     spawner.must_spawn(run_usb(usb));
     spawner.must_spawn(usb_writer(writer));
+    serial::spawn(spawner, serial);
+}
+
+/// Reports the host going away so the app can lock. Without VBUS sensing an
+/// unplug shows up as a bus suspend, so both count as a disconnect.
+struct ConnectionHandler;
+
+impl Handler for ConnectionHandler {
+    fn enabled(&mut self, enabled: bool) {
+        if !enabled {
+            usb_hid_queue::notify_usb_disconnected();
+        }
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        if suspended {
+            usb_hid_queue::notify_usb_disconnected();
+        }
+    }
 }
 
 #[embassy_executor::task]
@@ -74,9 +106,16 @@ async fn usb_writer(mut writer: HidWriter<'static, OtgDriver<'static>, 8>) {
 
     loop {
         match usb_hid_queue::receive().await {
-            UsbHidCommand::TypeText(text) => {
+            UsbHidCommand::TypeText {
+                text,
+                layout,
+                delay_ms,
+            } => {
+                // A clear from before the command was taken doesn't stop it
+                usb_hid_queue::take_typing_cancelled();
                 writer.ready().await;
-                type_text(&mut writer, text.as_str()).await;
+                let delay = Duration::from_millis(u64::from(delay_ms));
+                type_text(&mut writer, text.as_str(), layout, delay).await;
             }
         }
     }
@@ -84,7 +123,13 @@ async fn usb_writer(mut writer: HidWriter<'static, OtgDriver<'static>, 8>) {
 
 const MOD_LSHIFT: u8 = 0x02;
 
-fn hid_key_for_char(ch: char) -> Option<(u8, u8)> {
+fn hid_key_for_char(ch: char, layout: KeyboardLayout) -> Option<(u8, u8)> {
+    match layout {
+        KeyboardLayout::Us => us_key_for_char(ch),
+    }
+}
+
+fn us_key_for_char(ch: char) -> Option<(u8, u8)> {
     let byte = u8::try_from(ch).ok()?;
     match ch {
         'a'..='z' => Some((0, 0x04 + (byte - b'a'))),
@@ -98,9 +143,18 @@ fn hid_key_for_char(ch: char) -> Option<(u8, u8)> {
     }
 }
 
-async fn type_text(writer: &mut HidWriter<'static, OtgDriver<'static>, 8>, text: &str) {
+async fn type_text(
+    writer: &mut HidWriter<'static, OtgDriver<'static>, 8>,
+    text: &str,
+    layout: KeyboardLayout,
+    delay: Duration,
+) {
     for ch in text.chars() {
-        let Some((modifier, keycode)) = hid_key_for_char(ch) else {
+        if usb_hid_queue::take_typing_cancelled() {
+            info!("USB HID: typing cancelled");
+            return;
+        }
+        let Some((modifier, keycode)) = hid_key_for_char(ch, layout) else {
             warn!("USB HID: unsupported character");
             continue;
         };
@@ -127,6 +181,6 @@ async fn type_text(writer: &mut HidWriter<'static, OtgDriver<'static>, 8>, text:
             return;
         }
 
-        Timer::after(Duration::from_millis(2)).await;
+        Timer::after(delay).await;
     }
 }
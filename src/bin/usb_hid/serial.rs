@@ -0,0 +1,89 @@
+use alloc::boxed::Box;
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_usb::Builder;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::EndpointError;
+use esp_hal::otg_fs::asynch::Driver as OtgDriver;
+use zeroize::Zeroize;
+
+use passbuddy::usb_transfer::{self, Reply, RequestReader};
+
+const MAX_PACKET_SIZE: u16 = 64;
+
+/// Adds the serial port KeePass files are imported and exported through; see
+/// [`usb_transfer`] for what goes over it.
+pub fn add(
+    builder: &mut Builder<'static, OtgDriver<'static>>,
+) -> CdcAcmClass<'static, OtgDriver<'static>> {
+    let state = Box::leak(Box::new(State::new()));
+    CdcAcmClass::new(builder, state, MAX_PACKET_SIZE)
+}
+
+pub fn spawn(spawner: &Spawner, class: CdcAcmClass<'static, OtgDriver<'static>>) {
+    spawner.must_spawn(serial_transfer(class));
+}
+
+#[embassy_executor::task]
+async fn serial_transfer(mut class: CdcAcmClass<'static, OtgDriver<'static>>) {
+    let mut reader = RequestReader::new();
+    let mut packet = [0u8; MAX_PACKET_SIZE as usize];
+    loop {
+        class.wait_connection().await;
+        info!("USB serial connected");
+        if let Err(err) = serve(&mut class, &mut reader, &mut packet).await {
+            warn!("USB serial: {:?}", err);
+        }
+        // A request cut off by the host going away is dropped whole
+        reader.reset();
+        packet.zeroize();
+    }
+}
+
+async fn serve(
+    class: &mut CdcAcmClass<'static, OtgDriver<'static>>,
+    reader: &mut RequestReader,
+    packet: &mut [u8],
+) -> Result<(), EndpointError> {
+    loop {
+        let len = class.read_packet(packet).await?;
+        let request = reader.feed(&packet[..len]);
+        packet.zeroize();
+        match request {
+            None => {}
+            Some(Ok(request)) => {
+                usb_transfer::send_request(request).await;
+                // Every part of the answer is taken, even once the host is
+                // gone, or the app would wait on it forever
+                let mut written = Ok(());
+                loop {
+                    let reply = usb_transfer::receive_reply().await;
+                    if written.is_ok() {
+                        written = write_reply(class, &reply).await;
+                    }
+                    if reply.is_last() {
+                        break;
+                    }
+                }
+                written?;
+            }
+            Some(Err(err)) => write_reply(class, &Reply::Failed(err)).await?,
+        }
+    }
+}
+
+async fn write_reply(
+    class: &mut CdcAcmClass<'static, OtgDriver<'static>>,
+    reply: &Reply,
+) -> Result<(), EndpointError> {
+    let bytes = reply.to_bytes();
+    let packet_size = usize::from(MAX_PACKET_SIZE);
+    for chunk in bytes.chunks(packet_size) {
+        class.write_packet(chunk).await?;
+    }
+    // A full last packet needs an empty one after it to end the transfer
+    if bytes.len() % packet_size == 0 {
+        class.write_packet(&[]).await?;
+    }
+    Ok(())
+}
@@ -1,19 +1,205 @@
-use esp_hal::{
-    hmac::{Hmac, HmacPurpose, KeyId},
-    rng::Rng,
-};
-use nb::block;
-
-pub fn derive_sw_key(hmac: &mut Hmac, pin: &[u8], key_id: KeyId) -> [u8; 32] {
-    let rng = Rng::new();
-    let mut buffer = [0u8; 32];
-    rng.read(buffer.as_mut_slice());
-
-    let mut sw_key = [0u8; 32];
-    hmac.init();
-    block!(hmac.configure(HmacPurpose::ToUser, key_id)).expect("key purpose missmatch");
-    block!(hmac.update(pin)).expect("it takes any message");
-    block!(hmac.finalize(sw_key.as_mut_slice())).unwrap();
-
-    sw_key
+use aes::Aes256;
+use aes::cipher::{BlockEncrypt, generic_array::GenericArray};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use defmt::Format;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::secret::SecretBytes;
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+/// Extra bytes a sealed record carries on top of its plaintext (nonce + tag).
+pub const SEAL_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum CryptoError {
+    /// The tag didn't verify: wrong key or the bytes were tampered with.
+    AuthenticationFailed,
+    /// The record buffer can't hold a nonce and a tag.
+    BufferTooSmall,
+}
+
+/// Key sealing the KeePass region, derived from the software key and the
+/// database master seed.
+#[derive(Clone)]
+pub struct DbKey(SecretBytes<KEY_SIZE>);
+
+impl DbKey {
+    pub fn derive(sw_key: &SecretBytes<KEY_SIZE>, master_seed: &[u8; 16]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(master_seed);
+        hasher.update(sw_key.expose());
+        let mut digest: [u8; KEY_SIZE] = hasher.finalize().into();
+        Self(SecretBytes::take_from(&mut digest))
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(self.0.expose()))
+    }
+}
+
+impl core::fmt::Debug for DbKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("DbKey(..)")
+    }
+}
+
+/// HMAC-SHA256 keyed by a secret bound to the device, which the software key
+/// is derived with.
+pub trait DeviceHmac {
+    /// Writes the HMAC of `parts`, taken as one message, to `out`.
+    fn hmac(&mut self, parts: &[&[u8]], out: &mut [u8; KEY_SIZE]);
+}
+
+/// The efuse HMAC, keyed by efuse key block 0, which software can't read.
+#[cfg(target_arch = "xtensa")]
+impl DeviceHmac for esp_hal::hmac::Hmac<'_> {
+    fn hmac(&mut self, parts: &[&[u8]], out: &mut [u8; KEY_SIZE]) {
+        use esp_hal::hmac::{HmacPurpose, KeyId};
+        use nb::block;
+
+        self.init();
+        block!(self.configure(HmacPurpose::ToUser, KeyId::Key0)).expect("key purpose missmatch");
+        for &part in parts {
+            let mut message = part;
+            while !message.is_empty() {
+                message = block!(self.update(message)).expect("it takes any message");
+            }
+        }
+        block!(self.finalize(out.as_mut_slice())).unwrap();
+    }
+}
+
+/// HMAC-SHA256 with a key held in RAM. It binds nothing to the hardware, so
+/// it is only meant for running the storage code on a host.
+pub struct SoftwareHmac(SecretBytes<KEY_SIZE>);
+
+impl SoftwareHmac {
+    pub fn new(key: SecretBytes<KEY_SIZE>) -> Self {
+        Self(key)
+    }
+}
+
+impl DeviceHmac for SoftwareHmac {
+    fn hmac(&mut self, parts: &[&[u8]], out: &mut [u8; KEY_SIZE]) {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.0.expose())
+            .expect("HMAC takes keys of any size");
+        for part in parts {
+            mac.update(part);
+        }
+        out.copy_from_slice(&mac.finalize().into_bytes());
+    }
+}
+
+/// Derives the software key from the PIN.
+///
+/// The device HMAC is keyed by the chip and salted with the database
+/// `transform_seed`, so the same PIN yields unrelated keys on different
+/// devices or databases. The result is then stretched with `transform_rounds`
+/// AES rounds to make every PIN guess expensive.
+pub fn derive_sw_key(
+    hmac: &mut impl DeviceHmac,
+    pin: &[u8],
+    transform_seed: &[u8; 32],
+    transform_rounds: u32,
+) -> SecretBytes<KEY_SIZE> {
+    let mut hmac_out = SecretBytes::<KEY_SIZE>::zeroed();
+    hmac.hmac(&[transform_seed.as_slice(), pin], hmac_out.expose_mut());
+
+    transform_key(&hmac_out, transform_seed, transform_rounds)
+}
+
+/// KeePass key transformation: encrypts both halves of `key` with AES-256
+/// keyed by `seed`, `rounds` times, then hashes the result with SHA-256.
+pub fn transform_key(
+    key: &SecretBytes<KEY_SIZE>,
+    seed: &[u8; 32],
+    rounds: u32,
+) -> SecretBytes<KEY_SIZE> {
+    let cipher = Aes256::new(GenericArray::from_slice(seed));
+    let mut transformed = key.clone();
+    {
+        let (left, right) = transformed.expose_mut().split_at_mut(16);
+        let left = GenericArray::from_mut_slice(left);
+        let right = GenericArray::from_mut_slice(right);
+        for _ in 0..rounds {
+            cipher.encrypt_block(left);
+            cipher.encrypt_block(right);
+        }
+    }
+
+    let mut out: [u8; KEY_SIZE] = Sha256::digest(transformed.expose()).into();
+    SecretBytes::take_from(&mut out)
+}
+
+/// Fills `dst` from the hardware RNG.
+#[cfg(target_arch = "xtensa")]
+pub fn fill_random(dst: &mut [u8]) {
+    esp_hal::rng::Rng::new().read(dst);
+}
+
+/// Fills `dst` from the operating system's RNG.
+#[cfg(not(target_arch = "xtensa"))]
+pub fn fill_random(dst: &mut [u8]) {
+    getrandom::fill(dst).expect("the OS RNG is available");
+}
+
+/// Seals a record laid out as `nonce | plaintext | tag` in place.
+///
+/// A fresh random nonce is drawn on every call, so rewriting the same slot
+/// never reuses a nonce under the same key.
+pub fn seal_in_place(key: &DbKey, aad: &[u8], record: &mut [u8]) -> Result<(), CryptoError> {
+    if record.len() < SEAL_OVERHEAD {
+        return Err(CryptoError::BufferTooSmall);
+    }
+
+    let (nonce, rest) = record.split_at_mut(NONCE_SIZE);
+    let (payload, tag) = rest.split_at_mut(rest.len() - TAG_SIZE);
+    fill_random(nonce);
+
+    let computed = key
+        .cipher()
+        .encrypt_in_place_detached(Nonce::from_slice(nonce), aad, payload)
+        .map_err(|_| CryptoError::BufferTooSmall)?;
+    tag.copy_from_slice(&computed);
+    Ok(())
+}
+
+/// Verifies and decrypts a record sealed by [`seal_in_place`].
+///
+/// On success the plaintext sits in `record[NONCE_SIZE..record.len() - TAG_SIZE]`.
+pub fn open_in_place(key: &DbKey, aad: &[u8], record: &mut [u8]) -> Result<(), CryptoError> {
+    if record.len() < SEAL_OVERHEAD {
+        return Err(CryptoError::BufferTooSmall);
+    }
+
+    let (nonce, rest) = record.split_at_mut(NONCE_SIZE);
+    let (payload, tag) = rest.split_at_mut(rest.len() - TAG_SIZE);
+    key.cipher()
+        .decrypt_in_place_detached(Nonce::from_slice(nonce), aad, payload, Tag::from_slice(tag))
+        .map_err(|_| CryptoError::AuthenticationFailed)
+}
+
+/// Authenticates `aad` without encrypting anything. Used for the plaintext
+/// KeePass header, which must stay readable before the PIN is entered.
+pub fn tag_for(key: &DbKey, nonce: &[u8; NONCE_SIZE], aad: &[u8]) -> [u8; TAG_SIZE] {
+    let tag = key
+        .cipher()
+        .encrypt_in_place_detached(Nonce::from_slice(nonce), aad, &mut [])
+        .unwrap_or_default();
+    tag.into()
+}
+
+pub fn verify_tag(
+    key: &DbKey,
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    tag: &[u8; TAG_SIZE],
+) -> Result<(), CryptoError> {
+    key.cipher()
+        .decrypt_in_place_detached(Nonce::from_slice(nonce), aad, &mut [], Tag::from_slice(tag))
+        .map_err(|_| CryptoError::AuthenticationFailed)
 }
@@ -1,14 +1,58 @@
 use super::{Entry, KDBHeader};
+use zeroize::Zeroize;
+
+use crate::encryption::DbKey;
 use crate::keepass::group::Group; // or your slim v1 Group type
+use crate::storage::journal::Journal;
+use crate::storage::record_log::RecordLog;
 use crate::storage::region::RegionHandle;
 
 // TODO: Make the lenght configurable through a const
-#[derive(Debug, Clone)]
+/// Not `Clone` outside the tests, so the decrypted records and the key are
+/// never copied.
+#[derive(Debug)]
+#[cfg_attr(test, derive(Clone))]
 pub struct KeePassDb {
     pub storage: RegionHandle,
+    /// Carries a rekey's new PIN verifier until its header record is in `log`.
+    pub(crate) journal: Journal,
+    /// The record log in `storage` every change is appended to.
+    pub(crate) log: RecordLog,
     pub signature1: u32, // expect 0x9AA2D903
     pub signature2: u32, // expect 0xB54BFB65
     pub header: KDBHeader,
     pub groups: [Option<Group>; 4],
     pub entries: [Option<Entry>; 256],
+    /// Key sealing the region. `None` while the database is locked, in which
+    /// case `groups` and `entries` are empty.
+    pub(crate) key: Option<DbKey>,
+    /// Whether the header carries a tag yet. A freshly initialized database is
+    /// sealed with the first PIN entered on the device.
+    pub(crate) sealed: bool,
+}
+
+impl KeePassDb {
+    pub fn is_unlocked(&self) -> bool {
+        self.key.is_some()
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    /// Drops the key and wipes every decrypted group and entry from RAM. The
+    /// header stays loaded so the database can be unlocked again.
+    pub fn lock(&mut self) {
+        for slot in self.groups.iter_mut() {
+            if let Some(group) = slot.as_mut() {
+                group.name.zeroize();
+            }
+            *slot = None;
+        }
+        for slot in self.entries.iter_mut() {
+            // Dropping the entry zeroizes its text.
+            *slot = None;
+        }
+        self.key = None;
+    }
 }
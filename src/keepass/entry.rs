@@ -1,83 +1,296 @@
+use super::error::KDBError;
 use super::times::Times;
 
+use alloc::vec::Vec;
+use core::fmt;
+
 use defmt::Format;
+use zeroize::Zeroize;
+
+use crate::secret::{Redacted, SecretVec};
+
+/// Longest title an entry holds, in bytes.
+pub const MAX_TITLE_LEN: usize = 128;
+/// Longest username an entry holds, in bytes.
+pub const MAX_USERNAME_LEN: usize = 128;
+/// Longest password an entry holds, in bytes.
+pub const MAX_PASSWORD_LEN: usize = 128;
+/// Longest URL an entry holds, in bytes.
+pub const MAX_URL_LEN: usize = 256;
+/// Longest notes an entry holds, in bytes.
+pub const MAX_NOTES_LEN: usize = 256;
+
+// uuid = 16; group_id = 4; times = 20; autotype = 1; then the title,
+// username, password, URL and notes, each a 2-byte length followed by that
+// many bytes.
+const ENTRY_FIXED_SIZE: usize = 16 + 4 + 20 + 1; // 41
+/// Largest encoded entry, with every text field at its limit.
+pub const MAX_ENTRY_SIZE: usize = ENTRY_FIXED_SIZE
+    + 5 * 2
+    + MAX_TITLE_LEN
+    + MAX_USERNAME_LEN
+    + MAX_PASSWORD_LEN
+    + MAX_URL_LEN
+    + MAX_NOTES_LEN; // 947
 
-// uuid = 16; group_id = 4; title = 64; username = 64; password = 64;
-// times = 20; autotype = 1; padding = 3;
-pub const ENTRY_SIZE: usize = 16 + 4 + 64 + 64 + 64 + 20 + 1 + 3; // 236
+// As storage layout v1 kept entries: uuid = 16; group_id = 4; title = 64;
+// username = 64; password = 64; times = 20; autotype = 1; padding = 3; text
+// NUL-padded
+pub const ENTRY_V1_SIZE: usize = 16 + 4 + 64 + 64 + 64 + 20 + 1 + 3; // 236
 
-#[derive(Clone, Copy, Format, Debug)]
+/// The text fields are kept no longer than their limits, which the setters
+/// enforce. Dropping an entry zeroizes them. `Debug` and `Format` redact
+/// every text field.
+#[derive(Clone)]
 pub struct Entry {
     pub uuid: [u8; 16],
     pub group_id: u32,
 
-    pub title: [u8; 64],
-    pub username: [u8; 64],
-    pub password: [u8; 64],
+    title: Vec<u8>,
+    username: Vec<u8>,
+    password: SecretVec,
+    url: Vec<u8>,
+    notes: Vec<u8>,
     pub times: Times,
     pub autotype: bool,
 }
 
 impl Entry {
+    /// An entry with empty text fields and unset times.
+    pub fn new(uuid: [u8; 16], group_id: u32) -> Self {
+        Entry {
+            uuid,
+            group_id,
+            title: Vec::new(),
+            username: Vec::new(),
+            password: SecretVec::new(),
+            url: Vec::new(),
+            notes: Vec::new(),
+            times: Times::zero(),
+            autotype: true,
+        }
+    }
+
     pub fn default_with_group_id(group_id: u32) -> Self {
-        let uuid: [u8; 16] = 1u128.to_le_bytes();
+        let mut entry = Entry::new(1u128.to_le_bytes(), group_id);
+        entry.title = Vec::from(b"Google");
+        entry.username = Vec::from(b"carlos");
+        entry.password = SecretVec::from_slice(b"123456");
+        entry
+    }
 
-        let mut title = [0u8; 64];
-        title[..b"Google".len()].copy_from_slice(b"Google");
+    pub fn title(&self) -> &[u8] {
+        &self.title
+    }
 
-        let mut username = [0u8; 64];
-        username[..b"carlos".len()].copy_from_slice(b"carlos");
+    pub fn username(&self) -> &[u8] {
+        &self.username
+    }
 
-        let mut password = [0u8; 64];
-        password[..b"123456".len()].copy_from_slice(b"123456");
+    pub fn password(&self) -> &SecretVec {
+        &self.password
+    }
 
-        let times = Times::zero();
-        let autotype = true;
+    pub fn url(&self) -> &[u8] {
+        &self.url
+    }
 
-        Entry {
-            uuid,
-            group_id,
-            title,
-            username,
-            password,
-            times,
-            autotype,
+    pub fn notes(&self) -> &[u8] {
+        &self.notes
+    }
+
+    /// Fails with [`KDBError::FieldTooLong`] past [`MAX_TITLE_LEN`], leaving
+    /// the title as it was.
+    pub fn set_title(&mut self, title: &[u8]) -> Result<(), KDBError> {
+        replace_text(&mut self.title, title, MAX_TITLE_LEN)
+    }
+
+    /// Fails with [`KDBError::FieldTooLong`] past [`MAX_USERNAME_LEN`],
+    /// leaving the username as it was.
+    pub fn set_username(&mut self, username: &[u8]) -> Result<(), KDBError> {
+        replace_text(&mut self.username, username, MAX_USERNAME_LEN)
+    }
+
+    /// Fails with [`KDBError::FieldTooLong`] past [`MAX_PASSWORD_LEN`],
+    /// leaving the password as it was.
+    pub fn set_password(&mut self, password: &[u8]) -> Result<(), KDBError> {
+        if password.len() > MAX_PASSWORD_LEN {
+            return Err(KDBError::FieldTooLong);
         }
+        self.password = SecretVec::from_slice(password);
+        Ok(())
     }
 
-    pub fn new_from_bytes(bytes: &[u8]) -> Self {
-        let uuid: [u8; 16] = bytes[0..16].try_into().unwrap();
-        let group_id = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
+    /// Fails with [`KDBError::FieldTooLong`] past [`MAX_URL_LEN`], leaving the
+    /// URL as it was.
+    pub fn set_url(&mut self, url: &[u8]) -> Result<(), KDBError> {
+        replace_text(&mut self.url, url, MAX_URL_LEN)
+    }
 
-        let title: [u8; 64] = bytes[20..84].try_into().unwrap();
-        let username: [u8; 64] = bytes[84..148].try_into().unwrap();
-        let password: [u8; 64] = bytes[148..212].try_into().unwrap();
-        let times = Times::new_from_bytes(&bytes[212..232]);
-        let autotype = bytes[232] != 0;
+    /// Fails with [`KDBError::FieldTooLong`] past [`MAX_NOTES_LEN`], leaving
+    /// the notes as they were.
+    pub fn set_notes(&mut self, notes: &[u8]) -> Result<(), KDBError> {
+        replace_text(&mut self.notes, notes, MAX_NOTES_LEN)
+    }
 
-        Entry {
-            uuid,
-            group_id,
-            title,
-            username,
-            password,
-            times,
-            autotype,
+    /// Bytes [`Entry::to_bytes`] writes.
+    pub fn encoded_len(&self) -> usize {
+        ENTRY_FIXED_SIZE
+            + 5 * 2
+            + self.title.len()
+            + self.username.len()
+            + self.password.len()
+            + self.url.len()
+            + self.notes.len()
+    }
+
+    /// Decodes an entry record, checking every length against its limit.
+    pub fn new_from_bytes(bytes: &[u8]) -> Result<Self, KDBError> {
+        let fixed = bytes
+            .get(..ENTRY_FIXED_SIZE)
+            .ok_or(KDBError::DatabaseIntegrityError)?;
+        let mut entry = Entry::new(
+            fixed[0..16].try_into().unwrap(),
+            u32::from_le_bytes(fixed[16..20].try_into().unwrap()),
+        );
+        entry.times = Times::new_from_bytes(&fixed[20..40]);
+        entry.autotype = fixed[40] != 0;
+
+        let mut rest = &bytes[ENTRY_FIXED_SIZE..];
+        let title = read_text(&mut rest)?;
+        let username = read_text(&mut rest)?;
+        let password = read_text(&mut rest)?;
+        let url = read_text(&mut rest)?;
+        let notes = read_text(&mut rest)?;
+        if !rest.is_empty() {
+            return Err(KDBError::DatabaseIntegrityError);
         }
+        // A field over its limit was never written by this firmware
+        entry
+            .set_title(title)
+            .map_err(|_| KDBError::DatabaseIntegrityError)?;
+        entry
+            .set_username(username)
+            .map_err(|_| KDBError::DatabaseIntegrityError)?;
+        entry
+            .set_password(password)
+            .map_err(|_| KDBError::DatabaseIntegrityError)?;
+        entry
+            .set_url(url)
+            .map_err(|_| KDBError::DatabaseIntegrityError)?;
+        entry
+            .set_notes(notes)
+            .map_err(|_| KDBError::DatabaseIntegrityError)?;
+        Ok(entry)
     }
 
-    pub fn to_bytes(&self) -> [u8; ENTRY_SIZE] {
-        let mut bytes = [0u8; ENTRY_SIZE];
+    /// Decodes an entry as storage layout v1 kept it, with NUL-padded 64-byte
+    /// text.
+    pub fn new_from_v1_bytes(bytes: &[u8]) -> Self {
+        let mut entry = Entry::new(
+            bytes[0..16].try_into().unwrap(),
+            u32::from_le_bytes(bytes[16..20].try_into().unwrap()),
+        );
+        // 64 bytes are within every limit
+        entry.title = Vec::from(until_nul(&bytes[20..84]));
+        entry.username = Vec::from(until_nul(&bytes[84..148]));
+        entry.password = SecretVec::from_slice(until_nul(&bytes[148..212]));
+        entry.times = Times::new_from_bytes(&bytes[212..232]);
+        entry.autotype = bytes[232] != 0;
+        entry
+    }
 
+    /// Encodes the entry at the start of `bytes`, returning its length.
+    pub fn to_bytes(&self, bytes: &mut [u8; MAX_ENTRY_SIZE]) -> usize {
         bytes[0..16].copy_from_slice(&self.uuid);
         bytes[16..20].copy_from_slice(&self.group_id.to_le_bytes());
+        bytes[20..40].copy_from_slice(&self.times.to_bytes());
+        bytes[40] = self.autotype as u8;
+
+        let mut len = ENTRY_FIXED_SIZE;
+        let texts = [
+            &self.title[..],
+            &self.username,
+            self.password.expose(),
+            &self.url,
+            &self.notes,
+        ];
+        for text in texts {
+            bytes[len..len + 2].copy_from_slice(&(text.len() as u16).to_le_bytes());
+            bytes[len + 2..len + 2 + text.len()].copy_from_slice(text);
+            len += 2 + text.len();
+        }
+        len
+    }
+}
 
-        bytes[20..84].copy_from_slice(&self.title);
-        bytes[84..148].copy_from_slice(&self.username);
-        bytes[148..212].copy_from_slice(&self.password);
-        bytes[212..232].copy_from_slice(&self.times.to_bytes());
-        bytes[232] = self.autotype as u8;
+impl Drop for Entry {
+    fn drop(&mut self) {
+        // The password zeroizes itself
+        self.title.as_mut_slice().zeroize();
+        self.username.as_mut_slice().zeroize();
+        self.url.as_mut_slice().zeroize();
+        self.notes.as_mut_slice().zeroize();
+    }
+}
+
+/// Swaps `dst` for a copy of `value`, zeroizing the old text.
+fn replace_text(dst: &mut Vec<u8>, value: &[u8], max_len: usize) -> Result<(), KDBError> {
+    if value.len() > max_len {
+        return Err(KDBError::FieldTooLong);
+    }
+    dst.as_mut_slice().zeroize();
+    *dst = Vec::with_capacity(value.len());
+    dst.extend_from_slice(value);
+    Ok(())
+}
+
+/// Reads a 2-byte length and that many bytes off the front of `bytes`.
+fn read_text<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], KDBError> {
+    let (len, rest) = bytes
+        .split_first_chunk::<2>()
+        .ok_or(KDBError::DatabaseIntegrityError)?;
+    let len = u16::from_le_bytes(*len) as usize;
+    let text = rest.get(..len).ok_or(KDBError::DatabaseIntegrityError)?;
+    *bytes = &rest[len..];
+    Ok(text)
+}
+
+fn until_nul(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Entry")
+            .field("uuid", &self.uuid)
+            .field("group_id", &self.group_id)
+            .field("title", &Redacted(&self.title))
+            .field("username", &Redacted(&self.username))
+            .field("password", &self.password)
+            .field("url", &Redacted(&self.url))
+            .field("notes", &Redacted(&self.notes))
+            .field("times", &self.times)
+            .field("autotype", &self.autotype)
+            .finish()
+    }
+}
 
-        bytes
+impl Format for Entry {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "Entry {{ uuid: {}, group_id: {}, title: {}, username: {}, password: {}, url: {}, notes: {}, times: {}, autotype: {} }}",
+            self.uuid,
+            self.group_id,
+            Redacted(&self.title),
+            Redacted(&self.username),
+            self.password,
+            Redacted(&self.url),
+            Redacted(&self.notes),
+            self.times,
+            self.autotype,
+        );
     }
 }
@@ -1,7 +1,34 @@
-#[derive(Debug, defmt::Format)]
+use crate::storage::layout::StorageError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum KDBError {
     /// The database is corrupted
     DatabaseIntegrityError,
     /// The item select wasn't found
     EntryNotFound,
+    /// The key derived from the PIN doesn't open the database
+    InvalidKey,
+    /// The database hasn't been unlocked yet
+    Locked,
+    /// The stored records don't match the header's `contents_hash`
+    ContentsHashMismatch,
+    /// Reading or writing the flash failed
+    Io,
+    /// The records use a newer format than this firmware understands
+    UnsupportedFormat(u32),
+    /// The KeePass region has no room left for the change
+    StorageFull,
+    /// The database already holds as many groups or entries as it can
+    DatabaseFull,
+    /// A text field is longer than the record can hold
+    FieldTooLong,
+}
+
+impl From<StorageError> for KDBError {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::Io => KDBError::Io,
+            _ => KDBError::DatabaseIntegrityError,
+        }
+    }
 }
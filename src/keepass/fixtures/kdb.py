@@ -0,0 +1,110 @@
+#!/usr/bin/env python3
+"""Writes the .kdb fixtures the kdb unit tests read.
+
+The files follow the .kdb layout as the reader reads it: groups, then
+entries, then a KeePassX-style meta-stream entry, AES-256-CBC with the key
+stretched by AES-ECB rounds. It was written alongside the reader, so it
+shares any misreading of the format; no file saved by KeePass 1.x or
+KeePassX is checked in, and the tests don't vouch for compatibility with
+them.
+
+    python3 kdb.py          # rewrites sample.kdb and too_long.kdb
+
+Needs the `cryptography` package. The password is "correct horse".
+"""
+
+import hashlib
+import os
+import struct
+
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+
+PASSWORD = b"correct horse"
+ROUNDS = 1000
+
+
+def pack_time(year, month, day, hour, minute, second):
+    return bytes([
+        (year >> 6) & 0x3F,
+        ((year & 0x3F) << 2) | ((month >> 2) & 0x03),
+        ((month & 0x03) << 6) | ((day & 0x1F) << 1) | ((hour >> 4) & 0x01),
+        ((hour & 0x0F) << 4) | ((minute >> 2) & 0x0F),
+        ((minute & 0x03) << 6) | (second & 0x3F),
+    ])
+
+
+CREATED = pack_time(2024, 5, 17, 13, 45, 9)
+NEVER = pack_time(2999, 12, 28, 23, 59, 59)
+
+
+def field(kind, data):
+    return struct.pack("<HI", kind, len(data)) + data
+
+
+def text(kind, value):
+    return field(kind, value.encode() + b"\0")
+
+
+def group(group_id, name, level):
+    return (field(1, struct.pack("<I", group_id)) + text(2, name)
+            + field(3, CREATED) + field(4, CREATED) + field(5, CREATED)
+            + field(6, NEVER) + field(7, struct.pack("<I", 48))
+            + field(8, struct.pack("<H", level)) + field(9, struct.pack("<I", 0))
+            + field(0xFFFF, b""))
+
+
+def entry(uuid, group_id, title, url, username, password, notes,
+          binary_desc="", binary=b""):
+    return (field(1, uuid) + field(2, struct.pack("<I", group_id))
+            + field(3, struct.pack("<I", 0)) + text(4, title) + text(5, url)
+            + text(6, username) + text(7, password) + text(8, notes)
+            + field(9, CREATED) + field(10, CREATED) + field(11, CREATED)
+            + field(12, NEVER) + text(13, binary_desc) + field(14, binary)
+            + field(0xFFFF, b""))
+
+
+def file_key(master_seed, transform_seed):
+    key = hashlib.sha256(PASSWORD).digest()
+    ecb = Cipher(algorithms.AES(transform_seed), modes.ECB()).encryptor()
+    for _ in range(ROUNDS):
+        key = ecb.update(key)
+    return hashlib.sha256(master_seed + hashlib.sha256(key).digest()).digest()
+
+
+def write(path, bank_title):
+    groups = [
+        group(1, "Internet", 0),
+        group(2, "eMail", 1),
+        group(7, "Banking", 0),
+    ]
+    entries = [
+        entry(bytes(range(0, 16)), 1, "GitHub", "https://github.com",
+              "octocat", "hunter2", "notes here"),
+        entry(bytes(range(16, 32)), 2, "Mail", "", "me@example.com",
+              "p" * 64, ""),
+        entry(bytes(range(32, 48)), 7, bank_title, "", "acct", "1234", ""),
+        # KeePassX keeps its tree state in an entry like this
+        entry(bytes(16), 1, "Meta-Info", "$", "SYSTEM", "",
+              "KPX_GROUP_TREE_STATE", "bin-stream", b"\x01\x02"),
+    ]
+    plaintext = b"".join(groups) + b"".join(entries)
+
+    master_seed, iv, transform_seed = os.urandom(16), os.urandom(16), os.urandom(32)
+    header = (struct.pack("<IIII", 0x9AA2D903, 0xB54BFB65, 3, 0x00030004)
+              + master_seed + iv
+              + struct.pack("<II", len(groups), len(entries))
+              + hashlib.sha256(plaintext).digest()
+              + transform_seed + struct.pack("<I", ROUNDS))
+    padding = 16 - len(plaintext) % 16
+    cbc = Cipher(algorithms.AES(file_key(master_seed, transform_seed)),
+                 modes.CBC(iv)).encryptor()
+    payload = cbc.update(plaintext + bytes([padding]) * padding) + cbc.finalize()
+    with open(path, "wb") as file:
+        file.write(header + payload)
+
+
+if __name__ == "__main__":
+    here = os.path.dirname(os.path.abspath(__file__))
+    write(os.path.join(here, "sample.kdb"), "Bank")
+    # One title longer than an entry record holds
+    write(os.path.join(here, "too_long.kdb"), "Bank" * 33)
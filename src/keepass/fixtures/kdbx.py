@@ -0,0 +1,205 @@
+#!/usr/bin/env python3
+"""Writes the .kdbx fixtures the kdbx unit tests read.
+
+The files are KDBX 4 the way KeePassXC saves them: a header with its SHA-256
+and HMAC, HMAC-protected blocks, an inner header with a ChaCha20 stream for
+protected values, then the XML. This is an independent writer for the
+format, so it also checks our reader against someone else's reading of the
+spec.
+
+    python3 kdbx.py     # rewrites aes_kdf.kdbx, argon2id.kdbx and argon2d.kdbx
+
+Needs the `cryptography` package. The password is "correct horse".
+"""
+
+import base64
+import datetime
+import gzip
+import hashlib
+import hmac
+import os
+import struct
+
+from cryptography.hazmat.primitives import padding
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.hazmat.primitives.kdf.argon2 import Argon2d, Argon2id
+
+PASSWORD = b"correct horse"
+
+CIPHER_AES256 = bytes.fromhex("31c1f2e6bf714350be5805216afc5aff")
+CIPHER_CHACHA20 = bytes.fromhex("d6038a2b8b6f4cb5a524339a31dbb59a")
+KDF_AES = bytes.fromhex("c9d9f39a628a4460bf740d08c18a4fea")
+KDF_ARGON2D = bytes.fromhex("ef636ddf8c29444b91f7a9a403e30a0c")
+KDF_ARGON2ID = bytes.fromhex("9e298b1956db4773b23dfc3ec6f0a1e6")
+
+
+def variant_dictionary(items):
+    out = struct.pack("<H", 0x0100)
+    for kind, name, value in items:
+        name = name.encode()
+        out += struct.pack("<BI", kind, len(name)) + name
+        out += struct.pack("<I", len(value)) + value
+    return out + b"\0"
+
+
+def field(field_id, data):
+    return struct.pack("<BI", field_id, len(data)) + data
+
+
+def kdbx_time(*parts):
+    secs = (datetime.datetime(*parts) - datetime.datetime(1, 1, 1)).total_seconds()
+    return base64.b64encode(struct.pack("<q", int(secs))).decode()
+
+
+CREATED = kdbx_time(2024, 5, 17, 13, 45, 9)
+EXPIRES = kdbx_time(2030, 1, 2, 3, 4, 5)
+
+
+def times(expires=False):
+    return (f"<Times><CreationTime>{CREATED}</CreationTime>"
+            f"<LastModificationTime>{CREATED}</LastModificationTime>"
+            f"<LastAccessTime>{CREATED}</LastAccessTime>"
+            f"<ExpiryTime>{EXPIRES}</ExpiryTime>"
+            f"<Expires>{expires}</Expires><UsageCount>0</UsageCount></Times>")
+
+
+def random_uuid():
+    return base64.b64encode(os.urandom(16)).decode()
+
+
+class Xml:
+    """Builds the XML, masking protected values in document order."""
+
+    def __init__(self, stream):
+        self.stream = stream
+
+    def entry(self, title, username, password, url="", notes="",
+              history=None, autotype=True, expires=False, uuid=None):
+        masked = self.stream.update(password.encode())
+        # An entry's history comes after its own values
+        history = history() if history else ""
+        return (f"<Entry><UUID>{uuid or random_uuid()}</UUID><IconID>0</IconID>"
+                f"{times(expires)}"
+                f"<String><Key>Notes</Key><Value>{notes}</Value></String>"
+                f"<String><Key>Password</Key><Value Protected=\"True\">"
+                f"{base64.b64encode(masked).decode()}</Value></String>"
+                f"<String><Key>Title</Key><Value>{title}</Value></String>"
+                f"<String><Key>URL</Key><Value>{url}</Value></String>"
+                f"<String><Key>UserName</Key><Value>{username}</Value></String>"
+                f"<AutoType><Enabled>{autotype}</Enabled>"
+                f"<DataTransferObfuscation>0</DataTransferObfuscation></AutoType>"
+                f"{history}</Entry>")
+
+    def group(self, name, *children):
+        return (f"<Group><UUID>{random_uuid()}</UUID><Name>{name}</Name>"
+                f"{times()}{''.join(children)}</Group>")
+
+    def document(self):
+        # Protected values are masked in the order they appear, so the
+        # entries are built front to back
+        github_uuid = base64.b64encode(bytes(range(16))).decode()
+        history = lambda: ("<History>" + self.entry("GitHub", "octocat", "old password")
+                           + "</History>")
+        github = self.entry("GitHub", "octocat", "hunter2 & more",
+                            url="https://github.com",
+                            notes="Recovery codes &amp; keys\nare in the safe",
+                            history=history, uuid=github_uuid)
+        mail = self.entry("Mail", "me@example.com", "p" * 64,
+                          autotype=False, expires=True)
+        too_long = self.entry("T" * 200, "x", "y")
+        deep = self.group("Deep", self.entry("Nested", "n", "nested"),
+                          self.group("Deeper", self.entry("Nested 2", "n2", "nested 2")))
+        after_deep = self.entry("After deep", "a", "after")
+        root = self.group(
+            "Root",
+            github,
+            self.group("Work &amp; Co", mail, too_long, deep, after_deep),
+            self.group("Empty"),
+        )
+        return ("<?xml version=\"1.0\" encoding=\"utf-8\" standalone=\"yes\"?>\n"
+                "<KeePassFile><Meta><Generator>KeePassXC</Generator>"
+                "<DatabaseName>Team</DatabaseName></Meta>"
+                f"<Root>{root}<DeletedObjects/></Root></KeePassFile>")
+
+
+def transform(kdf, seed):
+    composite = hashlib.sha256(hashlib.sha256(PASSWORD).digest()).digest()
+    if kdf == "aes":
+        rounds = 2000
+        params = [(0x42, "$UUID", KDF_AES), (0x05, "R", struct.pack("<Q", rounds)),
+                  (0x42, "S", seed)]
+        ecb = Cipher(algorithms.AES(seed), modes.ECB()).encryptor()
+        key = composite
+        for _ in range(rounds):
+            key = ecb.update(key)
+        return params, hashlib.sha256(key).digest()
+
+    uuid, argon2, memory, lanes = {
+        "argon2id": (KDF_ARGON2ID, Argon2id, 32 * 1024, 2),
+        "argon2d": (KDF_ARGON2D, Argon2d, 16 * 1024, 1),
+    }[kdf]
+    iterations = 2
+    params = [(0x42, "$UUID", uuid), (0x42, "S", seed),
+              (0x04, "P", struct.pack("<I", lanes)),
+              (0x05, "M", struct.pack("<Q", memory)),
+              (0x05, "I", struct.pack("<Q", iterations)),
+              (0x04, "V", struct.pack("<I", 0x13))]
+    key = argon2(salt=seed, length=32, iterations=iterations, lanes=lanes,
+                 memory_cost=memory // 1024).derive(composite)
+    return params, key
+
+
+def write(path, kdf, cipher, compress):
+    master_seed = os.urandom(32)
+    kdf_params, transformed = transform(kdf, os.urandom(32))
+    if cipher == "aes":
+        cipher_id, iv = CIPHER_AES256, os.urandom(16)
+    else:
+        cipher_id, iv = CIPHER_CHACHA20, os.urandom(12)
+
+    header = (struct.pack("<III", 0x9AA2D903, 0xB54BFB67, 0x00040000)
+              + field(2, cipher_id)
+              + field(3, struct.pack("<I", int(compress)))
+              + field(4, master_seed)
+              + field(7, iv)
+              + field(11, variant_dictionary(kdf_params))
+              + field(0, b"\r\n\r\n"))
+    cipher_key = hashlib.sha256(master_seed + transformed).digest()
+    hmac_key = hashlib.sha512(master_seed + transformed + b"\x01").digest()
+
+    def block_mac(index, data):
+        key = hashlib.sha512(struct.pack("<Q", index) + hmac_key).digest()
+        return hmac.new(key, data, hashlib.sha256).digest()
+
+    stream_key = os.urandom(64)
+    digest = hashlib.sha512(stream_key).digest()
+    stream = Cipher(algorithms.ChaCha20(digest[:32], b"\0" * 4 + digest[32:44]),
+                    mode=None).encryptor()
+    inner = (field(1, struct.pack("<I", 3)) + field(2, stream_key)
+             + field(3, b"\x01attachment") + field(0, b"")
+             + Xml(stream).document().encode())
+    if compress:
+        inner = gzip.compress(inner)
+
+    if cipher == "aes":
+        padder = padding.PKCS7(128).padder()
+        cbc = Cipher(algorithms.AES(cipher_key), modes.CBC(iv)).encryptor()
+        payload = cbc.update(padder.update(inner) + padder.finalize()) + cbc.finalize()
+    else:
+        chacha = Cipher(algorithms.ChaCha20(cipher_key, b"\0" * 4 + iv), mode=None)
+        payload = chacha.encryptor().update(inner)
+
+    out = header + hashlib.sha256(header).digest() + block_mac(2**64 - 1, header)
+    blocks = [payload[i:i + 1000] for i in range(0, len(payload), 1000)] + [b""]
+    for index, block in enumerate(blocks):
+        out += block_mac(index, struct.pack("<QI", index, len(block)) + block)
+        out += struct.pack("<I", len(block)) + block
+    with open(path, "wb") as file:
+        file.write(out)
+
+
+if __name__ == "__main__":
+    here = os.path.dirname(os.path.abspath(__file__))
+    write(os.path.join(here, "aes_kdf.kdbx"), "aes", "aes", compress=True)
+    write(os.path.join(here, "argon2id.kdbx"), "argon2id", "chacha20", compress=False)
+    write(os.path.join(here, "argon2d.kdbx"), "argon2d", "aes", compress=True)
@@ -1,10 +1,14 @@
 use super::times::Times;
+use core::fmt;
 use defmt::Format;
 
+use crate::secret::Redacted;
+
 // group_id = 4; name = 64; times = 20;
 pub const GROUP_SIZE: usize = 4 + 64 + 20; // 88
 
-#[derive(Clone, Copy, Format, Debug)]
+/// `Debug` and `Format` redact the name.
+#[derive(Clone, Copy)]
 pub struct Group {
     /// The unique identifier of the group
     pub group_id: u32,
@@ -48,3 +52,25 @@ impl Group {
         bytes
     }
 }
+
+impl fmt::Debug for Group {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Group")
+            .field("group_id", &self.group_id)
+            .field("name", &Redacted(&self.name))
+            .field("times", &self.times)
+            .finish()
+    }
+}
+
+impl Format for Group {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "Group {{ group_id: {}, name: {}, times: {} }}",
+            self.group_id,
+            Redacted(&self.name),
+            self.times,
+        );
+    }
+}
@@ -1,11 +1,24 @@
 use super::error::KDBError;
 
+use core::fmt;
+
 use defmt::Format;
 
+use crate::secret::Redacted;
+
 pub const KDB_SIGNATURE1: u32 = 0x9AA2D903;
 pub const KDB_SIGNATURE2: u32 = 0xB54BFB65;
+/// Format of the sealed group and entry records, kept in
+/// [`KDBHeader::subversion`]. Bump it when a record layout changes and teach
+/// the record decoders in `storage::keepass` to read the previous one;
+/// databases are rewritten in the current format when they are unlocked.
+pub const KDB_FORMAT_VERSION: u32 = 1;
+/// Key stretching rounds for new databases (roughly one second on the ESP32-S3).
+pub const DEFAULT_TRANSFORM_ROUNDS: u32 = 100_000;
 
-#[derive(Clone, Copy, Format, Debug)]
+/// `Debug` and `Format` redact the seeds, IV and contents hash, which feed
+/// the key derivation.
+#[derive(Clone, Copy)]
 pub struct KDBHeader {
     // https://gist.github.com/lgg/e6ccc6e212d18dd2ecd8a8c116fb1e45
     pub flags: u32,
@@ -86,3 +99,37 @@ fn copy_array<const N: usize>(data: &[u8], start: usize) -> Result<[u8; N], KDBE
     out.copy_from_slice(slice);
     Ok(out)
 }
+
+impl fmt::Debug for KDBHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KDBHeader")
+            .field("flags", &self.flags)
+            .field("subversion", &self.subversion)
+            .field("master_seed", &Redacted(&self.master_seed))
+            .field("encryption_iv", &Redacted(&self.encryption_iv))
+            .field("num_groups", &self.num_groups)
+            .field("num_entries", &self.num_entries)
+            .field("contents_hash", &Redacted(&self.contents_hash))
+            .field("transform_seed", &Redacted(&self.transform_seed))
+            .field("transform_rounds", &self.transform_rounds)
+            .finish()
+    }
+}
+
+impl Format for KDBHeader {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "KDBHeader {{ flags: {}, subversion: {}, master_seed: {}, encryption_iv: {}, num_groups: {}, num_entries: {}, contents_hash: {}, transform_seed: {}, transform_rounds: {} }}",
+            self.flags,
+            self.subversion,
+            Redacted(&self.master_seed),
+            Redacted(&self.encryption_iv),
+            self.num_groups,
+            self.num_entries,
+            Redacted(&self.contents_hash),
+            Redacted(&self.transform_seed),
+            self.transform_rounds,
+        );
+    }
+}
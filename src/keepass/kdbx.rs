@@ -0,0 +1,1627 @@
+//! KDBX 4 import and export, for databases from and for KeePass 2.x,
+//! KeePassXC and the like.
+//!
+//! The whole file is handled in memory: the header and its HMAC are checked,
+//! the key is derived with AES-KDF or Argon2, and the HMAC-protected blocks
+//! are decrypted (AES-256-CBC or ChaCha20) and decompressed. The inner XML is
+//! then walked once, unmasking protected values with the inner ChaCha20
+//! stream as they come. Only password-only files are supported.
+//!
+//! The device keeps a flat list of groups: the groups right under the root
+//! come across, and so does the root if it holds entries of its own. Deeper
+//! groups don't. Anything that doesn't fit, a deeper group or a value longer
+//! than its record holds, is left out and listed in [`KdbxImport::misfits`]
+//! rather than cut to size. Custom fields, attachments and history are not
+//! kept.
+//!
+//! The firmware has a 72 KiB heap, shared with the unlocked database, so an
+//! import stays within a budget: the file is at most [`MAX_FILE_SIZE`] bytes,
+//! its XML at most [`MAX_INFLATED_SIZE`] once inflated, and the device allows
+//! Argon2 [`MAX_KDF_MEMORY`]. While Argon2 runs only the file is held, 40 KiB
+//! in all; after that the file, its decrypted copy, the XML and what is read
+//! out of it come to at most 48 KiB. KeePassXC asks for 64 MiB by default, so
+//! on the device its files need AES-KDF.
+//!
+//! [`export`] goes the other way without holding the file in memory: the XML
+//! is written out as it is encrypted (AES-KDF, AES-256-CBC, no compression)
+//! and cut into HMAC-protected blocks of [`EXPORT_BLOCK_SIZE`] bytes. The
+//! device's groups end up under a root group of their own.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use defmt::Format;
+use hmac::{Hmac, Mac};
+use miniz_oxide::inflate::TINFLStatus;
+use sha2::{Digest, Sha256, Sha512};
+use xmlparser::{ElementEnd, Token, Tokenizer};
+use zeroize::Zeroize;
+
+use super::entry::Entry;
+use super::group::Group;
+use super::header::KDB_SIGNATURE1;
+use super::kdb::{BLOCK_SIZE, CbcWriter, cbc_decrypt, unpadded_len};
+use super::times::{KdbTime, Times};
+use crate::encryption::{self, KEY_SIZE};
+use crate::secret::{Redacted, SecretBytes};
+
+pub const KDBX_SIGNATURE2: u32 = 0xB54BFB67;
+/// Major format version, in the top half of the version field.
+const KDBX_MAJOR_VERSION: u32 = 4;
+
+const HEADER_END: u8 = 0;
+const HEADER_CIPHER_ID: u8 = 2;
+const HEADER_COMPRESSION: u8 = 3;
+const HEADER_MASTER_SEED: u8 = 4;
+const HEADER_ENCRYPTION_IV: u8 = 7;
+const HEADER_KDF_PARAMETERS: u8 = 11;
+
+const INNER_END: u8 = 0;
+const INNER_STREAM_ID: u8 = 1;
+const INNER_STREAM_KEY: u8 = 2;
+
+const CIPHER_AES256: [u8; 16] = [
+    0x31, 0xC1, 0xF2, 0xE6, 0xBF, 0x71, 0x43, 0x50, 0xBE, 0x58, 0x05, 0x21, 0x6A, 0xFC, 0x5A, 0xFF,
+];
+const CIPHER_CHACHA20: [u8; 16] = [
+    0xD6, 0x03, 0x8A, 0x2B, 0x8B, 0x6F, 0x4C, 0xB5, 0xA5, 0x24, 0x33, 0x9A, 0x31, 0xDB, 0xB5, 0x9A,
+];
+const KDF_AES: [u8; 16] = [
+    0xC9, 0xD9, 0xF3, 0x9A, 0x62, 0x8A, 0x44, 0x60, 0xBF, 0x74, 0x0D, 0x08, 0xC1, 0x8A, 0x4F, 0xEA,
+];
+const KDF_ARGON2D: [u8; 16] = [
+    0xEF, 0x63, 0x6D, 0xDF, 0x8C, 0x29, 0x44, 0x4B, 0x91, 0xF7, 0xA9, 0xA4, 0x03, 0xE3, 0x0A, 0x0C,
+];
+const KDF_ARGON2ID: [u8; 16] = [
+    0x9E, 0x29, 0x8B, 0x19, 0x56, 0xDB, 0x47, 0x73, 0xB2, 0x3D, 0xFC, 0x3E, 0xC6, 0xF0, 0xA1, 0xE6,
+];
+
+const COMPRESSION_NONE: u32 = 0;
+const COMPRESSION_GZIP: u32 = 1;
+const INNER_STREAM_NONE: u32 = 0;
+const INNER_STREAM_CHACHA20: u32 = 3;
+
+/// HMAC key index the header is authenticated with.
+const HEADER_BLOCK_INDEX: u64 = u64::MAX;
+
+const VARIANT_DICTIONARY_VERSION: u16 = 0x0100;
+const VARIANT_END: u8 = 0;
+const VARIANT_U64: u8 = 0x05;
+const VARIANT_BYTES: u8 = 0x42;
+
+/// Payload bytes per HMAC-protected block in an exported file. KeePass uses
+/// 1 MiB; any size reads back.
+pub const EXPORT_BLOCK_SIZE: usize = 1024;
+/// Name of the root group and the database in an exported file.
+const EXPORT_ROOT_NAME: &str = "Passbuddy";
+
+/// Largest file [`import`] takes.
+pub const MAX_FILE_SIZE: usize = 8 * 1024;
+/// Largest XML, inner header included, a file may inflate to.
+pub const MAX_INFLATED_SIZE: usize = 16 * 1024;
+/// Argon2 memory the device lets a file ask for.
+pub const MAX_KDF_MEMORY: u64 = 32 * 1024;
+
+/// Depth of the deepest group kept: the root's children.
+pub const MAX_GROUP_DEPTH: usize = 1;
+/// Size of the name field in [`Group`].
+const TEXT_LEN: usize = 64;
+
+/// Seconds from 0001-01-01, where KDBX 4 times count from, to 1970-01-01.
+const UNIX_EPOCH_SECS: i64 = 62_135_596_800;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum KdbxError {
+    /// Not a KDBX file, or a damaged one
+    Malformed,
+    /// A KDBX version other than 4
+    UnsupportedVersion(u32),
+    UnsupportedCipher,
+    UnsupportedKdf,
+    UnsupportedCompression,
+    /// Protected values use a stream other than ChaCha20
+    UnsupportedInnerStream,
+    /// The password doesn't open the file
+    InvalidKey,
+    /// A block doesn't match its HMAC, so the file was damaged or tampered with
+    Corrupted,
+    /// Argon2 would need this many bytes, more than the caller allows
+    KdfTooExpensive(u64),
+    /// The file is bigger than [`MAX_FILE_SIZE`], or inflates to more than
+    /// [`MAX_INFLATED_SIZE`]
+    TooLarge,
+}
+
+/// A field of a group or entry that may not fit its record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Field {
+    GroupName,
+    Title,
+    UserName,
+    Password,
+    Url,
+    Notes,
+}
+
+/// Something in the file the device can't hold. `entries` counts the entries
+/// left out because of it. `Debug` and `Format` redact the names.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Misfit {
+    /// A group below [`MAX_GROUP_DEPTH`], left out with everything under it
+    GroupTooDeep { group: String, entries: usize },
+    /// A value of `len` bytes, more than its record holds. A group is left
+    /// out with everything under it, an entry on its own.
+    FieldTooLong {
+        name: String,
+        field: Field,
+        len: usize,
+        entries: usize,
+    },
+}
+
+impl fmt::Debug for Misfit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Misfit::GroupTooDeep { group, entries } => f
+                .debug_struct("GroupTooDeep")
+                .field("group", &Redacted(group.as_bytes()))
+                .field("entries", entries)
+                .finish(),
+            Misfit::FieldTooLong {
+                name,
+                field,
+                len,
+                entries,
+            } => f
+                .debug_struct("FieldTooLong")
+                .field("name", &Redacted(name.as_bytes()))
+                .field("field", field)
+                .field("len", len)
+                .field("entries", entries)
+                .finish(),
+        }
+    }
+}
+
+impl Format for Misfit {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            Misfit::GroupTooDeep { group, entries } => defmt::write!(
+                f,
+                "GroupTooDeep {{ group: {}, entries: {} }}",
+                Redacted(group.as_bytes()),
+                entries,
+            ),
+            Misfit::FieldTooLong {
+                name,
+                field,
+                len,
+                entries,
+            } => defmt::write!(
+                f,
+                "FieldTooLong {{ name: {}, field: {}, len: {}, entries: {} }}",
+                Redacted(name.as_bytes()),
+                field,
+                len,
+                entries,
+            ),
+        }
+    }
+}
+
+/// Groups and entries mapped onto the device's records, in file order.
+/// Group ids are handed out from 1 in that order.
+#[derive(Debug, Default)]
+pub struct KdbxImport {
+    pub groups: Vec<Group>,
+    pub entries: Vec<Entry>,
+    pub misfits: Vec<Misfit>,
+}
+
+/// Decrypts a KDBX 4 `file` with `password` and maps its groups and entries
+/// onto the device's records. Argon2 may take up to `max_kdf_memory` bytes;
+/// a file asking for more fails with [`KdbxError::KdfTooExpensive`] rather
+/// than exhausting the heap. The device passes [`MAX_KDF_MEMORY`].
+pub fn import(file: &[u8], password: &[u8], max_kdf_memory: u64) -> Result<KdbxImport, KdbxError> {
+    if file.len() > MAX_FILE_SIZE {
+        return Err(KdbxError::TooLarge);
+    }
+
+    // 1. The header, checked against its hash
+    let header = read_header(file)?;
+    let header_bytes = &file[..header.len];
+    let header_hash = file
+        .get(header.len..header.len + 32)
+        .ok_or(KdbxError::Malformed)?;
+    let header_hmac = file
+        .get(header.len + 32..header.len + 64)
+        .ok_or(KdbxError::Malformed)?;
+    if Sha256::digest(header_bytes).as_slice() != header_hash {
+        return Err(KdbxError::Corrupted);
+    }
+
+    // 2. The keys, checked against the header HMAC
+    let (cipher_key, hmac_key) = derive_keys(&header, password, max_kdf_memory)?;
+    let mut mac = block_mac(&hmac_key, HEADER_BLOCK_INDEX);
+    mac.update(header_bytes);
+    mac.verify_slice(header_hmac)
+        .map_err(|_| KdbxError::InvalidKey)?;
+
+    // 3. The payload, decrypted and decompressed
+    let mut payload = read_blocks(&file[header.len + 64..], &hmac_key)?;
+    let decrypted = decrypt(&header, &cipher_key, &mut payload);
+    let inner = decrypted.and_then(|len| match header.compression {
+        COMPRESSION_NONE => Ok(payload[..len].to_vec()),
+        COMPRESSION_GZIP => gunzip(&payload[..len]),
+        _ => Err(KdbxError::UnsupportedCompression),
+    });
+    payload.as_mut_slice().zeroize();
+    drop(payload);
+    let mut inner = inner?;
+
+    // 4. The inner header, then the XML
+    let imported = read_inner(&inner);
+    inner.as_mut_slice().zeroize();
+    imported
+}
+
+/// Writes `groups` and `entries` as a KDBX 4 file for `password`, handing
+/// it to `sink` piece by piece. Entries go in the first group with their
+/// group id, or in the root group if there is none. Missing or repeated
+/// entry UUIDs are replaced with fresh ones.
+pub async fn export<'g, 'e, G, E, W, SinkError>(
+    groups: G,
+    entries: E,
+    password: &[u8],
+    transform_rounds: u32,
+    mut sink: W,
+) -> Result<(), SinkError>
+where
+    G: Iterator<Item = &'g Group> + Clone,
+    E: Iterator<Item = &'e Entry> + Clone,
+    W: AsyncFnMut(&[u8]) -> Result<(), SinkError>,
+{
+    // 1. Fresh seeds and the keys they give
+    let mut master_seed = [0u8; 32];
+    let mut iv = [0u8; BLOCK_SIZE];
+    let mut kdf_seed = [0u8; 32];
+    let mut stream_key = [0u8; 64];
+    encryption::fill_random(&mut master_seed);
+    encryption::fill_random(&mut iv);
+    encryption::fill_random(&mut kdf_seed);
+    encryption::fill_random(&mut stream_key);
+    let stream_key = SecretBytes::take_from(&mut stream_key);
+    let transformed =
+        encryption::transform_key(&composite_key(password), &kdf_seed, transform_rounds);
+    let (cipher_key, hmac_key) = session_keys(&master_seed, &transformed);
+
+    // 2. The header, its hash and its HMAC
+    let mut kdf = Vec::new();
+    kdf.extend_from_slice(&VARIANT_DICTIONARY_VERSION.to_le_bytes());
+    push_variant(&mut kdf, VARIANT_BYTES, b"$UUID", &KDF_AES);
+    push_variant(
+        &mut kdf,
+        VARIANT_U64,
+        b"R",
+        &u64::from(transform_rounds).to_le_bytes(),
+    );
+    push_variant(&mut kdf, VARIANT_BYTES, b"S", &kdf_seed);
+    kdf.push(VARIANT_END);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&KDB_SIGNATURE1.to_le_bytes());
+    header.extend_from_slice(&KDBX_SIGNATURE2.to_le_bytes());
+    header.extend_from_slice(&(KDBX_MAJOR_VERSION << 16).to_le_bytes());
+    push_field(&mut header, HEADER_CIPHER_ID, &CIPHER_AES256);
+    push_field(
+        &mut header,
+        HEADER_COMPRESSION,
+        &COMPRESSION_NONE.to_le_bytes(),
+    );
+    push_field(&mut header, HEADER_MASTER_SEED, &master_seed);
+    push_field(&mut header, HEADER_ENCRYPTION_IV, &iv);
+    push_field(&mut header, HEADER_KDF_PARAMETERS, &kdf);
+    push_field(&mut header, HEADER_END, b"\r\n\r\n");
+    let mut mac = block_mac(&hmac_key, HEADER_BLOCK_INDEX);
+    mac.update(&header);
+    sink(&header).await?;
+    sink(&Sha256::digest(&header)).await?;
+    sink(&mac.finalize().into_bytes()).await?;
+
+    // 3. The inner header and the XML, encrypted and cut into blocks
+    let mut blocks = BlockWriter::new(&hmac_key, &mut sink);
+    let mut to_blocks = async |bytes: &[u8]| blocks.write(bytes).await;
+    let mut cbc = CbcWriter::new(&cipher_key, iv, &mut to_blocks);
+    cbc.write(&[INNER_STREAM_ID]).await?;
+    cbc.write(&4u32.to_le_bytes()).await?;
+    cbc.write(&INNER_STREAM_CHACHA20.to_le_bytes()).await?;
+    cbc.write(&[INNER_STREAM_KEY]).await?;
+    cbc.write(&64u32.to_le_bytes()).await?;
+    cbc.write(stream_key.expose()).await?;
+    cbc.write(&[INNER_END]).await?;
+    cbc.write(&0u32.to_le_bytes()).await?;
+
+    let mut digest: [u8; 64] = Sha512::digest(stream_key.expose()).into();
+    let stream = ChaCha20::new(digest[..32].into(), digest[32..44].into());
+    digest.zeroize();
+    XmlWriter {
+        out: Vec::new(),
+        stream,
+    }
+    .document(groups, entries, &mut cbc)
+    .await?;
+    cbc.finish().await?;
+    blocks.finish().await
+}
+
+struct OuterHeader<'a> {
+    /// Header bytes, signatures included
+    len: usize,
+    cipher: &'a [u8],
+    compression: u32,
+    master_seed: &'a [u8],
+    iv: &'a [u8],
+    kdf: KdfParams<'a>,
+}
+
+#[derive(Default)]
+struct KdfParams<'a> {
+    uuid: &'a [u8],
+    /// AES-KDF rounds
+    rounds: u64,
+    /// AES-KDF seed or Argon2 salt
+    seed: &'a [u8],
+    parallelism: u32,
+    memory: u64,
+    iterations: u64,
+    version: u32,
+}
+
+fn read_header(file: &[u8]) -> Result<OuterHeader<'_>, KdbxError> {
+    let signature1 = read_u32(file.get(0..4).ok_or(KdbxError::Malformed)?)?;
+    let signature2 = read_u32(file.get(4..8).ok_or(KdbxError::Malformed)?)?;
+    if signature1 != KDB_SIGNATURE1 || signature2 != KDBX_SIGNATURE2 {
+        return Err(KdbxError::Malformed);
+    }
+    let version = read_u32(file.get(8..12).ok_or(KdbxError::Malformed)?)?;
+    if version >> 16 != KDBX_MAJOR_VERSION {
+        return Err(KdbxError::UnsupportedVersion(version));
+    }
+
+    let mut header = OuterHeader {
+        len: 0,
+        cipher: &[],
+        compression: COMPRESSION_NONE,
+        master_seed: &[],
+        iv: &[],
+        kdf: KdfParams::default(),
+    };
+    let mut fields = Fields {
+        data: file,
+        pos: 12,
+    };
+    loop {
+        let (id, data) = fields.next_field()?;
+        match id {
+            HEADER_CIPHER_ID => header.cipher = data,
+            HEADER_COMPRESSION => header.compression = read_u32(data)?,
+            HEADER_MASTER_SEED => header.master_seed = data,
+            HEADER_ENCRYPTION_IV => header.iv = data,
+            HEADER_KDF_PARAMETERS => header.kdf = read_kdf_params(data)?,
+            HEADER_END => break,
+            // Public custom data and anything newer
+            _ => {}
+        }
+    }
+    if header.master_seed.len() != 32 {
+        return Err(KdbxError::Malformed);
+    }
+    header.len = fields.pos;
+    Ok(header)
+}
+
+/// Reads the KDF parameters, a KeePass variant dictionary.
+fn read_kdf_params(data: &[u8]) -> Result<KdfParams<'_>, KdbxError> {
+    let mut params = KdfParams::default();
+    // A 2-byte version, then items of type, name and value till a 0 type
+    let mut pos = 2;
+    loop {
+        let kind = *data.get(pos).ok_or(KdbxError::Malformed)?;
+        if kind == 0 {
+            return Ok(params);
+        }
+        let (name, next) = sized(data, pos + 1)?;
+        let (value, next) = sized(data, next)?;
+        pos = next;
+        match name {
+            b"$UUID" => params.uuid = value,
+            b"R" => params.rounds = read_u64(value)?,
+            b"S" => params.seed = value,
+            b"P" => params.parallelism = read_u32(value)?,
+            b"M" => params.memory = read_u64(value)?,
+            b"I" => params.iterations = read_u64(value)?,
+            b"V" => params.version = read_u32(value)?,
+            // Argon2 secret key and associated data are left unused by KeePass
+            _ => {}
+        }
+    }
+}
+
+/// A value prefixed with its 4-byte size at `pos`, and the position after it.
+fn sized(data: &[u8], pos: usize) -> Result<(&[u8], usize), KdbxError> {
+    let size = read_u32(data.get(pos..pos + 4).ok_or(KdbxError::Malformed)?)? as usize;
+    let value = data
+        .get(pos + 4..)
+        .and_then(|rest| rest.get(..size))
+        .ok_or(KdbxError::Malformed)?;
+    Ok((value, pos + 4 + size))
+}
+
+/// The payload cipher key and the HMAC base key.
+fn derive_keys(
+    header: &OuterHeader,
+    password: &[u8],
+    max_kdf_memory: u64,
+) -> Result<(SecretBytes<KEY_SIZE>, SecretBytes<64>), KdbxError> {
+    let transformed = transform(&header.kdf, &composite_key(password), max_kdf_memory)?;
+    Ok(session_keys(header.master_seed, &transformed))
+}
+
+/// The composite key of a password-only database.
+fn composite_key(password: &[u8]) -> SecretBytes<KEY_SIZE> {
+    let mut digest: [u8; KEY_SIZE] = Sha256::digest(Sha256::digest(password)).into();
+    SecretBytes::take_from(&mut digest)
+}
+
+/// Mixes the master seed into the transformed key.
+fn session_keys(
+    master_seed: &[u8],
+    transformed: &SecretBytes<KEY_SIZE>,
+) -> (SecretBytes<KEY_SIZE>, SecretBytes<64>) {
+    let mut hasher = Sha256::new();
+    hasher.update(master_seed);
+    hasher.update(transformed.expose());
+    let mut cipher_key: [u8; KEY_SIZE] = hasher.finalize().into();
+
+    let mut hasher = Sha512::new();
+    hasher.update(master_seed);
+    hasher.update(transformed.expose());
+    hasher.update([1]);
+    let mut hmac_key: [u8; 64] = hasher.finalize().into();
+    (
+        SecretBytes::take_from(&mut cipher_key),
+        SecretBytes::take_from(&mut hmac_key),
+    )
+}
+
+fn transform(
+    kdf: &KdfParams,
+    composite: &SecretBytes<KEY_SIZE>,
+    max_kdf_memory: u64,
+) -> Result<SecretBytes<KEY_SIZE>, KdbxError> {
+    let algorithm = match kdf.uuid {
+        uuid if uuid == KDF_AES => {
+            let seed: &[u8; 32] = kdf.seed.try_into().map_err(|_| KdbxError::Malformed)?;
+            let rounds = u32::try_from(kdf.rounds).map_err(|_| KdbxError::UnsupportedKdf)?;
+            return Ok(encryption::transform_key(composite, seed, rounds));
+        }
+        uuid if uuid == KDF_ARGON2D => Algorithm::Argon2d,
+        uuid if uuid == KDF_ARGON2ID => Algorithm::Argon2id,
+        _ => return Err(KdbxError::UnsupportedKdf),
+    };
+
+    if kdf.memory > max_kdf_memory {
+        return Err(KdbxError::KdfTooExpensive(kdf.memory));
+    }
+    let version = match kdf.version {
+        0x10 => Version::V0x10,
+        0x13 => Version::V0x13,
+        _ => return Err(KdbxError::UnsupportedKdf),
+    };
+    let params = Params::new(
+        (kdf.memory / 1024) as u32,
+        u32::try_from(kdf.iterations).map_err(|_| KdbxError::UnsupportedKdf)?,
+        kdf.parallelism,
+        Some(KEY_SIZE),
+    )
+    .map_err(|_| KdbxError::UnsupportedKdf)?;
+
+    let mut out = [0u8; KEY_SIZE];
+    Argon2::new(algorithm, version, params)
+        .hash_password_into(composite.expose(), kdf.seed, &mut out)
+        .map_err(|_| KdbxError::UnsupportedKdf)?;
+    Ok(SecretBytes::take_from(&mut out))
+}
+
+/// HMAC for block `index`, keyed from the HMAC base key.
+fn block_mac(hmac_key: &SecretBytes<64>, index: u64) -> Hmac<Sha256> {
+    let mut hasher = Sha512::new();
+    hasher.update(index.to_le_bytes());
+    hasher.update(hmac_key.expose());
+    let mut key: [u8; 64] = hasher.finalize().into();
+    let mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC takes keys of any size");
+    key.zeroize();
+    mac
+}
+
+/// Joins the HMAC-protected blocks following the header, checking each.
+fn read_blocks(data: &[u8], hmac_key: &SecretBytes<64>) -> Result<Vec<u8>, KdbxError> {
+    let mut payload = Vec::new();
+    let mut pos = 0;
+    let mut index = 0u64;
+    loop {
+        let block_hmac = data.get(pos..pos + 32).ok_or(KdbxError::Malformed)?;
+        let (block, next) = sized(data, pos + 32)?;
+        let mut mac = block_mac(hmac_key, index);
+        mac.update(&index.to_le_bytes());
+        mac.update(&(block.len() as u32).to_le_bytes());
+        mac.update(block);
+        mac.verify_slice(block_hmac)
+            .map_err(|_| KdbxError::Corrupted)?;
+        // An empty block ends the stream
+        if block.is_empty() {
+            return Ok(payload);
+        }
+        payload.extend_from_slice(block);
+        pos = next;
+        index += 1;
+    }
+}
+
+/// Cuts what it is given into HMAC-protected blocks.
+struct BlockWriter<'s, W> {
+    hmac_key: &'s SecretBytes<64>,
+    index: u64,
+    block: [u8; EXPORT_BLOCK_SIZE],
+    len: usize,
+    sink: &'s mut W,
+}
+
+impl<'s, E, W: AsyncFnMut(&[u8]) -> Result<(), E>> BlockWriter<'s, W> {
+    fn new(hmac_key: &'s SecretBytes<64>, sink: &'s mut W) -> Self {
+        BlockWriter {
+            hmac_key,
+            index: 0,
+            block: [0; EXPORT_BLOCK_SIZE],
+            len: 0,
+            sink,
+        }
+    }
+
+    async fn write(&mut self, mut bytes: &[u8]) -> Result<(), E> {
+        while !bytes.is_empty() {
+            let take = (EXPORT_BLOCK_SIZE - self.len).min(bytes.len());
+            self.block[self.len..self.len + take].copy_from_slice(&bytes[..take]);
+            self.len += take;
+            bytes = &bytes[take..];
+            if self.len == EXPORT_BLOCK_SIZE {
+                self.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), E> {
+        let block = &self.block[..self.len];
+        let len = (block.len() as u32).to_le_bytes();
+        let mut mac = block_mac(self.hmac_key, self.index);
+        mac.update(&self.index.to_le_bytes());
+        mac.update(&len);
+        mac.update(block);
+        (self.sink)(&mac.finalize().into_bytes()).await?;
+        (self.sink)(&len).await?;
+        (self.sink)(block).await?;
+        self.index += 1;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Writes what is left, then the empty block that ends the stream.
+    async fn finish(mut self) -> Result<(), E> {
+        if self.len > 0 {
+            self.flush().await?;
+        }
+        self.flush().await
+    }
+}
+
+/// Decrypts `payload` in place, returning the length of the plaintext.
+fn decrypt(
+    header: &OuterHeader,
+    key: &SecretBytes<KEY_SIZE>,
+    payload: &mut [u8],
+) -> Result<usize, KdbxError> {
+    match header.cipher {
+        cipher if cipher == CIPHER_AES256 => {
+            let iv: &[u8; BLOCK_SIZE] = header.iv.try_into().map_err(|_| KdbxError::Malformed)?;
+            if !payload.len().is_multiple_of(BLOCK_SIZE) {
+                return Err(KdbxError::Malformed);
+            }
+            cbc_decrypt(key, iv, payload);
+            unpadded_len(payload).ok_or(KdbxError::Malformed)
+        }
+        cipher if cipher == CIPHER_CHACHA20 => {
+            let iv: &[u8; 12] = header.iv.try_into().map_err(|_| KdbxError::Malformed)?;
+            ChaCha20::new(key.expose().into(), iv.into()).apply_keystream(payload);
+            Ok(payload.len())
+        }
+        _ => Err(KdbxError::UnsupportedCipher),
+    }
+}
+
+/// Inflates a gzip member.
+fn gunzip(data: &[u8]) -> Result<Vec<u8>, KdbxError> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    if data.len() < 10 || data[0..3] != [0x1F, 0x8B, 0x08] {
+        return Err(KdbxError::Malformed);
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let extra = data.get(pos..pos + 2).ok_or(KdbxError::Malformed)?;
+        pos += 2 + u16::from_le_bytes([extra[0], extra[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let rest = data.get(pos..).ok_or(KdbxError::Malformed)?;
+            pos += rest
+                .iter()
+                .position(|&byte| byte == 0)
+                .ok_or(KdbxError::Malformed)?
+                + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+
+    let deflated = data.get(pos..).ok_or(KdbxError::Malformed)?;
+    miniz_oxide::inflate::decompress_to_vec_with_limit(deflated, MAX_INFLATED_SIZE).map_err(
+        |mut err| {
+            err.output.zeroize();
+            match err.status {
+                TINFLStatus::HasMoreOutput => KdbxError::TooLarge,
+                _ => KdbxError::Malformed,
+            }
+        },
+    )
+}
+
+/// Reads the inner header for the protected value stream, then the XML.
+fn read_inner(inner: &[u8]) -> Result<KdbxImport, KdbxError> {
+    let mut stream_id = INNER_STREAM_NONE;
+    let mut stream_key: &[u8] = &[];
+    let mut fields = Fields {
+        data: inner,
+        pos: 0,
+    };
+    loop {
+        let (id, data) = fields.next_field()?;
+        match id {
+            INNER_STREAM_ID => stream_id = read_u32(data)?,
+            INNER_STREAM_KEY => stream_key = data,
+            INNER_END => break,
+            // Attachments
+            _ => {}
+        }
+    }
+
+    let stream = match stream_id {
+        INNER_STREAM_NONE => None,
+        INNER_STREAM_CHACHA20 => {
+            let mut digest: [u8; 64] = Sha512::digest(stream_key).into();
+            let cipher = ChaCha20::new(digest[..32].into(), digest[32..44].into());
+            digest.zeroize();
+            Some(cipher)
+        }
+        _ => return Err(KdbxError::UnsupportedInnerStream),
+    };
+
+    let xml = core::str::from_utf8(&inner[fields.pos..]).map_err(|_| KdbxError::Malformed)?;
+    XmlWalker::new(stream).walk(xml)
+}
+
+/// Reads the outer and inner header fields: a 1-byte id and a 4-byte size.
+struct Fields<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn next_field(&mut self) -> Result<(u8, &'a [u8]), KdbxError> {
+        let id = *self.data.get(self.pos).ok_or(KdbxError::Malformed)?;
+        let (data, next) = sized(self.data, self.pos + 1)?;
+        self.pos = next;
+        Ok((id, data))
+    }
+}
+
+/// Where a group's entries end up.
+#[derive(Clone, Copy)]
+enum Fate {
+    /// Not decided until its name and times have been read
+    Pending,
+    Kept(u32),
+    /// Left out, as listed in this misfit
+    LeftOut(usize),
+}
+
+struct GroupState {
+    name: String,
+    times: Times,
+    fate: Fate,
+}
+
+struct EntryState {
+    entry: Entry,
+    title: String,
+    /// The first field that didn't fit, and its length
+    too_long: Option<(Field, usize)>,
+}
+
+/// Builds the import from the XML's elements as they open and close.
+struct XmlWalker<'x> {
+    stream: Option<ChaCha20>,
+    import: KdbxImport,
+    /// Names of the open elements
+    path: Vec<&'x str>,
+    /// Text of the innermost open element
+    text: String,
+    protected: bool,
+    groups: Vec<GroupState>,
+    entry: Option<EntryState>,
+    /// How many `History` elements are open; entries in them are old versions
+    history: usize,
+    string_key: String,
+    string_value: Vec<u8>,
+    expiry: KdbTime,
+    expires: bool,
+}
+
+impl<'x> XmlWalker<'x> {
+    fn new(stream: Option<ChaCha20>) -> Self {
+        XmlWalker {
+            stream,
+            import: KdbxImport::default(),
+            path: Vec::new(),
+            text: String::new(),
+            protected: false,
+            groups: Vec::new(),
+            entry: None,
+            history: 0,
+            string_key: String::new(),
+            string_value: Vec::new(),
+            expiry: KdbTime::NEVER,
+            expires: false,
+        }
+    }
+
+    fn walk(mut self, xml: &'x str) -> Result<KdbxImport, KdbxError> {
+        for token in Tokenizer::from(xml) {
+            match token.map_err(|_| KdbxError::Malformed)? {
+                Token::ElementStart { local, .. } => self.open(local.as_str()),
+                Token::Attribute { local, value, .. }
+                    if local.as_str() == "Protected" && value.as_str() == "True" =>
+                {
+                    self.protected = true;
+                }
+                Token::ElementEnd {
+                    end: ElementEnd::Close(..) | ElementEnd::Empty,
+                    ..
+                } => self.close()?,
+                Token::Text { text } => unescape(text.as_str(), &mut self.text),
+                Token::Cdata { text, .. } => self.text.push_str(text.as_str()),
+                _ => {}
+            }
+        }
+        if !self.path.is_empty() {
+            return Err(KdbxError::Malformed);
+        }
+        Ok(core::mem::take(&mut self.import))
+    }
+
+    fn open(&mut self, name: &'x str) {
+        let parent = self.path.last().copied();
+        self.path.push(name);
+        self.text.clear();
+        self.protected = false;
+
+        match (name, parent) {
+            ("Group", Some("Root" | "Group")) => {
+                // The root only becomes a group once it turns out to hold
+                // entries of its own
+                if self.groups.len() > 1 {
+                    self.resolve_group();
+                }
+                self.groups.push(GroupState {
+                    name: String::new(),
+                    times: Times::zero(),
+                    fate: Fate::Pending,
+                });
+            }
+            ("Entry", Some("Group")) if self.history == 0 => {
+                self.resolve_group();
+                self.entry = Some(EntryState {
+                    entry: Entry::new([0; 16], 0),
+                    title: String::new(),
+                    too_long: None,
+                });
+            }
+            ("History", _) => self.history += 1,
+            ("Times", _) => {
+                self.expiry = KdbTime::NEVER;
+                self.expires = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn close(&mut self) -> Result<(), KdbxError> {
+        let name = self.path.pop().ok_or(KdbxError::Malformed)?;
+        let parent = self.path.last().copied();
+        let grandparent = self.path.iter().rev().nth(1).copied();
+
+        // Every protected value advances the stream, wherever it is
+        if name == "Value" && parent == Some("String") {
+            self.string_value.zeroize();
+            self.string_value.clear();
+            if self.protected {
+                self.string_value = base64_decode(&self.text).ok_or(KdbxError::Malformed)?;
+                if let Some(stream) = &mut self.stream {
+                    stream.apply_keystream(&mut self.string_value);
+                }
+            } else {
+                self.string_value.extend_from_slice(self.text.as_bytes());
+            }
+            self.text.zeroize();
+            return Ok(());
+        }
+
+        let current_entry = self.history == 0 && self.entry.is_some();
+        match (name, parent, grandparent) {
+            ("Group", Some("Root" | "Group"), _) => {
+                if self.groups.len() > 1 {
+                    self.resolve_group();
+                }
+                self.groups.pop();
+            }
+            ("Name", Some("Group"), _) => {
+                if let Some(group) = self.groups.last_mut() {
+                    group.name = core::mem::take(&mut self.text);
+                }
+            }
+            ("Entry", Some("Group"), _) if self.history == 0 => self.finish_entry(),
+            ("History", _, _) => self.history = self.history.saturating_sub(1),
+            ("UUID", Some("Entry"), _) if current_entry => {
+                if let Some(uuid) = base64_decode(&self.text).and_then(|b| b.try_into().ok()) {
+                    self.entry_mut().entry.uuid = uuid;
+                }
+            }
+            ("Key", Some("String"), _) => self.string_key = core::mem::take(&mut self.text),
+            ("String", Some("Entry"), _) if current_entry => self.apply_string(),
+            ("Enabled", Some("AutoType"), Some("Entry")) if current_entry => {
+                self.entry_mut().entry.autotype = self.text == "True";
+            }
+            (_, Some("Times"), Some(owner)) => self.read_time(name, owner, current_entry),
+            ("Times", Some(owner), _) => {
+                let expires = if self.expires {
+                    self.expiry
+                } else {
+                    KdbTime::NEVER
+                };
+                if let Some(times) = self.times_mut(owner, current_entry) {
+                    times.expires = expires;
+                }
+            }
+            _ => {}
+        }
+        self.text.clear();
+        Ok(())
+    }
+
+    fn entry_mut(&mut self) -> &mut EntryState {
+        self.entry.as_mut().expect("checked by the caller")
+    }
+
+    fn times_mut(&mut self, owner: &str, current_entry: bool) -> Option<&mut Times> {
+        match owner {
+            "Group" => self.groups.last_mut().map(|group| &mut group.times),
+            "Entry" if current_entry => self.entry.as_mut().map(|entry| &mut entry.entry.times),
+            _ => None,
+        }
+    }
+
+    fn read_time(&mut self, name: &str, owner: &str, current_entry: bool) {
+        if name == "Expires" {
+            self.expires = self.text == "True";
+            return;
+        }
+        // A time that doesn't parse is left unset
+        let Some(time) = kdbx_time(&self.text) else {
+            return;
+        };
+        if name == "ExpiryTime" {
+            self.expiry = time;
+            return;
+        }
+        let Some(times) = self.times_mut(owner, current_entry) else {
+            return;
+        };
+        match name {
+            "CreationTime" => times.created = time,
+            "LastModificationTime" => times.modified = time,
+            "LastAccessTime" => times.accessed = time,
+            _ => {}
+        }
+    }
+
+    fn apply_string(&mut self) {
+        let mut value = core::mem::take(&mut self.string_value);
+        let state = self.entry.as_mut().expect("checked by the caller");
+        let entry = &mut state.entry;
+        let (field, set) = match self.string_key.as_str() {
+            "Title" => {
+                state.title = String::from_utf8_lossy(&value).into_owned();
+                (Field::Title, entry.set_title(&value))
+            }
+            "UserName" => (Field::UserName, entry.set_username(&value)),
+            "Password" => (Field::Password, entry.set_password(&value)),
+            "URL" => (Field::Url, entry.set_url(&value)),
+            "Notes" => (Field::Notes, entry.set_notes(&value)),
+            // Custom fields
+            _ => {
+                value.zeroize();
+                return;
+            }
+        };
+        if set.is_err() && state.too_long.is_none() {
+            state.too_long = Some((field, value.len()));
+        }
+        value.zeroize();
+    }
+
+    /// Decides whether the innermost group is kept, once its name and times
+    /// are known.
+    fn resolve_group(&mut self) {
+        let depth = self.groups.len().saturating_sub(1);
+        // The root's children stand on their own, whatever becomes of it
+        let parent_fate = match depth {
+            0 | 1 => None,
+            _ => self.groups.get(depth - 1).map(|group| group.fate),
+        };
+        let Some(group) = self.groups.last_mut() else {
+            return;
+        };
+        if !matches!(group.fate, Fate::Pending) {
+            return;
+        }
+
+        let misfit = if let Some(Fate::LeftOut(index)) = parent_fate {
+            group.fate = Fate::LeftOut(index);
+            return;
+        } else if depth > MAX_GROUP_DEPTH {
+            Misfit::GroupTooDeep {
+                group: group.name.clone(),
+                entries: 0,
+            }
+        } else if group.name.len() > TEXT_LEN {
+            Misfit::FieldTooLong {
+                name: group.name.clone(),
+                field: Field::GroupName,
+                len: group.name.len(),
+                entries: 0,
+            }
+        } else {
+            let group_id = self.import.groups.len() as u32 + 1;
+            let mut name = [0; TEXT_LEN];
+            fill_text(&mut name, group.name.as_bytes());
+            self.import.groups.push(Group {
+                group_id,
+                name,
+                times: group.times,
+            });
+            group.fate = Fate::Kept(group_id);
+            return;
+        };
+        group.fate = Fate::LeftOut(self.import.misfits.len());
+        self.import.misfits.push(misfit);
+    }
+
+    fn finish_entry(&mut self) {
+        let Some(mut state) = self.entry.take() else {
+            return;
+        };
+        let fate = self.groups.last().map_or(Fate::Pending, |group| group.fate);
+        match (fate, state.too_long) {
+            (Fate::LeftOut(index), _) => match &mut self.import.misfits[index] {
+                Misfit::GroupTooDeep { entries, .. } | Misfit::FieldTooLong { entries, .. } => {
+                    *entries += 1
+                }
+            },
+            (Fate::Kept(group_id), None) => {
+                state.entry.group_id = group_id;
+                self.import.entries.push(state.entry);
+            }
+            (Fate::Kept(_), Some((field, len))) => self.import.misfits.push(Misfit::FieldTooLong {
+                name: core::mem::take(&mut state.title),
+                field,
+                len,
+                entries: 1,
+            }),
+            // Groups are resolved before their first entry opens
+            (Fate::Pending, _) => {}
+        }
+    }
+}
+
+impl Drop for XmlWalker<'_> {
+    fn drop(&mut self) {
+        self.text.zeroize();
+        self.string_value.zeroize();
+    }
+}
+
+/// Writes the XML of an export, masking protected values with the inner
+/// ChaCha20 stream in document order. The XML is put together a group or an
+/// entry at a time, then sent to `cbc`.
+struct XmlWriter {
+    out: Vec<u8>,
+    stream: ChaCha20,
+}
+
+impl XmlWriter {
+    async fn document<'g, 'e, G, En, E, W>(
+        &mut self,
+        groups: G,
+        entries: En,
+        cbc: &mut CbcWriter<'_, W>,
+    ) -> Result<(), E>
+    where
+        G: Iterator<Item = &'g Group> + Clone,
+        En: Iterator<Item = &'e Entry> + Clone,
+        W: AsyncFnMut(&[u8]) -> Result<(), E>,
+    {
+        self.raw("<?xml version=\"1.0\" encoding=\"utf-8\" standalone=\"yes\"?>");
+        self.raw("<KeePassFile><Meta>");
+        self.element("Generator", EXPORT_ROOT_NAME.as_bytes());
+        self.element("DatabaseName", EXPORT_ROOT_NAME.as_bytes());
+        self.raw("</Meta><Root><Group>");
+        self.uuid(&random_uuid());
+        self.element("Name", EXPORT_ROOT_NAME.as_bytes());
+
+        // Entries whose group isn't there
+        let has_group = |id| groups.clone().any(|group: &Group| group.group_id == id);
+        for (index, entry) in entries.clone().enumerate() {
+            if !has_group(entry.group_id) {
+                self.entry(entry, export_uuid(entries.clone(), index));
+                self.send(cbc).await?;
+            }
+        }
+
+        for (position, group) in groups.clone().enumerate() {
+            self.raw("<Group>");
+            self.uuid(&random_uuid());
+            self.element("Name", text_of(&group.name));
+            self.times(&group.times);
+            // Only the first group with an id gets its entries
+            let first = !groups
+                .clone()
+                .take(position)
+                .any(|earlier| earlier.group_id == group.group_id);
+            for (index, entry) in entries.clone().enumerate() {
+                if first && entry.group_id == group.group_id {
+                    self.entry(entry, export_uuid(entries.clone(), index));
+                    self.send(cbc).await?;
+                }
+            }
+            self.raw("</Group>");
+        }
+        self.raw("</Group></Root></KeePassFile>");
+        self.send(cbc).await
+    }
+
+    fn entry(&mut self, entry: &Entry, uuid: [u8; 16]) {
+        self.raw("<Entry>");
+        self.uuid(&uuid);
+        self.times(&entry.times);
+        self.raw("<AutoType><Enabled>");
+        self.raw(if entry.autotype { "True" } else { "False" });
+        self.raw("</Enabled></AutoType>");
+        self.string("Title", entry.title());
+        self.string("UserName", entry.username());
+        self.string("URL", entry.url());
+        self.string("Notes", entry.notes());
+
+        // The masked value is written out as base64
+        let password = entry.password().expose();
+        let mut masked = match core::str::from_utf8(password) {
+            Ok(_) => password.to_vec(),
+            Err(_) => String::from_utf8_lossy(password).into_owned().into_bytes(),
+        };
+        self.stream.apply_keystream(&mut masked);
+        let encoded = base64_encode(&masked);
+        masked.zeroize();
+        self.raw("<String><Key>Password</Key><Value Protected=\"True\">");
+        self.raw(&encoded);
+        self.raw("</Value></String></Entry>")
+    }
+
+    fn string(&mut self, key: &str, value: &[u8]) {
+        self.raw("<String>");
+        self.element("Key", key.as_bytes());
+        self.element("Value", value);
+        self.raw("</String>")
+    }
+
+    /// Unset times are left out for the reader to fill in.
+    fn times(&mut self, times: &Times) {
+        self.raw("<Times>");
+        for (name, time) in [
+            ("CreationTime", times.created),
+            ("LastModificationTime", times.modified),
+            ("LastAccessTime", times.accessed),
+            ("ExpiryTime", times.expires),
+        ] {
+            if let Some(secs) = kdbx_secs(&time) {
+                self.element(name, base64_encode(&secs.to_le_bytes()).as_bytes());
+            }
+        }
+        let expires = times.expires != KdbTime::NEVER;
+        self.element("Expires", if expires { b"True" } else { b"False" });
+        self.raw("</Times>")
+    }
+
+    fn uuid(&mut self, uuid: &[u8; 16]) {
+        self.element("UUID", base64_encode(uuid).as_bytes())
+    }
+
+    /// `<name>value</name>`, with `value` escaped.
+    fn element(&mut self, name: &str, value: &[u8]) {
+        self.raw("<");
+        self.raw(name);
+        self.raw(">");
+        self.text(value);
+        self.raw("</");
+        self.raw(name);
+        self.raw(">")
+    }
+
+    /// Writes `value` as XML text. Invalid UTF-8 is replaced and control
+    /// characters XML can't hold are dropped.
+    fn text(&mut self, value: &[u8]) {
+        let text = String::from_utf8_lossy(value);
+        let mut start = 0;
+        for (pos, ch) in text.char_indices() {
+            let escaped = match ch {
+                '&' => "&amp;",
+                '<' => "&lt;",
+                '>' => "&gt;",
+                '\t' | '\n' | '\r' => continue,
+                ch if ch < ' ' => "",
+                _ => continue,
+            };
+            self.raw(&text[start..pos]);
+            self.raw(escaped);
+            start = pos + ch.len_utf8();
+        }
+        self.raw(&text[start..])
+    }
+
+    fn raw(&mut self, text: &str) {
+        self.out.extend_from_slice(text.as_bytes());
+    }
+
+    /// Encrypts what has been put together so far.
+    async fn send<E, W>(&mut self, cbc: &mut CbcWriter<'_, W>) -> Result<(), E>
+    where
+        W: AsyncFnMut(&[u8]) -> Result<(), E>,
+    {
+        let sent = cbc.write(&self.out).await;
+        // Passwords are masked, but titles and the rest are not
+        self.out.zeroize();
+        self.out.clear();
+        sent
+    }
+}
+
+impl Drop for XmlWriter {
+    fn drop(&mut self) {
+        self.out.zeroize();
+    }
+}
+
+/// The UUID the entry at `index` is exported with: its own, unless it is
+/// unset or an earlier entry already has it.
+fn export_uuid<'e>(entries: impl Iterator<Item = &'e Entry> + Clone, index: usize) -> [u8; 16] {
+    let Some(entry) = entries.clone().nth(index) else {
+        return random_uuid();
+    };
+    let taken = entries
+        .take(index)
+        .any(|earlier| earlier.uuid == entry.uuid);
+    if entry.uuid == [0; 16] || taken {
+        random_uuid()
+    } else {
+        entry.uuid
+    }
+}
+
+fn random_uuid() -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    encryption::fill_random(&mut uuid);
+    uuid
+}
+
+/// A header field: a 1-byte id and a 4-byte size.
+fn push_field(out: &mut Vec<u8>, id: u8, data: &[u8]) {
+    out.push(id);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+/// A variant dictionary item: type, sized name and sized value.
+fn push_variant(out: &mut Vec<u8>, kind: u8, name: &[u8], value: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    out.extend_from_slice(name);
+    out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+/// A NUL-padded record field, up to its first NUL.
+fn text_of(field: &[u8]) -> &[u8] {
+    let len = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    &field[..len]
+}
+
+/// Copies `value`, checked to fit, into a NUL-padded record field.
+fn fill_text(dst: &mut [u8; TEXT_LEN], value: &[u8]) {
+    dst.fill(0);
+    dst[..value.len()].copy_from_slice(value);
+}
+
+/// Appends `text` to `out` with the XML entities replaced.
+fn unescape(text: &str, out: &mut String) {
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let ch = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match ch {
+            Some(ch) => {
+                out.push(ch);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for byte in text.bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        count += 1;
+        if count == 4 {
+            out.extend_from_slice(&bits.to_be_bytes()[1..]);
+            bits = 0;
+            count = 0;
+        }
+    }
+    match count {
+        0 => {}
+        2 => out.push((bits >> 4) as u8),
+        3 => out.extend_from_slice(&((bits >> 2) as u16).to_be_bytes()),
+        _ => return None,
+    }
+    Some(out)
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let mut bytes = [0u8; 4];
+        bytes[1..1 + chunk.len()].copy_from_slice(chunk);
+        let bits = u32::from_be_bytes(bytes);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// The seconds since 0001-01-01 of a packed time, `None` if it is unset or
+/// not a real date.
+fn kdbx_secs(time: &KdbTime) -> Option<i64> {
+    let (year, month, day, hour, minute, second) = time.parts();
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year == 0 {
+        return None;
+    }
+
+    // A civil date to days since 1970-01-01
+    let (month, day) = (i64::from(month), i64::from(day));
+    let year = i64::from(year) - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    let secs_of_day = i64::from(hour) * 3600 + i64::from(minute) * 60 + i64::from(second);
+    Some(days * 86_400 + secs_of_day + UNIX_EPOCH_SECS)
+}
+
+/// A KDBX 4 time: base64 of the seconds since 0001-01-01 as a 64-bit integer.
+fn kdbx_time(text: &str) -> Option<KdbTime> {
+    let secs = i64::from_le_bytes(base64_decode(text)?.try_into().ok()?);
+    let unix = secs.checked_sub(UNIX_EPOCH_SECS)?;
+    let (days, secs_of_day) = (unix.div_euclid(86_400), unix.rem_euclid(86_400));
+
+    // Days since 1970-01-01 to a civil date
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    Some(KdbTime::from_parts(
+        u16::try_from(year).ok()?,
+        month as u8,
+        day as u8,
+        (secs_of_day / 3600) as u8,
+        (secs_of_day / 60 % 60) as u8,
+        (secs_of_day % 60) as u8,
+    ))
+}
+
+fn read_u32(data: &[u8]) -> Result<u32, KdbxError> {
+    data.try_into()
+        .map(u32::from_le_bytes)
+        .map_err(|_| KdbxError::Malformed)
+}
+
+fn read_u64(data: &[u8]) -> Result<u64, KdbxError> {
+    data.try_into()
+        .map(u64::from_le_bytes)
+        .map_err(|_| KdbxError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    // Written by `fixtures/kdbx.py`, the way KeePassXC saves files: all three
+    // hold the same groups and entries.
+    /// AES-KDF, AES-256 and gzip.
+    const AES_KDF: &[u8] = include_bytes!("fixtures/aes_kdf.kdbx");
+    /// Argon2id with 32 KiB and two lanes, ChaCha20, not compressed.
+    const ARGON2ID: &[u8] = include_bytes!("fixtures/argon2id.kdbx");
+    /// Argon2d with 16 KiB, AES-256 and gzip.
+    const ARGON2D: &[u8] = include_bytes!("fixtures/argon2d.kdbx");
+    const PASSWORD: &[u8] = b"correct horse";
+
+    /// Checks `import` holds what every fixture does. Deeper groups and the
+    /// entry with a 200-character title don't fit, and the history entry and
+    /// the attachment are left out.
+    fn check_sample(import: &KdbxImport) {
+        let groups: Vec<_> = import
+            .groups
+            .iter()
+            .map(|group| (group.group_id, text_of(&group.name)))
+            .collect();
+        assert_eq!(
+            groups,
+            [(1, &b"Root"[..]), (2, b"Work & Co"), (3, b"Empty")]
+        );
+
+        let entries: Vec<_> = import
+            .entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.group_id,
+                    entry.title(),
+                    entry.username(),
+                    entry.password().expose(),
+                )
+            })
+            .collect();
+        let long_password = [b'p'; 64];
+        assert_eq!(
+            entries,
+            [
+                (1, &b"GitHub"[..], &b"octocat"[..], &b"hunter2 & more"[..]),
+                (2, b"Mail", b"me@example.com", &long_password[..]),
+                (2, b"After deep", b"a", b"after"),
+            ]
+        );
+
+        let github = &import.entries[0];
+        assert_eq!(github.uuid, core::array::from_fn(|i| i as u8));
+        assert_eq!(github.url(), b"https://github.com");
+        assert_eq!(github.notes(), b"Recovery codes & keys\nare in the safe");
+        assert!(github.autotype);
+        assert_eq!(
+            github.times.created,
+            KdbTime::from_parts(2024, 5, 17, 13, 45, 9)
+        );
+        assert_eq!(github.times.expires, KdbTime::NEVER);
+        let mail = &import.entries[1];
+        assert!(!mail.autotype);
+        assert_eq!(mail.times.expires, KdbTime::from_parts(2030, 1, 2, 3, 4, 5));
+
+        assert_eq!(
+            import.misfits,
+            [
+                Misfit::FieldTooLong {
+                    name: "T".repeat(200),
+                    field: Field::Title,
+                    len: 200,
+                    entries: 1,
+                },
+                Misfit::GroupTooDeep {
+                    group: "Deep".into(),
+                    entries: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_aes_kdf_with_gzip() {
+        check_sample(&import(AES_KDF, PASSWORD, MAX_KDF_MEMORY).unwrap());
+    }
+
+    #[test]
+    fn reads_argon2id_with_chacha20() {
+        check_sample(&import(ARGON2ID, PASSWORD, MAX_KDF_MEMORY).unwrap());
+    }
+
+    #[test]
+    fn reads_argon2d_with_gzip() {
+        check_sample(&import(ARGON2D, PASSWORD, MAX_KDF_MEMORY).unwrap());
+    }
+
+    #[test]
+    fn refuses_a_wrong_password() {
+        for file in [AES_KDF, ARGON2ID] {
+            let result = import(file, b"correct horse battery", MAX_KDF_MEMORY);
+            assert_eq!(result.err(), Some(KdbxError::InvalidKey));
+        }
+    }
+
+    #[test]
+    fn refuses_a_damaged_block() {
+        let mut file = AES_KDF.to_vec();
+        let header_len = read_header(AES_KDF).unwrap().len;
+        // Past the header hash and HMAC, the first block's HMAC and size
+        file[header_len + 64 + 36 + 10] ^= 1;
+        let result = import(&file, PASSWORD, MAX_KDF_MEMORY);
+        assert_eq!(result.err(), Some(KdbxError::Corrupted));
+    }
+
+    #[test]
+    fn keeps_to_the_memory_budget() {
+        let result = import(ARGON2ID, PASSWORD, 16 * 1024);
+        assert_eq!(result.err(), Some(KdbxError::KdfTooExpensive(32 * 1024)));
+
+        let mut file = AES_KDF.to_vec();
+        file.resize(MAX_FILE_SIZE + 1, 0);
+        let result = import(&file, PASSWORD, MAX_KDF_MEMORY);
+        assert_eq!(result.err(), Some(KdbxError::TooLarge));
+
+        let bomb = gzip(&[b' '; MAX_INFLATED_SIZE + 1]);
+        assert_eq!(gunzip(&bomb).err(), Some(KdbxError::TooLarge));
+        let fits = gzip(&[b' '; MAX_INFLATED_SIZE]);
+        assert_eq!(gunzip(&fits).unwrap().len(), MAX_INFLATED_SIZE);
+    }
+
+    #[test]
+    fn round_trips_through_export() {
+        let sample = import(AES_KDF, PASSWORD, MAX_KDF_MEMORY).unwrap();
+        let mut file = Vec::new();
+        let written = block_on(export(
+            sample.groups.iter(),
+            sample.entries.iter(),
+            b"new password",
+            100,
+            async |bytes: &[u8]| -> Result<(), KdbxError> {
+                file.extend_from_slice(bytes);
+                Ok(())
+            },
+        ));
+        written.unwrap();
+        // Long enough to take more than one block
+        assert!(file.len() > EXPORT_BLOCK_SIZE);
+
+        let reread = import(&file, b"new password", MAX_KDF_MEMORY).unwrap();
+        assert!(reread.misfits.is_empty());
+        let summary = |import: &KdbxImport| {
+            let groups: Vec<_> = import
+                .groups
+                .iter()
+                .map(|group| (group.group_id, group.name, group.times))
+                .collect();
+            let entries: Vec<_> = import
+                .entries
+                .iter()
+                .map(|entry| {
+                    (
+                        (entry.uuid, entry.group_id, entry.autotype, entry.times),
+                        [entry.title(), entry.username(), entry.password().expose()],
+                        [entry.url(), entry.notes()],
+                    )
+                })
+                .collect();
+            alloc::format!("{groups:?} {entries:?}")
+        };
+        assert_eq!(summary(&reread), summary(&sample));
+
+        let result = import(&file, PASSWORD, MAX_KDF_MEMORY);
+        assert_eq!(result.err(), Some(KdbxError::InvalidKey));
+    }
+
+    /// A bare gzip member around `data`.
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x1F, 0x8B, 0x08, 0, 0, 0, 0, 0, 0, 0xFF];
+        out.extend(miniz_oxide::deflate::compress_to_vec(data, 6));
+        // The CRC and size, which the reader doesn't check
+        out.extend_from_slice(&[0; 8]);
+        out
+    }
+}
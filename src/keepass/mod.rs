@@ -3,6 +3,8 @@ pub mod entry;
 pub mod error;
 pub mod group;
 pub mod header;
+pub mod kdb;
+pub mod kdbx;
 pub mod times;
 
 pub use db::KeePassDb;
@@ -1,14 +1,14 @@
 use defmt::Format;
 
-/// KeePass v1 stores timestamps as a packed 5-byte little-endian value (40 bits).
+/// KeePass v1 stores timestamps as a packed 5-byte big-endian value (40 bits).
 ///
-/// Layout (least-significant bits first):
-/// - bits  0..= 5: second (0..=59)
-/// - bits  6..=11: minute (0..=59)
-/// - bits 12..=16: hour   (0..=23)
-/// - bits 17..=21: day    (1..=31)
-/// - bits 22..=25: month  (1..=12)
-/// - bits 26..=39: year   (e.g. 2025)
+/// Layout (most-significant bits first):
+/// - bits 39..=26: year   (e.g. 2025)
+/// - bits 25..=22: month  (1..=12)
+/// - bits 21..=17: day    (1..=31)
+/// - bits 16..=12: hour   (0..=23)
+/// - bits 11..= 6: minute (0..=59)
+/// - bits  5..= 0: second (0..=59)
 #[derive(Clone, Copy, PartialEq, Eq, Format, Debug)]
 pub struct KdbTime {
     raw: [u8; 5],
@@ -17,6 +17,39 @@ pub struct KdbTime {
 impl KdbTime {
     pub const NEVER: Self = Self { raw: [0; 5] };
 
+    /// Packs a calendar date and time of day.
+    pub const fn from_parts(
+        year: u16,
+        month: u8,
+        day: u8,
+        hour: u8,
+        minute: u8,
+        second: u8,
+    ) -> Self {
+        Self {
+            raw: [
+                (year >> 6) as u8 & 0x3F,
+                ((year & 0x3F) as u8) << 2 | (month >> 2) & 0x03,
+                (month & 0x03) << 6 | (day & 0x1F) << 1 | (hour >> 4) & 0x01,
+                (hour & 0x0F) << 4 | (minute >> 2) & 0x0F,
+                (minute & 0x03) << 6 | (second & 0x3F),
+            ],
+        }
+    }
+
+    /// Unpacks the year, month, day, hour, minute and second.
+    pub const fn parts(&self) -> (u16, u8, u8, u8, u8, u8) {
+        let raw = &self.raw;
+        (
+            (raw[0] as u16 & 0x3F) << 6 | (raw[1] >> 2) as u16,
+            (raw[1] & 0x03) << 2 | raw[2] >> 6,
+            (raw[2] >> 1) & 0x1F,
+            (raw[2] & 0x01) << 4 | raw[3] >> 4,
+            (raw[3] & 0x0F) << 2 | raw[4] >> 6,
+            raw[4] & 0x3F,
+        )
+    }
+
     pub const fn from_raw(raw: [u8; 5]) -> Self {
         Self { raw }
     }
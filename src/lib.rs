@@ -1,12 +1,32 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 extern crate alloc;
 
 pub mod app;
 pub mod display;
+#[cfg(target_arch = "xtensa")]
 pub mod dma_helpers;
 pub mod encryption;
+#[cfg(target_arch = "xtensa")]
 pub mod input;
 pub mod keepass;
+pub mod secret;
 pub mod storage;
 pub mod usb_hid_queue;
+pub mod usb_transfer;
+
+/// Host tests have nowhere to send defmt frames, so they are dropped.
+#[cfg(test)]
+mod test_logger {
+    #[defmt::global_logger]
+    struct Discard;
+
+    unsafe impl defmt::Logger for Discard {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("");
+}
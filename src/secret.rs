@@ -0,0 +1,259 @@
+//! Containers for plaintext secrets: passwords, PINs and derived keys.
+//!
+//! None of the types is `Copy`, so every duplicate is an explicit `clone()`,
+//! and all of them overwrite their whole buffer with zeros when dropped.
+//!
+//! Their `Debug` and `Format` impls, like every other secret field in the
+//! crate, go through [`Redacted`] and only print the contents when the
+//! `insecure-debug-logging` feature is enabled.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use defmt::Format;
+use heapless::{CapacityError, String};
+use zeroize::Zeroize;
+
+/// Fixed-size secret bytes, such as a key or a NUL-padded password field.
+pub struct SecretBytes<const N: usize>([u8; N]);
+
+impl<const N: usize> SecretBytes<N> {
+    pub const fn new(bytes: [u8; N]) -> Self {
+        Self(bytes)
+    }
+
+    pub const fn zeroed() -> Self {
+        Self([0u8; N])
+    }
+
+    /// Copies `bytes` in, zeroing the original.
+    pub fn take_from(bytes: &mut [u8; N]) -> Self {
+        let secret = Self(*bytes);
+        bytes.zeroize();
+        secret
+    }
+
+    pub fn expose(&self) -> &[u8; N] {
+        &self.0
+    }
+
+    pub fn expose_mut(&mut self) -> &mut [u8; N] {
+        &mut self.0
+    }
+}
+
+impl<const N: usize> Clone for SecretBytes<N> {
+    fn clone(&self) -> Self {
+        Self(self.0)
+    }
+}
+
+impl<const N: usize> Zeroize for SecretBytes<N> {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<const N: usize> Drop for SecretBytes<N> {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl<const N: usize> fmt::Debug for SecretBytes<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SecretBytes")
+            .field(&Redacted(&self.0))
+            .finish()
+    }
+}
+
+impl<const N: usize> Format for SecretBytes<N> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "SecretBytes({})", Redacted(&self.0));
+    }
+}
+
+/// Secret bytes of any length on the heap, such as an entry's password. The
+/// buffer is allocated at its final size and never grown, so no copy of the
+/// contents is left behind in freed memory.
+#[derive(Default)]
+pub struct SecretVec(Vec<u8>);
+
+impl SecretVec {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        let mut vec = Vec::with_capacity(bytes.len());
+        vec.extend_from_slice(bytes);
+        Self(vec)
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Clone for SecretVec {
+    fn clone(&self) -> Self {
+        Self::from_slice(&self.0)
+    }
+}
+
+impl Zeroize for SecretVec {
+    fn zeroize(&mut self) {
+        self.0.as_mut_slice().zeroize();
+        self.0.clear();
+    }
+}
+
+impl Drop for SecretVec {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretVec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SecretVec")
+            .field(&Redacted(&self.0))
+            .finish()
+    }
+}
+
+impl Format for SecretVec {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "SecretVec({})", Redacted(&self.0));
+    }
+}
+
+/// Bounded secret text, such as a PIN, a passphrase or a password on its way
+/// to the USB keyboard.
+pub struct SecretString<const N: usize>(String<N>);
+
+impl<const N: usize> SecretString<N> {
+    pub const fn new() -> Self {
+        Self(String::new())
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn push(&mut self, ch: char) -> Result<(), CapacityError> {
+        self.0.push(ch)
+    }
+
+    pub fn push_str(&mut self, text: &str) -> Result<(), CapacityError> {
+        self.0.push_str(text)
+    }
+
+    /// Removes the last character and zeroes the bytes it occupied.
+    pub fn pop(&mut self) -> Option<char> {
+        let ch = self.0.pop();
+        self.zeroize_spare();
+        ch
+    }
+
+    pub fn clear(&mut self) {
+        self.zeroize();
+    }
+
+    fn zeroize_spare(&mut self) {
+        // SAFETY: only the spare capacity past `len` is written, so the
+        // string stays valid UTF-8.
+        unsafe { self.0.as_mut_vec() }
+            .spare_capacity_mut()
+            .zeroize();
+    }
+}
+
+impl<const N: usize> Default for SecretString<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Clone for SecretString<N> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<const N: usize> Zeroize for SecretString<N> {
+    fn zeroize(&mut self) {
+        self.0.clear();
+        self.zeroize_spare();
+    }
+}
+
+impl<const N: usize> Drop for SecretString<N> {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl<const N: usize> fmt::Debug for SecretString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SecretString")
+            .field(&Redacted(self.as_bytes()))
+            .finish()
+    }
+}
+
+impl<const N: usize> Format for SecretString<N> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "SecretString({})", Redacted(self.as_bytes()));
+    }
+}
+
+/// Formats sensitive bytes (a password, a title, key material) as
+/// `<redacted>`. With the `insecure-debug-logging` feature they are printed
+/// as a byte string instead.
+pub struct Redacted<'a>(pub &'a [u8]);
+
+impl fmt::Debug for Redacted<'_> {
+    #[cfg(feature = "insecure-debug-logging")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.0, f)
+    }
+
+    #[cfg(not(feature = "insecure-debug-logging"))]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl Format for Redacted<'_> {
+    #[cfg(feature = "insecure-debug-logging")]
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=[u8]:a}", self.0);
+    }
+
+    #[cfg(not(feature = "insecure-debug-logging"))]
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "<redacted>");
+    }
+}
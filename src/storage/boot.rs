@@ -0,0 +1,172 @@
+//! Bringing the storage up at boot: repairing what a power loss left behind,
+//! migrating an older layout, bootstrapping a blank device and loading the
+//! PIN state and the database.
+//!
+//! Nothing here panics. When the storage can't be opened the caller gets a
+//! [`BootError`] and decides what to do, e.g. offer a factory reset.
+
+use defmt::{Format, info, warn};
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::keepass::{KDBError, KeePassDb};
+use crate::storage::layout::{StorageError, StorageLayout};
+use crate::storage::migrate;
+use crate::storage::region::DataRegion;
+use crate::storage::user_config::UserConfig;
+
+/// Everything the app needs from the storage.
+pub struct BootedStorage {
+    pub layout: StorageLayout,
+    pub user_config: UserConfig,
+    pub kpdb: KeePassDb,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BootError {
+    /// The layout or one of its regions is unusable
+    Storage(StorageError),
+    /// The database header can't be read or doesn't match its records
+    Database(KDBError),
+    /// A wipe is pending, which read-only mode can't finish
+    WipePending,
+}
+
+impl From<StorageError> for BootError {
+    fn from(err: StorageError) -> Self {
+        BootError::Storage(err)
+    }
+}
+
+impl From<KDBError> for BootError {
+    fn from(err: KDBError) -> Self {
+        BootError::Database(err)
+    }
+}
+
+/// Opens the storage, finishing any change a power loss cut short. An older
+/// layout is migrated in place and a blank device is bootstrapped; nothing
+/// holding data is ever erased here.
+pub fn open<S: NorFlash>(storage: &mut S) -> Result<BootedStorage, BootError> {
+    // 1. A metadata rewrite cut short by a power loss is finished before the
+    // healthcheck, which would otherwise find the storage magic gone
+    if StorageLayout::finish_pending_metadata(storage)? {
+        info!("Finished an interrupted metadata update");
+    }
+    // Likewise a `UserConfig` rewrite, which leaves the region erased for a while
+    if UserConfig::finish_pending_rewrite(storage)? {
+        info!("Finished an interrupted PIN state update");
+    }
+    // And the v1 image an interrupted migration left in front of the log
+    if migrate::finish_pending_migration(storage)? {
+        info!("Finished an interrupted layout migration");
+    }
+
+    let mut recheck = false;
+    match StorageLayout::run_healthcheck(storage) {
+        Ok(()) => info!("Storage found; good to read"),
+        Err(StorageError::BadMagic) => {
+            // Only a blank device gets bootstrapped. If a database is still
+            // where the layout puts it the metadata got damaged, and wiping
+            // it is left to the user.
+            info!("Storage not found; initializing");
+            StorageLayout::bootstrap_storage_write(storage)?;
+        }
+        Err(StorageError::UnsupportedLayout(version)) => {
+            // Upgrade in place. A layout without a migration path is reported,
+            // and only the user can choose to bootstrap over it.
+            info!("Migrating the storage layout from v{}", version);
+            migrate::migrate_layout(storage)?;
+            recheck = true;
+        }
+        Err(StorageError::CorruptRegions(regions)) => {
+            // An interrupted wipe or PIN change can leave a region out of
+            // line with its descriptor until it is finished below
+            warn!("Corrupt storage regions: {}", regions);
+            recheck = true;
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    let layout = StorageLayout::new(storage)?;
+    let magic_str = core::str::from_utf8(&layout.header.magic).unwrap_or("<invalid utf8>");
+    info!("magic: {=str}", magic_str);
+
+    // 2. Finish a wipe that a power loss cut short before anything reads the database
+    if layout.finish_pending_wipe(storage)? {
+        info!("Finished an interrupted wipe");
+    }
+    // Likewise install the verifier of a PIN change that was committed but
+    // not yet finished. A database change needs nothing: the record log
+    // drops whatever was appended after its last header record.
+    if layout.finish_pending_commit(storage)? {
+        info!("Finished an interrupted PIN change");
+    }
+    if recheck {
+        StorageLayout::run_healthcheck(storage)?;
+    }
+
+    // 3. Load the PIN verifier and the failed-attempt counter. If the last
+    // allowed attempt was counted but the wipe never started, wipe now.
+    let user_config_region = layout.region_handle(DataRegion::UserConfig)?;
+    let mut user_config = UserConfig::load(storage, user_config_region)?;
+    if user_config.wipe_due() {
+        info!("Too many failed PIN attempts; wiping the device");
+        layout.wipe_secrets(storage)?;
+        user_config = UserConfig::load(storage, user_config_region)?;
+    }
+
+    // 4. Get the keepass header. Groups and entries stay encrypted until the
+    // PIN screen derives the key and unlocks the database.
+    let keepass_region = layout.region_handle(DataRegion::KeePassDb)?;
+    if !KeePassDb::check_if_exists(storage, keepass_region)? {
+        info!("Creating a new keepass");
+        KeePassDb::initialize_db(storage, keepass_region)?;
+    }
+
+    info!("Reading the keepass header");
+    let kpdb = KeePassDb::new(storage, &layout)?;
+
+    Ok(BootedStorage {
+        layout,
+        user_config,
+        kpdb,
+    })
+}
+
+/// Opens the storage as it is, without repairing or initializing anything.
+/// Regions failing their checksum are tolerated, so whatever still decrypts
+/// can be read back before a factory reset.
+pub fn open_read_only<S: NorFlash>(storage: &mut S) -> Result<BootedStorage, BootError> {
+    match StorageLayout::run_healthcheck(storage) {
+        Ok(()) => {}
+        Err(StorageError::CorruptRegions(regions)) => {
+            warn!("Opening read-only despite corrupt regions: {}", regions);
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    let layout = StorageLayout::new(storage)?;
+    let user_config_region = layout.region_handle(DataRegion::UserConfig)?;
+    let user_config = UserConfig::load(storage, user_config_region)?;
+    if UserConfig::wipe_pending(storage, user_config_region)? || user_config.wipe_due() {
+        return Err(BootError::WipePending);
+    }
+
+    let kpdb = KeePassDb::new(storage, &layout)?;
+    Ok(BootedStorage {
+        layout,
+        user_config,
+        kpdb,
+    })
+}
+
+/// Erases every region and the layout, checks they read back erased, and
+/// bootstraps a fresh layout with an empty database. Every secret, the
+/// database, the PIN and the settings go with it.
+pub fn factory_reset<S: NorFlash>(storage: &mut S) -> Result<(), BootError> {
+    warn!("Factory reset");
+    StorageLayout::reset_storage(storage)?;
+    let layout = StorageLayout::new(storage)?;
+    KeePassDb::initialize_db(storage, layout.region_handle(DataRegion::KeePassDb)?)?;
+    Ok(())
+}
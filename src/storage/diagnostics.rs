@@ -0,0 +1,80 @@
+//! How full and how healthy the storage is, for the diagnostics screen.
+
+use defmt::Format;
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::keepass::KeePassDb;
+use crate::storage::keepass::{MAX_ENTRIES, MAX_GROUPS};
+use crate::storage::layout::{REGION_COUNT, StorageError, StorageLayout};
+use crate::storage::region::{DataRegion, RegionDescriptor};
+
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct RegionUsage {
+    pub region: DataRegion,
+    /// Bytes in use. For `KeePassDb`, the records of the committed database;
+    /// for the others, the `used_len` in their descriptor.
+    pub used: u32,
+    pub capacity: u32,
+    /// Whether the contents match the CRC32 in the descriptor
+    pub crc_ok: bool,
+}
+
+/// Snapshot of the storage, taken when the diagnostics screen opens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Diagnostics {
+    pub firmware_version: &'static str,
+    pub layout_version: u16,
+    pub regions: [RegionUsage; REGION_COUNT],
+    pub groups: u32,
+    pub max_groups: u32,
+    pub entries: u32,
+    pub max_entries: u32,
+    /// Bytes the record log takes before garbage has to be collected
+    pub log_free: u32,
+    /// Lowest and highest erase count among the record log's sectors
+    pub erase_counts: (u32, u32),
+}
+
+impl Diagnostics {
+    /// Reads the layout's descriptors and checks every region's CRC32. The
+    /// counts come from the database header, so the database may be locked.
+    pub fn collect<S: NorFlash>(
+        storage: &mut S,
+        layout: &StorageLayout,
+        kpdb: &KeePassDb,
+    ) -> Result<Self, StorageError> {
+        let corrupt = StorageLayout::corrupt_regions(storage)?;
+        let regions = layout.regions.map(|desc: RegionDescriptor| RegionUsage {
+            region: desc.kind,
+            used: match desc.kind {
+                DataRegion::KeePassDb => kpdb.log.live_bytes(),
+                _ => desc.used_len,
+            },
+            capacity: desc.capacity,
+            crc_ok: !corrupt.contains(desc.kind),
+        });
+        let erase_counts = kpdb
+            .log
+            .erase_counts()
+            .fold(None, |range: Option<(u32, u32)>, count| {
+                Some(range.map_or((count, count), |(min, max)| {
+                    (min.min(count), max.max(count))
+                }))
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            firmware_version: FIRMWARE_VERSION,
+            layout_version: layout.header.layout_version,
+            regions,
+            groups: kpdb.header.num_groups,
+            max_groups: MAX_GROUPS,
+            entries: kpdb.header.num_entries,
+            max_entries: MAX_ENTRIES,
+            log_free: kpdb.log.free_bytes(),
+            erase_counts,
+        })
+    }
+}
@@ -0,0 +1,86 @@
+//! Byte-granular flash access on top of `embedded_storage`'s NOR flash traits.
+//!
+//! `ReadNorFlash` and `NorFlash` only take aligned offsets and lengths and
+//! never erase on their own. The storage code reads and rewrites small,
+//! unaligned records, so it goes through [`read`] and [`write`] here. Both
+//! work with any NOR flash: the on-chip `FlashStorage` on the device, or a
+//! [`RamFlash`](crate::storage::ram_flash::RamFlash) on a host.
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+/// Erase granularity the layout is planned around. Flash passed to the
+/// storage code must erase in sectors no larger than this.
+pub const SECTOR_SIZE: u32 = 4096;
+
+/// Aligned bounce buffer for unaligned reads. A multiple of every `READ_SIZE`
+/// we expect to see.
+const READ_CHUNK: usize = 64;
+
+/// Reads `bytes` at `offset`, whatever their alignment.
+pub fn read<S: ReadNorFlash>(
+    storage: &mut S,
+    offset: u32,
+    bytes: &mut [u8],
+) -> Result<(), S::Error> {
+    let align = S::READ_SIZE;
+    if (offset as usize).is_multiple_of(align) && bytes.len().is_multiple_of(align) {
+        return storage.read(offset, bytes);
+    }
+
+    let mut chunk = [0u8; READ_CHUNK];
+    let mut done = 0;
+    while done < bytes.len() {
+        let address = offset as usize + done;
+        let start = address - address % align;
+        let skip = address - start;
+        let len = (skip + bytes.len() - done)
+            .next_multiple_of(align)
+            .min(READ_CHUNK);
+        storage.read(start as u32, &mut chunk[..len])?;
+
+        let copied = (len - skip).min(bytes.len() - done);
+        bytes[done..done + copied].copy_from_slice(&chunk[skip..skip + copied]);
+        done += copied;
+    }
+    Ok(())
+}
+
+/// Writes `bytes` at `offset`, whatever their alignment, by reading back each
+/// sector it touches, merging the new bytes in and programming it again. The
+/// sector is only erased when the write needs to set a cleared bit.
+///
+/// Not power-loss safe: an interrupted rewrite can leave a sector erased.
+pub fn write<S: NorFlash>(storage: &mut S, offset: u32, bytes: &[u8]) -> Result<(), S::Error> {
+    let sector_size = S::ERASE_SIZE;
+    assert!(
+        sector_size <= SECTOR_SIZE as usize,
+        "flash sectors too large"
+    );
+
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    let sector = &mut sector[..sector_size];
+    let mut done = 0;
+    while done < bytes.len() {
+        let address = offset as usize + done;
+        let start = address - address % sector_size;
+        let skip = address - start;
+        let copied = (sector_size - skip).min(bytes.len() - done);
+        let data = &bytes[done..done + copied];
+
+        storage.read(start as u32, sector)?;
+        let current = &mut sector[skip..skip + copied];
+        // NOR programming can only clear bits
+        let needs_erase = current
+            .iter()
+            .zip(data)
+            .any(|(&old, &new)| old & new != new);
+        current.copy_from_slice(data);
+        if needs_erase {
+            storage.erase(start as u32, (start + sector_size) as u32)?;
+        }
+        storage.write(start as u32, sector)?;
+
+        done += copied;
+    }
+    Ok(())
+}
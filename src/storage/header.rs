@@ -1,9 +1,10 @@
 use defmt::Format;
 
-// This offset is used so the storage writes don't overlap with the bootloader and flash.
-const STORAGE_OFFSET: u32 = 0x200000;
+// Offsets are relative to the data partition (see `storage::partition`), which
+// keeps the storage clear of the bootloader and the app image.
+const STORAGE_OFFSET: u32 = 0;
 pub const STORAGE_MAGIC: [u8; 4] = *b"PBDY";
-pub const STORAGE_LAYOUT_VERSION: u16 = 1;
+pub const STORAGE_LAYOUT_VERSION: u16 = 2;
 pub(crate) const LAYOUT_HEADER_SIZE: usize = 8;
 
 /// Small header to sit ahead of the descriptors.
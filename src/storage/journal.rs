@@ -0,0 +1,160 @@
+//! Rekeys, journaled through `Scratch`.
+//!
+//! A change to the database is committed by its header record in the
+//! [record log](crate::storage::record_log) alone. A rekey also replaces the
+//! PIN verifier in `UserConfig`, which must not get out of step with the key
+//! sealing the database. So before the rekey's header record is appended, a
+//! commit record carrying the new verifier, and the sequence number that
+//! header record gets, is written to `Scratch`. Once the header record is in
+//! the log the verifier is installed and the commit record erased; if power
+//! is lost in between, [`StorageLayout::finish_pending_commit`] installs it
+//! on the next boot. A commit record whose header record never made it into
+//! the log is dropped.
+
+use defmt::{Format, info};
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::storage::flash::{self, SECTOR_SIZE};
+use crate::storage::layout::{StorageError, StorageLayout, keepass_error};
+use crate::storage::record_log::RecordLog;
+use crate::storage::region::DataRegion;
+use crate::storage::user_config::{UnlockMode, UserConfig, VERIFIER_SIZE};
+
+const COMMIT_MAGIC: [u8; 4] = *b"PBJC";
+// verifier = 44; header_seq = 4; unlock_mode = 2; reserved = 2; magic = 4;
+const COMMIT_BODY_SIZE: usize = VERIFIER_SIZE + 8;
+const COMMIT_SIZE: usize = COMMIT_BODY_SIZE + COMMIT_MAGIC.len();
+
+/// Commit record written ahead of a rekey's header record.
+pub(crate) struct Commit {
+    /// Sequence number of the header record committing the rekey
+    pub(crate) header_seq: u32,
+    /// Verifier and unlock mode to install once it is in the log
+    pub(crate) verifier: [u8; VERIFIER_SIZE],
+    pub(crate) unlock_mode: UnlockMode,
+}
+
+impl Commit {
+    fn to_bytes(&self) -> [u8; COMMIT_SIZE] {
+        let mut bytes = [0xFFu8; COMMIT_SIZE];
+        bytes[..VERIFIER_SIZE].copy_from_slice(&self.verifier);
+        bytes[VERIFIER_SIZE..VERIFIER_SIZE + 4].copy_from_slice(&self.header_seq.to_le_bytes());
+        bytes[VERIFIER_SIZE + 4..VERIFIER_SIZE + 6].copy_from_slice(&self.unlock_mode.to_bytes());
+        bytes[COMMIT_BODY_SIZE..].copy_from_slice(&COMMIT_MAGIC);
+        bytes
+    }
+
+    fn new_from_bytes(bytes: &[u8; COMMIT_SIZE]) -> Option<Self> {
+        if bytes[COMMIT_BODY_SIZE..] != COMMIT_MAGIC {
+            return None;
+        }
+        Some(Self {
+            header_seq: u32::from_le_bytes(
+                bytes[VERIFIER_SIZE..VERIFIER_SIZE + 4].try_into().unwrap(),
+            ),
+            verifier: bytes[..VERIFIER_SIZE].try_into().unwrap(),
+            unlock_mode: UnlockMode::from_bytes(
+                bytes[VERIFIER_SIZE + 4..VERIFIER_SIZE + 6]
+                    .try_into()
+                    .unwrap(),
+            ),
+        })
+    }
+}
+
+/// Where a rekey's commit record goes: the first sector of `Scratch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Journal {
+    commit_offset: u32,
+}
+
+impl Journal {
+    pub fn new(layout: &StorageLayout) -> Result<Self, StorageError> {
+        let scratch = layout.region_handle(DataRegion::Scratch)?;
+        if !scratch.contains_range(0, SECTOR_SIZE as usize) {
+            return Err(StorageError::BufferTooSmall);
+        }
+
+        Ok(Self {
+            commit_offset: scratch.base,
+        })
+    }
+
+    /// Writes the commit record. Its magic goes in last, so a torn record is
+    /// never taken for one.
+    pub(crate) fn begin<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        commit: &Commit,
+    ) -> Result<(), StorageError> {
+        self.erase(storage)?;
+        let commit_bytes = commit.to_bytes();
+        NorFlash::write(
+            storage,
+            self.commit_offset,
+            &commit_bytes[..COMMIT_BODY_SIZE],
+        )
+        .map_err(|_| StorageError::Io)?;
+        NorFlash::write(
+            storage,
+            self.commit_offset + COMMIT_BODY_SIZE as u32,
+            &commit_bytes[COMMIT_BODY_SIZE..],
+        )
+        .map_err(|_| StorageError::Io)
+    }
+
+    /// Installs the verifier of a rekey whose header record is in the log,
+    /// which also clears the failed-attempt counter, then drops the commit
+    /// record. Both steps can be repeated if power is lost midway.
+    pub(crate) fn finish<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        commit: &Commit,
+        user_config: &mut UserConfig,
+    ) -> Result<(), StorageError> {
+        user_config.install_verifier(commit.verifier, commit.unlock_mode, storage)?;
+        self.erase(storage)
+    }
+
+    /// Drops the commit record of a rekey that failed before its header
+    /// record was appended.
+    pub(crate) fn abort<S: NorFlash>(&self, storage: &mut S) -> Result<(), StorageError> {
+        self.erase(storage)
+    }
+
+    fn erase<S: NorFlash>(&self, storage: &mut S) -> Result<(), StorageError> {
+        let commit_sector = self.commit_offset - self.commit_offset % SECTOR_SIZE;
+        NorFlash::erase(storage, commit_sector, commit_sector + SECTOR_SIZE)
+            .map_err(|_| StorageError::Io)
+    }
+}
+
+impl StorageLayout {
+    /// Finishes a rekey that was committed before a power loss, or drops one
+    /// that never was. Returns `true` if one was pending.
+    pub fn finish_pending_commit<S: NorFlash>(
+        &self,
+        storage: &mut S,
+    ) -> Result<bool, StorageError> {
+        let journal = Journal::new(self)?;
+        let mut commit_bytes = [0u8; COMMIT_SIZE];
+        flash::read(storage, journal.commit_offset, &mut commit_bytes)
+            .map_err(|_| StorageError::Io)?;
+        let Some(commit) = Commit::new_from_bytes(&commit_bytes) else {
+            return Ok(false);
+        };
+
+        let log = RecordLog::mount(storage, self.region_handle(DataRegion::KeePassDb)?)
+            .map_err(keepass_error)?;
+        if log.committed_seq() >= commit.header_seq {
+            info!("Installing the verifier of an interrupted rekey");
+            let mut user_config =
+                UserConfig::load(storage, self.region_handle(DataRegion::UserConfig)?)?;
+            journal.finish(storage, &commit, &mut user_config)?;
+        } else {
+            info!("Dropping a rekey that never committed");
+            journal.abort(storage)?;
+        }
+        Ok(true)
+    }
+}
@@ -1,309 +1,573 @@
-use crate::storage::header::{LAYOUT_HEADER_SIZE, get_user_storage_offset};
-use crate::storage::region::{DataRegion, REGION_DESCRIPTOR_SIZE, RegionDescriptor, RegionHandle};
-use defmt::info;
-use embedded_storage::ReadStorage;
-use embedded_storage::Storage;
-use esp_storage::FlashStorage;
+use crate::encryption::{
+    self, DbKey, KEY_SIZE, NONCE_SIZE, SEAL_OVERHEAD, TAG_SIZE, open_in_place, seal_in_place,
+};
+use crate::secret::SecretBytes;
+use crate::storage::journal::Journal;
+use crate::storage::layout::StorageLayout;
+use crate::storage::record_log::{
+    LogIndex, MAX_RECORD_PAYLOAD, RecordKind, RecordLog, max_header_payload, record_size,
+};
+use crate::storage::region::{DataRegion, RegionHandle};
+use defmt::{info, warn};
+use embedded_storage::nor_flash::NorFlash;
+use heapless::Vec;
+use zeroize::Zeroize;
 
 use crate::keepass::{
     Entry, Group, HEADER_SIZE, KDBError, KDBHeader, KeePassDb,
-    entry::ENTRY_SIZE,
+    entry::MAX_ENTRY_SIZE,
     group::GROUP_SIZE,
-    header::{KDB_SIGNATURE1, KDB_SIGNATURE2},
+    header::{DEFAULT_TRANSFORM_ROUNDS, KDB_FORMAT_VERSION, KDB_SIGNATURE1, KDB_SIGNATURE2},
 };
 
-const SIGNATURE1_OFFSET_REL: u32 = 0;
-const SIGNATURE2_OFFSET_REL: u32 = 4;
-const HEADER_OFFSET_REL: u32 = 8;
-const MAX_GROUPS: u32 = 4;
-const MAX_ENTRIES: u32 = 256;
+pub(crate) const MAX_GROUPS: u32 = 4;
+pub(crate) const MAX_ENTRIES: u32 = 256;
+
+/// Groups and entries are stored as `nonce | ciphertext | tag`.
+pub(crate) const SEALED_GROUP_SIZE: usize = GROUP_SIZE + SEAL_OVERHEAD;
+/// Entry records vary in length, up to this.
+pub(crate) const MAX_SEALED_ENTRY_SIZE: usize = MAX_ENTRY_SIZE + SEAL_OVERHEAD;
+const _: () = assert!(MAX_SEALED_ENTRY_SIZE <= MAX_RECORD_PAYLOAD);
+/// The header record carries the header followed by its tag.
+pub(crate) const HEADER_BLOB_SIZE: usize = HEADER_SIZE + TAG_SIZE;
+
+/// An erased header tag marks a database that hasn't been sealed with a PIN
+/// yet. Its records, if it has any, are plaintext.
+const UNSEALED_TAG: [u8; TAG_SIZE] = [0xFF; TAG_SIZE];
+
+/// Set in [`KDBHeader::flags`] by a commit whose replaced records must not
+/// stay on flash, and cleared by [`KeePassDb::purge`] once they are erased.
+/// The flags mean nothing else on the device.
+pub(crate) const FLAG_PURGE_PENDING: u32 = 1 << 31;
+
+const RECORD_KIND_GROUP: u8 = 1;
+const RECORD_KIND_ENTRY: u8 = 2;
+
+/// Bytes of log a change writing `groups` groups and entry records of
+/// `entry_bytes` in all (see [`entry_record_size`]) takes up, its header
+/// record included.
+pub(crate) const fn change_size(groups: usize, entry_bytes: u32) -> u32 {
+    groups as u32 * record_size(SEALED_GROUP_SIZE)
+        + entry_bytes
+        + record_size(max_header_payload(HEADER_BLOB_SIZE))
+}
 
-const fn groups_offset_rel() -> u32 {
-    HEADER_OFFSET_REL + HEADER_SIZE as u32
+/// Bytes of log the record of `entry` takes up.
+pub(crate) fn entry_record_size(entry: &Entry) -> u32 {
+    record_size(entry.encoded_len() + SEAL_OVERHEAD)
 }
 
-const fn entries_offset_rel() -> u32 {
-    groups_offset_rel() + (MAX_GROUPS * GROUP_SIZE as u32)
+/// Decodes an opened group record written in record `format`.
+fn decode_group(format: u32, plaintext: &[u8]) -> Result<Group, KDBError> {
+    match format {
+        1 if plaintext.len() == GROUP_SIZE => Ok(Group::new_from_bytes(plaintext)),
+        1 => Err(KDBError::DatabaseIntegrityError),
+        _ => Err(KDBError::UnsupportedFormat(format)),
+    }
 }
 
-fn checked_absolute(
-    region: RegionHandle,
-    relative_offset: u32,
-    len: usize,
-) -> Result<u32, KDBError> {
-    if !region.contains_range(relative_offset, len) {
-        return Err(KDBError::DatabaseIntegrityError);
+/// Decodes an opened entry record written in record `format`.
+fn decode_entry(format: u32, plaintext: &[u8]) -> Result<Entry, KDBError> {
+    match format {
+        1 => Entry::new_from_bytes(plaintext),
+        _ => Err(KDBError::UnsupportedFormat(format)),
     }
-    region
-        .absolute(relative_offset)
-        .ok_or(KDBError::DatabaseIntegrityError)
 }
 
-fn descriptor_offset(kind: DataRegion) -> u32 {
-    get_user_storage_offset()
-        + LAYOUT_HEADER_SIZE as u32
-        + (REGION_DESCRIPTOR_SIZE as u32 * kind.index() as u32)
+/// Associated data binding a sealed record to its slot and to this database,
+/// so records can't be swapped between slots or replayed from another device.
+fn record_aad(kind: u8, slot: u32, header: &KDBHeader) -> [u8; 21] {
+    let mut aad = [0u8; 21];
+    aad[0] = kind;
+    aad[1..5].copy_from_slice(&slot.to_le_bytes());
+    aad[5..21].copy_from_slice(&header.master_seed);
+    aad
+}
+
+/// Associated data for the header tag: both signatures plus every header field.
+fn header_aad(header: &KDBHeader) -> [u8; 8 + HEADER_SIZE] {
+    let mut aad = [0u8; 8 + HEADER_SIZE];
+    aad[0..4].copy_from_slice(&KDB_SIGNATURE1.to_le_bytes());
+    aad[4..8].copy_from_slice(&KDB_SIGNATURE2.to_le_bytes());
+    aad[8..].copy_from_slice(&header.to_bytes());
+    aad
+}
+
+fn header_nonce(header: &KDBHeader) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce.copy_from_slice(&header.encryption_iv[..NONCE_SIZE]);
+    nonce
+}
+
+/// Header of a new database, with fresh seeds. The transform seed salts the
+/// PIN derivation and is never regenerated afterwards.
+pub(crate) fn fresh_header() -> KDBHeader {
+    let mut header = KDBHeader::empty();
+    header.subversion = KDB_FORMAT_VERSION;
+    encryption::fill_random(&mut header.master_seed);
+    encryption::fill_random(&mut header.encryption_iv);
+    encryption::fill_random(&mut header.transform_seed);
+    header.transform_rounds = DEFAULT_TRANSFORM_ROUNDS;
+    header
+}
+
+/// Header bytes followed by an erased tag, for a database no PIN has sealed
+/// yet.
+pub(crate) fn unsealed_header(header: &KDBHeader) -> [u8; HEADER_BLOB_SIZE] {
+    let mut header_buffer = [0u8; HEADER_BLOB_SIZE];
+    header_buffer[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+    header_buffer[HEADER_SIZE..].copy_from_slice(&UNSEALED_TAG);
+    header_buffer
+}
+
+/// Header bytes followed by their tag. Callers refresh the IV first.
+pub(crate) fn sealed_header(header: &KDBHeader, key: &DbKey) -> [u8; HEADER_BLOB_SIZE] {
+    let tag = encryption::tag_for(key, &header_nonce(header), &header_aad(header));
+
+    let mut header_buffer = [0u8; HEADER_BLOB_SIZE];
+    header_buffer[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+    header_buffer[HEADER_SIZE..].copy_from_slice(&tag);
+    header_buffer
+}
+
+pub(crate) fn sealed_group(
+    header: &KDBHeader,
+    key: &DbKey,
+    group_slot: u32,
+    group: &Group,
+) -> Result<[u8; SEALED_GROUP_SIZE], KDBError> {
+    let mut group_buffer = [0u8; SEALED_GROUP_SIZE];
+    group_buffer[NONCE_SIZE..NONCE_SIZE + GROUP_SIZE].copy_from_slice(&group.to_bytes());
+    let aad = record_aad(RECORD_KIND_GROUP, group_slot, header);
+    seal_in_place(key, &aad, &mut group_buffer).map_err(|_| KDBError::DatabaseIntegrityError)?;
+    Ok(group_buffer)
+}
+
+/// The sealed record of `entry`, as long as [`Entry::encoded_len`] needs.
+pub(crate) fn sealed_entry(
+    header: &KDBHeader,
+    key: &DbKey,
+    entry_slot: u32,
+    entry: &Entry,
+) -> Result<Vec<u8, MAX_SEALED_ENTRY_SIZE>, KDBError> {
+    let mut plaintext = [0u8; MAX_ENTRY_SIZE];
+    let len = entry.to_bytes(&mut plaintext);
+    let mut entry_buffer = Vec::new();
+    entry_buffer
+        .resize(len + SEAL_OVERHEAD, 0)
+        .map_err(|_| KDBError::DatabaseIntegrityError)?;
+    entry_buffer[NONCE_SIZE..NONCE_SIZE + len].copy_from_slice(&plaintext[..len]);
+    plaintext.zeroize();
+    let aad = record_aad(RECORD_KIND_ENTRY, entry_slot, header);
+    seal_in_place(key, &aad, &mut entry_buffer).map_err(|_| KDBError::DatabaseIntegrityError)?;
+    Ok(entry_buffer)
+}
+
+/// Commits the change staged in `staged` by appending its header record.
+/// `header` gets the new counts, a fresh IV and the hash of the sealed
+/// records in use, which the header tag in turn authenticates. Returns the
+/// header as written.
+pub(crate) fn commit_header<S: NorFlash>(
+    log: &mut RecordLog,
+    storage: &mut S,
+    staged: LogIndex,
+    mut header: KDBHeader,
+    key: &DbKey,
+) -> Result<KDBHeader, KDBError> {
+    header.num_groups = staged.group_slots().len() as u32;
+    header.num_entries = staged.entry_slots().len() as u32;
+    // The IV doubles as the tag nonce, so it must change on every write.
+    encryption::fill_random(&mut header.encryption_iv);
+    header.contents_hash = log.contents_hash(storage, &staged)?;
+    log.commit(storage, staged, &sealed_header(&header, key))?;
+    Ok(header)
+}
+
+/// Reads the committed header from `log` and checks the records it lists
+/// against it. Returns the header and whether it carries a tag.
+fn read_committed<S: NorFlash>(
+    storage: &mut S,
+    log: &RecordLog,
+) -> Result<(KDBHeader, bool), KDBError> {
+    let mut header_buffer = [0u8; HEADER_BLOB_SIZE];
+    log.read_header_blob(storage, &mut header_buffer)?;
+    let header = KDBHeader::new_from_bytes(&header_buffer[..HEADER_SIZE])?;
+    if header.num_groups as usize != log.index().group_slots().len()
+        || header.num_entries as usize != log.index().entry_slots().len()
+    {
+        return Err(KDBError::DatabaseIntegrityError);
+    }
+    if header.subversion > KDB_FORMAT_VERSION {
+        return Err(KDBError::UnsupportedFormat(header.subversion));
+    }
+    if log.contents_hash(storage, log.index())? != header.contents_hash {
+        return Err(KDBError::ContentsHashMismatch);
+    }
+    Ok((header, header_buffer[HEADER_SIZE..] != UNSEALED_TAG))
 }
 
 impl KeePassDb {
-    pub fn check_if_exists(
-        storage: &mut FlashStorage,
+    pub fn check_if_exists<S: NorFlash>(
+        storage: &mut S,
         region: RegionHandle,
     ) -> Result<bool, KDBError> {
-        // 1. we check the magic signatures are there
-        let mut signature1_buffer = [0u8; 4];
-        storage
-            .read(
-                checked_absolute(region, SIGNATURE1_OFFSET_REL, signature1_buffer.len())?,
-                &mut signature1_buffer,
-            )
-            .unwrap();
-        if signature1_buffer != KDB_SIGNATURE1.to_le_bytes() {
-            return Ok(false);
+        Ok(!RecordLog::mount(storage, region)?.is_empty())
+    }
+
+    pub fn initialize_db<S: NorFlash>(
+        storage: &mut S,
+        region: RegionHandle,
+    ) -> Result<(), KDBError> {
+        let mut log = RecordLog::mount(storage, region)?;
+        let staged = log.begin(storage, change_size(0, 0))?;
+
+        // 1. We add the header with fresh seeds. The tag stays erased until
+        // the first PIN is entered and seals the database.
+        let mut header = fresh_header();
+        header.contents_hash = log.contents_hash(storage, &staged)?;
+
+        // 2. Its header record commits the empty database, so an interrupted
+        // initialization is simply started again on the next boot
+        log.commit(storage, staged, &unsealed_header(&header))
+    }
+
+    /// Checks the database committed in the log in `region`: its header
+    /// record, the records it lists and their contents hash. An empty log
+    /// passes; the boot initializes it.
+    pub(crate) fn check_committed<S: NorFlash>(
+        storage: &mut S,
+        region: RegionHandle,
+    ) -> Result<(), KDBError> {
+        let log = RecordLog::mount(storage, region)?;
+        if log.is_empty() {
+            return Ok(());
         }
-        let mut signature2_buffer = [0u8; 4];
-        storage
-            .read(
-                checked_absolute(region, SIGNATURE2_OFFSET_REL, signature2_buffer.len())?,
-                &mut signature2_buffer,
-            )
-            .unwrap();
-        if signature2_buffer != KDB_SIGNATURE2.to_le_bytes() {
-            return Ok(false);
+        read_committed(storage, &log).map(|_| ())
+    }
+
+    /// Reads the plaintext header. The returned database is locked: groups and
+    /// entries are only decrypted by [`KeePassDb::unlock`].
+    pub fn new<S: NorFlash>(storage: &mut S, layout: &StorageLayout) -> Result<Self, KDBError> {
+        let region = layout.region_handle(DataRegion::KeePassDb)?;
+        let journal = Journal::new(layout)?;
+        // 1. We find the committed database in the log
+        info!("Mounting the record log");
+        let log = RecordLog::mount(storage, region)?;
+        if log.is_empty() {
+            return Err(KDBError::DatabaseIntegrityError);
         }
 
-        Ok(true)
+        // 2. We get the header and its tag, and check the records against it
+        let (header, sealed) = read_committed(storage, &log)?;
+        info!("Header: {}", header);
+
+        // 3. Return the locked database
+        Ok(KeePassDb {
+            storage: region,
+            journal,
+            log,
+            signature1: KDB_SIGNATURE1,
+            signature2: KDB_SIGNATURE2,
+            header,
+            groups: [None; 4],
+            entries: [const { None }; 256],
+            key: None,
+            sealed,
+        })
     }
 
-    pub fn initialize_db(storage: &mut FlashStorage, region: RegionHandle) -> Result<(), KDBError> {
-        info!("---- offset: {:?}", region.base);
-        // 1. Initialize the database by writing the magic signatures
-        let mut signature1_buffer = [0u8; 4];
-        signature1_buffer.copy_from_slice(&KDB_SIGNATURE1.to_le_bytes());
-        storage
-            .write(
-                checked_absolute(region, SIGNATURE1_OFFSET_REL, signature1_buffer.len())?,
-                &signature1_buffer,
-            )
-            .unwrap();
-        info!("---- Signature1: {:?}", &signature1_buffer);
-        let mut signature2_buffer = [0u8; 4];
-        signature2_buffer.copy_from_slice(&KDB_SIGNATURE2.to_le_bytes());
-        storage
-            .write(
-                checked_absolute(region, SIGNATURE2_OFFSET_REL, signature2_buffer.len())?,
-                &signature2_buffer,
-            )
-            .unwrap();
-        info!("---- Signature2: {:?}", &signature2_buffer);
-
-        // 2. We add the header
-        let header = KDBHeader::empty();
-        info!("---- Header: {:?}", &header.to_bytes());
-        storage
-            .write(
-                checked_absolute(region, HEADER_OFFSET_REL, HEADER_SIZE)?,
-                &header.to_bytes(),
-            )
-            .unwrap();
+    /// Derives the key sealing this database from the PIN-derived software key.
+    pub fn derive_key(&self, sw_key: &SecretBytes<KEY_SIZE>) -> DbKey {
+        DbKey::derive(sw_key, &self.header.master_seed)
+    }
+
+    /// Verifies the header tag with `key` and decrypts every group and entry.
+    /// An unsealed database (freshly initialized, or migrated from layout v1
+    /// with its records in plaintext) gets sealed with this key instead.
+    pub fn unlock<S: NorFlash>(&mut self, key: DbKey, storage: &mut S) -> Result<(), KDBError> {
+        // 1. Check the key against the header tag
+        if self.stored_tag(storage)? == UNSEALED_TAG {
+            return self.seal(key, storage);
+        }
+
+        self.check_key(&key, storage)?;
+
+        // 2. We get the groups and the entries
+        self.read_records(storage, Some(&key))?;
+
+        // 3. Finish erasing what a change cut short by a power loss replaced
+        if self.header.flags & FLAG_PURGE_PENDING != 0
+            && let Err(err) = self.purge(storage, &key)
+        {
+            warn!("Erasing replaced records failed: {}", err);
+        }
+
+        // 4. Rewrite records left in an older format. The database stays
+        // readable if this fails, and the next unlock tries again.
+        if self.header.subversion < KDB_FORMAT_VERSION {
+            info!(
+                "Upgrading records from format {} to {}",
+                self.header.subversion, KDB_FORMAT_VERSION
+            );
+            let mut header = self.header;
+            header.subversion = KDB_FORMAT_VERSION;
+            let upgraded = self
+                .stage_all(storage, &header, &key)
+                .and_then(|staged| commit_header(&mut self.log, storage, staged, header, &key));
+            match upgraded {
+                Ok(header) => self.header = header,
+                Err(err) => warn!("Upgrading the records failed: {}", err),
+            }
+        }
+
+        // 5. Keep the key around for writes
+        self.key = Some(key);
         Ok(())
     }
 
-    pub fn new(storage: &mut FlashStorage, region: RegionHandle) -> Result<Self, KDBError> {
-        // 1. we check the magic signatures are there
-        info!("Getting the magic signatures");
-        let mut signature1_buffer = [0u8; 4];
-        storage
-            .read(
-                checked_absolute(region, SIGNATURE1_OFFSET_REL, signature1_buffer.len())?,
-                &mut signature1_buffer,
-            )
-            .unwrap();
-        if signature1_buffer != KDB_SIGNATURE1.to_le_bytes() {
-            return Err(KDBError::DatabaseIntegrityError);
+    /// Seals an unsealed database with `key`: its records, if it has any, are
+    /// read as plaintext, committed again sealed, and the plaintext copies
+    /// are erased from the log.
+    fn seal<S: NorFlash>(&mut self, key: DbKey, storage: &mut S) -> Result<(), KDBError> {
+        info!("Sealing new database");
+        self.read_records(storage, None)?;
+
+        // Any records are plaintext, which has to go once they are sealed
+        let plaintext = self.header.num_groups != 0 || self.header.num_entries != 0;
+        let mut header = self.header;
+        header.subversion = KDB_FORMAT_VERSION;
+        if plaintext {
+            header.flags |= FLAG_PURGE_PENDING;
         }
-        let mut signature2_buffer = [0u8; 4];
-        storage
-            .read(
-                checked_absolute(region, SIGNATURE2_OFFSET_REL, signature2_buffer.len())?,
-                &mut signature2_buffer,
-            )
-            .unwrap();
-        if signature2_buffer != KDB_SIGNATURE2.to_le_bytes() {
-            return Err(KDBError::DatabaseIntegrityError);
+        let sealed = self
+            .stage_all(storage, &header, &key)
+            .and_then(|staged| commit_header(&mut self.log, storage, staged, header, &key));
+        match sealed {
+            Ok(header) => self.header = header,
+            Err(err) => {
+                self.lock();
+                return Err(err);
+            }
         }
-        info!("Signature1 buffer: {:?}", signature1_buffer);
-        info!("Signature2 buffer: {:?}", signature2_buffer);
-
-        // 2. We get the header
-        info!("Getting the header");
-        let mut header_buffer = [0u8; HEADER_SIZE];
-        storage
-            .read(
-                checked_absolute(region, HEADER_OFFSET_REL, header_buffer.len())?,
-                &mut header_buffer,
-            )
-            .unwrap();
-        let header = KDBHeader::new_from_bytes(&header_buffer)?;
-        info!("Header: {}", header);
-        if header.num_groups > MAX_GROUPS || header.num_entries > MAX_ENTRIES {
-            return Err(KDBError::DatabaseIntegrityError);
+        self.sealed = true;
+
+        // The database is sealed either way; the next unlock tries again
+        if plaintext && let Err(err) = self.purge(storage, &key) {
+            warn!("Erasing the plaintext records failed: {}", err);
         }
+        self.key = Some(key);
+        Ok(())
+    }
+
+    /// Erases every record the committed database no longer uses, then
+    /// commits the header again without [`FLAG_PURGE_PENDING`].
+    pub(crate) fn purge<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+        key: &DbKey,
+    ) -> Result<(), KDBError> {
+        self.log.purge(storage)?;
+        let mut header = self.header;
+        header.flags &= !FLAG_PURGE_PENDING;
+        let staged = self.log.begin(storage, change_size(0, 0))?;
+        self.header = commit_header(&mut self.log, storage, staged, header, key)?;
+        Ok(())
+    }
+
+    /// Reads every group and entry in use into memory, opening them with
+    /// `key`, or as plaintext without one. Nothing changes on failure.
+    fn read_records<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+        key: Option<&DbKey>,
+    ) -> Result<(), KDBError> {
+        let body = |len: usize| {
+            if key.is_some() {
+                NONCE_SIZE..len.saturating_sub(TAG_SIZE)
+            } else {
+                0..len
+            }
+        };
 
-        // 3. We get the groups
         info!("Getting the groups");
-        let mut group_buffer = [0u8; GROUP_SIZE];
         let mut groups: [Option<Group>; 4] = [None; 4];
-        for i in 0..header.num_groups as usize {
-            storage
-                .read(
-                    checked_absolute(
-                        region,
-                        groups_offset_rel() + (i as u32 * GROUP_SIZE as u32),
-                        group_buffer.len(),
-                    )?,
-                    &mut group_buffer,
-                )
-                .unwrap();
-            groups[i] = Some(Group::new_from_bytes(&group_buffer));
+        for (group, &slot) in groups.iter_mut().zip(self.log.index().group_slots()) {
+            let mut group_buffer = [0u8; SEALED_GROUP_SIZE];
+            let len = self
+                .log
+                .read_var(storage, RecordKind::Group, slot, &mut group_buffer)?;
+            if let Some(key) = key {
+                let aad = record_aad(RECORD_KIND_GROUP, slot as u32, &self.header);
+                open_in_place(key, &aad, &mut group_buffer[..len])
+                    .map_err(|_| KDBError::DatabaseIntegrityError)?;
+            }
+            *group = Some(decode_group(
+                self.header.subversion,
+                &group_buffer[body(len)],
+            )?);
         }
         info!("Groups: {:?}", groups);
 
-        // 4. We get the entries
         info!("Getting the entries");
-        let mut entry_buffer = [0u8; ENTRY_SIZE];
-        let mut entries: [Option<Entry>; 256] = [None; 256];
-        for i in 0..header.num_entries as usize {
-            storage
-                .read(
-                    checked_absolute(
-                        region,
-                        entries_offset_rel() + (i as u32 * ENTRY_SIZE as u32),
-                        entry_buffer.len(),
-                    )?,
-                    &mut entry_buffer,
-                )
-                .unwrap();
-            entries[i] = Some(Entry::new_from_bytes(&entry_buffer));
+        let mut entries: [Option<Entry>; 256] = [const { None }; 256];
+        for (entry, &slot) in entries.iter_mut().zip(self.log.index().entry_slots()) {
+            let mut entry_buffer = [0u8; MAX_SEALED_ENTRY_SIZE];
+            let len = self
+                .log
+                .read_var(storage, RecordKind::Entry, slot, &mut entry_buffer)?;
+            if let Some(key) = key {
+                let aad = record_aad(RECORD_KIND_ENTRY, slot as u32, &self.header);
+                open_in_place(key, &aad, &mut entry_buffer[..len])
+                    .map_err(|_| KDBError::DatabaseIntegrityError)?;
+            }
+            let decoded = decode_entry(self.header.subversion, &entry_buffer[body(len)]);
+            entry_buffer.zeroize();
+            *entry = Some(decoded?);
         }
         info!("Entries: {:?}", entries);
+        self.groups = groups;
+        self.entries = entries;
+        Ok(())
+    }
 
-        // 5. Return the database
-        info!("Returning database");
-        Ok(KeePassDb {
-            storage: region,
-            signature1: u32::from_le_bytes(signature1_buffer),
-            signature2: u32::from_le_bytes(signature2_buffer),
-            header: header,
-            groups: groups,
-            entries: entries,
-        })
+    pub(crate) fn key(&self) -> Result<&DbKey, KDBError> {
+        self.key.as_ref().ok_or(KDBError::Locked)
+    }
+
+    /// The header tag as committed to the log.
+    fn stored_tag<S: NorFlash>(&self, storage: &mut S) -> Result<[u8; TAG_SIZE], KDBError> {
+        let mut header_buffer = [0u8; HEADER_BLOB_SIZE];
+        self.log.read_header_blob(storage, &mut header_buffer)?;
+        let mut tag = [0u8; TAG_SIZE];
+        tag.copy_from_slice(&header_buffer[HEADER_SIZE..]);
+        Ok(tag)
+    }
+
+    /// Checks `key` against the header tag on flash without touching the
+    /// decrypted records.
+    pub fn check_key<S: NorFlash>(&self, key: &DbKey, storage: &mut S) -> Result<(), KDBError> {
+        let tag = self.stored_tag(storage)?;
+        if tag == UNSEALED_TAG {
+            return Err(KDBError::InvalidKey);
+        }
+
+        encryption::verify_tag(
+            key,
+            &header_nonce(&self.header),
+            &header_aad(&self.header),
+            &tag,
+        )
+        .map_err(|_| KDBError::InvalidKey)
     }
 
-    pub fn create_group(
+    /// Appends every group and entry in use, sealed with `key` for `header`,
+    /// to the log. Returns the staged change for [`commit_header`]; nothing
+    /// in memory changes.
+    pub(crate) fn stage_all<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+        header: &KDBHeader,
+        key: &DbKey,
+    ) -> Result<LogIndex, KDBError> {
+        let group_count = self.log.index().group_slots().len();
+        let entry_count = self.log.index().entry_slots().len();
+        let entry_bytes = self.entries[..entry_count]
+            .iter()
+            .flatten()
+            .map(entry_record_size)
+            .sum();
+        let mut staged = self
+            .log
+            .begin(storage, change_size(group_count, entry_bytes))?;
+
+        for i in 0..group_count {
+            let slot = staged.group_slots()[i];
+            let group = self.groups[i]
+                .as_ref()
+                .ok_or(KDBError::DatabaseIntegrityError)?;
+            let group_buffer = sealed_group(header, key, slot as u32, group)?;
+            self.log
+                .append(storage, &mut staged, RecordKind::Group, slot, &group_buffer)?;
+        }
+        for i in 0..entry_count {
+            let slot = staged.entry_slots()[i];
+            let entry = self.entries[i]
+                .as_ref()
+                .ok_or(KDBError::DatabaseIntegrityError)?;
+            let entry_buffer = sealed_entry(header, key, slot as u32, entry)?;
+            self.log
+                .append(storage, &mut staged, RecordKind::Entry, slot, &entry_buffer)?;
+        }
+        Ok(staged)
+    }
+
+    pub fn create_group<S: NorFlash>(
         &mut self,
         group: Group,
-        storage: &mut FlashStorage,
+        storage: &mut S,
     ) -> Result<(), KDBError> {
         if self.header.num_groups >= MAX_GROUPS {
-            return Err(KDBError::DatabaseIntegrityError);
+            return Err(KDBError::DatabaseFull);
         }
-
-        let group_index = self.header.num_groups;
-        let group_offset = checked_absolute(
-            self.storage,
-            groups_offset_rel() + (group_index * GROUP_SIZE as u32),
-            GROUP_SIZE,
-        )?;
-        let group_bytes = group.to_bytes();
-
-        // 1. Write group contents.
-        storage.write(group_offset, &group_bytes).unwrap();
-
-        // 2. Update internal region descriptor (best-effort bookkeeping).
-        let keepass_region_offset = descriptor_offset(DataRegion::KeePassDb);
-        let mut keepass_region_buffer = [0u8; REGION_DESCRIPTOR_SIZE];
-        storage
-            .read(keepass_region_offset, &mut keepass_region_buffer)
-            .unwrap();
-        let mut keepass_region = RegionDescriptor::new_from_bytes(&keepass_region_buffer);
-        let written_end = groups_offset_rel() + ((group_index + 1) * GROUP_SIZE as u32);
-        keepass_region.used_len = keepass_region.used_len.max(written_end);
-        storage
-            .write(keepass_region_offset, &keepass_region.to_bytes())
-            .unwrap();
-
-        // 3. Update and persist the KeePass header.
-        self.header.num_groups += 1;
-        storage
-            .write(
-                checked_absolute(self.storage, HEADER_OFFSET_REL, HEADER_SIZE)?,
-                &self.header.to_bytes(),
-            )
-            .unwrap();
-
-        // 4. Update in-memory cache.
-        self.groups[group_index as usize] = Some(group);
-
-        info!("Created group at offset {}", group_offset);
+        let key = self.key.as_ref().ok_or(KDBError::Locked)?;
+
+        // 1. Append the group to the log in a free slot, then commit
+        let group_index = self.header.num_groups as usize;
+        let mut staged = self.log.begin(storage, change_size(1, 0))?;
+        let slot = staged
+            .free_group_slot()
+            .ok_or(KDBError::DatabaseIntegrityError)?;
+        staged.push_group(slot)?;
+        let group_buffer = sealed_group(&self.header, key, slot as u32, &group)?;
+        self.log
+            .append(storage, &mut staged, RecordKind::Group, slot, &group_buffer)?;
+        let header = commit_header(&mut self.log, storage, staged, self.header, key)?;
+
+        // 2. Update in-memory cache.
+        self.header = header;
+        self.groups[group_index] = Some(group);
+
+        info!("Created group {}", group_index);
         Ok(())
     }
 
-    pub fn create_entry(
+    pub fn create_entry<S: NorFlash>(
         &mut self,
         entry: Entry,
-        storage: &mut FlashStorage,
+        storage: &mut S,
     ) -> Result<(), KDBError> {
         if self.header.num_entries >= MAX_ENTRIES {
-            return Err(KDBError::DatabaseIntegrityError);
+            return Err(KDBError::DatabaseFull);
         }
+        let key = self.key.as_ref().ok_or(KDBError::Locked)?;
+
+        // 1. Append the entry to the log in a free slot, then commit
+        let entry_index = self.header.num_entries as usize;
+        let mut staged = self
+            .log
+            .begin(storage, change_size(0, entry_record_size(&entry)))?;
+        let slot = staged
+            .free_entry_slot()
+            .ok_or(KDBError::DatabaseIntegrityError)?;
+        staged.push_entry(slot)?;
+        let entry_buffer = sealed_entry(&self.header, key, slot as u32, &entry)?;
+        self.log
+            .append(storage, &mut staged, RecordKind::Entry, slot, &entry_buffer)?;
+        let header = commit_header(&mut self.log, storage, staged, self.header, key)?;
+
+        // 2. Update in-memory cache.
+        self.header = header;
+        self.entries[entry_index] = Some(entry);
 
-        // 1. Calculate the offset for the new entry.
-        let entry_index = self.header.num_entries;
-        let entry_offset = checked_absolute(
-            self.storage,
-            entries_offset_rel() + (entry_index * ENTRY_SIZE as u32),
-            ENTRY_SIZE,
-        )?;
-        let mut entry_bytes = entry.to_bytes();
-
-        // 2. Write the entry contents
-        storage.write(entry_offset, &mut entry_bytes).unwrap();
-
-        // 3. Update internal region descriptors (for bookkeeping)
-        let keepass_region_offset = descriptor_offset(DataRegion::KeePassDb);
-        let mut keepass_region_buffer = [0u8; REGION_DESCRIPTOR_SIZE];
-        storage
-            .read(keepass_region_offset, &mut keepass_region_buffer)
-            .unwrap();
-        let mut keepass_region = RegionDescriptor::new_from_bytes(&keepass_region_buffer);
-        let written_end = entries_offset_rel() + ((entry_index + 1) * ENTRY_SIZE as u32);
-        keepass_region.used_len = keepass_region.used_len.max(written_end);
-        storage
-            .write(keepass_region_offset, &keepass_region.to_bytes())
-            .unwrap();
-
-        // 4. Update and persist the keepass header
-        self.header.num_entries += 1;
-        storage
-            .write(
-                checked_absolute(self.storage, HEADER_OFFSET_REL, HEADER_SIZE)?,
-                &self.header.to_bytes(),
-            )
-            .unwrap();
-
-        // 4. Update in-memory cache.
-        self.entries[entry_index as usize] = Some(entry);
-
-        info!("Created entry at offset {}", entry_offset);
+        info!("Created entry {}", entry_index);
         Ok(())
     }
 
-    pub fn update_entry(
+    pub fn update_entry<S: NorFlash>(
         &mut self,
         entry_index: usize,
         entry: Entry,
-        storage: &mut FlashStorage,
+        storage: &mut S,
     ) -> Result<(), KDBError> {
         if entry_index >= MAX_ENTRIES as usize {
             return Err(KDBError::EntryNotFound);
@@ -311,23 +575,32 @@ impl KeePassDb {
         if entry_index >= self.header.num_entries as usize {
             return Err(KDBError::EntryNotFound);
         }
-
-        let relative_offset = entries_offset_rel() + ((entry_index as u32) * ENTRY_SIZE as u32);
-        let entry_offset = checked_absolute(self.storage, relative_offset, ENTRY_SIZE)?;
-
-        let entry_bytes = entry.to_bytes();
-        storage
-            .write(entry_offset, &entry_bytes)
-            .map_err(|_| KDBError::DatabaseIntegrityError)?;
-
+        let key = self.key.as_ref().ok_or(KDBError::Locked)?;
+
+        // Only the entry's own record is rewritten; the old one becomes garbage
+        let slot = *self
+            .log
+            .index()
+            .entry_slots()
+            .get(entry_index)
+            .ok_or(KDBError::DatabaseIntegrityError)?;
+        let mut staged = self
+            .log
+            .begin(storage, change_size(0, entry_record_size(&entry)))?;
+        let entry_buffer = sealed_entry(&self.header, key, slot as u32, &entry)?;
+        self.log
+            .append(storage, &mut staged, RecordKind::Entry, slot, &entry_buffer)?;
+        let header = commit_header(&mut self.log, storage, staged, self.header, key)?;
+
+        self.header = header;
         self.entries[entry_index] = Some(entry);
         Ok(())
     }
 
-    pub fn delete_entry(
+    pub fn delete_entry<S: NorFlash>(
         &mut self,
         entry_index: usize,
-        storage: &mut FlashStorage,
+        storage: &mut S,
     ) -> Result<(), KDBError> {
         // 0. Check the entry actually exists
         if entry_index >= MAX_ENTRIES as usize {
@@ -336,62 +609,189 @@ impl KeePassDb {
         if entry_index >= self.header.num_entries as usize {
             return Err(KDBError::EntryNotFound);
         }
+        let key = self.key.as_ref().ok_or(KDBError::Locked)?;
+
+        // 1. Commit a header record without the entry, flagged until the
+        // entry's record is erased
+        let mut staged = self.log.begin(storage, change_size(0, 0))?;
+        staged.remove_entry(entry_index)?;
+        let mut header = self.header;
+        header.flags |= FLAG_PURGE_PENDING;
+        let header = commit_header(&mut self.log, storage, staged, header, key)?;
+
+        // 2. Shift the following entries one position to the front. The deleted
+        // entry ends up in the last slot; dropping it zeroizes it.
+        let last_index = (self.header.num_entries - 1) as usize;
+        self.entries[entry_index..=last_index].rotate_left(1);
+        self.entries[last_index] = None;
+        self.header = header;
+
+        // 3. Erase the sectors still holding the entry's record rather than
+        // leave it to the garbage collector. The entry is gone either way; if
+        // this fails the next unlock tries again.
+        if let Some(key) = self.key.take() {
+            if let Err(err) = self.purge(storage, &key) {
+                warn!("Erasing the deleted entry failed: {}", err);
+            }
+            self.key = Some(key);
+        }
 
-        // 1. Get the offsets
-        let relative_offset = entries_offset_rel() + ((entry_index as u32) * ENTRY_SIZE as u32);
-        let entry_offset = checked_absolute(self.storage, relative_offset, ENTRY_SIZE)?;
-
-        // 2. Remove from storage. We should set everything to zero, just to make sure when copying we don't keep any unnecesary data
-        storage
-            .write(entry_offset, &[0; ENTRY_SIZE])
-            .map_err(|_| KDBError::DatabaseIntegrityError)?;
+        Ok(())
+    }
+}
 
-        // 3. Shift the following entries one position to the front (skip if deleting the last).
-        let last_index = (self.header.num_entries - 1) as usize;
-        if entry_index < last_index {
-            let mut current_entry_offset = entry_offset;
-
-            for _ in entry_index..last_index {
-                let next_entry_offset = current_entry_offset + ENTRY_SIZE as u32;
-                let mut entry_buffer = [0u8; ENTRY_SIZE];
-                let _ = storage.read(next_entry_offset, &mut entry_buffer);
-                storage
-                    .write(current_entry_offset, &entry_buffer)
-                    .map_err(|_| KDBError::DatabaseIntegrityError)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::boot;
+    use crate::storage::ram_flash::RamFlash;
+    use crate::storage::testing::{self, SW_KEY, check_power_cuts, entry, holds, titles};
+
+    /// A database with one group and two entries.
+    fn populated() -> RamFlash {
+        let mut flash = testing::fresh_flash();
+        let (mut booted, _) = testing::unlock(&mut flash);
+        booted
+            .kpdb
+            .create_group(Group::random(), &mut flash)
+            .unwrap();
+        for title in ["mail", "bank"] {
+            booted
+                .kpdb
+                .create_entry(entry(1, title), &mut flash)
+                .unwrap();
+        }
+        flash
+    }
 
-                current_entry_offset = next_entry_offset;
-            }
+    #[test]
+    fn changes_survive_a_reboot() {
+        let mut flash = testing::fresh_flash();
+        let (mut booted, key) = testing::unlock(&mut flash);
+        let db = &mut booted.kpdb;
+        db.create_group(Group::random(), &mut flash).unwrap();
+        for title in ["mail", "bank", "forum"] {
+            db.create_entry(entry(1, title), &mut flash).unwrap();
         }
+        let mut bank = entry(1, "bank");
+        bank.set_username(b"bob").unwrap();
+        db.update_entry(1, bank, &mut flash).unwrap();
+        db.delete_entry(0, &mut flash).unwrap();
+
+        let mut booted = boot::open(&mut flash).unwrap();
+        assert!(!booted.kpdb.is_unlocked());
+        assert_eq!(booted.kpdb.header.num_groups, 1);
+        assert_eq!(booted.kpdb.header.num_entries, 2);
+        booted.kpdb.unlock(key, &mut flash).unwrap();
+        assert_eq!(titles(&booted.kpdb), [b"bank".to_vec(), b"forum".to_vec()]);
+        let bank = booted.kpdb.entries[0].as_ref().unwrap();
+        assert_eq!(bank.username(), b"bob");
+        assert_eq!(bank.password().expose(), b"bank");
+        assert_eq!(&booted.kpdb.groups[0].unwrap().name[..7], b"Private");
+    }
 
-        // 4. Update the kpdb
-        if entry_index < last_index {
-            for i in entry_index..last_index {
-                self.entries[i] = self.entries[i + 1];
+    #[test]
+    fn deleting_erases_the_entry() {
+        let mut flash = populated();
+        let (mut booted, _) = testing::unlock(&mut flash);
+        let slot = booted.kpdb.log.index().entry_slots()[0];
+        let mut sealed = [0u8; MAX_RECORD_PAYLOAD];
+        let len = booted
+            .kpdb
+            .log
+            .read_var(&mut flash, RecordKind::Entry, slot, &mut sealed)
+            .unwrap();
+        assert!(holds(&flash, &sealed[..len]));
+
+        booted.kpdb.delete_entry(0, &mut flash).unwrap();
+        assert!(!holds(&flash, &sealed[..len]));
+        assert_eq!(booted.kpdb.header.flags & FLAG_PURGE_PENDING, 0);
+        assert_eq!(titles(&booted.kpdb), [b"bank".to_vec()]);
+    }
+
+    #[test]
+    fn refuses_a_wrong_key() {
+        let mut flash = testing::fresh_flash();
+        let (mut booted, _) = testing::unlock(&mut flash);
+        booted
+            .kpdb
+            .create_entry(entry(1, "mail"), &mut flash)
+            .unwrap();
+
+        let mut booted = boot::open(&mut flash).unwrap();
+        let wrong = booted.kpdb.derive_key(&SecretBytes::new([8; KEY_SIZE]));
+        assert_eq!(
+            booted.kpdb.unlock(wrong, &mut flash),
+            Err(KDBError::InvalidKey)
+        );
+        assert!(booted.kpdb.entries.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn refuses_a_tampered_record() {
+        let mut flash = testing::fresh_flash();
+        let (mut booted, _) = testing::unlock(&mut flash);
+        booted
+            .kpdb
+            .create_entry(entry(1, "mail"), &mut flash)
+            .unwrap();
+
+        // Flip a bit in the entry's ciphertext. Its record's CRC is fixed up,
+        // so only the contents hash can notice.
+        let slot = booted.kpdb.log.index().entry_slots()[0];
+        booted
+            .kpdb
+            .log
+            .tamper(&mut flash, RecordKind::Entry, slot, NONCE_SIZE);
+
+        assert_eq!(
+            KeePassDb::new(&mut flash, &booted.layout).err(),
+            Some(KDBError::ContentsHashMismatch)
+        );
+    }
+
+    #[test]
+    fn changes_survive_a_power_cut() {
+        let base = populated();
+        check_power_cuts(&base, &[SW_KEY], |booted, flash| {
+            booted.kpdb.create_group(Group::random(), flash)
+        });
+        check_power_cuts(&base, &[SW_KEY], |booted, flash| {
+            booted.kpdb.create_entry(entry(1, "forum"), flash)
+        });
+        check_power_cuts(&base, &[SW_KEY], |booted, flash| {
+            booted.kpdb.update_entry(1, entry(1, "shop"), flash)
+        });
+        check_power_cuts(&base, &[SW_KEY], |booted, flash| {
+            booted.kpdb.delete_entry(0, flash)
+        });
+    }
+
+    #[test]
+    fn garbage_collection_survives_a_power_cut() {
+        // Rewrite an entry until the log has to collect garbage to take
+        // another copy of it
+        let mut base = populated();
+        let (mut booted, _) = testing::unlock(&mut base);
+        let mut long = entry(1, "bank");
+        long.set_notes(&[b'n'; 256]).unwrap();
+        loop {
+            // Only collecting garbage leaves more room after a change
+            let mut probe = booted.kpdb.clone();
+            probe
+                .update_entry(1, long.clone(), &mut base.clone())
+                .unwrap();
+            if probe.log.free_bytes() > booted.kpdb.log.free_bytes() {
+                break;
             }
+            booted
+                .kpdb
+                .update_entry(1, long.clone(), &mut base)
+                .unwrap();
         }
-        self.entries[last_index] = None;
 
-        // Clear the last entry slot in storage after any shift.
-        let last_entry_offset = checked_absolute(
-            self.storage,
-            entries_offset_rel() + ((last_index as u32) * ENTRY_SIZE as u32),
-            ENTRY_SIZE,
-        )?;
-        storage
-            .write(last_entry_offset, &[0; ENTRY_SIZE])
-            .map_err(|_| KDBError::DatabaseIntegrityError)?;
-
-        // 5. Substract 1 to the num of entries in memory
-        self.header.num_entries -= 1;
-
-        // 6. Update the header in storage
-        storage
-            .write(
-                checked_absolute(self.storage, HEADER_OFFSET_REL, HEADER_SIZE)?,
-                &self.header.to_bytes(),
-            )
-            .map_err(|_| KDBError::DatabaseIntegrityError)?;
-
-        Ok(())
+        check_power_cuts(&base, &[SW_KEY], |booted, flash| {
+            booted.kpdb.update_entry(1, entry(1, "shop"), flash)
+        });
     }
 }
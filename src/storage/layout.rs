@@ -1,39 +1,58 @@
 use defmt::Format;
-use embedded_storage::ReadStorage;
-use esp_storage::FlashStorage;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
 
+use crate::keepass::{KDBError, KeePassDb};
 use crate::storage::{
+    flash::{self, SECTOR_SIZE},
     header::{
         LAYOUT_HEADER_SIZE, LayoutHeader, STORAGE_LAYOUT_VERSION, STORAGE_MAGIC,
         get_user_storage_offset, storage_magic_offset,
     },
-    region::{DataRegion, REGION_DESCRIPTOR_SIZE, RegionDescriptor, RegionHandle},
+    migrate,
+    record_log::RecordLog,
+    region::{CorruptRegions, DataRegion, REGION_DESCRIPTOR_SIZE, RegionDescriptor, RegionHandle},
+    user_config::UserConfig,
 };
-use embedded_storage::Storage;
 pub const REGION_COUNT: usize = 4;
 
-const STORAGE_METADATA_BYTES: u32 = FlashStorage::SECTOR_SIZE;
-const REGION_PROJECT_CAPACITY: u32 = FlashStorage::SECTOR_SIZE;
-const REGION_USER_CONFIG_CAPACITY: u32 = FlashStorage::SECTOR_SIZE;
-const REGION_KEEPASS_CAPACITY: u32 = 64 * 1024;
-const REGION_SCRATCH_CAPACITY: u32 = FlashStorage::SECTOR_SIZE;
-
-const STORAGE_TOTAL_BYTES: u32 = STORAGE_METADATA_BYTES
+const STORAGE_METADATA_BYTES: u32 = SECTOR_SIZE;
+const REGION_PROJECT_CAPACITY: u32 = SECTOR_SIZE;
+const REGION_USER_CONFIG_CAPACITY: u32 = SECTOR_SIZE;
+// Record log for 4 groups and 256 entries, with room for a second copy of
+// every record so a rekey fits without erasing the first.
+const REGION_KEEPASS_CAPACITY: u32 = 192 * 1024;
+// One sector for a rekey's commit record, one where the metadata sector is
+// staged before it is rewritten, and a last one where `UserConfig` is.
+const REGION_SCRATCH_CAPACITY: u32 = 3 * SECTOR_SIZE;
+
+/// Chunk size for reading region contents back, to checksum or verify them.
+const CRC_CHUNK: usize = 256;
+// kind = 1; offset = 4; capacity = 4; then used_len and crc32
+const DESCRIPTOR_CONTENTS_OFFSET: u32 = 9;
+
+pub(crate) const STORAGE_TOTAL_BYTES: u32 = STORAGE_METADATA_BYTES
     + REGION_PROJECT_CAPACITY
     + REGION_USER_CONFIG_CAPACITY
     + REGION_KEEPASS_CAPACITY
     + REGION_SCRATCH_CAPACITY;
 
-const fn region_capacity(region: DataRegion) -> u32 {
-    match region {
-        DataRegion::ProjectConfig => REGION_PROJECT_CAPACITY,
-        DataRegion::UserConfig => REGION_USER_CONFIG_CAPACITY,
-        DataRegion::KeePassDb => REGION_KEEPASS_CAPACITY,
-        DataRegion::Scratch => REGION_SCRATCH_CAPACITY,
-    }
-}
+/// Capacity of each region, in descriptor order.
+pub(crate) const REGION_CAPACITIES: [u32; REGION_COUNT] = [
+    REGION_PROJECT_CAPACITY,
+    REGION_USER_CONFIG_CAPACITY,
+    REGION_KEEPASS_CAPACITY,
+    REGION_SCRATCH_CAPACITY,
+];
 
 fn expected_region_descriptors() -> [RegionDescriptor; REGION_COUNT] {
+    region_descriptors(REGION_CAPACITIES)
+}
+
+/// Regions laid out back to back after the metadata sector, with the given
+/// capacities. Older layouts only differ in these.
+pub(crate) fn region_descriptors(
+    capacities: [u32; REGION_COUNT],
+) -> [RegionDescriptor; REGION_COUNT] {
     let mut regions = [RegionDescriptor::empty(); REGION_COUNT];
     let mut next_offset = storage_magic_offset() + STORAGE_METADATA_BYTES;
 
@@ -46,7 +65,7 @@ fn expected_region_descriptors() -> [RegionDescriptor; REGION_COUNT] {
             _ => unreachable!("REGION_COUNT must match fixed region list"),
         };
 
-        let capacity = region_capacity(kind);
+        let capacity = capacities[idx];
         *region = RegionDescriptor {
             kind,
             offset: next_offset,
@@ -61,6 +80,173 @@ fn expected_region_descriptors() -> [RegionDescriptor; REGION_COUNT] {
     regions
 }
 
+fn descriptor_offset(region: DataRegion) -> u32 {
+    get_user_storage_offset()
+        + LAYOUT_HEADER_SIZE as u32
+        + (region.index() * REGION_DESCRIPTOR_SIZE) as u32
+}
+
+/// CRC32 of the first `used_len` bytes starting at `base`.
+fn region_crc32<S: NorFlash>(
+    storage: &mut S,
+    base: u32,
+    used_len: u32,
+) -> Result<u32, StorageError> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut chunk = [0u8; CRC_CHUNK];
+    let mut offset = 0u32;
+    while offset < used_len {
+        let len = (used_len - offset).min(CRC_CHUNK as u32) as usize;
+        flash::read(storage, base + offset, &mut chunk[..len]).map_err(|_| StorageError::Io)?;
+        hasher.update(&chunk[..len]);
+        offset += len as u32;
+    }
+    Ok(hasher.finalize())
+}
+
+/// Second sector of `Scratch`, where a new metadata sector is staged.
+pub(crate) fn metadata_backup_offset() -> u32 {
+    let scratch = expected_region_descriptors()[DataRegion::Scratch.index()];
+    scratch.offset + SECTOR_SIZE
+}
+
+/// Last sector of `Scratch`, where a new `UserConfig` is staged.
+pub(crate) fn user_config_staging_offset() -> u32 {
+    let scratch = expected_region_descriptors()[DataRegion::Scratch.index()];
+    scratch.offset + scratch.capacity - SECTOR_SIZE
+}
+
+/// Replaces the metadata sector (storage magic, layout header and region
+/// descriptors) without risking it on a power loss: the new contents are
+/// staged in the backup sector first, with the magic written last, and
+/// [`StorageLayout::finish_pending_metadata`] copies them over on the next
+/// boot if the rewrite got cut short.
+fn rewrite_metadata<S: NorFlash>(
+    storage: &mut S,
+    sector: &[u8; SECTOR_SIZE as usize],
+) -> Result<(), StorageError> {
+    let backup = metadata_backup_offset();
+    let magic_len = STORAGE_MAGIC.len();
+
+    // 1. Stage the new sector
+    NorFlash::erase(storage, backup, backup + SECTOR_SIZE).map_err(|_| StorageError::Io)?;
+    NorFlash::write(storage, backup + magic_len as u32, &sector[magic_len..])
+        .map_err(|_| StorageError::Io)?;
+    NorFlash::write(storage, backup, &sector[..magic_len]).map_err(|_| StorageError::Io)?;
+
+    // 2. Copy it into place, then drop the staged copy
+    install_metadata_backup(storage, sector)
+}
+
+fn install_metadata_backup<S: NorFlash>(
+    storage: &mut S,
+    sector: &[u8; SECTOR_SIZE as usize],
+) -> Result<(), StorageError> {
+    let start = storage_magic_offset();
+    NorFlash::erase(storage, start, start + SECTOR_SIZE).map_err(|_| StorageError::Io)?;
+    NorFlash::write(storage, start, sector).map_err(|_| StorageError::Io)?;
+
+    let backup = metadata_backup_offset();
+    NorFlash::erase(storage, backup, backup + SECTOR_SIZE).map_err(|_| StorageError::Io)
+}
+
+/// Whether the storage window, as this firmware lays it out, fits in `storage`.
+pub(crate) fn check_capacity<S: NorFlash>(storage: &S) -> Result<(), StorageError> {
+    let end = storage_magic_offset()
+        .checked_add(STORAGE_TOTAL_BYTES)
+        .ok_or(StorageError::InvalidLayout)?;
+    if end > storage.capacity() as u32 {
+        return Err(StorageError::InvalidLayout);
+    }
+    Ok(())
+}
+
+/// Where `region` lives in the current layout, before anything is read from
+/// flash.
+pub(crate) fn expected_region_handle(region: DataRegion) -> RegionHandle {
+    let desc = expected_region_descriptors()[region.index()];
+    RegionHandle {
+        base: desc.offset,
+        capacity: desc.capacity,
+    }
+}
+
+/// Rewrites the whole metadata sector for layout `layout_version`, whose
+/// regions have `capacities`, with each region's `used_len` taken from
+/// `used_lens` and its CRC32 computed from flash. Used by migrations, which
+/// must keep the regions' contents.
+pub(crate) fn write_metadata<S: NorFlash>(
+    storage: &mut S,
+    layout_version: u16,
+    capacities: [u32; REGION_COUNT],
+    used_lens: [u32; REGION_COUNT],
+) -> Result<(), StorageError> {
+    let header = LayoutHeader {
+        magic: STORAGE_MAGIC,
+        layout_version,
+        region_count: REGION_COUNT as u8,
+    };
+
+    let mut sector = [0xFFu8; SECTOR_SIZE as usize];
+    sector[..STORAGE_MAGIC.len()].copy_from_slice(&STORAGE_MAGIC);
+    let mut offset = (get_user_storage_offset() - storage_magic_offset()) as usize;
+    sector[offset..offset + LAYOUT_HEADER_SIZE].copy_from_slice(&header.get_bytes());
+    offset += LAYOUT_HEADER_SIZE;
+    for (mut desc, used_len) in region_descriptors(capacities).into_iter().zip(used_lens) {
+        if used_len > desc.capacity {
+            return Err(StorageError::InvalidLayout);
+        }
+        desc.used_len = used_len;
+        desc.crc32 = region_crc32(storage, desc.offset, used_len)?;
+        sector[offset..offset + REGION_DESCRIPTOR_SIZE].copy_from_slice(&desc.to_bytes());
+        offset += REGION_DESCRIPTOR_SIZE;
+    }
+
+    rewrite_metadata(storage, &sector)
+}
+
+/// Reports a failure to read the KeePass region as a storage error.
+pub(crate) fn keepass_error(err: KDBError) -> StorageError {
+    match err {
+        KDBError::Io => StorageError::Io,
+        _ => {
+            let mut regions = CorruptRegions::default();
+            regions.insert(DataRegion::KeePassDb);
+            StorageError::CorruptRegions(regions)
+        }
+    }
+}
+
+/// Records that the first `used_len` bytes of `region` were just written:
+/// updates its descriptor's `used_len` and `crc32` to match what is on flash.
+///
+/// Every path writing a region calls this once its contents are final.
+pub(crate) fn record_region_contents<S: NorFlash>(
+    storage: &mut S,
+    region: DataRegion,
+    used_len: u32,
+) -> Result<(), StorageError> {
+    let desc = expected_region_descriptors()[region.index()];
+    if used_len > desc.capacity {
+        return Err(StorageError::BufferTooSmall);
+    }
+    let crc32 = region_crc32(storage, desc.offset, used_len)?;
+
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    flash::read(storage, storage_magic_offset(), &mut sector).map_err(|_| StorageError::Io)?;
+    let start =
+        (descriptor_offset(region) + DESCRIPTOR_CONTENTS_OFFSET - storage_magic_offset()) as usize;
+    let mut contents = [0u8; 8];
+    contents[..4].copy_from_slice(&used_len.to_le_bytes());
+    contents[4..].copy_from_slice(&crc32.to_le_bytes());
+    // Rewrites that leave the region as it was don't wear the metadata sector
+    if sector[start..start + 8] == contents {
+        return Ok(());
+    }
+    sector[start..start + 8].copy_from_slice(&contents);
+    rewrite_metadata(storage, &sector)
+}
+
 /// Fixed set of descriptors baked into firmware for now.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
 pub struct StorageLayout {
@@ -69,15 +255,13 @@ pub struct StorageLayout {
 }
 
 impl StorageLayout {
-    pub fn new(storage: &mut FlashStorage) -> Self {
+    pub fn new<S: NorFlash>(storage: &mut S) -> Result<Self, StorageError> {
         // 1. Go to the storage layout and ensure we read the header
         let mut offset = get_user_storage_offset();
 
         // 2. Read the header from the storage layout
         let mut header_buffer = [0u8; LAYOUT_HEADER_SIZE];
-        storage
-            .read(offset, &mut header_buffer)
-            .expect("Read failed");
+        flash::read(storage, offset, &mut header_buffer).map_err(|_| StorageError::Io)?;
 
         let layout_header = LayoutHeader::new_from_bytes(&header_buffer);
         offset += LAYOUT_HEADER_SIZE as u32;
@@ -87,20 +271,19 @@ impl StorageLayout {
         let mut regions = [RegionDescriptor::empty(); REGION_COUNT];
         for region in &mut regions {
             let mut region_buffer = [0u8; REGION_DESCRIPTOR_SIZE];
-            storage
-                .read(current_offset, &mut region_buffer)
-                .expect("region reading failed");
+            flash::read(storage, current_offset, &mut region_buffer)
+                .map_err(|_| StorageError::Io)?;
 
             current_offset += REGION_DESCRIPTOR_SIZE as u32;
-            *region = RegionDescriptor::new_from_bytes(&region_buffer);
+            *region = RegionDescriptor::new_from_bytes(&region_buffer)?;
         }
 
-        Self {
+        Ok(Self {
             header: layout_header,
             regions,
-        }
+        })
     }
-    pub fn run_healthcheck(storage: &mut FlashStorage) -> Result<(), StorageError> {
+    pub fn run_healthcheck<S: NorFlash>(storage: &mut S) -> Result<(), StorageError> {
         // Ensure the declared storage window fits within flash.
         let capacity = storage.capacity() as u32;
         let start = storage_magic_offset();
@@ -112,18 +295,16 @@ impl StorageLayout {
         }
 
         let mut magic_buffer = [0u8; 4];
-        storage
-            .read(storage_magic_offset(), &mut magic_buffer)
-            .expect("Read failed");
+        flash::read(storage, storage_magic_offset(), &mut magic_buffer)
+            .map_err(|_| StorageError::Io)?;
 
         if magic_buffer != STORAGE_MAGIC {
             return Err(StorageError::BadMagic);
         }
 
         let mut header_buffer = [0u8; LAYOUT_HEADER_SIZE];
-        storage
-            .read(get_user_storage_offset(), &mut header_buffer)
-            .expect("header read failed");
+        flash::read(storage, get_user_storage_offset(), &mut header_buffer)
+            .map_err(|_| StorageError::Io)?;
         let header = LayoutHeader::new_from_bytes(&header_buffer);
         if header.magic != STORAGE_MAGIC {
             return Err(StorageError::InvalidLayout);
@@ -140,10 +321,8 @@ impl StorageLayout {
         let mut desc_offset = get_user_storage_offset() + LAYOUT_HEADER_SIZE as u32;
         for (idx, expected_desc) in expected.iter().enumerate() {
             let mut region_buffer = [0u8; REGION_DESCRIPTOR_SIZE];
-            storage
-                .read(desc_offset, &mut region_buffer)
-                .expect("region descriptor read failed");
-            let actual = RegionDescriptor::new_from_bytes(&region_buffer);
+            flash::read(storage, desc_offset, &mut region_buffer).map_err(|_| StorageError::Io)?;
+            let actual = RegionDescriptor::new_from_bytes(&region_buffer)?;
             if actual.kind != expected_desc.kind
                 || actual.offset != expected_desc.offset
                 || actual.capacity != expected_desc.capacity
@@ -152,9 +331,7 @@ impl StorageLayout {
             }
 
             // Basic overlap/alignment checks.
-            if actual.offset % FlashStorage::SECTOR_SIZE != 0
-                || actual.capacity % FlashStorage::SECTOR_SIZE != 0
-            {
+            if actual.offset % SECTOR_SIZE != 0 || actual.capacity % SECTOR_SIZE != 0 {
                 return Err(StorageError::InvalidLayout);
             }
             let actual_end = actual
@@ -178,35 +355,148 @@ impl StorageLayout {
             desc_offset += REGION_DESCRIPTOR_SIZE as u32;
         }
 
+        // The layout is sound; now check each region's contents against its descriptor.
+        let corrupt = Self::corrupt_regions(storage)?;
+        if !corrupt.is_empty() {
+            return Err(StorageError::CorruptRegions(corrupt));
+        }
+
         Ok(())
     }
 
-    pub fn wipe_layout(storage: &mut FlashStorage) -> Result<(), StorageError> {
-        use embedded_storage::nor_flash::NorFlash;
+    /// Regions whose contents don't match the `used_len` and CRC32 in their
+    /// descriptor. `KeePassDb` is checked against its committed header record
+    /// instead: the records it lists, their CRC32s and their contents hash.
+    /// Only meaningful once the layout itself checks out.
+    pub fn corrupt_regions<S: NorFlash>(storage: &mut S) -> Result<CorruptRegions, StorageError> {
+        let mut corrupt = CorruptRegions::default();
+        let keepass = expected_region_handle(DataRegion::KeePassDb);
+        match KeePassDb::check_committed(storage, keepass) {
+            // A newer format is reported when the database is opened
+            Ok(()) | Err(KDBError::UnsupportedFormat(_)) => {}
+            Err(KDBError::Io) => return Err(StorageError::Io),
+            Err(_) => corrupt.insert(DataRegion::KeePassDb),
+        }
+        for expected_desc in expected_region_descriptors() {
+            let mut region_buffer = [0u8; REGION_DESCRIPTOR_SIZE];
+            flash::read(
+                storage,
+                descriptor_offset(expected_desc.kind),
+                &mut region_buffer,
+            )
+            .map_err(|_| StorageError::Io)?;
+            let actual = RegionDescriptor::new_from_bytes(&region_buffer)?;
+            if actual.used_len > actual.capacity
+                || region_crc32(storage, actual.offset, actual.used_len)? != actual.crc32
+            {
+                corrupt.insert(actual.kind);
+            }
+        }
+        Ok(corrupt)
+    }
+
+    /// Finishes a metadata rewrite cut short by a power loss. Runs before the
+    /// healthcheck, since the metadata sector may be erased at that point.
+    /// Returns `true` if one was pending.
+    pub fn finish_pending_metadata<S: NorFlash>(storage: &mut S) -> Result<bool, StorageError> {
+        check_capacity(storage)?;
+
+        let mut sector = [0u8; SECTOR_SIZE as usize];
+        flash::read(storage, metadata_backup_offset(), &mut sector)
+            .map_err(|_| StorageError::Io)?;
+        // In v1 this sector was outside the storage window, so it must
+        // look like a complete layout, not just start with the magic
+        let header_start = (get_user_storage_offset() - storage_magic_offset()) as usize;
+        let header = LayoutHeader::new_from_bytes(
+            sector[header_start..header_start + LAYOUT_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        // A migration stages the version it migrates to
+        let known_version = header.layout_version == STORAGE_LAYOUT_VERSION
+            || migrate::can_migrate(header.layout_version);
+        if sector[..STORAGE_MAGIC.len()] != STORAGE_MAGIC
+            || header.magic != STORAGE_MAGIC
+            || !known_version
+        {
+            return Ok(false);
+        }
+
+        install_metadata_backup(storage, &sector)?;
+        Ok(true)
+    }
 
-        let start = storage_magic_offset(); // 0x200000
-        let end = start + FlashStorage::SECTOR_SIZE; // + 4KiB
+    pub fn wipe_layout<S: NorFlash>(storage: &mut S) -> Result<(), StorageError> {
+        let start = storage_magic_offset();
+        let end = start + SECTOR_SIZE; // + 4KiB
 
         storage.erase(start, end).map_err(|_| StorageError::Io)
     }
 
-    pub fn bootstrap_storage_write(storage: &mut FlashStorage) -> Result<(), StorageError> {
-        let capacity = storage.capacity() as u32;
+    /// Erases every region of the layout, then the metadata sector, and reads
+    /// the whole storage window back to check it is all `0xFF`. The regions
+    /// holding secrets go first, so an erase cut short never leaves them
+    /// behind a blank layout.
+    pub fn secure_erase<S: NorFlash>(storage: &mut S) -> Result<(), StorageError> {
+        check_capacity(storage)?;
+
+        // 1. Erase the regions, secrets first, then the metadata sector
+        for region in [
+            DataRegion::UserConfig,
+            DataRegion::KeePassDb,
+            DataRegion::Scratch,
+            DataRegion::ProjectConfig,
+        ] {
+            let handle = expected_region_handle(region);
+            NorFlash::erase(storage, handle.base, handle.base + handle.capacity)
+                .map_err(|_| StorageError::Io)?;
+        }
+        Self::wipe_layout(storage)?;
+
+        // 2. Check that every byte reads back erased
         let start = storage_magic_offset();
-        let end = start
-            .checked_add(STORAGE_TOTAL_BYTES)
-            .ok_or(StorageError::InvalidLayout)?;
-        if end > capacity {
-            return Err(StorageError::InvalidLayout);
+        let mut chunk = [0u8; CRC_CHUNK];
+        let mut offset = 0;
+        while offset < STORAGE_TOTAL_BYTES {
+            flash::read(storage, start + offset, &mut chunk).map_err(|_| StorageError::Io)?;
+            if chunk.iter().any(|&b| b != 0xFF) {
+                let sector = start + offset - (start + offset) % SECTOR_SIZE;
+                return Err(StorageError::NotErased(sector));
+            }
+            offset += CRC_CHUNK as u32;
         }
+        Ok(())
+    }
 
-        // Start from a clean slate; this project is early-stage so we prefer a hard reset.
-        embedded_storage::nor_flash::NorFlash::erase(storage, start, end)
-            .map_err(|_| StorageError::Io)?;
+    /// Writes a fresh layout on a blank device. Storage that still holds a
+    /// layout or a database, even behind damaged metadata, is refused with
+    /// [`StorageError::NotBlank`]; only [`Self::reset_storage`] erases it.
+    pub fn bootstrap_storage_write<S: NorFlash>(storage: &mut S) -> Result<(), StorageError> {
+        if Self::holds_data(storage)? {
+            return Err(StorageError::NotBlank);
+        }
+        Self::reset_storage(storage)
+    }
 
-        storage
-            .write(storage_magic_offset(), &STORAGE_MAGIC)
-            .expect("Storage magic write failed");
+    /// Whether the storage carries a layout, or a database where the layout
+    /// puts it: in this layout's log or in an older layout's image.
+    fn holds_data<S: NorFlash>(storage: &mut S) -> Result<bool, StorageError> {
+        check_capacity(storage)?;
+        if Self::run_healthcheck(storage) != Err(StorageError::BadMagic) {
+            return Ok(true);
+        }
+        let keepass = expected_region_handle(DataRegion::KeePassDb);
+        Ok(
+            !matches!(KeePassDb::check_if_exists(storage, keepass), Ok(false))
+                || migrate::holds_legacy_database(storage)?,
+        )
+    }
+
+    /// Erases the whole storage, secrets and database included, and writes a
+    /// fresh layout. For the wipe and the factory reset.
+    pub fn reset_storage<S: NorFlash>(storage: &mut S) -> Result<(), StorageError> {
+        // 1. Start from a clean slate, checked to have been erased
+        Self::secure_erase(storage)?;
 
         // 2. Create the header
         let header = LayoutHeader {
@@ -216,21 +506,74 @@ impl StorageLayout {
         };
 
         // 3. Write the header to the storage layout
-        storage
-            .write(get_user_storage_offset(), &header.get_bytes())
-            .expect("Write failed");
+        flash::write(storage, get_user_storage_offset(), &header.get_bytes())
+            .map_err(|_| StorageError::Io)?;
 
         // 4. Initialize regions deterministically (aligned and non-overlapping).
         let expected = expected_region_descriptors();
         let mut regions_offset = get_user_storage_offset() + LAYOUT_HEADER_SIZE as u32;
         for desc in expected {
-            storage
-                .write(regions_offset, &desc.to_bytes())
-                .expect("region descriptor write failed");
+            flash::write(storage, regions_offset, &desc.to_bytes())
+                .map_err(|_| StorageError::Io)?;
             regions_offset += REGION_DESCRIPTOR_SIZE as u32;
         }
 
-        Ok(())
+        // 5. The magic goes last: until it is written the boot sees a blank
+        // device and bootstraps it again
+        flash::write(storage, storage_magic_offset(), &STORAGE_MAGIC).map_err(|_| StorageError::Io)
+    }
+
+    /// Erases every sector of `region`.
+    pub fn erase_region<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        region: DataRegion,
+    ) -> Result<(), StorageError> {
+        let handle = self.region_handle(region)?;
+        let end = handle
+            .absolute(handle.capacity)
+            .ok_or(StorageError::InvalidLayout)?;
+        NorFlash::erase(storage, handle.base, end).map_err(|_| StorageError::Io)
+    }
+
+    /// Erases the database and the PIN verifier and returns the device to the
+    /// factory bootstrap state.
+    ///
+    /// A marker is written to `UserConfig` first, so a wipe cut short by a
+    /// power loss is finished by [`StorageLayout::finish_pending_wipe`] on the
+    /// next boot. It stays until the database and the metadata are erased;
+    /// past that point the boot bootstraps the blank device instead.
+    pub fn wipe_secrets<S: NorFlash>(&self, storage: &mut S) -> Result<(), StorageError> {
+        let user_config = self.region_handle(DataRegion::UserConfig)?;
+        UserConfig::mark_wipe_pending(storage, user_config)?;
+        self.complete_wipe(storage)
+    }
+
+    /// Finishes a wipe interrupted by a power loss. Returns `true` if one was pending.
+    pub fn finish_pending_wipe<S: NorFlash>(&self, storage: &mut S) -> Result<bool, StorageError> {
+        let user_config = self.region_handle(DataRegion::UserConfig)?;
+        if !UserConfig::wipe_pending(storage, user_config)? {
+            return Ok(false);
+        }
+
+        self.complete_wipe(storage)?;
+        Ok(true)
+    }
+
+    fn complete_wipe<S: NorFlash>(&self, storage: &mut S) -> Result<(), StorageError> {
+        // 1. Erase the database, and any copy of it, while the marker is still
+        // set. The log's sectors keep their erase counts.
+        RecordLog::erase_all(storage, self.region_handle(DataRegion::KeePassDb)?)
+            .map_err(keepass_error)?;
+        self.erase_region(storage, DataRegion::Scratch)?;
+
+        // 2. Erase the metadata before the marker goes. From here on the boot
+        // finds no layout and nothing where the database was, so a cut leaves
+        // a blank device that gets bootstrapped.
+        Self::wipe_layout(storage)?;
+
+        // 3. Start over; this also clears the marker, verifier and counter
+        Self::reset_storage(storage)
     }
 
     pub fn get_offset_to_region(&self, region: DataRegion) -> Result<u32, StorageError> {
@@ -269,4 +612,170 @@ pub enum StorageError {
     BufferTooSmall,
     Io,
     InvalidLayout,
+    /// The layout is intact, but these regions don't match the CRC32 in
+    /// their descriptor.
+    CorruptRegions(CorruptRegions),
+    /// The partition table has no data partition for the storage
+    PartitionNotFound,
+    /// The data partition can't hold the layout; carries its size
+    PartitionTooSmall(u32),
+    /// The data partition isn't sector aligned or runs past the flash
+    InvalidPartition,
+    /// A sector still holds data after being erased; carries its offset
+    NotErased(u32),
+    /// A bootstrap found a layout or a database it would have overwritten
+    NotBlank,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::NONCE_SIZE;
+    use crate::storage::boot;
+    use crate::storage::ram_flash::{PowerCutFlash, RamFlash};
+    use crate::storage::record_log::RecordKind;
+    use crate::storage::testing;
+
+    #[test]
+    fn bootstrap_passes_the_healthcheck() {
+        let mut flash = RamFlash::default();
+        assert_eq!(
+            StorageLayout::run_healthcheck(&mut flash),
+            Err(StorageError::BadMagic)
+        );
+
+        StorageLayout::bootstrap_storage_write(&mut flash).unwrap();
+        StorageLayout::run_healthcheck(&mut flash).unwrap();
+        let layout = StorageLayout::new(&mut flash).unwrap();
+        assert_eq!(layout.header.layout_version, STORAGE_LAYOUT_VERSION);
+        for region in [
+            DataRegion::ProjectConfig,
+            DataRegion::UserConfig,
+            DataRegion::KeePassDb,
+            DataRegion::Scratch,
+        ] {
+            assert_eq!(
+                layout.region_handle(region).unwrap(),
+                expected_region_handle(region)
+            );
+        }
+    }
+
+    #[test]
+    fn bootstrap_refuses_storage_in_use() {
+        let mut flash = testing::fresh_flash();
+        testing::unlock(&mut flash);
+        assert_eq!(
+            StorageLayout::bootstrap_storage_write(&mut flash),
+            Err(StorageError::NotBlank)
+        );
+
+        // A database behind damaged metadata is still refused
+        StorageLayout::wipe_layout(&mut flash).unwrap();
+        assert_eq!(
+            StorageLayout::bootstrap_storage_write(&mut flash),
+            Err(StorageError::NotBlank)
+        );
+        let keepass = expected_region_handle(DataRegion::KeePassDb);
+        assert_eq!(KeePassDb::check_if_exists(&mut flash, keepass), Ok(true));
+
+        // Only a reset erases it
+        StorageLayout::reset_storage(&mut flash).unwrap();
+        assert_eq!(KeePassDb::check_if_exists(&mut flash, keepass), Ok(false));
+        StorageLayout::run_healthcheck(&mut flash).unwrap();
+    }
+
+    #[test]
+    fn wipe_survives_a_power_cut() {
+        let mut base = testing::fresh_flash();
+        let (mut booted, _) = testing::unlock(&mut base);
+        booted
+            .kpdb
+            .create_entry(testing::entry(1, "mail"), &mut base)
+            .unwrap();
+        let fresh = boot::open(&mut testing::fresh_flash()).unwrap().kpdb.header;
+
+        for budget in 0.. {
+            let mut flash = base.clone();
+            let layout = StorageLayout::new(&mut flash).unwrap();
+            let mut cut_flash = PowerCutFlash::new(flash, budget);
+            let result = layout.wipe_secrets(&mut cut_flash);
+            let cut = cut_flash.is_cut();
+            let mut flash = cut_flash.into_inner();
+
+            // Once the marker is written, every cut boots to a fresh device
+            let booted = boot::open(&mut flash)
+                .unwrap_or_else(|err| panic!("cut after {budget} operations: {err:?}"));
+            if budget == 0 {
+                assert!(booted.kpdb.is_sealed());
+                assert_eq!(booted.kpdb.header.num_entries, 1);
+                continue;
+            }
+            assert!(!booted.kpdb.is_sealed(), "cut after {budget} operations");
+            assert!(!booted.user_config.has_verifier());
+            assert_eq!(booted.user_config.failed_attempts(), 0);
+            assert_eq!(booted.kpdb.header.num_groups, fresh.num_groups);
+            assert_eq!(booted.kpdb.header.num_entries, 0);
+            if !cut {
+                result.unwrap();
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn healthcheck_rejects_another_layout_version() {
+        let mut flash = testing::fresh_flash();
+        let version = get_user_storage_offset() as usize + 4;
+        flash.as_bytes_mut()[version..version + 2].copy_from_slice(&[0xFE, 0x00]);
+
+        assert_eq!(
+            StorageLayout::run_healthcheck(&mut flash),
+            Err(StorageError::UnsupportedLayout(0xFE))
+        );
+    }
+
+    #[test]
+    fn healthcheck_reports_corrupt_regions() {
+        let mut flash = testing::fresh_flash();
+        testing::unlock(&mut flash);
+        StorageLayout::run_healthcheck(&mut flash).unwrap();
+
+        // Flip a bit in the PIN verifier
+        let user_config = expected_region_handle(DataRegion::UserConfig);
+        flash.as_bytes_mut()[user_config.base as usize + 20] ^= 1;
+
+        let Err(StorageError::CorruptRegions(corrupt)) = StorageLayout::run_healthcheck(&mut flash)
+        else {
+            panic!("the healthcheck missed the corruption");
+        };
+        assert!(corrupt.contains(DataRegion::UserConfig));
+        assert!(!corrupt.contains(DataRegion::KeePassDb));
+    }
+
+    #[test]
+    fn healthcheck_reports_a_corrupt_log() {
+        let mut flash = testing::fresh_flash();
+        let (mut booted, _) = testing::unlock(&mut flash);
+        booted
+            .kpdb
+            .create_entry(testing::entry(1, "mail"), &mut flash)
+            .unwrap();
+        StorageLayout::run_healthcheck(&mut flash).unwrap();
+
+        // The log reports no used bytes, so only its committed header can
+        // notice the entry's ciphertext changing
+        let slot = booted.kpdb.log.index().entry_slots()[0];
+        booted
+            .kpdb
+            .log
+            .tamper(&mut flash, RecordKind::Entry, slot, NONCE_SIZE);
+
+        let Err(StorageError::CorruptRegions(corrupt)) = StorageLayout::run_healthcheck(&mut flash)
+        else {
+            panic!("the healthcheck missed the corruption");
+        };
+        assert!(corrupt.contains(DataRegion::KeePassDb));
+        assert!(!corrupt.contains(DataRegion::UserConfig));
+    }
 }
@@ -0,0 +1,386 @@
+//! In-place upgrades of the storage layout, keyed on
+//! [`LayoutHeader::layout_version`](crate::storage::header::LayoutHeader).
+//!
+//! Each step takes the layout one version forward and keeps the data inside
+//! the regions. A step finishes by rewriting the metadata sector with its
+//! target version, which is power-loss safe, so a step cut short simply runs
+//! again on the next boot. A layout without a path to the current version is
+//! left alone; bootstrapping over it is up to the user.
+//!
+//! The format of the records inside the KeePass region is versioned
+//! separately, in `KDBHeader::subversion`, and upgraded on unlock since that
+//! needs the key. For the same reason a migration never seals anything: the
+//! v1 database, which was kept in plaintext, is sealed by the first PIN
+//! entered after the upgrade.
+
+use defmt::info;
+use embedded_storage::nor_flash::NorFlash;
+use zeroize::Zeroize;
+
+use crate::keepass::{
+    Entry, HEADER_SIZE, KDBError, KDBHeader,
+    entry::{ENTRY_V1_SIZE, MAX_ENTRY_SIZE},
+    group::GROUP_SIZE,
+    header::{KDB_SIGNATURE1, KDB_SIGNATURE2},
+};
+use crate::storage::flash;
+use crate::storage::header::{
+    LAYOUT_HEADER_SIZE, LayoutHeader, STORAGE_LAYOUT_VERSION, STORAGE_MAGIC,
+    get_user_storage_offset,
+};
+use crate::storage::keepass::{
+    MAX_ENTRIES, MAX_GROUPS, change_size, entry_record_size, fresh_header, unsealed_header,
+};
+use crate::storage::layout::{
+    REGION_CAPACITIES, REGION_COUNT, StorageError, check_capacity, expected_region_handle,
+    keepass_error, write_metadata,
+};
+use crate::storage::record_log::{RecordKind, RecordLog};
+use crate::storage::region::{DataRegion, RegionHandle};
+use crate::storage::user_config::UserConfig;
+
+/// v1 kept the database as one plaintext image at the start of the KeePass
+/// region: both signatures, the header, then every group and entry slot.
+const V1_KEEPASS_CAPACITY: u32 = 64 * 1024;
+const V1_HEADER_OFFSET: u32 = 8;
+const V1_GROUPS_OFFSET: u32 = V1_HEADER_OFFSET + HEADER_SIZE as u32;
+const V1_ENTRIES_OFFSET: u32 = V1_GROUPS_OFFSET + MAX_GROUPS * GROUP_SIZE as u32;
+
+/// Oldest layout version that can still be upgraded in place.
+pub const OLDEST_MIGRATABLE_LAYOUT: u16 = 1;
+
+/// Whether [`migrate_layout`] can bring `version` up to date.
+pub fn can_migrate(version: u16) -> bool {
+    (OLDEST_MIGRATABLE_LAYOUT..STORAGE_LAYOUT_VERSION).contains(&version)
+}
+
+/// Upgrades the stored layout to [`STORAGE_LAYOUT_VERSION`], one version at a
+/// time. Returns the version it started from.
+pub fn migrate_layout<S: NorFlash>(storage: &mut S) -> Result<u16, StorageError> {
+    let header = stored_header(storage)?;
+    if header.magic != STORAGE_MAGIC {
+        return Err(StorageError::BadMagic);
+    }
+
+    let from = header.layout_version;
+    if from != STORAGE_LAYOUT_VERSION && !can_migrate(from) {
+        return Err(StorageError::UnsupportedLayout(from));
+    }
+
+    let mut version = from;
+    while version < STORAGE_LAYOUT_VERSION {
+        match version {
+            1 => v1_to_v2(storage)?,
+            _ => return Err(StorageError::UnsupportedLayout(version)),
+        }
+        version += 1;
+        info!("Storage layout migrated to v{}", version);
+    }
+    Ok(from)
+}
+
+/// Erases the v1 image a migration cut short left in front of the log, once
+/// the layout is current. Returns `true` if there was one.
+pub fn finish_pending_migration<S: NorFlash>(storage: &mut S) -> Result<bool, StorageError> {
+    check_capacity(storage)?;
+    let header = stored_header(storage)?;
+    if header.magic != STORAGE_MAGIC
+        || header.layout_version != STORAGE_LAYOUT_VERSION
+        || !holds_legacy_database(storage)?
+    {
+        return Ok(false);
+    }
+
+    let keepass = expected_region_handle(DataRegion::KeePassDb);
+    NorFlash::erase(storage, keepass.base, keepass.base + V1_KEEPASS_CAPACITY)
+        .map_err(|_| StorageError::Io)?;
+    Ok(true)
+}
+
+/// Whether a v1 database image sits at the start of the KeePass region. The
+/// region starts at the same place in every layout, and the log never
+/// starts a sector with the signatures.
+pub fn holds_legacy_database<S: NorFlash>(storage: &mut S) -> Result<bool, StorageError> {
+    let keepass = expected_region_handle(DataRegion::KeePassDb);
+    let mut signatures = [0u8; 8];
+    flash::read(storage, keepass.base, &mut signatures).map_err(|_| StorageError::Io)?;
+    Ok(signatures[..4] == KDB_SIGNATURE1.to_le_bytes()
+        && signatures[4..] == KDB_SIGNATURE2.to_le_bytes())
+}
+
+fn stored_header<S: NorFlash>(storage: &mut S) -> Result<LayoutHeader, StorageError> {
+    let mut header_buffer = [0u8; LAYOUT_HEADER_SIZE];
+    flash::read(storage, get_user_storage_offset(), &mut header_buffer)
+        .map_err(|_| StorageError::Io)?;
+    Ok(LayoutHeader::new_from_bytes(&header_buffer))
+}
+
+/// Header of the v1 image, checked against the slots it has room for, or
+/// `None` if there is no image.
+fn legacy_header<S: NorFlash>(storage: &mut S) -> Result<Option<KDBHeader>, StorageError> {
+    if !holds_legacy_database(storage)? {
+        return Ok(None);
+    }
+    let keepass = expected_region_handle(DataRegion::KeePassDb);
+    let mut header_buffer = [0u8; HEADER_SIZE];
+    flash::read(storage, keepass.base + V1_HEADER_OFFSET, &mut header_buffer)
+        .map_err(|_| StorageError::Io)?;
+    let header = KDBHeader::new_from_bytes(&header_buffer).map_err(keepass_error)?;
+    if header.num_groups > MAX_GROUPS || header.num_entries > MAX_ENTRIES {
+        return Err(keepass_error(KDBError::DatabaseIntegrityError));
+    }
+    Ok(Some(header))
+}
+
+/// Entry `index` of the v1 image.
+fn legacy_entry<S: NorFlash>(storage: &mut S, index: u32) -> Result<Entry, StorageError> {
+    let keepass = expected_region_handle(DataRegion::KeePassDb);
+    let offset = keepass.base + V1_ENTRIES_OFFSET + index * ENTRY_V1_SIZE as u32;
+    let mut entry_buffer = [0u8; ENTRY_V1_SIZE];
+    flash::read(storage, offset, &mut entry_buffer).map_err(|_| StorageError::Io)?;
+    let entry = Entry::new_from_v1_bytes(&entry_buffer);
+    entry_buffer.zeroize();
+    Ok(entry)
+}
+
+/// v2 turned the KeePass region into a [record log](crate::storage::record_log)
+/// of sealed records, three times the size, and grew `Scratch` to three
+/// sectors: a rekey's commit record, then the staging sectors for the
+/// metadata and for `UserConfig`. Descriptors started carrying each region's
+/// `used_len` and `crc32`.
+///
+/// Sealing needs the key, so the v1 records go into the log as plaintext, in
+/// the current record format, each group and entry keeping its index as its
+/// slot. They are committed with an unsealed header, and
+/// [`KeePassDb::unlock`](crate::keepass::KeePassDb::unlock) seals them with
+/// the first PIN entered and erases the plaintext.
+fn v1_to_v2<S: NorFlash>(storage: &mut S) -> Result<(), StorageError> {
+    check_capacity(storage)?;
+    let keepass = expected_region_handle(DataRegion::KeePassDb);
+    let scratch = expected_region_handle(DataRegion::Scratch);
+    // Everything past the v1 image, up to the end of the v2 region
+    let tail = RegionHandle {
+        base: keepass.base + V1_KEEPASS_CAPACITY,
+        capacity: keepass.capacity - V1_KEEPASS_CAPACITY,
+    };
+
+    // 1. The log is built behind the v1 image, over what was `Scratch` and
+    // what lay past the v1 window. That takes in the new `Scratch` too, so
+    // nothing there is taken for a staged change.
+    NorFlash::erase(storage, tail.base, scratch.base + scratch.capacity)
+        .map_err(|_| StorageError::Io)?;
+
+    // 2. Copy the records over and commit them with a fresh, unsealed header
+    if let Some(legacy) = legacy_header(storage)? {
+        let mut entry_bytes = 0;
+        for i in 0..legacy.num_entries {
+            entry_bytes += entry_record_size(&legacy_entry(storage, i)?);
+        }
+        let mut log = RecordLog::mount(storage, tail).map_err(keepass_error)?;
+        let mut staged = log
+            .begin(
+                storage,
+                change_size(legacy.num_groups as usize, entry_bytes),
+            )
+            .map_err(keepass_error)?;
+
+        let mut group_buffer = [0u8; GROUP_SIZE];
+        for i in 0..legacy.num_groups {
+            let offset = keepass.base + V1_GROUPS_OFFSET + i * GROUP_SIZE as u32;
+            flash::read(storage, offset, &mut group_buffer).map_err(|_| StorageError::Io)?;
+            staged.push_group(i as u8).map_err(keepass_error)?;
+            log.append(
+                storage,
+                &mut staged,
+                RecordKind::Group,
+                i as u8,
+                &group_buffer,
+            )
+            .map_err(keepass_error)?;
+        }
+        let mut entry_buffer = [0u8; MAX_ENTRY_SIZE];
+        for i in 0..legacy.num_entries {
+            let len = legacy_entry(storage, i)?.to_bytes(&mut entry_buffer);
+            staged.push_entry(i as u8).map_err(keepass_error)?;
+            let appended = log.append(
+                storage,
+                &mut staged,
+                RecordKind::Entry,
+                i as u8,
+                &entry_buffer[..len],
+            );
+            entry_buffer.zeroize();
+            appended.map_err(keepass_error)?;
+        }
+
+        let mut header = fresh_header();
+        header.num_groups = legacy.num_groups;
+        header.num_entries = legacy.num_entries;
+        header.contents_hash = log.contents_hash(storage, &staged).map_err(keepass_error)?;
+        log.commit(storage, staged, &unsealed_header(&header))
+            .map_err(keepass_error)?;
+    }
+
+    // 3. Stamp the new version. The log checks its own records, so the
+    // KeePass region reports no contents.
+    let mut used_lens = [0u32; REGION_COUNT];
+    used_lens[DataRegion::UserConfig.index()] =
+        UserConfig::stored_len(storage, expected_region_handle(DataRegion::UserConfig))?;
+    write_metadata(storage, 2, REGION_CAPACITIES, used_lens)?;
+
+    // 4. Only now drop the v1 image; the log mounts its sectors as
+    // unformatted. Cut short, this is finished by
+    // [`finish_pending_migration`].
+    NorFlash::erase(storage, keepass.base, tail.base).map_err(|_| StorageError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::KEY_SIZE;
+    use crate::keepass::{Group, KeePassDb};
+    use crate::secret::SecretBytes;
+    use crate::storage::boot;
+    use crate::storage::flash::SECTOR_SIZE;
+    use crate::storage::layout::StorageLayout;
+    use crate::storage::ram_flash::{PowerCutFlash, RamFlash};
+    use crate::storage::testing::{self, SW_KEY, holds, titles};
+
+    /// v1 region capacities, in descriptor order.
+    const V1_CAPACITIES: [u32; REGION_COUNT] =
+        [SECTOR_SIZE, SECTOR_SIZE, V1_KEEPASS_CAPACITY, SECTOR_SIZE];
+    const TITLES: [&str; 3] = ["mail", "bank", "forum"];
+
+    fn v1_entry(title: &str) -> [u8; ENTRY_V1_SIZE] {
+        let mut bytes = [0u8; ENTRY_V1_SIZE];
+        bytes[16..20].copy_from_slice(&1u32.to_le_bytes());
+        bytes[20..20 + title.len()].copy_from_slice(title.as_bytes());
+        bytes[84..89].copy_from_slice(b"alice");
+        let password = password(title);
+        bytes[148..148 + password.len()].copy_from_slice(&password);
+        bytes[232] = 1;
+        bytes
+    }
+
+    fn password(title: &str) -> Vec<u8> {
+        [b"secret-", title.as_bytes()].concat()
+    }
+
+    /// Flash as v1 left it: its layout and a plaintext database with one
+    /// group and an entry for each of [`TITLES`].
+    fn v1_flash() -> RamFlash {
+        let mut flash = RamFlash::default();
+        write_metadata(&mut flash, 1, V1_CAPACITIES, [0; REGION_COUNT]).unwrap();
+
+        let base = expected_region_handle(DataRegion::KeePassDb).base as usize;
+        let mut header = KDBHeader::empty();
+        header.num_groups = 1;
+        header.num_entries = TITLES.len() as u32;
+        let bytes = flash.as_bytes_mut();
+        bytes[base..base + 4].copy_from_slice(&KDB_SIGNATURE1.to_le_bytes());
+        bytes[base + 4..base + 8].copy_from_slice(&KDB_SIGNATURE2.to_le_bytes());
+        let at = base + V1_HEADER_OFFSET as usize;
+        bytes[at..at + HEADER_SIZE].copy_from_slice(&header.to_bytes());
+        let at = base + V1_GROUPS_OFFSET as usize;
+        bytes[at..at + GROUP_SIZE].copy_from_slice(&Group::random().to_bytes());
+        for (i, title) in TITLES.iter().enumerate() {
+            let at = base + V1_ENTRIES_OFFSET as usize + i * ENTRY_V1_SIZE;
+            bytes[at..at + ENTRY_V1_SIZE].copy_from_slice(&v1_entry(title));
+        }
+        flash
+    }
+
+    fn check_unlocked(db: &KeePassDb) {
+        let expected: Vec<_> = TITLES
+            .iter()
+            .map(|title| title.as_bytes().to_vec())
+            .collect();
+        assert_eq!(titles(db), expected);
+        for (entry, title) in db.entries.iter().flatten().zip(TITLES) {
+            assert_eq!(entry.username(), b"alice");
+            assert_eq!(entry.password().expose(), password(title));
+        }
+        assert_eq!(db.groups.iter().flatten().count(), 1);
+    }
+
+    #[test]
+    fn a_v1_database_is_sealed_by_the_first_pin() {
+        let mut flash = v1_flash();
+        let booted = boot::open(&mut flash).unwrap();
+        assert_eq!(booted.layout.header.layout_version, STORAGE_LAYOUT_VERSION);
+        assert!(!booted.kpdb.is_sealed());
+        assert_eq!(booted.kpdb.header.num_entries, TITLES.len() as u32);
+        assert!(!holds_legacy_database(&mut flash).unwrap());
+        StorageLayout::run_healthcheck(&mut flash).unwrap();
+
+        let (booted, _) = testing::unlock(&mut flash);
+        assert!(booted.kpdb.is_sealed());
+        check_unlocked(&booted.kpdb);
+        for title in TITLES {
+            assert!(
+                !holds(&flash, &password(title)),
+                "{title} left in plaintext"
+            );
+        }
+
+        // Sealed for good: only the key opens it now
+        let mut booted = boot::open(&mut flash).unwrap();
+        let wrong = booted.kpdb.derive_key(&SecretBytes::new([8; KEY_SIZE]));
+        assert_eq!(
+            booted.kpdb.unlock(wrong, &mut flash),
+            Err(KDBError::InvalidKey)
+        );
+        let (booted, _) = testing::unlock(&mut flash);
+        check_unlocked(&booted.kpdb);
+    }
+
+    #[test]
+    fn migration_survives_a_power_cut() {
+        let base = v1_flash();
+        for budget in 0.. {
+            let mut cut_flash = PowerCutFlash::new(base.clone(), budget);
+            let result = boot::open(&mut cut_flash);
+            let cut = cut_flash.is_cut();
+            let mut flash = cut_flash.into_inner();
+            if !cut {
+                result.unwrap();
+                return;
+            }
+
+            let (booted, _) = testing::unlock(&mut flash);
+            check_unlocked(&booted.kpdb);
+            assert!(
+                !holds_legacy_database(&mut flash).unwrap(),
+                "power cut after {budget} operations left the v1 image"
+            );
+        }
+    }
+
+    #[test]
+    fn sealing_survives_a_power_cut() {
+        let mut base = v1_flash();
+        boot::open(&mut base).unwrap();
+        for budget in 0.. {
+            let mut flash = base.clone();
+            let mut booted = boot::open(&mut flash).unwrap();
+            let key = booted.kpdb.derive_key(&SecretBytes::new(SW_KEY));
+            let mut cut_flash = PowerCutFlash::new(flash, budget);
+            let result = booted.kpdb.unlock(key, &mut cut_flash);
+            let cut = cut_flash.is_cut();
+            let mut flash = cut_flash.into_inner();
+            if !cut {
+                result.unwrap();
+                return;
+            }
+
+            let (booted, _) = testing::unlock(&mut flash);
+            check_unlocked(&booted.kpdb);
+            for title in TITLES {
+                assert!(
+                    !holds(&flash, &password(title)),
+                    "{title} left in plaintext"
+                );
+            }
+        }
+    }
+}
@@ -1,4 +1,19 @@
+pub mod boot;
+pub mod diagnostics;
+pub mod flash;
 pub mod header;
+pub mod journal;
 pub mod keepass;
 pub mod layout;
+pub mod migrate;
+pub mod partition;
+pub mod ram_flash;
+pub mod record_log;
 pub mod region;
+pub mod rekey;
+pub mod settings;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod transfer;
+pub mod user_config;
+pub mod write_protect;
@@ -0,0 +1,371 @@
+//! Finding the data partition in the ESP-IDF partition table and confining
+//! the storage to it.
+//!
+//! The table is the one `esp-bootloader-esp-idf` and `espflash` use: up to
+//! 95 entries of 32 bytes at [`PARTITION_TABLE_OFFSET`], each one
+//!
+//! | bytes  | field                     |
+//! |--------|---------------------------|
+//! | 0..2   | magic `AA 50`             |
+//! | 2      | type (`0x01` = data)      |
+//! | 3      | subtype                   |
+//! | 4..8   | offset, little endian     |
+//! | 8..12  | size, little endian       |
+//! | 12..28 | label, NUL padded         |
+//! | 28..32 | flags                     |
+//!
+//! followed by an MD5 entry (magic `EB EB`) and erased bytes. The storage
+//! lives in the data partition labelled [`DATA_PARTITION_LABEL`], see
+//! `partitions.csv`; a table without that label may instead give it the
+//! [`DATA_PARTITION_SUBTYPE`] subtype.
+
+use defmt::Format;
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+use crate::storage::flash::{self, SECTOR_SIZE};
+use crate::storage::header::storage_magic_offset;
+use crate::storage::layout::{STORAGE_TOTAL_BYTES, StorageError};
+
+/// Where the bootloader expects the partition table.
+pub const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+/// The table may take up to this many bytes, MD5 entry included.
+const PARTITION_TABLE_SIZE: u32 = 0xC00;
+const PARTITION_ENTRY_SIZE: usize = 32;
+const PARTITION_LABEL_SIZE: usize = 16;
+
+const ENTRY_MAGIC: [u8; 2] = [0xAA, 0x50];
+const MD5_MAGIC: [u8; 2] = [0xEB, 0xEB];
+const PARTITION_TYPE_DATA: u8 = 0x01;
+
+/// Label of the data partition holding the storage.
+pub const DATA_PARTITION_LABEL: &str = "passbuddy";
+/// Subtype of the data partition holding the storage when none carries
+/// [`DATA_PARTITION_LABEL`]: ESP-IDF's `undefined`.
+pub const DATA_PARTITION_SUBTYPE: u8 = 0x06;
+
+/// One row of the partition table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct PartitionEntry {
+    pub kind: u8,
+    pub subtype: u8,
+    pub offset: u32,
+    pub size: u32,
+    pub label: [u8; PARTITION_LABEL_SIZE],
+}
+
+impl PartitionEntry {
+    fn new_from_bytes(bytes: &[u8; PARTITION_ENTRY_SIZE]) -> Self {
+        PartitionEntry {
+            kind: bytes[2],
+            subtype: bytes[3],
+            offset: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            size: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            label: bytes[12..28].try_into().unwrap(),
+        }
+    }
+
+    /// The label up to its first NUL.
+    pub fn label(&self) -> &[u8] {
+        let len = self
+            .label
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(PARTITION_LABEL_SIZE);
+        &self.label[..len]
+    }
+}
+
+/// Looks up the data partition labelled [`DATA_PARTITION_LABEL`] in the
+/// partition table, or failing that the first one with the
+/// [`DATA_PARTITION_SUBTYPE`] subtype, and checks that the storage layout
+/// fits in it.
+pub fn find_data_partition<S: ReadNorFlash>(
+    storage: &mut S,
+) -> Result<PartitionEntry, StorageError> {
+    let mut bytes = [0u8; PARTITION_ENTRY_SIZE];
+    let mut offset = PARTITION_TABLE_OFFSET;
+    let mut by_subtype = None;
+    let mut by_label = None;
+    while offset + PARTITION_ENTRY_SIZE as u32 <= PARTITION_TABLE_OFFSET + PARTITION_TABLE_SIZE {
+        flash::read(storage, offset, &mut bytes).map_err(|_| StorageError::Io)?;
+        offset += PARTITION_ENTRY_SIZE as u32;
+
+        if bytes[..2] == MD5_MAGIC {
+            continue;
+        }
+        if bytes[..2] != ENTRY_MAGIC {
+            // Erased bytes end the table, anything else means it is damaged
+            if bytes.iter().all(|&byte| byte == 0xFF) {
+                break;
+            }
+            return Err(StorageError::InvalidPartition);
+        }
+
+        let entry = PartitionEntry::new_from_bytes(&bytes);
+        if entry.kind != PARTITION_TYPE_DATA {
+            continue;
+        }
+        if entry.label() == DATA_PARTITION_LABEL.as_bytes() {
+            by_label = Some(entry);
+            break;
+        }
+        if entry.subtype == DATA_PARTITION_SUBTYPE && by_subtype.is_none() {
+            by_subtype = Some(entry);
+        }
+    }
+    let entry = by_label
+        .or(by_subtype)
+        .ok_or(StorageError::PartitionNotFound)?;
+
+    let fits = entry.offset.is_multiple_of(SECTOR_SIZE)
+        && entry
+            .offset
+            .checked_add(entry.size)
+            .is_some_and(|end| end as usize <= storage.capacity());
+    if !fits {
+        return Err(StorageError::InvalidPartition);
+    }
+    if entry.size < storage_magic_offset() + STORAGE_TOTAL_BYTES {
+        return Err(StorageError::PartitionTooSmall(entry.size));
+    }
+    Ok(entry)
+}
+
+/// Flash confined to one partition: offsets start at the partition and
+/// nothing past its end can be read or written.
+pub struct Partition<S> {
+    inner: S,
+    offset: u32,
+    size: u32,
+}
+
+impl<S> Partition<S> {
+    /// `entry` should come from [`find_data_partition`], which checks it
+    /// lies within the flash.
+    pub fn new(inner: S, entry: &PartitionEntry) -> Self {
+        Self {
+            inner,
+            offset: entry.offset,
+            size: entry.size,
+        }
+    }
+
+    fn translate(&self, offset: u32, len: usize) -> Result<u32, NorFlashErrorKind> {
+        let end = offset
+            .checked_add(len as u32)
+            .ok_or(NorFlashErrorKind::OutOfBounds)?;
+        if end > self.size {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        Ok(self.offset + offset)
+    }
+}
+
+impl<S: NorFlash> ErrorType for Partition<S> {
+    type Error = NorFlashErrorKind;
+}
+
+impl<S: NorFlash> ReadNorFlash for Partition<S> {
+    const READ_SIZE: usize = S::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = self.translate(offset, bytes.len())?;
+        self.inner.read(offset, bytes).map_err(|err| err.kind())
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl<S: NorFlash> NorFlash for Partition<S> {
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+    const ERASE_SIZE: usize = S::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if to < from {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        let len = to - from;
+        let from = self.translate(from, len as usize)?;
+        self.inner.erase(from, from + len).map_err(|err| err.kind())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = self.translate(offset, bytes.len())?;
+        self.inner.write(offset, bytes).map_err(|err| err.kind())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::ram_flash::RamFlash;
+
+    const DATA_OFFSET: u32 = 0x200000;
+    const DATA_SIZE: u32 = 0x40000;
+
+    /// One row of the table, the way `espflash` writes it.
+    fn row(kind: u8, subtype: u8, offset: u32, size: u32, label: &str) -> [u8; 32] {
+        let mut row = [0u8; PARTITION_ENTRY_SIZE];
+        row[..2].copy_from_slice(&ENTRY_MAGIC);
+        row[2] = kind;
+        row[3] = subtype;
+        row[4..8].copy_from_slice(&offset.to_le_bytes());
+        row[8..12].copy_from_slice(&size.to_le_bytes());
+        row[12..12 + label.len()].copy_from_slice(label.as_bytes());
+        row
+    }
+
+    /// Flash holding `rows` as its partition table, followed by the MD5
+    /// entry.
+    fn flash_with(rows: &[[u8; 32]]) -> RamFlash {
+        let mut flash = RamFlash::default();
+        let table = &mut flash.as_bytes_mut()[PARTITION_TABLE_OFFSET as usize..];
+        for (i, row) in rows.iter().enumerate() {
+            table[i * PARTITION_ENTRY_SIZE..][..PARTITION_ENTRY_SIZE].copy_from_slice(row);
+        }
+        let md5 = &mut table[rows.len() * PARTITION_ENTRY_SIZE..][..PARTITION_ENTRY_SIZE];
+        md5[..2].copy_from_slice(&MD5_MAGIC);
+        md5[16..].fill(0x5A);
+        flash
+    }
+
+    /// The rows of `partitions.csv`, with the storage's row given.
+    fn table(storage: [u8; 32]) -> [[u8; 32]; 4] {
+        [
+            row(PARTITION_TYPE_DATA, 0x02, 0x9000, 0x6000, "nvs"),
+            row(PARTITION_TYPE_DATA, 0x01, 0xF000, 0x1000, "phy_init"),
+            row(0x00, 0x00, 0x10000, 0x1F0000, "factory"),
+            storage,
+        ]
+    }
+
+    #[test]
+    fn finds_the_partition_by_label() {
+        let mut flash = flash_with(&table(row(
+            PARTITION_TYPE_DATA,
+            0x81,
+            DATA_OFFSET,
+            DATA_SIZE,
+            DATA_PARTITION_LABEL,
+        )));
+        let entry = find_data_partition(&mut flash).unwrap();
+        assert_eq!(entry.offset, DATA_OFFSET);
+        assert_eq!(entry.size, DATA_SIZE);
+        assert_eq!(entry.label(), DATA_PARTITION_LABEL.as_bytes());
+    }
+
+    #[test]
+    fn finds_the_partition_by_subtype() {
+        let mut flash = flash_with(&table(row(
+            PARTITION_TYPE_DATA,
+            DATA_PARTITION_SUBTYPE,
+            DATA_OFFSET,
+            DATA_SIZE,
+            "storage",
+        )));
+        let entry = find_data_partition(&mut flash).unwrap();
+        assert_eq!(entry.offset, DATA_OFFSET);
+        assert_eq!(entry.subtype, DATA_PARTITION_SUBTYPE);
+    }
+
+    #[test]
+    fn prefers_the_label_to_the_subtype() {
+        let mut rows = table(row(
+            PARTITION_TYPE_DATA,
+            0x81,
+            DATA_OFFSET,
+            DATA_SIZE,
+            DATA_PARTITION_LABEL,
+        ));
+        rows[1] = row(
+            PARTITION_TYPE_DATA,
+            DATA_PARTITION_SUBTYPE,
+            0xF000,
+            0x1000,
+            "other",
+        );
+        let mut flash = flash_with(&rows);
+        assert_eq!(find_data_partition(&mut flash).unwrap().offset, DATA_OFFSET);
+    }
+
+    #[test]
+    fn reports_a_missing_partition() {
+        let mut flash = flash_with(&table(row(0x00, 0x10, DATA_OFFSET, DATA_SIZE, "ota_0")));
+        assert_eq!(
+            find_data_partition(&mut flash),
+            Err(StorageError::PartitionNotFound)
+        );
+
+        let mut flash = RamFlash::default();
+        assert_eq!(
+            find_data_partition(&mut flash),
+            Err(StorageError::PartitionNotFound)
+        );
+    }
+
+    #[test]
+    fn reports_a_partition_too_small() {
+        let size = STORAGE_TOTAL_BYTES - SECTOR_SIZE;
+        let mut flash = flash_with(&table(row(
+            PARTITION_TYPE_DATA,
+            DATA_PARTITION_SUBTYPE,
+            DATA_OFFSET,
+            size,
+            DATA_PARTITION_LABEL,
+        )));
+        assert_eq!(
+            find_data_partition(&mut flash),
+            Err(StorageError::PartitionTooSmall(size))
+        );
+    }
+
+    #[test]
+    fn reports_a_misaligned_partition() {
+        let mut flash = flash_with(&table(row(
+            PARTITION_TYPE_DATA,
+            DATA_PARTITION_SUBTYPE,
+            DATA_OFFSET + 0x100,
+            DATA_SIZE,
+            DATA_PARTITION_LABEL,
+        )));
+        assert_eq!(
+            find_data_partition(&mut flash),
+            Err(StorageError::InvalidPartition)
+        );
+
+        // Running past the end of the flash is no better
+        let mut flash = flash_with(&table(row(
+            PARTITION_TYPE_DATA,
+            DATA_PARTITION_SUBTYPE,
+            DATA_OFFSET,
+            u32::MAX - DATA_OFFSET,
+            DATA_PARTITION_LABEL,
+        )));
+        assert_eq!(
+            find_data_partition(&mut flash),
+            Err(StorageError::InvalidPartition)
+        );
+    }
+
+    #[test]
+    fn reports_a_corrupt_entry() {
+        // A bit flip in the magic of the row before the storage's
+        let mut rows = table(row(
+            PARTITION_TYPE_DATA,
+            DATA_PARTITION_SUBTYPE,
+            DATA_OFFSET,
+            DATA_SIZE,
+            DATA_PARTITION_LABEL,
+        ));
+        rows[2][1] ^= 1;
+        let mut flash = flash_with(&rows);
+        assert_eq!(
+            find_data_partition(&mut flash),
+            Err(StorageError::InvalidPartition)
+        );
+    }
+}
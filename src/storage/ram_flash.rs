@@ -0,0 +1,210 @@
+//! NOR flash emulated in RAM, for running the storage and database code on a
+//! host.
+//!
+//! It behaves like the ESP32-S3 flash as far as the storage code can tell:
+//! offsets and lengths must be aligned, erasing sets whole sectors to `0xFF`
+//! and programming can only clear bits, so a missing erase shows up as
+//! corrupted data just like on the device. [`PowerCutFlash`] adds power
+//! losses on top.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use embedded_storage::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+    check_erase, check_read, check_write,
+};
+
+use crate::storage::flash::SECTOR_SIZE;
+
+/// Capacity of the ESP32-S3 module the firmware targets.
+pub const DEFAULT_CAPACITY: usize = 4 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct RamFlash {
+    bytes: Vec<u8>,
+}
+
+impl RamFlash {
+    /// Fully erased flash of `capacity` bytes, rounded up to whole sectors.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            bytes: vec![0xFF; capacity.next_multiple_of(SECTOR_SIZE as usize)],
+        }
+    }
+
+    /// Raw contents, for inspecting what the storage code left behind.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Raw contents, for corrupting flash behind the storage code's back.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+impl Default for RamFlash {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl ErrorType for RamFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 4;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let start = offset as usize;
+        bytes.copy_from_slice(&self.bytes[start..start + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl NorFlash for RamFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.bytes[from as usize..to as usize].fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        let start = offset as usize;
+        for (cell, &byte) in self.bytes[start..start + bytes.len()].iter_mut().zip(bytes) {
+            *cell &= byte;
+        }
+        Ok(())
+    }
+}
+
+// Like the real flash, a word can be programmed again as long as bits only
+// go from 1 to 0.
+impl MultiwriteNorFlash for RamFlash {}
+
+/// Wraps a flash and cuts the power after a set number of program and erase
+/// operations: that operation and every one after it fail without touching
+/// the flash. Reads keep working, so the state left behind can be inspected.
+///
+/// Used to check that storage changes survive a power loss at any point, by
+/// running the same change with every budget from zero upwards and booting
+/// the resulting flash again.
+pub struct PowerCutFlash<F> {
+    inner: F,
+    budget: usize,
+    ops: usize,
+}
+
+impl<F> PowerCutFlash<F> {
+    /// Lets `budget` program or erase operations through before cutting the power.
+    pub fn new(inner: F, budget: usize) -> Self {
+        Self {
+            inner,
+            budget,
+            ops: 0,
+        }
+    }
+
+    /// Program and erase operations attempted so far, including refused ones.
+    pub fn ops(&self) -> usize {
+        self.ops
+    }
+
+    /// Whether the power has been cut.
+    pub fn is_cut(&self) -> bool {
+        self.ops > self.budget
+    }
+
+    /// Restores power, handing back the flash as the cut left it.
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    fn spend(&mut self) -> Result<(), NorFlashErrorKind> {
+        self.ops += 1;
+        if self.is_cut() {
+            return Err(NorFlashErrorKind::Other);
+        }
+        Ok(())
+    }
+}
+
+impl<F: NorFlash> ErrorType for PowerCutFlash<F> {
+    type Error = NorFlashErrorKind;
+}
+
+impl<F: NorFlash> ReadNorFlash for PowerCutFlash<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.inner.read(offset, bytes).map_err(|err| err.kind())
+    }
+
+    fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+}
+
+impl<F: NorFlash> NorFlash for PowerCutFlash<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.spend()?;
+        self.inner.erase(from, to).map_err(|err| err.kind())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.spend()?;
+        self.inner.write(offset, bytes).map_err(|err| err.kind())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_only_clear_bits() {
+        let mut flash = RamFlash::new(2 * SECTOR_SIZE as usize);
+        flash.write(0, &[0b1010_1010; 4]).unwrap();
+        flash.write(0, &[0b0110_0110; 4]).unwrap();
+        assert_eq!(flash.as_bytes()[..4], [0b0010_0010; 4]);
+
+        flash.erase(0, SECTOR_SIZE).unwrap();
+        assert!(flash.as_bytes().iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn refuses_misaligned_access() {
+        let mut flash = RamFlash::new(2 * SECTOR_SIZE as usize);
+        assert!(flash.write(2, &[0; 4]).is_err());
+        assert!(flash.write(0, &[0; 3]).is_err());
+        assert!(flash.erase(4, SECTOR_SIZE).is_err());
+        assert!(flash.erase(0, 3 * SECTOR_SIZE).is_err());
+    }
+
+    #[test]
+    fn power_cut_refuses_every_later_operation() {
+        let mut flash = PowerCutFlash::new(RamFlash::new(SECTOR_SIZE as usize), 1);
+        flash.write(0, &[0; 4]).unwrap();
+        assert!(flash.write(4, &[0; 4]).is_err());
+        assert!(flash.erase(0, SECTOR_SIZE).is_err());
+        assert!(flash.is_cut());
+
+        let mut bytes = [0xAA; 8];
+        flash.read(0, &mut bytes).unwrap();
+        assert_eq!(bytes, [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+}
@@ -0,0 +1,1069 @@
+//! Append-only, wear-levelled record log behind the KeePass region.
+//!
+//! The region is split into sectors, each starting with a header that
+//! carries its erase count. Records are only ever appended: a change writes
+//! the new version of every record it touches, then a header record listing
+//! the groups and entries in use, in order. The header record goes last and
+//! commits the change. On boot the latest header record wins and anything
+//! written after it is discarded, so a power cut leaves either the old or the
+//! new database.
+//!
+//! A record replaced by a newer version, or left out of the header record, is
+//! garbage. It is collected when space runs low, by copying the live records
+//! of the sector with the most garbage forward and erasing it.
+//!
+//! Wear is spread by always opening the least erased free sector, and by
+//! collecting a sector whose erase count falls too far behind the others
+//! even when it holds no garbage: it keeps records that never change, and
+//! would otherwise never be erased again.
+//!
+//! Sector header (16 bytes):
+//!
+//! | bytes  | field                      |
+//! |--------|----------------------------|
+//! | 0..4   | magic `PBLG`               |
+//! | 4..8   | erase count                |
+//! | 8..12  | reserved, `0xFF`           |
+//! | 12..16 | CRC32 of bytes 0..12       |
+//!
+//! Record (16-byte header, then the payload padded to 4 bytes with `0xFF`):
+//!
+//! | bytes  | field                                      |
+//! |--------|--------------------------------------------|
+//! | 0      | `0xFF`, or `0x00` once discarded           |
+//! | 1      | kind, see [`RecordKind`]                   |
+//! | 2      | slot                                       |
+//! | 3      | reserved, `0xFF`                           |
+//! | 4..6   | payload length                             |
+//! | 6..8   | reserved, `0xFF`                           |
+//! | 8..12  | sequence number                            |
+//! | 12..16 | CRC32 of bytes 1..12 and the payload       |
+//!
+//! The header record's payload is the group count, a reserved byte, the
+//! entry count (2 bytes), the group slots, the entry slots, and then the
+//! sealed database header, which the log doesn't look into.
+
+use defmt::{Format, info, warn};
+use embedded_storage::nor_flash::NorFlash;
+use heapless::Vec;
+use sha2::{Digest, Sha256};
+
+use crate::keepass::KDBError;
+use crate::storage::flash::{self, SECTOR_SIZE};
+use crate::storage::keepass::{MAX_ENTRIES, MAX_GROUPS};
+use crate::storage::region::RegionHandle;
+
+const SECTOR_MAGIC: [u8; 4] = *b"PBLG";
+const SECTOR_HEADER_SIZE: u32 = 16;
+/// Bytes of a sector available to records.
+const SECTOR_PAYLOAD: u32 = SECTOR_SIZE - SECTOR_HEADER_SIZE;
+const RECORD_HEADER_SIZE: usize = 16;
+const RECORD_LIVE: u8 = 0xFF;
+const RECORD_DISCARDED: u8 = 0x00;
+/// Counts ahead of the slot lists in the header record's payload.
+const HEADER_LISTS_PREFIX: usize = 4;
+
+/// Largest payload a record can carry.
+pub(crate) const MAX_RECORD_PAYLOAD: usize = 1024;
+/// Most sectors a log can span.
+pub const MAX_LOG_SECTORS: usize = 48;
+/// How far a sector's erase count may fall behind the most erased sector
+/// before its records are moved so it gets reused.
+const WEAR_LEVEL_THRESHOLD: u32 = 64;
+
+/// Bytes a record with a `len`-byte payload takes up in the log.
+pub(crate) const fn record_size(len: usize) -> u32 {
+    (RECORD_HEADER_SIZE + len).next_multiple_of(4) as u32
+}
+
+/// Largest header record payload, for a header blob of `blob_len` bytes.
+pub(crate) const fn max_header_payload(blob_len: usize) -> usize {
+    HEADER_LISTS_PREFIX + MAX_GROUPS as usize + MAX_ENTRIES as usize + blob_len
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub(crate) enum RecordKind {
+    Header = 1,
+    Group = 2,
+    Entry = 3,
+}
+
+impl RecordKind {
+    const fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(RecordKind::Header),
+            2 => Some(RecordKind::Group),
+            3 => Some(RecordKind::Entry),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+enum SectorState {
+    /// No valid sector header; erased before it is used
+    Unformatted,
+    /// Erased, with a header and no records
+    Free,
+    /// Where records are being appended
+    Open,
+    /// Takes no more records until it is collected
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, Format)]
+struct Sector {
+    state: SectorState,
+    erase_count: u32,
+    /// Where the next record would go, relative to the sector
+    write_offset: u32,
+    /// End of the records that passed their CRC, relative to the sector
+    scan_end: u32,
+    /// Bytes taken by the records of the committed database
+    live_bytes: u32,
+}
+
+impl Sector {
+    /// Bytes written to the sector that the committed database doesn't use.
+    fn garbage(&self) -> u32 {
+        (self.write_offset - SECTOR_HEADER_SIZE).saturating_sub(self.live_bytes)
+    }
+
+    const fn unformatted() -> Self {
+        Sector {
+            state: SectorState::Unformatted,
+            erase_count: 0,
+            write_offset: SECTOR_SIZE,
+            scan_end: SECTOR_HEADER_SIZE,
+            live_bytes: 0,
+        }
+    }
+}
+
+/// Where a record sits, relative to the log's region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+struct RecordLoc {
+    offset: u32,
+    len: u16,
+    seq: u32,
+}
+
+impl RecordLoc {
+    fn sector(&self) -> usize {
+        (self.offset / SECTOR_SIZE) as usize
+    }
+
+    fn size(&self) -> u32 {
+        record_size(self.len as usize)
+    }
+}
+
+/// Header fields of a record read back from flash.
+struct RecordHeader {
+    flags: u8,
+    kind: Option<RecordKind>,
+    slot: u8,
+    len: u16,
+    seq: u32,
+}
+
+impl RecordHeader {
+    fn new_from_bytes(bytes: &[u8; RECORD_HEADER_SIZE]) -> Self {
+        RecordHeader {
+            flags: bytes[0],
+            kind: RecordKind::from_u8(bytes[1]),
+            slot: bytes[2],
+            len: u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+            seq: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+        }
+    }
+}
+
+enum Scan {
+    /// Erased flash: no more records in this sector
+    End,
+    /// A torn or otherwise unreadable record
+    Invalid,
+    Record(RecordHeader),
+}
+
+/// The records making up one version of the database, and their order.
+#[derive(Debug, Clone)]
+pub(crate) struct LogIndex {
+    header: Option<RecordLoc>,
+    groups: [Option<RecordLoc>; MAX_GROUPS as usize],
+    entries: [Option<RecordLoc>; MAX_ENTRIES as usize],
+    group_slots: Vec<u8, { MAX_GROUPS as usize }>,
+    entry_slots: Vec<u8, { MAX_ENTRIES as usize }>,
+}
+
+impl LogIndex {
+    const fn empty() -> Self {
+        LogIndex {
+            header: None,
+            groups: [None; MAX_GROUPS as usize],
+            entries: [None; MAX_ENTRIES as usize],
+            group_slots: Vec::new(),
+            entry_slots: Vec::new(),
+        }
+    }
+
+    /// Slots of the groups in use, in order.
+    pub(crate) fn group_slots(&self) -> &[u8] {
+        &self.group_slots
+    }
+
+    /// Slots of the entries in use, in order.
+    pub(crate) fn entry_slots(&self) -> &[u8] {
+        &self.entry_slots
+    }
+
+    /// Lowest slot no group in use takes.
+    pub(crate) fn free_group_slot(&self) -> Option<u8> {
+        (0..MAX_GROUPS as u8).find(|slot| !self.group_slots.contains(slot))
+    }
+
+    /// Lowest slot no entry in use takes.
+    pub(crate) fn free_entry_slot(&self) -> Option<u8> {
+        (0..=(MAX_ENTRIES - 1) as u8).find(|slot| !self.entry_slots.contains(slot))
+    }
+
+    pub(crate) fn push_group(&mut self, slot: u8) -> Result<(), KDBError> {
+        self.group_slots
+            .push(slot)
+            .map_err(|_| KDBError::DatabaseIntegrityError)
+    }
+
+    pub(crate) fn push_entry(&mut self, slot: u8) -> Result<(), KDBError> {
+        self.entry_slots
+            .push(slot)
+            .map_err(|_| KDBError::DatabaseIntegrityError)
+    }
+
+    /// Drops the entry at `index` from the order. Its records become garbage.
+    pub(crate) fn remove_entry(&mut self, index: usize) -> Result<(), KDBError> {
+        if index >= self.entry_slots.len() {
+            return Err(KDBError::EntryNotFound);
+        }
+        let slot = self.entry_slots.remove(index);
+        self.entries[slot as usize] = None;
+        Ok(())
+    }
+
+    /// Drops every group and entry from the order. Their records become
+    /// garbage.
+    pub(crate) fn clear(&mut self) {
+        self.groups = [None; MAX_GROUPS as usize];
+        self.entries = [None; MAX_ENTRIES as usize];
+        self.group_slots.clear();
+        self.entry_slots.clear();
+    }
+
+    fn loc(&self, kind: RecordKind, slot: u8) -> Option<RecordLoc> {
+        match kind {
+            RecordKind::Header => self.header,
+            RecordKind::Group => self.groups.get(slot as usize).copied().flatten(),
+            RecordKind::Entry => self.entries.get(slot as usize).copied().flatten(),
+        }
+    }
+
+    fn loc_mut(&mut self, kind: RecordKind, slot: u8) -> Option<&mut Option<RecordLoc>> {
+        match kind {
+            RecordKind::Header => Some(&mut self.header),
+            RecordKind::Group => self.groups.get_mut(slot as usize),
+            RecordKind::Entry => self.entries.get_mut(slot as usize),
+        }
+    }
+
+    /// Every record of this version of the database.
+    fn live(&self) -> impl Iterator<Item = RecordLoc> + '_ {
+        let groups = self
+            .group_slots
+            .iter()
+            .filter_map(|&slot| self.loc(RecordKind::Group, slot));
+        let entries = self
+            .entry_slots
+            .iter()
+            .filter_map(|&slot| self.loc(RecordKind::Entry, slot));
+        self.header.into_iter().chain(groups).chain(entries)
+    }
+}
+
+/// The log in the KeePass region: which sectors hold what, and where each
+/// record of the committed database sits.
+#[derive(Debug, Clone)]
+pub struct RecordLog {
+    region: RegionHandle,
+    sector_count: usize,
+    sectors: [Sector; MAX_LOG_SECTORS],
+    open: Option<usize>,
+    next_seq: u32,
+    index: LogIndex,
+    /// Set while a change is under way, and left set if it fails. Whatever it
+    /// appended is then discarded by mounting the log again before the next
+    /// change.
+    stale: bool,
+}
+
+impl RecordLog {
+    /// Scans the log in `region`, finds the committed database and discards
+    /// any record written after it.
+    pub fn mount<S: NorFlash>(storage: &mut S, region: RegionHandle) -> Result<Self, KDBError> {
+        let sector_count = (region.capacity / SECTOR_SIZE) as usize;
+        if !region.base.is_multiple_of(SECTOR_SIZE)
+            || !(2..=MAX_LOG_SECTORS).contains(&sector_count)
+        {
+            return Err(KDBError::DatabaseIntegrityError);
+        }
+
+        let mut log = RecordLog {
+            region,
+            sector_count,
+            sectors: [Sector::unformatted(); MAX_LOG_SECTORS],
+            open: None,
+            next_seq: 1,
+            index: LogIndex::empty(),
+            stale: false,
+        };
+
+        // 1. Check every record and find the latest header record
+        let mut latest_write: Option<(u32, usize)> = None;
+        let mut header: Option<RecordLoc> = None;
+        for sector in 0..sector_count {
+            log.sectors[sector] = log.scan_sector(storage, sector, |loc, record| {
+                if latest_write.is_none_or(|(seq, _)| record.seq > seq) {
+                    latest_write = Some((record.seq, loc.sector()));
+                }
+                if record.kind == Some(RecordKind::Header)
+                    && record.flags == RECORD_LIVE
+                    && header.is_none_or(|current| record.seq > current.seq)
+                {
+                    header = Some(loc);
+                }
+            })?;
+        }
+        log.index.header = header;
+        if let Some((seq, sector)) = latest_write {
+            log.next_seq = seq.wrapping_add(1);
+            if log.sectors[sector].write_offset < SECTOR_SIZE {
+                log.sectors[sector].state = SectorState::Open;
+                log.open = Some(sector);
+            }
+        }
+
+        // 2. Read the order of groups and entries from the header record
+        let committed_seq = log.index.header.map_or(0, |loc| loc.seq);
+        if let Some(loc) = log.index.header {
+            let mut payload = [0u8; MAX_RECORD_PAYLOAD];
+            let payload = log.read_loc(storage, loc, &mut payload)?;
+            let (group_slots, entry_slots) = header_lists(payload)?;
+            log.index.group_slots =
+                Vec::from_slice(group_slots).map_err(|_| KDBError::DatabaseIntegrityError)?;
+            log.index.entry_slots =
+                Vec::from_slice(entry_slots).map_err(|_| KDBError::DatabaseIntegrityError)?;
+        }
+
+        // 3. Find the latest committed version of each record, and discard
+        // whatever a change cut short left after the header record
+        for sector in 0..sector_count {
+            let mut offset = SECTOR_HEADER_SIZE;
+            while offset < log.sectors[sector].scan_end {
+                let loc_offset = sector as u32 * SECTOR_SIZE + offset;
+                let record = log.read_record_header(storage, loc_offset)?;
+                let loc = RecordLoc {
+                    offset: loc_offset,
+                    len: record.len,
+                    seq: record.seq,
+                };
+                offset += loc.size();
+
+                let Some(kind) = record.kind else {
+                    continue;
+                };
+                if record.flags != RECORD_LIVE {
+                    continue;
+                }
+                if record.seq > committed_seq {
+                    warn!("Discarding an uncommitted {} record", kind);
+                    let absolute = log.absolute(loc_offset)?;
+                    flash::write(storage, absolute, &[RECORD_DISCARDED])
+                        .map_err(|_| KDBError::Io)?;
+                    continue;
+                }
+                let in_use = match kind {
+                    RecordKind::Header => false,
+                    RecordKind::Group => log.index.group_slots.contains(&record.slot),
+                    RecordKind::Entry => log.index.entry_slots.contains(&record.slot),
+                };
+                if !in_use {
+                    continue;
+                }
+                if let Some(current) = log.index.loc_mut(kind, record.slot)
+                    && current.is_none_or(|current| record.seq > current.seq)
+                {
+                    *current = Some(loc);
+                }
+            }
+        }
+
+        // 4. Every group and entry in use must have been found
+        let complete = log
+            .index
+            .group_slots
+            .iter()
+            .all(|&slot| log.index.loc(RecordKind::Group, slot).is_some())
+            && log
+                .index
+                .entry_slots
+                .iter()
+                .all(|&slot| log.index.loc(RecordKind::Entry, slot).is_some());
+        if !complete {
+            return Err(KDBError::DatabaseIntegrityError);
+        }
+        log.count_live();
+
+        Ok(log)
+    }
+
+    /// Whether no database has been committed yet.
+    pub fn is_empty(&self) -> bool {
+        self.index.header.is_none()
+    }
+
+    /// The committed database.
+    pub(crate) fn index(&self) -> &LogIndex {
+        &self.index
+    }
+
+    /// Sequence number of the committed header record, 0 without one.
+    pub(crate) fn committed_seq(&self) -> u32 {
+        self.index.header.map_or(0, |loc| loc.seq)
+    }
+
+    /// Sequence number the next record appended gets.
+    pub(crate) fn next_seq(&self) -> u32 {
+        self.next_seq
+    }
+
+    /// Erase count of every sector of the log, in flash order. A sector that
+    /// has never been formatted reports 0.
+    pub fn erase_counts(&self) -> impl Iterator<Item = u32> + '_ {
+        self.sectors[..self.sector_count]
+            .iter()
+            .map(|sector| sector.erase_count)
+    }
+
+    /// Bytes left for records without collecting any garbage.
+    pub fn free_bytes(&self) -> u32 {
+        self.available()
+    }
+
+    /// Bytes taken by the records of the committed database, headers
+    /// included; everything else in the log is garbage or free.
+    pub fn live_bytes(&self) -> u32 {
+        self.sectors[..self.sector_count]
+            .iter()
+            .map(|sector| sector.live_bytes)
+            .sum()
+    }
+
+    /// Copies the committed header blob (the sealed database header) into `blob`.
+    pub(crate) fn read_header_blob<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        blob: &mut [u8],
+    ) -> Result<(), KDBError> {
+        let loc = self.index.header.ok_or(KDBError::DatabaseIntegrityError)?;
+        let mut payload = [0u8; MAX_RECORD_PAYLOAD];
+        let payload = self.read_loc(storage, loc, &mut payload)?;
+        let lists_len =
+            HEADER_LISTS_PREFIX + self.index.group_slots.len() + self.index.entry_slots.len();
+        if payload.len() != lists_len + blob.len() {
+            return Err(KDBError::DatabaseIntegrityError);
+        }
+        blob.copy_from_slice(&payload[lists_len..]);
+        Ok(())
+    }
+
+    /// Copies the committed payload of the `kind` record in `slot` to the
+    /// start of `payload`, for records whose length varies. Returns the
+    /// length.
+    pub(crate) fn read_var<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        kind: RecordKind,
+        slot: u8,
+        payload: &mut [u8],
+    ) -> Result<usize, KDBError> {
+        let loc = self
+            .index
+            .loc(kind, slot)
+            .ok_or(KDBError::DatabaseIntegrityError)?;
+        let len = loc.len as usize;
+        let dst = payload
+            .get_mut(..len)
+            .ok_or(KDBError::DatabaseIntegrityError)?;
+        let mut buffer = [0u8; MAX_RECORD_PAYLOAD];
+        dst.copy_from_slice(self.read_loc(storage, loc, &mut buffer)?);
+        Ok(len)
+    }
+
+    /// SHA-256 over the group and then the entry payloads of `index`, in
+    /// order, exactly as they sit in the log.
+    pub(crate) fn contents_hash<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        index: &LogIndex,
+    ) -> Result<[u8; 32], KDBError> {
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; MAX_RECORD_PAYLOAD];
+        let records = index
+            .group_slots
+            .iter()
+            .map(|&slot| index.loc(RecordKind::Group, slot))
+            .chain(
+                index
+                    .entry_slots
+                    .iter()
+                    .map(|&slot| index.loc(RecordKind::Entry, slot)),
+            );
+        for loc in records {
+            let loc = loc.ok_or(KDBError::DatabaseIntegrityError)?;
+            hasher.update(self.read_loc(storage, loc, &mut buffer)?);
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// Starts a change that appends up to `need` bytes of records (as counted
+    /// by [`record_size`]), collecting garbage first if that doesn't fit.
+    /// Returns the committed database for the change to be staged on.
+    pub(crate) fn begin<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+        need: u32,
+    ) -> Result<LogIndex, KDBError> {
+        if self.stale {
+            *self = Self::mount(storage, self.region)?;
+        }
+        // Until the change commits, whatever it appends must not outlive it
+        self.stale = true;
+        self.make_room(storage, need)?;
+        Ok(self.index.clone())
+    }
+
+    /// Appends a `kind` record for `slot` and points `staged` at it.
+    pub(crate) fn append<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+        staged: &mut LogIndex,
+        kind: RecordKind,
+        slot: u8,
+        payload: &[u8],
+    ) -> Result<(), KDBError> {
+        let loc = self.append_record(storage, kind, slot, payload)?;
+        *staged
+            .loc_mut(kind, slot)
+            .ok_or(KDBError::DatabaseIntegrityError)? = Some(loc);
+        Ok(())
+    }
+
+    /// Commits `staged` by appending its header record, carrying `blob`, the
+    /// sealed database header.
+    pub(crate) fn commit<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+        mut staged: LogIndex,
+        blob: &[u8],
+    ) -> Result<(), KDBError> {
+        let mut payload = [0xFFu8; MAX_RECORD_PAYLOAD];
+        let groups = staged.group_slots.len();
+        let entries = staged.entry_slots.len();
+        let len = HEADER_LISTS_PREFIX + groups + entries + blob.len();
+        if len > MAX_RECORD_PAYLOAD {
+            return Err(KDBError::DatabaseIntegrityError);
+        }
+        payload[0] = groups as u8;
+        payload[2..4].copy_from_slice(&(entries as u16).to_le_bytes());
+        let mut offset = HEADER_LISTS_PREFIX;
+        payload[offset..offset + groups].copy_from_slice(&staged.group_slots);
+        offset += groups;
+        payload[offset..offset + entries].copy_from_slice(&staged.entry_slots);
+        offset += entries;
+        payload[offset..len].copy_from_slice(blob);
+
+        self.append(storage, &mut staged, RecordKind::Header, 0, &payload[..len])?;
+        self.index = staged;
+        self.stale = false;
+        self.count_live();
+        Ok(())
+    }
+
+    /// Collects every sector holding garbage, so no record the committed
+    /// database replaced or dropped is left on flash.
+    pub(crate) fn purge<S: NorFlash>(&mut self, storage: &mut S) -> Result<(), KDBError> {
+        if self.stale {
+            *self = Self::mount(storage, self.region)?;
+        }
+        // The open sector's records have to move out of it too
+        if let Some(open) = self.open
+            && self.sectors[open].garbage() > 0
+        {
+            self.sectors[open].state = SectorState::Full;
+            self.open = None;
+        }
+        while let Some(victim) = self.most_garbage_held() {
+            info!("Erasing the garbage in sector {}", victim);
+            self.collect(storage, victim)?;
+        }
+        Ok(())
+    }
+
+    /// Erases every sector of the log in `region`, keeping their erase counts.
+    pub fn erase_all<S: NorFlash>(storage: &mut S, region: RegionHandle) -> Result<(), KDBError> {
+        let sector_count = (region.capacity / SECTOR_SIZE) as usize;
+        let mut counts = [None; MAX_LOG_SECTORS];
+        for (sector, count) in counts.iter_mut().enumerate().take(sector_count) {
+            *count = read_sector_header(storage, region.base + sector as u32 * SECTOR_SIZE)?;
+        }
+        let estimate = counts.iter().flatten().copied().max().unwrap_or(0);
+        for (sector, count) in counts.iter().enumerate().take(sector_count) {
+            erase_sector(
+                storage,
+                region.base + sector as u32 * SECTOR_SIZE,
+                count.unwrap_or(estimate) + 1,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn absolute(&self, offset: u32) -> Result<u32, KDBError> {
+        if !self.region.contains_range(offset, 0) {
+            return Err(KDBError::DatabaseIntegrityError);
+        }
+        self.region
+            .absolute(offset)
+            .ok_or(KDBError::DatabaseIntegrityError)
+    }
+
+    /// Reads the sector header and validates every record in `sector`,
+    /// handing each valid one to `on_record`.
+    fn scan_sector<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        sector: usize,
+        mut on_record: impl FnMut(RecordLoc, &RecordHeader),
+    ) -> Result<Sector, KDBError> {
+        let base = sector as u32 * SECTOR_SIZE;
+        let Some(erase_count) = read_sector_header(storage, self.absolute(base)?)? else {
+            return Ok(Sector::unformatted());
+        };
+
+        let mut offset = SECTOR_HEADER_SIZE;
+        let write_offset = loop {
+            if offset + RECORD_HEADER_SIZE as u32 > SECTOR_SIZE {
+                break SECTOR_SIZE;
+            }
+            match self.check_record(storage, base + offset)? {
+                Scan::End => {
+                    // A torn write can leave bits cleared past what looks
+                    // like the end; appending there would corrupt the record
+                    if self.is_erased(storage, base + offset, SECTOR_SIZE - offset)? {
+                        break offset;
+                    }
+                    break SECTOR_SIZE;
+                }
+                Scan::Invalid => break SECTOR_SIZE,
+                Scan::Record(record) => {
+                    let loc = RecordLoc {
+                        offset: base + offset,
+                        len: record.len,
+                        seq: record.seq,
+                    };
+                    offset += loc.size();
+                    on_record(loc, &record);
+                }
+            }
+        };
+
+        Ok(Sector {
+            state: if offset == SECTOR_HEADER_SIZE && write_offset == offset {
+                SectorState::Free
+            } else {
+                SectorState::Full
+            },
+            erase_count,
+            write_offset,
+            scan_end: offset,
+            live_bytes: 0,
+        })
+    }
+
+    fn read_record_header<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        offset: u32,
+    ) -> Result<RecordHeader, KDBError> {
+        let mut bytes = [0u8; RECORD_HEADER_SIZE];
+        flash::read(storage, self.absolute(offset)?, &mut bytes).map_err(|_| KDBError::Io)?;
+        Ok(RecordHeader::new_from_bytes(&bytes))
+    }
+
+    fn check_record<S: NorFlash>(&self, storage: &mut S, offset: u32) -> Result<Scan, KDBError> {
+        let mut bytes = [0u8; RECORD_HEADER_SIZE];
+        flash::read(storage, self.absolute(offset)?, &mut bytes).map_err(|_| KDBError::Io)?;
+        if bytes.iter().all(|&byte| byte == 0xFF) {
+            return Ok(Scan::End);
+        }
+
+        let record = RecordHeader::new_from_bytes(&bytes);
+        let sector_end = offset - offset % SECTOR_SIZE + SECTOR_SIZE;
+        if record.len as usize > MAX_RECORD_PAYLOAD
+            || offset + record_size(record.len as usize) > sector_end
+        {
+            return Ok(Scan::Invalid);
+        }
+
+        let mut payload = [0u8; MAX_RECORD_PAYLOAD];
+        let payload = &mut payload[..record.len as usize];
+        flash::read(
+            storage,
+            self.absolute(offset + RECORD_HEADER_SIZE as u32)?,
+            payload,
+        )
+        .map_err(|_| KDBError::Io)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&bytes[1..12]);
+        hasher.update(payload);
+        if hasher.finalize().to_le_bytes() != bytes[12..16] {
+            return Ok(Scan::Invalid);
+        }
+        Ok(Scan::Record(record))
+    }
+
+    fn is_erased<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        offset: u32,
+        len: u32,
+    ) -> Result<bool, KDBError> {
+        let mut chunk = [0u8; 256];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(chunk.len() as u32) as usize;
+            flash::read(storage, self.absolute(offset + done)?, &mut chunk[..n])
+                .map_err(|_| KDBError::Io)?;
+            if chunk[..n].iter().any(|&byte| byte != 0xFF) {
+                return Ok(false);
+            }
+            done += n as u32;
+        }
+        Ok(true)
+    }
+
+    /// Reads the payload of the record at `loc` into the front of `buffer`.
+    fn read_loc<'b, S: NorFlash>(
+        &self,
+        storage: &mut S,
+        loc: RecordLoc,
+        buffer: &'b mut [u8; MAX_RECORD_PAYLOAD],
+    ) -> Result<&'b [u8], KDBError> {
+        let payload = &mut buffer[..loc.len as usize];
+        flash::read(
+            storage,
+            self.absolute(loc.offset + RECORD_HEADER_SIZE as u32)?,
+            payload,
+        )
+        .map_err(|_| KDBError::Io)?;
+        Ok(payload)
+    }
+
+    fn count_live(&mut self) {
+        for sector in self.sectors.iter_mut() {
+            sector.live_bytes = 0;
+        }
+        for loc in self.index.live() {
+            self.sectors[loc.sector()].live_bytes += loc.size();
+        }
+    }
+
+    /// Bytes left for records: what the open sector has left plus every free
+    /// sector but one, which stays in reserve for collecting garbage.
+    fn available(&self) -> u32 {
+        let open = self
+            .open
+            .map_or(0, |sector| SECTOR_SIZE - self.sectors[sector].write_offset);
+        let free = self.sectors[..self.sector_count]
+            .iter()
+            .filter(|sector| matches!(sector.state, SectorState::Free | SectorState::Unformatted))
+            .count() as u32;
+        open + free.saturating_sub(1) * SECTOR_PAYLOAD
+    }
+
+    /// Collects garbage until `need` bytes of records fit, allowing for the
+    /// space lost at the end of each sector a record doesn't fit in.
+    fn make_room<S: NorFlash>(&mut self, storage: &mut S, need: u32) -> Result<(), KDBError> {
+        let max_record = record_size(MAX_RECORD_PAYLOAD);
+        let need = need + (need / SECTOR_PAYLOAD + 1) * max_record;
+
+        // Cold records move first, while there is still room for them
+        if let Some(cold) = self.coldest_behind() {
+            info!(
+                "Moving cold records out of sector {} (erased {} times)",
+                cold, self.sectors[cold].erase_count
+            );
+            self.collect(storage, cold)?;
+        }
+
+        let mut collected = 0;
+        while self.available() < need {
+            if collected == self.sector_count {
+                return Err(KDBError::StorageFull);
+            }
+            let victim = self.most_garbage().ok_or(KDBError::StorageFull)?;
+            self.collect(storage, victim)?;
+            collected += 1;
+        }
+        Ok(())
+    }
+
+    /// The full sector with the most garbage, least erased first on a tie.
+    fn most_garbage(&self) -> Option<usize> {
+        self.sectors[..self.sector_count]
+            .iter()
+            .enumerate()
+            .filter(|(_, sector)| {
+                sector.state == SectorState::Full && sector.live_bytes < SECTOR_PAYLOAD
+            })
+            .max_by_key(|(_, sector)| {
+                (
+                    SECTOR_PAYLOAD - sector.live_bytes,
+                    u32::MAX - sector.erase_count,
+                )
+            })
+            .map(|(idx, _)| idx)
+    }
+
+    /// The full sector holding the most garbage, if any holds some. Unlike
+    /// [`RecordLog::most_garbage`], room left unwritten doesn't count.
+    fn most_garbage_held(&self) -> Option<usize> {
+        self.sectors[..self.sector_count]
+            .iter()
+            .enumerate()
+            .filter(|(_, sector)| sector.state == SectorState::Full && sector.garbage() > 0)
+            .max_by_key(|(_, sector)| sector.garbage())
+            .map(|(idx, _)| idx)
+    }
+
+    /// The least erased full sector, if it has fallen more than
+    /// [`WEAR_LEVEL_THRESHOLD`] erases behind the most erased one.
+    fn coldest_behind(&self) -> Option<usize> {
+        let sectors = &self.sectors[..self.sector_count];
+        let most = sectors.iter().map(|sector| sector.erase_count).max()?;
+        let (coldest, sector) = sectors
+            .iter()
+            .enumerate()
+            .filter(|(_, sector)| sector.state == SectorState::Full)
+            .min_by_key(|(_, sector)| sector.erase_count)?;
+        (most - sector.erase_count > WEAR_LEVEL_THRESHOLD).then_some(coldest)
+    }
+
+    /// Copies the live records of `victim` forward, keeping their sequence
+    /// numbers, then erases it. Cut short, the copies are either torn and
+    /// ignored or identical to the originals.
+    fn collect<S: NorFlash>(&mut self, storage: &mut S, victim: usize) -> Result<(), KDBError> {
+        let mut moved = LogIndex::empty();
+        core::mem::swap(&mut moved, &mut self.index);
+        let result = self.move_records(storage, victim, &mut moved);
+        core::mem::swap(&mut moved, &mut self.index);
+        result?;
+
+        let base = victim as u32 * SECTOR_SIZE;
+        let erase_count = self.sectors[victim].erase_count + 1;
+        erase_sector(storage, self.absolute(base)?, erase_count)?;
+        self.sectors[victim] = Sector {
+            state: SectorState::Free,
+            erase_count,
+            write_offset: SECTOR_HEADER_SIZE,
+            scan_end: SECTOR_HEADER_SIZE,
+            live_bytes: 0,
+        };
+        self.count_live();
+        Ok(())
+    }
+
+    fn move_records<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+        victim: usize,
+        index: &mut LogIndex,
+    ) -> Result<(), KDBError> {
+        let locs = index
+            .header
+            .iter_mut()
+            .chain(index.groups.iter_mut().flatten())
+            .chain(index.entries.iter_mut().flatten());
+        let mut record = [0u8; RECORD_HEADER_SIZE + MAX_RECORD_PAYLOAD];
+        for loc in locs {
+            if loc.sector() != victim {
+                continue;
+            }
+            let size = loc.size() as usize;
+            flash::read(storage, self.absolute(loc.offset)?, &mut record[..size])
+                .map_err(|_| KDBError::Io)?;
+            let offset = self.place(storage, loc.size())?;
+            NorFlash::write(storage, self.absolute(offset)?, &record[..size])
+                .map_err(|_| KDBError::Io)?;
+            self.sectors[offset as usize / SECTOR_SIZE as usize].write_offset += loc.size();
+            loc.offset = offset;
+        }
+        Ok(())
+    }
+
+    /// Finds room for a record of `size` bytes, closing the open sector and
+    /// opening the least erased free one if it doesn't fit.
+    fn place<S: NorFlash>(&mut self, storage: &mut S, size: u32) -> Result<u32, KDBError> {
+        if let Some(open) = self.open {
+            let sector = &self.sectors[open];
+            if sector.write_offset + size <= SECTOR_SIZE {
+                return Ok(open as u32 * SECTOR_SIZE + sector.write_offset);
+            }
+            self.sectors[open].state = SectorState::Full;
+            self.open = None;
+        }
+
+        let next = self.sectors[..self.sector_count]
+            .iter()
+            .enumerate()
+            .filter(|(_, sector)| {
+                matches!(sector.state, SectorState::Free | SectorState::Unformatted)
+            })
+            .min_by_key(|(_, sector)| sector.erase_count)
+            .map(|(idx, _)| idx)
+            .ok_or(KDBError::StorageFull)?;
+        if self.sectors[next].state == SectorState::Unformatted {
+            // Its erase count was lost; assume the worst
+            let estimate = self.sectors[..self.sector_count]
+                .iter()
+                .map(|sector| sector.erase_count)
+                .max()
+                .unwrap_or(0);
+            let base = next as u32 * SECTOR_SIZE;
+            erase_sector(storage, self.absolute(base)?, estimate + 1)?;
+            self.sectors[next].erase_count = estimate + 1;
+        }
+        self.sectors[next].state = SectorState::Open;
+        self.sectors[next].write_offset = SECTOR_HEADER_SIZE;
+        self.sectors[next].scan_end = SECTOR_HEADER_SIZE;
+        self.open = Some(next);
+        Ok(next as u32 * SECTOR_SIZE + SECTOR_HEADER_SIZE)
+    }
+
+    fn append_record<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+        kind: RecordKind,
+        slot: u8,
+        payload: &[u8],
+    ) -> Result<RecordLoc, KDBError> {
+        if payload.len() > MAX_RECORD_PAYLOAD {
+            return Err(KDBError::DatabaseIntegrityError);
+        }
+        let size = record_size(payload.len());
+        let offset = self.place(storage, size)?;
+
+        let seq = self.next_seq;
+        let mut record = [0xFFu8; RECORD_HEADER_SIZE + MAX_RECORD_PAYLOAD];
+        record[0] = RECORD_LIVE;
+        record[1] = kind as u8;
+        record[2] = slot;
+        record[4..6].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        record[8..12].copy_from_slice(&seq.to_le_bytes());
+        record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + payload.len()].copy_from_slice(payload);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&record[1..12]);
+        hasher.update(payload);
+        record[12..16].copy_from_slice(&hasher.finalize().to_le_bytes());
+
+        // Whatever happens to the write, the space and the sequence number are used up
+        self.sectors[offset as usize / SECTOR_SIZE as usize].write_offset += size;
+        self.next_seq = seq.wrapping_add(1);
+        NorFlash::write(storage, self.absolute(offset)?, &record[..size as usize])
+            .map_err(|_| KDBError::Io)?;
+
+        Ok(RecordLoc {
+            offset,
+            len: payload.len() as u16,
+            seq,
+        })
+    }
+}
+
+#[cfg(test)]
+impl RecordLog {
+    /// Flips a bit in byte `at` of the payload of the committed `kind` record
+    /// in `slot` and fixes up its CRC, the way someone holding the flash could.
+    pub(crate) fn tamper(
+        &self,
+        flash: &mut crate::storage::ram_flash::RamFlash,
+        kind: RecordKind,
+        slot: u8,
+        at: usize,
+    ) {
+        let loc = self.index.loc(kind, slot).unwrap();
+        let start = self.absolute(loc.offset).unwrap() as usize;
+        let end = start + RECORD_HEADER_SIZE + loc.len as usize;
+        let record = &mut flash.as_bytes_mut()[start..end];
+        record[RECORD_HEADER_SIZE + at] ^= 1;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&record[1..12]);
+        hasher.update(&record[RECORD_HEADER_SIZE..]);
+        record[12..16].copy_from_slice(&hasher.finalize().to_le_bytes());
+    }
+}
+
+/// Splits a header record's payload into the group and entry slot lists.
+fn header_lists(payload: &[u8]) -> Result<(&[u8], &[u8]), KDBError> {
+    if payload.len() < HEADER_LISTS_PREFIX {
+        return Err(KDBError::DatabaseIntegrityError);
+    }
+    let groups = payload[0] as usize;
+    let entries = u16::from_le_bytes(payload[2..4].try_into().unwrap()) as usize;
+    let lists_end = HEADER_LISTS_PREFIX + groups + entries;
+    if groups > MAX_GROUPS as usize || entries > MAX_ENTRIES as usize || payload.len() < lists_end {
+        return Err(KDBError::DatabaseIntegrityError);
+    }
+    Ok((
+        &payload[HEADER_LISTS_PREFIX..HEADER_LISTS_PREFIX + groups],
+        &payload[HEADER_LISTS_PREFIX + groups..lists_end],
+    ))
+}
+
+/// Erase count in the sector header at `absolute`, or `None` if it doesn't
+/// carry a valid one.
+fn read_sector_header<S: NorFlash>(
+    storage: &mut S,
+    absolute: u32,
+) -> Result<Option<u32>, KDBError> {
+    let mut bytes = [0u8; SECTOR_HEADER_SIZE as usize];
+    flash::read(storage, absolute, &mut bytes).map_err(|_| KDBError::Io)?;
+    if bytes[..4] != SECTOR_MAGIC || crc32fast::hash(&bytes[..12]).to_le_bytes() != bytes[12..16] {
+        return Ok(None);
+    }
+    Ok(Some(u32::from_le_bytes(bytes[4..8].try_into().unwrap())))
+}
+
+/// Erases the sector at `absolute` and writes its header with `erase_count`.
+fn erase_sector<S: NorFlash>(
+    storage: &mut S,
+    absolute: u32,
+    erase_count: u32,
+) -> Result<(), KDBError> {
+    NorFlash::erase(storage, absolute, absolute + SECTOR_SIZE).map_err(|_| KDBError::Io)?;
+    let mut bytes = [0xFFu8; SECTOR_HEADER_SIZE as usize];
+    bytes[..4].copy_from_slice(&SECTOR_MAGIC);
+    bytes[4..8].copy_from_slice(&erase_count.to_le_bytes());
+    let crc = crc32fast::hash(&bytes[..12]);
+    bytes[12..16].copy_from_slice(&crc.to_le_bytes());
+    NorFlash::write(storage, absolute, &bytes).map_err(|_| KDBError::Io)
+}
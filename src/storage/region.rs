@@ -1,5 +1,7 @@
 use defmt::Format;
 
+use crate::storage::layout::StorageError;
+
 pub(crate) const REGION_DESCRIPTOR_SIZE: usize = 20;
 
 /// Regions we plan to keep in flash. Add more as the layout evolves.
@@ -16,6 +18,16 @@ impl DataRegion {
     pub const fn index(self) -> usize {
         self as usize
     }
+
+    pub const fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(DataRegion::ProjectConfig),
+            1 => Some(DataRegion::UserConfig),
+            2 => Some(DataRegion::KeePassDb),
+            3 => Some(DataRegion::Scratch),
+            _ => None,
+        }
+    }
 }
 
 /// Describes where a region lives in flash.
@@ -26,8 +38,14 @@ pub struct RegionDescriptor {
     /// Total bytes reserved for this region in flash.
     pub capacity: u32,
     /// Bytes currently used (header + ciphertext). 0 means empty/uninitialized.
+    /// Two regions always report 0. `KeePassDb` is a log whose records carry
+    /// their own CRC32; the healthcheck checks them against the committed
+    /// header record instead, so a change doesn't rewrite the metadata.
+    /// `Scratch` has no used bytes at rest: each of its sectors is erased or
+    /// holds a copy staged magic last, which its `finish_pending_*` step
+    /// checks before the healthcheck runs and which the next stage erases.
     pub used_len: u32,
-    /// TODO: Populate/validate CRC32 for region contents.
+    /// CRC32 of the first `used_len` bytes, checked by the healthcheck.
     pub crc32: u32,
 }
 
@@ -51,6 +69,48 @@ impl RegionHandle {
     }
 }
 
+/// Set of regions whose contents don't match their descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CorruptRegions(u8);
+
+impl CorruptRegions {
+    pub fn insert(&mut self, region: DataRegion) {
+        self.0 |= 1 << region.index();
+    }
+
+    pub fn contains(self, region: DataRegion) -> bool {
+        self.0 & (1 << region.index()) != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = DataRegion> {
+        [
+            DataRegion::ProjectConfig,
+            DataRegion::UserConfig,
+            DataRegion::KeePassDb,
+            DataRegion::Scratch,
+        ]
+        .into_iter()
+        .filter(move |&region| self.contains(region))
+    }
+}
+
+impl Format for CorruptRegions {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "[");
+        for (i, region) in self.iter().enumerate() {
+            if i > 0 {
+                defmt::write!(f, ", ");
+            }
+            defmt::write!(f, "{}", region);
+        }
+        defmt::write!(f, "]");
+    }
+}
+
 impl RegionDescriptor {
     pub const fn empty() -> Self {
         Self {
@@ -71,21 +131,17 @@ impl RegionDescriptor {
         }
     }
 
-    pub(crate) fn new_from_bytes(bytes: &[u8; REGION_DESCRIPTOR_SIZE]) -> Self {
-        let kind = match bytes[0] {
-            0 => DataRegion::ProjectConfig,
-            1 => DataRegion::UserConfig,
-            2 => DataRegion::KeePassDb,
-            3 => DataRegion::Scratch,
-            _ => panic!("Invalid region kind"),
-        };
-        RegionDescriptor {
+    pub(crate) fn new_from_bytes(
+        bytes: &[u8; REGION_DESCRIPTOR_SIZE],
+    ) -> Result<Self, StorageError> {
+        let kind = DataRegion::from_u8(bytes[0]).ok_or(StorageError::InvalidLayout)?;
+        Ok(RegionDescriptor {
             kind,
             offset: u32::from_le_bytes(bytes[1..5].try_into().unwrap()),
             capacity: u32::from_le_bytes(bytes[5..9].try_into().unwrap()),
             used_len: u32::from_le_bytes(bytes[9..13].try_into().unwrap()),
             crc32: u32::from_le_bytes(bytes[13..17].try_into().unwrap()),
-        }
+        })
     }
     pub(crate) fn to_bytes(self) -> [u8; REGION_DESCRIPTOR_SIZE] {
         let mut bytes = [0u8; REGION_DESCRIPTOR_SIZE];
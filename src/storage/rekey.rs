@@ -0,0 +1,150 @@
+//! Re-encrypting the KeePass region under a new PIN.
+//!
+//! Every group and entry is appended to the record log again, sealed under
+//! the new key, and committed by a single header record. The new PIN
+//! verifier goes through the [journal](crate::storage::journal) first, so
+//! the database and the verifier are replaced together, or not at all.
+//! Every sector still holding a record or a header under the old key is then
+//! collected and erased.
+
+use defmt::{info, warn};
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::encryption::{self, DbKey};
+use crate::keepass::{KDBError, KDBHeader, KeePassDb};
+use crate::storage::journal::Commit;
+use crate::storage::keepass::{FLAG_PURGE_PENDING, commit_header};
+use crate::storage::user_config::{UnlockMode, UserConfig};
+
+impl KeePassDb {
+    /// Copy of the header with fresh seeds, for deriving the key a new PIN
+    /// will seal the database with.
+    pub fn rekey_header(&self) -> KDBHeader {
+        let mut header = self.header;
+        encryption::fill_random(&mut header.master_seed);
+        encryption::fill_random(&mut header.transform_seed);
+        header
+    }
+
+    /// Re-encrypts every group and entry under `new_key` and swaps the PIN
+    /// verifier for one matching it. `new_header` comes from
+    /// [`KeePassDb::rekey_header`].
+    pub fn rekey<S: NorFlash>(
+        &mut self,
+        mut new_header: KDBHeader,
+        new_key: DbKey,
+        unlock_mode: UnlockMode,
+        user_config: &mut UserConfig,
+        storage: &mut S,
+    ) -> Result<(), KDBError> {
+        self.key()?;
+        let verifier =
+            UserConfig::seal_verifier(&new_key).map_err(|_| KDBError::DatabaseIntegrityError)?;
+
+        // 1. Append every record sealed under the new key. The commit is
+        // flagged until the old records are gone, so an unlock after a power
+        // cut finishes erasing them.
+        new_header.flags |= FLAG_PURGE_PENDING;
+        let staged = self.stage_all(storage, &new_header, &new_key)?;
+
+        // 2. Journal the verifier, then commit; the header record gets the
+        // next sequence number
+        let commit = Commit {
+            header_seq: self.log.next_seq(),
+            verifier,
+            unlock_mode,
+        };
+        self.journal.begin(storage, &commit)?;
+        let header = match commit_header(&mut self.log, storage, staged, new_header, &new_key) {
+            Ok(header) => header,
+            Err(err) => {
+                if self.journal.abort(storage).is_err() {
+                    warn!("Dropping the rekey's commit record failed");
+                }
+                return Err(err);
+            }
+        };
+        self.header = header;
+
+        // 3. Install the verifier. If this is cut short the next boot
+        // finishes it.
+        let installed = self.journal.finish(storage, &commit, user_config);
+
+        // 4. Erase whatever the old key still opens
+        if let Err(err) = self.purge(storage, &new_key) {
+            warn!("Erasing the records under the old key failed: {}", err);
+        }
+        self.key = Some(new_key);
+        installed?;
+        info!("Database re-encrypted under the new PIN");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::{DbKey, KEY_SIZE};
+    use crate::secret::SecretBytes;
+    use crate::storage::testing::{self, SW_KEY, check_power_cuts, entry, holds};
+    use crate::storage::user_config::UnlockMode;
+
+    const NEW_SW_KEY: [u8; KEY_SIZE] = [9; KEY_SIZE];
+
+    #[test]
+    fn rekey_erases_the_old_records() {
+        let mut flash = testing::fresh_flash();
+        let (mut booted, _) = testing::unlock(&mut flash);
+        for title in ["mail", "bank"] {
+            booted
+                .kpdb
+                .create_entry(entry(1, title), &mut flash)
+                .unwrap();
+        }
+        let old_seed = booted.kpdb.header.master_seed;
+        assert!(holds(&flash, &old_seed));
+
+        let header = booted.kpdb.rekey_header();
+        let key = DbKey::derive(&SecretBytes::new(NEW_SW_KEY), &header.master_seed);
+        booted
+            .kpdb
+            .rekey(
+                header,
+                key,
+                UnlockMode::Passphrase,
+                &mut booted.user_config,
+                &mut flash,
+            )
+            .unwrap();
+
+        // Every header record under the old key carried its seed
+        assert!(!holds(&flash, &old_seed));
+        assert!(holds(&flash, &booted.kpdb.header.master_seed));
+    }
+
+    #[test]
+    fn rekey_survives_a_power_cut() {
+        let mut base = testing::fresh_flash();
+        let (mut booted, _) = testing::unlock(&mut base);
+        for title in ["mail", "bank"] {
+            booted
+                .kpdb
+                .create_entry(entry(1, title), &mut base)
+                .unwrap();
+        }
+
+        // The contents stay the same, so tell the two databases apart by
+        // adding an entry under the new key
+        check_power_cuts(&base, &[SW_KEY, NEW_SW_KEY], |booted, flash| {
+            let header = booted.kpdb.rekey_header();
+            let key = DbKey::derive(&SecretBytes::new(NEW_SW_KEY), &header.master_seed);
+            booted.kpdb.rekey(
+                header,
+                key,
+                UnlockMode::Passphrase,
+                &mut booted.user_config,
+                flash,
+            )?;
+            booted.kpdb.create_entry(entry(1, "forum"), flash)
+        });
+    }
+}
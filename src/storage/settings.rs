@@ -0,0 +1,317 @@
+//! Device settings, kept in `UserConfig` behind the PIN state.
+//!
+//! Saving appends a new slot instead of rewriting the sector, and the last
+//! slot whose CRC checks out wins, so a save cut short by a power loss leaves
+//! the previous settings in place. Once the slots run out the sector is
+//! rewritten with the current settings in the first one; see
+//! [`UserConfig::save_settings`](crate::storage::user_config::UserConfig::save_settings).
+//!
+//! A field holding a value the firmware doesn't know, e.g. one written by a
+//! newer version, falls back to its default on its own.
+//!
+//! Slot (32 bytes):
+//!
+//! | bytes  | field                                  |
+//! |--------|----------------------------------------|
+//! | 0..4   | magic `PBST`                           |
+//! | 4..6   | version                                |
+//! | 6..8   | auto-lock timeout, seconds; 0 = never  |
+//! | 8..10  | HID typing delay, milliseconds         |
+//! | 10     | keyboard layout                        |
+//! | 11     | display contrast                       |
+//! | 12     | display rotation                       |
+//! | 13     | minimum PIN length                     |
+//! | 14     | minimum passphrase length              |
+//! | 15     | failed attempts before the auto-wipe   |
+//! | 16     | default group, `0xFF` for none         |
+//! | 17..28 | reserved, `0xFF`                       |
+//! | 28..32 | CRC32 of bytes 0..28                   |
+
+use defmt::Format;
+use embassy_time::Duration;
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::storage::flash;
+use crate::storage::keepass::MAX_GROUPS;
+use crate::storage::layout::{StorageError, expected_region_handle};
+use crate::storage::region::{DataRegion, RegionHandle};
+use crate::storage::user_config::{
+    MAX_PASSPHRASE_LEN, MAX_PIN_LEN, MIN_PASSPHRASE_LEN, MIN_PIN_LEN,
+};
+
+pub const SETTINGS_MAGIC: [u8; 4] = *b"PBST";
+pub const SETTINGS_VERSION: u16 = 1;
+
+/// Idle time after which an unlocked device locks itself; 0 never locks.
+pub const DEFAULT_AUTO_LOCK_SECS: u16 = 120;
+/// Pause between two keystrokes typed over USB HID.
+pub const DEFAULT_TYPING_DELAY_MS: u16 = 2;
+pub const MAX_TYPING_DELAY_MS: u16 = 1000;
+pub const DEFAULT_CONTRAST: u8 = 0x7F;
+/// Consecutive failed PIN attempts after which the device wipes itself.
+pub const DEFAULT_WIPE_AFTER_ATTEMPTS: u8 = 10;
+pub const MIN_WIPE_AFTER_ATTEMPTS: u8 = 3;
+pub const MAX_WIPE_AFTER_ATTEMPTS: u8 = 50;
+
+/// Where the slots start in `UserConfig`, after the PIN record, the wipe
+/// marker and the attempt bitmap.
+pub(crate) const SETTINGS_OFFSET_REL: u32 = 128;
+pub(crate) const SETTINGS_SIZE: usize = 32;
+const CRC_OFFSET: usize = SETTINGS_SIZE - 4;
+const NO_GROUP: u8 = 0xFF;
+
+/// Host keyboard layout the USB HID keystrokes are mapped for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub enum KeyboardLayout {
+    #[default]
+    Us,
+}
+
+impl KeyboardLayout {
+    fn to_byte(self) -> u8 {
+        match self {
+            KeyboardLayout::Us => 0,
+        }
+    }
+
+    fn from_byte(_byte: u8) -> Self {
+        KeyboardLayout::Us
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub enum DisplayRotation {
+    #[default]
+    Normal,
+    /// Turned by 180 degrees, for a device held the other way up.
+    Flipped,
+}
+
+impl DisplayRotation {
+    fn to_byte(self) -> u8 {
+        match self {
+            DisplayRotation::Normal => 0,
+            DisplayRotation::Flipped => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => DisplayRotation::Flipped,
+            _ => DisplayRotation::Normal,
+        }
+    }
+}
+
+/// What a new secret must look like, and how many wrong ones wipe the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct PinPolicy {
+    /// Within `MIN_PIN_LEN..=MAX_PIN_LEN`.
+    pub min_pin_len: u8,
+    /// Within `MIN_PASSPHRASE_LEN..=MAX_PASSPHRASE_LEN`.
+    pub min_passphrase_len: u8,
+    /// Within `MIN_WIPE_AFTER_ATTEMPTS..=MAX_WIPE_AFTER_ATTEMPTS`.
+    pub wipe_after_attempts: u8,
+}
+
+impl Default for PinPolicy {
+    fn default() -> Self {
+        Self {
+            min_pin_len: MIN_PIN_LEN as u8,
+            min_passphrase_len: MIN_PASSPHRASE_LEN as u8,
+            wipe_after_attempts: DEFAULT_WIPE_AFTER_ATTEMPTS,
+        }
+    }
+}
+
+impl PinPolicy {
+    /// Replaces every field out of range with its default.
+    fn sanitized(self) -> Self {
+        let default = Self::default();
+        let pick = |value: u8, min: usize, max: usize, default: u8| {
+            if (min..=max).contains(&(value as usize)) {
+                value
+            } else {
+                default
+            }
+        };
+        Self {
+            min_pin_len: pick(
+                self.min_pin_len,
+                MIN_PIN_LEN,
+                MAX_PIN_LEN,
+                default.min_pin_len,
+            ),
+            min_passphrase_len: pick(
+                self.min_passphrase_len,
+                MIN_PASSPHRASE_LEN,
+                MAX_PASSPHRASE_LEN,
+                default.min_passphrase_len,
+            ),
+            wipe_after_attempts: pick(
+                self.wipe_after_attempts,
+                MIN_WIPE_AFTER_ATTEMPTS as usize,
+                MAX_WIPE_AFTER_ATTEMPTS as usize,
+                default.wipe_after_attempts,
+            ),
+        }
+    }
+}
+
+/// Everything the user can tune on the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Settings {
+    /// Idle time before the device locks itself; 0 never locks.
+    pub auto_lock_secs: u16,
+    /// Pause after every keystroke typed over USB HID, at most
+    /// `MAX_TYPING_DELAY_MS`.
+    pub typing_delay_ms: u16,
+    pub keyboard_layout: KeyboardLayout,
+    pub contrast: u8,
+    pub rotation: DisplayRotation,
+    pub pin_policy: PinPolicy,
+    /// Group whose entries are shown right after unlocking.
+    pub default_group: Option<u8>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            auto_lock_secs: DEFAULT_AUTO_LOCK_SECS,
+            typing_delay_ms: DEFAULT_TYPING_DELAY_MS,
+            keyboard_layout: KeyboardLayout::default(),
+            contrast: DEFAULT_CONTRAST,
+            rotation: DisplayRotation::default(),
+            pin_policy: PinPolicy::default(),
+            default_group: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Settings stored in the current layout's `UserConfig`, or the defaults
+    /// if there are none or they can't be read. Meant for the start of boot,
+    /// before the storage has been opened, e.g. to set up the display.
+    pub fn load_or_default<S: NorFlash>(storage: &mut S) -> Self {
+        Self::load(storage, expected_region_handle(DataRegion::UserConfig))
+            .ok()
+            .and_then(|(settings, _)| settings)
+            .unwrap_or_default()
+    }
+
+    /// Scans the slots of `region`. Returns the last valid settings, if any,
+    /// and the index of the first erased slot, which is where the next save
+    /// goes; it equals [`Settings::slot_count`] once they are used up.
+    pub(crate) fn load<S: NorFlash>(
+        storage: &mut S,
+        region: RegionHandle,
+    ) -> Result<(Option<Self>, u32), StorageError> {
+        let mut settings = None;
+        let slots = Self::slot_count(region);
+        for slot in 0..slots {
+            let mut bytes = [0u8; SETTINGS_SIZE];
+            flash::read(storage, Self::slot_offset(region, slot)?, &mut bytes)
+                .map_err(|_| StorageError::Io)?;
+            if bytes.iter().all(|&b| b == 0xFF) {
+                return Ok((settings, slot));
+            }
+            // A torn slot is skipped, leaving the one before it in effect
+            if let Some(valid) = Self::from_bytes(&bytes) {
+                settings = Some(valid);
+            }
+        }
+        Ok((settings, slots))
+    }
+
+    /// Writes the settings to `slot`, which must be erased.
+    pub(crate) fn write_slot<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        region: RegionHandle,
+        slot: u32,
+    ) -> Result<(), StorageError> {
+        if slot >= Self::slot_count(region) {
+            return Err(StorageError::BufferTooSmall);
+        }
+        NorFlash::write(storage, Self::slot_offset(region, slot)?, &self.to_bytes())
+            .map_err(|_| StorageError::Io)
+    }
+
+    pub(crate) fn slot_count(region: RegionHandle) -> u32 {
+        region.capacity.saturating_sub(SETTINGS_OFFSET_REL) / SETTINGS_SIZE as u32
+    }
+
+    fn slot_offset(region: RegionHandle, slot: u32) -> Result<u32, StorageError> {
+        region
+            .absolute(SETTINGS_OFFSET_REL + slot * SETTINGS_SIZE as u32)
+            .ok_or(StorageError::InvalidLayout)
+    }
+
+    /// Idle time before the device locks itself, or `None` if it never does.
+    pub fn auto_lock(&self) -> Option<Duration> {
+        (self.auto_lock_secs > 0).then(|| Duration::from_secs(u64::from(self.auto_lock_secs)))
+    }
+
+    pub fn typing_delay(&self) -> Duration {
+        Duration::from_millis(u64::from(self.typing_delay_ms))
+    }
+
+    /// Replaces every field out of range with its default.
+    pub fn sanitized(self) -> Self {
+        let default = Self::default();
+        Self {
+            typing_delay_ms: if self.typing_delay_ms <= MAX_TYPING_DELAY_MS {
+                self.typing_delay_ms
+            } else {
+                default.typing_delay_ms
+            },
+            pin_policy: self.pin_policy.sanitized(),
+            default_group: self
+                .default_group
+                .filter(|&group| u32::from(group) < MAX_GROUPS),
+            ..self
+        }
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; SETTINGS_SIZE] {
+        let mut bytes = [0xFFu8; SETTINGS_SIZE];
+        bytes[0..4].copy_from_slice(&SETTINGS_MAGIC);
+        bytes[4..6].copy_from_slice(&SETTINGS_VERSION.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.auto_lock_secs.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.typing_delay_ms.to_le_bytes());
+        bytes[10] = self.keyboard_layout.to_byte();
+        bytes[11] = self.contrast;
+        bytes[12] = self.rotation.to_byte();
+        bytes[13] = self.pin_policy.min_pin_len;
+        bytes[14] = self.pin_policy.min_passphrase_len;
+        bytes[15] = self.pin_policy.wipe_after_attempts;
+        bytes[16] = self.default_group.unwrap_or(NO_GROUP);
+        let crc = crc32fast::hash(&bytes[..CRC_OFFSET]);
+        bytes[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; SETTINGS_SIZE]) -> Option<Self> {
+        if bytes[0..4] != SETTINGS_MAGIC
+            || crc32fast::hash(&bytes[..CRC_OFFSET]).to_le_bytes() != bytes[CRC_OFFSET..]
+        {
+            return None;
+        }
+        // Newer versions only ever use reserved bytes, so their slots read
+        // fine here; an older version would need converting, but there is none.
+        let settings = Self {
+            auto_lock_secs: u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
+            typing_delay_ms: u16::from_le_bytes(bytes[8..10].try_into().unwrap()),
+            keyboard_layout: KeyboardLayout::from_byte(bytes[10]),
+            contrast: bytes[11],
+            rotation: DisplayRotation::from_byte(bytes[12]),
+            pin_policy: PinPolicy {
+                min_pin_len: bytes[13],
+                min_passphrase_len: bytes[14],
+                wipe_after_attempts: bytes[15],
+            },
+            default_group: (bytes[16] != NO_GROUP).then_some(bytes[16]),
+        };
+        Some(settings.sanitized())
+    }
+}
@@ -0,0 +1,137 @@
+//! Fixtures shared by the storage unit tests, which run on the host against a
+//! [`RamFlash`].
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::encryption::{DbKey, KEY_SIZE};
+use crate::keepass::{Entry, KDBError, KeePassDb};
+use crate::secret::SecretBytes;
+use crate::storage::boot::{self, BootedStorage};
+use crate::storage::ram_flash::{PowerCutFlash, RamFlash};
+
+/// Software key the tests unlock with, standing in for the PIN and the efuse HMAC.
+pub(crate) const SW_KEY: [u8; KEY_SIZE] = [7; KEY_SIZE];
+
+/// Flash as the first boot leaves it: bootstrapped, with an empty database.
+pub(crate) fn fresh_flash() -> RamFlash {
+    let mut flash = RamFlash::default();
+    boot::open(&mut flash).unwrap();
+    flash
+}
+
+/// Boots `storage` and unlocks the database with [`SW_KEY`], enrolling it
+/// the way the PIN screen does on first use.
+pub(crate) fn unlock<S: NorFlash>(storage: &mut S) -> (BootedStorage, DbKey) {
+    let mut booted = boot::open(storage).unwrap();
+    let key = booted.kpdb.derive_key(&SecretBytes::new(SW_KEY));
+    booted.kpdb.unlock(key.clone(), storage).unwrap();
+    booted.user_config.record_success(&key, storage).unwrap();
+    (booted, key)
+}
+
+/// An entry in `group_id` with the given title, and a username and password
+/// derived from it.
+pub(crate) fn entry(group_id: u32, title: &str) -> Entry {
+    let mut entry = Entry::default_with_group_id(group_id);
+    entry.set_title(title.as_bytes()).unwrap();
+    entry.set_username(b"alice").unwrap();
+    entry.set_password(title.as_bytes()).unwrap();
+    entry
+}
+
+/// Whether `needle` is anywhere on `flash`.
+pub(crate) fn holds(flash: &RamFlash, needle: &[u8]) -> bool {
+    flash
+        .as_bytes()
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Titles of the unlocked entries, in order.
+pub(crate) fn titles(db: &KeePassDb) -> Vec<Vec<u8>> {
+    db.entries
+        .iter()
+        .flatten()
+        .map(|entry| entry.title().to_vec())
+        .collect()
+}
+
+/// What the power-cut tests compare: the group count and each entry's
+/// title, username and password, in order.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Contents {
+    groups: usize,
+    entries: Vec<[Vec<u8>; 3]>,
+}
+
+impl Contents {
+    fn of(db: &KeePassDb) -> Self {
+        Self {
+            groups: db.groups.iter().flatten().count(),
+            entries: db
+                .entries
+                .iter()
+                .flatten()
+                .map(|entry| {
+                    [
+                        entry.title().to_vec(),
+                        entry.username().to_vec(),
+                        entry.password().expose().to_vec(),
+                    ]
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Boots `flash` and unlocks it with whichever of `sw_keys` opens the
+/// database. The PIN verifier must accept that key too.
+fn reboot(flash: &mut RamFlash, sw_keys: &[[u8; KEY_SIZE]]) -> Contents {
+    let mut booted = boot::open(flash).unwrap();
+    for sw_key in sw_keys {
+        let key = booted.kpdb.derive_key(&SecretBytes::new(*sw_key));
+        if booted.kpdb.unlock(key.clone(), flash).is_ok() {
+            assert_eq!(booted.user_config.check_verifier(&key), Some(true));
+            return Contents::of(&booted.kpdb);
+        }
+    }
+    panic!("no key opens the database");
+}
+
+/// Runs `change` on a copy of `base`, unlocked with [`SW_KEY`], with the
+/// power cut after each number of flash writes and erases in turn, until it
+/// gets through. After every cut the flash is booted again and must hold the
+/// database either as it was before the change or as it is after it.
+pub(crate) fn check_power_cuts(
+    base: &RamFlash,
+    sw_keys: &[[u8; KEY_SIZE]],
+    change: impl Fn(&mut BootedStorage, &mut PowerCutFlash<RamFlash>) -> Result<(), KDBError>,
+) {
+    let run = |budget| {
+        let mut flash = base.clone();
+        let (mut booted, _) = unlock(&mut flash);
+        let mut cut_flash = PowerCutFlash::new(flash, budget);
+        let result = change(&mut booted, &mut cut_flash);
+        let cut = cut_flash.is_cut();
+        let contents = reboot(&mut cut_flash.into_inner(), sw_keys);
+        (result, cut, contents)
+    };
+
+    let before = reboot(&mut base.clone(), sw_keys);
+    let (result, _, after) = run(usize::MAX);
+    result.unwrap();
+    assert_ne!(after, before, "the change did nothing");
+
+    for budget in 0.. {
+        let (result, cut, contents) = run(budget);
+        if !cut {
+            result.unwrap();
+            assert_eq!(contents, after);
+            return;
+        }
+        assert!(
+            contents == before || contents == after,
+            "power cut after {budget} operations left {contents:?}"
+        );
+    }
+}
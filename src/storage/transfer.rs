@@ -0,0 +1,223 @@
+//! Moving the database to and from KeePass files, see
+//! [`crate::keepass::kdb`] and [`crate::keepass::kdbx`].
+//!
+//! An import replaces every group and entry with the file's, appended to the
+//! record log and committed by a single header record, so the device keeps
+//! either the old database or the imported one.
+
+use defmt::info;
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::keepass::header::DEFAULT_TRANSFORM_ROUNDS;
+use crate::keepass::kdb::{self, KdbFile, KdbItem};
+use crate::keepass::kdbx::{self, KdbxImport};
+use crate::keepass::{KDBError, KeePassDb};
+use crate::storage::keepass::{
+    MAX_ENTRIES, MAX_GROUPS, change_size, commit_header, entry_record_size, sealed_entry,
+    sealed_group,
+};
+use crate::storage::record_log::RecordKind;
+
+impl KeePassDb {
+    /// Writes the unlocked database as a `.kdb` file for `password`, handing
+    /// it to `sink` piece by piece.
+    pub async fn export_kdb<W>(&self, password: &[u8], sink: W) -> Result<(), KDBError>
+    where
+        W: AsyncFnMut(&[u8]) -> Result<(), KDBError>,
+    {
+        self.key()?;
+        kdb::write(
+            self.groups.iter().flatten(),
+            self.entries.iter().flatten(),
+            password,
+            DEFAULT_TRANSFORM_ROUNDS,
+            sink,
+        )
+        .await
+    }
+
+    /// Writes the unlocked database as a KDBX 4 file for `password`, handing
+    /// it to `sink` piece by piece.
+    pub async fn export_kdbx<W>(&self, password: &[u8], sink: W) -> Result<(), KDBError>
+    where
+        W: AsyncFnMut(&[u8]) -> Result<(), KDBError>,
+    {
+        self.key()?;
+        kdbx::export(
+            self.groups.iter().flatten(),
+            self.entries.iter().flatten(),
+            password,
+            DEFAULT_TRANSFORM_ROUNDS,
+            sink,
+        )
+        .await
+    }
+
+    /// Replaces every group and entry with those in the `.kdb` `file`.
+    /// `file` is decrypted in place and zeroized before returning.
+    pub fn import_kdb<S: NorFlash>(
+        &mut self,
+        file: &mut [u8],
+        password: &[u8],
+        storage: &mut S,
+    ) -> Result<(), KDBError> {
+        let kdb = KdbFile::open(file, password)?;
+        self.replace_contents(|| kdb.items(), storage)
+    }
+
+    /// Replaces every group and entry with those `import`ed from a KDBX
+    /// file. Whatever it lists as misfits stays left out.
+    pub fn import_kdbx<S: NorFlash>(
+        &mut self,
+        import: &KdbxImport,
+        storage: &mut S,
+    ) -> Result<(), KDBError> {
+        let items = || {
+            let groups = import.groups.iter().copied().map(KdbItem::Group);
+            let entries = import.entries.iter().cloned().map(KdbItem::Entry);
+            groups.chain(entries).map(Ok)
+        };
+        self.replace_contents(items, storage)
+    }
+
+    /// Swaps every group and entry for `items`, groups first. `items` is
+    /// gone through three times: to check it reads and fits, to stage it and
+    /// to fill the in-memory cache.
+    fn replace_contents<S, F, I>(&mut self, items: F, storage: &mut S) -> Result<(), KDBError>
+    where
+        S: NorFlash,
+        F: Fn() -> I,
+        I: Iterator<Item = Result<KdbItem, KDBError>>,
+    {
+        let key = self.key()?.clone();
+
+        // 1. Check everything reads and fits before touching the log
+        let (mut group_count, mut entry_count, mut entry_bytes) = (0, 0, 0);
+        for item in items() {
+            match item? {
+                KdbItem::Group(_) => group_count += 1,
+                KdbItem::Entry(entry) => {
+                    entry_count += 1;
+                    entry_bytes += entry_record_size(&entry);
+                }
+            }
+        }
+        if group_count > MAX_GROUPS as usize || entry_count > MAX_ENTRIES as usize {
+            return Err(KDBError::DatabaseFull);
+        }
+
+        // 2. Append the new records in place of the current ones, then commit
+        let mut staged = self
+            .log
+            .begin(storage, change_size(group_count, entry_bytes))?;
+        staged.clear();
+        for item in items() {
+            match item? {
+                KdbItem::Group(group) => {
+                    let slot = staged
+                        .free_group_slot()
+                        .ok_or(KDBError::DatabaseIntegrityError)?;
+                    staged.push_group(slot)?;
+                    let group_buffer = sealed_group(&self.header, &key, slot as u32, &group)?;
+                    self.log.append(
+                        storage,
+                        &mut staged,
+                        RecordKind::Group,
+                        slot,
+                        &group_buffer,
+                    )?;
+                }
+                KdbItem::Entry(entry) => {
+                    let slot = staged
+                        .free_entry_slot()
+                        .ok_or(KDBError::DatabaseIntegrityError)?;
+                    staged.push_entry(slot)?;
+                    let entry_buffer = sealed_entry(&self.header, &key, slot as u32, &entry)?;
+                    self.log.append(
+                        storage,
+                        &mut staged,
+                        RecordKind::Entry,
+                        slot,
+                        &entry_buffer,
+                    )?;
+                }
+            }
+        }
+        self.header = commit_header(&mut self.log, storage, staged, self.header, &key)?;
+
+        // 3. Update in-memory cache. Dropping the old entries zeroizes them.
+        self.groups = [None; MAX_GROUPS as usize];
+        self.entries = [const { None }; MAX_ENTRIES as usize];
+        let (mut groups, mut entries) = (self.groups.iter_mut(), self.entries.iter_mut());
+        for item in items().flatten() {
+            match item {
+                KdbItem::Group(group) => {
+                    *groups.next().ok_or(KDBError::DatabaseFull)? = Some(group)
+                }
+                KdbItem::Entry(entry) => {
+                    *entries.next().ok_or(KDBError::DatabaseFull)? = Some(entry)
+                }
+            }
+        }
+
+        info!(
+            "Imported {} groups and {} entries",
+            group_count, entry_count
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keepass::KDBError;
+    use crate::keepass::kdb::FILE_HEADER_SIZE;
+    use crate::storage::testing::{self, entry, titles};
+
+    const SAMPLE: &[u8] = include_bytes!("../keepass/fixtures/sample.kdb");
+    const TOO_LONG: &[u8] = include_bytes!("../keepass/fixtures/too_long.kdb");
+    const PASSWORD: &[u8] = b"correct horse";
+
+    #[test]
+    fn kdb_import_replaces_the_database() {
+        let mut flash = testing::fresh_flash();
+        let (mut booted, _) = testing::unlock(&mut flash);
+        booted
+            .kpdb
+            .create_entry(entry(1, "old"), &mut flash)
+            .unwrap();
+
+        let mut file = SAMPLE.to_vec();
+        booted
+            .kpdb
+            .import_kdb(&mut file, PASSWORD, &mut flash)
+            .unwrap();
+        // Only the padding, at most one cipher block, is left as it was
+        let plaintext = &file[FILE_HEADER_SIZE..SAMPLE.len() - 16];
+        assert!(plaintext.iter().all(|&byte| byte == 0));
+
+        let (booted, _) = testing::unlock(&mut flash);
+        assert_eq!(titles(&booted.kpdb), [&b"GitHub"[..], b"Mail", b"Bank"]);
+        assert_eq!(booted.kpdb.groups.iter().flatten().count(), 3);
+    }
+
+    #[test]
+    fn failed_kdb_import_keeps_the_database() {
+        let mut flash = testing::fresh_flash();
+        let (mut booted, _) = testing::unlock(&mut flash);
+        booted
+            .kpdb
+            .create_entry(entry(1, "old"), &mut flash)
+            .unwrap();
+
+        let mut file = SAMPLE.to_vec();
+        let result = booted.kpdb.import_kdb(&mut file, b"wrong", &mut flash);
+        assert_eq!(result, Err(KDBError::InvalidKey));
+        let mut file = TOO_LONG.to_vec();
+        let result = booted.kpdb.import_kdb(&mut file, PASSWORD, &mut flash);
+        assert_eq!(result, Err(KDBError::FieldTooLong));
+
+        let (booted, _) = testing::unlock(&mut flash);
+        assert_eq!(titles(&booted.kpdb), [b"old"]);
+    }
+}
@@ -0,0 +1,582 @@
+use defmt::Format;
+use embassy_time::Duration;
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::encryption::{DbKey, NONCE_SIZE, SEAL_OVERHEAD, open_in_place, seal_in_place};
+use crate::storage::flash::{self, SECTOR_SIZE};
+use crate::storage::header::{
+    LAYOUT_HEADER_SIZE, LayoutHeader, STORAGE_LAYOUT_VERSION, STORAGE_MAGIC,
+    get_user_storage_offset,
+};
+use crate::storage::layout::{
+    StorageError, check_capacity, expected_region_handle, record_region_contents,
+    user_config_staging_offset,
+};
+use crate::storage::region::{DataRegion, RegionHandle};
+use crate::storage::settings::{SETTINGS_OFFSET_REL, SETTINGS_SIZE, Settings};
+
+pub const USER_CONFIG_MAGIC: [u8; 4] = *b"PBUC";
+pub const USER_CONFIG_VERSION: u16 = 1;
+
+pub const MIN_PIN_LEN: usize = 4;
+pub const MAX_PIN_LEN: usize = 16;
+pub const MIN_PASSPHRASE_LEN: usize = 8;
+pub const MAX_PASSPHRASE_LEN: usize = 64;
+
+/// Longest lockout applied between two PIN attempts.
+const MAX_LOCKOUT_SECS: u64 = 60 * 60;
+
+// magic = 4; version = 2; reserved = 1; unlock_mode = 1; pin_len = 1; reserved = 3;
+const HEADER_SIZE: usize = 12;
+const UNLOCK_MODE_OFFSET: usize = 7;
+const PIN_LEN_OFFSET: usize = 8;
+const UNLOCK_MODE_PIN: u8 = 0;
+const UNLOCK_MODE_PASSPHRASE: u8 = 1;
+const VERIFIER_OFFSET_REL: u32 = HEADER_SIZE as u32;
+const KCV_PLAINTEXT: [u8; 16] = *b"passbuddy-kcv-v1";
+const KCV_AAD: &[u8] = b"pin-verifier";
+/// Sealed key-check value: nonce + 16-byte constant + tag.
+pub const VERIFIER_SIZE: usize = KCV_PLAINTEXT.len() + SEAL_OVERHEAD; // 44
+/// Header plus verifier, a multiple of the flash write size.
+const RECORD_SIZE: usize = HEADER_SIZE + VERIFIER_SIZE; // 56
+
+/// Written before a wipe starts and only cleared by the bootstrap that ends it.
+const WIPE_MARKER_OFFSET_REL: u32 = RECORD_SIZE as u32;
+const WIPE_MARKER: [u8; 4] = *b"WIPE";
+
+/// Attempts are counted by clearing one bit per attempt, and a successful one
+/// by clearing its bit in a second bitmap, so neither needs a sector erase.
+/// 32 bytes each hold 256 attempts; the failures since the last success are
+/// what counts towards the lockout and the auto-wipe.
+const ATTEMPTS_OFFSET_REL: u32 = 64;
+const ATTEMPTS_BYTES: usize = 64;
+const BITMAP_BYTES: usize = ATTEMPTS_BYTES / 2;
+const ATTEMPT_SLOTS: u32 = BITMAP_BYTES as u32 * 8;
+const WORD_SIZE: usize = 4;
+const _: () = assert!(ATTEMPTS_OFFSET_REL + ATTEMPTS_BYTES as u32 <= SETTINGS_OFFSET_REL);
+
+/// What a rewrite puts back: the header and verifier, the erased wipe marker,
+/// the attempt bitmaps and the first settings slot. It is staged in `Scratch`
+/// first, so a power cut never leaves the region half written.
+const IMAGE_SIZE: usize = SETTINGS_OFFSET_REL as usize + SETTINGS_SIZE;
+
+/// How the database is unlocked, chosen when the secret is first set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum UnlockMode {
+    /// A numeric PIN. `len` is `None` if the length was never recorded.
+    Pin { len: Option<u8> },
+    /// An alphanumeric passphrase typed on the keyboard.
+    Passphrase,
+}
+
+impl Default for UnlockMode {
+    fn default() -> Self {
+        Self::Pin { len: None }
+    }
+}
+
+impl UnlockMode {
+    /// Encodes the mode as `[mode, pin_len]`.
+    pub fn to_bytes(self) -> [u8; 2] {
+        match self {
+            UnlockMode::Pin { len } => [UNLOCK_MODE_PIN, len.unwrap_or(0xFF)],
+            UnlockMode::Passphrase => [UNLOCK_MODE_PASSPHRASE, 0xFF],
+        }
+    }
+
+    pub fn from_bytes(bytes: [u8; 2]) -> Self {
+        match bytes[0] {
+            UNLOCK_MODE_PASSPHRASE => UnlockMode::Passphrase,
+            _ => UnlockMode::Pin {
+                len: (MIN_PIN_LEN..=MAX_PIN_LEN)
+                    .contains(&(bytes[1] as usize))
+                    .then_some(bytes[1]),
+            },
+        }
+    }
+}
+
+/// PIN verifier, unlock mode, failed-attempt counter and device settings kept in the
+/// `UserConfig` region.
+#[derive(Debug, Clone, Format)]
+pub struct UserConfig {
+    region: RegionHandle,
+    verifier: Option<[u8; VERIFIER_SIZE]>,
+    unlock_mode: UnlockMode,
+    attempts: [u8; ATTEMPTS_BYTES],
+    settings: Settings,
+    /// First erased settings slot
+    next_settings_slot: u32,
+}
+
+impl UserConfig {
+    pub fn load<S: NorFlash>(storage: &mut S, region: RegionHandle) -> Result<Self, StorageError> {
+        if !region.contains_range(ATTEMPTS_OFFSET_REL, ATTEMPTS_BYTES) {
+            return Err(StorageError::BufferTooSmall);
+        }
+
+        // 1. Read the header and the verifier
+        let mut record = [0u8; RECORD_SIZE];
+        flash::read(storage, region.base, &mut record).map_err(|_| StorageError::Io)?;
+
+        let version = u16::from_le_bytes(record[4..6].try_into().unwrap());
+        let mut verifier = None;
+        let mut unlock_mode = UnlockMode::default();
+        if record[0..4] == USER_CONFIG_MAGIC && version == USER_CONFIG_VERSION {
+            let start = VERIFIER_OFFSET_REL as usize;
+            let bytes: [u8; VERIFIER_SIZE] =
+                record[start..start + VERIFIER_SIZE].try_into().unwrap();
+            if bytes.iter().any(|&b| b != 0xFF) {
+                verifier = Some(bytes);
+            }
+            unlock_mode =
+                UnlockMode::from_bytes([record[UNLOCK_MODE_OFFSET], record[PIN_LEN_OFFSET]]);
+        }
+
+        // 2. Read the attempt bitmap
+        let mut attempts = [0u8; ATTEMPTS_BYTES];
+        flash::read(storage, region.base + ATTEMPTS_OFFSET_REL, &mut attempts)
+            .map_err(|_| StorageError::Io)?;
+
+        // 3. Read the settings
+        let (settings, next_settings_slot) = Settings::load(storage, region)?;
+        let settings = settings.unwrap_or_default();
+
+        Ok(Self {
+            region,
+            verifier,
+            unlock_mode,
+            attempts,
+            settings,
+            next_settings_slot,
+        })
+    }
+
+    /// Bytes of `region` covered by its descriptor's checksum: the header and
+    /// verifier once a record has been written, nothing before that.
+    pub(crate) fn stored_len<S: NorFlash>(
+        storage: &mut S,
+        region: RegionHandle,
+    ) -> Result<u32, StorageError> {
+        let mut magic = [0u8; 4];
+        flash::read(storage, region.base, &mut magic).map_err(|_| StorageError::Io)?;
+        Ok(if magic == USER_CONFIG_MAGIC {
+            RECORD_SIZE as u32
+        } else {
+            0
+        })
+    }
+
+    /// Returns `true` if a wipe was started and never finished.
+    pub fn wipe_pending<S: NorFlash>(
+        storage: &mut S,
+        region: RegionHandle,
+    ) -> Result<bool, StorageError> {
+        let offset = region
+            .absolute(WIPE_MARKER_OFFSET_REL)
+            .ok_or(StorageError::InvalidLayout)?;
+        let mut marker = [0u8; 4];
+        flash::read(storage, offset, &mut marker).map_err(|_| StorageError::Io)?;
+        Ok(marker == WIPE_MARKER)
+    }
+
+    /// Records that a wipe is about to start. Only clears bits, so it needs no erase.
+    pub fn mark_wipe_pending<S: NorFlash>(
+        storage: &mut S,
+        region: RegionHandle,
+    ) -> Result<(), StorageError> {
+        let offset = region
+            .absolute(WIPE_MARKER_OFFSET_REL)
+            .ok_or(StorageError::InvalidLayout)?;
+        NorFlash::write(storage, offset, &WIPE_MARKER).map_err(|_| StorageError::Io)
+    }
+
+    pub fn has_verifier(&self) -> bool {
+        self.verifier.is_some()
+    }
+
+    /// Checks `key` against the stored verifier. Returns `None` if no PIN has
+    /// been enrolled yet.
+    pub fn check_verifier(&self, key: &DbKey) -> Option<bool> {
+        let mut verifier = self.verifier?;
+        let ok = open_in_place(key, KCV_AAD, &mut verifier).is_ok()
+            && verifier[NONCE_SIZE..NONCE_SIZE + KCV_PLAINTEXT.len()] == KCV_PLAINTEXT;
+        Some(ok)
+    }
+
+    pub fn unlock_mode(&self) -> UnlockMode {
+        self.unlock_mode
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Stores `settings`, with every field out of range replaced by its
+    /// default, in the next free slot. Once the slots are used up the region
+    /// is rewritten with the settings in the first one.
+    pub fn save_settings<S: NorFlash>(
+        &mut self,
+        settings: Settings,
+        storage: &mut S,
+    ) -> Result<(), StorageError> {
+        self.settings = settings.sanitized();
+        if self.next_settings_slot >= Settings::slot_count(self.region) {
+            return self.rewrite(self.failed_attempts(), storage);
+        }
+
+        self.settings
+            .write_slot(storage, self.region, self.next_settings_slot)?;
+        self.next_settings_slot += 1;
+        Ok(())
+    }
+
+    /// Idle time before the device locks itself, or `None` if it never does.
+    pub fn auto_lock(&self) -> Option<Duration> {
+        self.settings.auto_lock()
+    }
+
+    /// Attempts that failed since the last successful one.
+    pub fn failed_attempts(&self) -> u32 {
+        let made = self.attempts_made();
+        match Self::last_cleared(&self.attempts[BITMAP_BYTES..]) {
+            Some(success) => made.saturating_sub(success + 1),
+            None => made,
+        }
+    }
+
+    /// Attempts counted since the region was last rewritten.
+    fn attempts_made(&self) -> u32 {
+        self.attempts[..BITMAP_BYTES]
+            .iter()
+            .map(|b| b.count_zeros())
+            .sum()
+    }
+
+    /// Index of the highest cleared bit in `bitmap`.
+    fn last_cleared(bitmap: &[u8]) -> Option<u32> {
+        let (idx, byte) = bitmap.iter().enumerate().rev().find(|(_, b)| **b != 0xFF)?;
+        Some(idx as u32 * 8 + 7 - (!byte).leading_zeros())
+    }
+
+    pub fn wipe_after_attempts(&self) -> u8 {
+        self.settings.pin_policy.wipe_after_attempts
+    }
+
+    /// Attempts left before the device wipes itself.
+    pub fn remaining_attempts(&self) -> u32 {
+        u32::from(self.wipe_after_attempts()).saturating_sub(self.failed_attempts())
+    }
+
+    /// Whether enough consecutive attempts failed to trigger the auto-wipe.
+    pub fn wipe_due(&self) -> bool {
+        self.remaining_attempts() == 0
+    }
+
+    /// Lockout applied before the next attempt: 1s after the first failure,
+    /// doubling with every further failure up to an hour.
+    pub fn lockout(&self) -> Duration {
+        let failed = self.failed_attempts();
+        if failed == 0 {
+            return Duration::from_secs(0);
+        }
+        let secs = 1u64 << (failed - 1).min(16);
+        Duration::from_secs(secs.min(MAX_LOCKOUT_SECS))
+    }
+
+    /// Counts an attempt as failed. Call this *before* checking the PIN, so
+    /// cutting power mid-check can't be used to skip the count.
+    pub fn record_failed_attempt<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+    ) -> Result<(), StorageError> {
+        let mut made = self.attempts_made();
+        if made >= ATTEMPT_SLOTS {
+            // Out of bits: start over with only the failures carried over
+            self.rewrite(self.failed_attempts(), storage)?;
+            made = self.attempts_made();
+        }
+        self.clear_bit(0, made, storage)
+    }
+
+    /// Resets the failed-attempt counter after a successful unlock and stores
+    /// a verifier for `key` if none exists yet. Only the first unlock writes
+    /// more than a single word.
+    pub fn record_success<S: NorFlash>(
+        &mut self,
+        key: &DbKey,
+        storage: &mut S,
+    ) -> Result<(), StorageError> {
+        if self.verifier.is_none() {
+            self.verifier = Some(Self::seal_verifier(key)?);
+            return self.rewrite(0, storage);
+        }
+        if self.failed_attempts() == 0 {
+            return Ok(());
+        }
+
+        // Mark the last attempt counted, i.e. this one, as the successful one
+        let last = self.attempts_made().saturating_sub(1);
+        self.clear_bit(BITMAP_BYTES, last, storage)
+    }
+
+    /// Clears bit `index` of the bitmap `bitmap_start` bytes into the
+    /// attempts. NOR flash can clear bits without an erase.
+    fn clear_bit<S: NorFlash>(
+        &mut self,
+        bitmap_start: usize,
+        index: u32,
+        storage: &mut S,
+    ) -> Result<(), StorageError> {
+        let start = bitmap_start + (index as usize / 32) * WORD_SIZE;
+        let mut word =
+            u32::from_le_bytes(self.attempts[start..start + WORD_SIZE].try_into().unwrap());
+        word &= !(1 << (index % 32));
+        let word = word.to_le_bytes();
+
+        let offset = self
+            .region
+            .absolute(ATTEMPTS_OFFSET_REL + start as u32)
+            .ok_or(StorageError::InvalidLayout)?;
+        NorFlash::write(storage, offset, &word).map_err(|_| StorageError::Io)?;
+        self.attempts[start..start + WORD_SIZE].copy_from_slice(&word);
+        Ok(())
+    }
+
+    /// Replaces the verifier and the unlock mode, e.g. after the database was
+    /// sealed with a new key.
+    pub fn set_verifier<S: NorFlash>(
+        &mut self,
+        key: &DbKey,
+        unlock_mode: UnlockMode,
+        storage: &mut S,
+    ) -> Result<(), StorageError> {
+        let verifier = Self::seal_verifier(key)?;
+        self.install_verifier(verifier, unlock_mode, storage)
+    }
+
+    /// Stores a verifier sealed ahead of time by [`UserConfig::seal_verifier`],
+    /// e.g. one staged together with a re-encrypted database.
+    pub fn install_verifier<S: NorFlash>(
+        &mut self,
+        verifier: [u8; VERIFIER_SIZE],
+        unlock_mode: UnlockMode,
+        storage: &mut S,
+    ) -> Result<(), StorageError> {
+        self.verifier = Some(verifier);
+        self.unlock_mode = unlock_mode;
+        self.rewrite(0, storage)
+    }
+
+    pub fn seal_verifier(key: &DbKey) -> Result<[u8; VERIFIER_SIZE], StorageError> {
+        let mut verifier = [0u8; VERIFIER_SIZE];
+        verifier[NONCE_SIZE..NONCE_SIZE + KCV_PLAINTEXT.len()].copy_from_slice(&KCV_PLAINTEXT);
+        seal_in_place(key, KCV_AAD, &mut verifier).map_err(|_| StorageError::BufferTooSmall)?;
+        Ok(verifier)
+    }
+
+    /// Rewrites the region with the header, verifier and current settings,
+    /// and `failed` attempts counted, which clears the successful ones.
+    ///
+    /// The new contents are staged in `Scratch` first, with the magic written
+    /// last, and only then copied into place. If power is lost midway,
+    /// [`UserConfig::finish_pending_rewrite`] copies them on the next boot, so
+    /// the verifier, the settings and the counter are never lost.
+    fn rewrite<S: NorFlash>(&mut self, failed: u32, storage: &mut S) -> Result<(), StorageError> {
+        let image = self.image(failed.min(ATTEMPT_SLOTS));
+
+        // 1. Stage the new contents
+        let staging = user_config_staging_offset();
+        NorFlash::erase(storage, staging, staging + SECTOR_SIZE).map_err(|_| StorageError::Io)?;
+        let magic_len = USER_CONFIG_MAGIC.len();
+        NorFlash::write(storage, staging + magic_len as u32, &image[magic_len..])
+            .map_err(|_| StorageError::Io)?;
+        NorFlash::write(storage, staging, &image[..magic_len]).map_err(|_| StorageError::Io)?;
+
+        // 2. Copy them into place, then drop the staged copy
+        Self::install(storage, self.region, &image)?;
+        let start = ATTEMPTS_OFFSET_REL as usize;
+        self.attempts
+            .copy_from_slice(&image[start..start + ATTEMPTS_BYTES]);
+        self.next_settings_slot = 1;
+        Ok(())
+    }
+
+    /// Contents of the region as a rewrite leaves it, with the first `failed`
+    /// attempts counted.
+    fn image(&self, failed: u32) -> [u8; IMAGE_SIZE] {
+        let mut image = [0xFFu8; IMAGE_SIZE];
+        image[0..4].copy_from_slice(&USER_CONFIG_MAGIC);
+        image[4..6].copy_from_slice(&USER_CONFIG_VERSION.to_le_bytes());
+        let [mode, pin_len] = self.unlock_mode.to_bytes();
+        image[UNLOCK_MODE_OFFSET] = mode;
+        image[PIN_LEN_OFFSET] = pin_len;
+        if let Some(verifier) = self.verifier {
+            let start = VERIFIER_OFFSET_REL as usize;
+            image[start..start + VERIFIER_SIZE].copy_from_slice(&verifier);
+        }
+
+        let attempts = ATTEMPTS_OFFSET_REL as usize;
+        for bit in 0..failed as usize {
+            image[attempts + bit / 8] &= !(1 << (bit % 8));
+        }
+
+        let settings = SETTINGS_OFFSET_REL as usize;
+        image[settings..settings + SETTINGS_SIZE].copy_from_slice(&self.settings.to_bytes());
+        image
+    }
+
+    /// Erases `region`, writes `image` at its start and drops the staged copy.
+    fn install<S: NorFlash>(
+        storage: &mut S,
+        region: RegionHandle,
+        image: &[u8; IMAGE_SIZE],
+    ) -> Result<(), StorageError> {
+        let end = region
+            .absolute(region.capacity)
+            .ok_or(StorageError::InvalidLayout)?;
+        NorFlash::erase(storage, region.base, end).map_err(|_| StorageError::Io)?;
+        NorFlash::write(storage, region.base, image).map_err(|_| StorageError::Io)?;
+        // The attempt bitmaps, the wipe marker and the settings slots only
+        // ever clear bits between rewrites, so they stay outside the
+        // checksummed bytes. The settings carry their own CRC.
+        record_region_contents(storage, DataRegion::UserConfig, RECORD_SIZE as u32)?;
+
+        let staging = user_config_staging_offset();
+        NorFlash::erase(storage, staging, staging + SECTOR_SIZE).map_err(|_| StorageError::Io)
+    }
+
+    /// Finishes a rewrite cut short by a power loss. Runs before the
+    /// healthcheck, since the region may be erased at that point. Returns
+    /// `true` if one was pending.
+    pub fn finish_pending_rewrite<S: NorFlash>(storage: &mut S) -> Result<bool, StorageError> {
+        check_capacity(storage)?;
+
+        // The staging sector only belongs to this layout; an older one, still
+        // to be migrated, may keep anything there
+        let mut header = [0u8; LAYOUT_HEADER_SIZE];
+        flash::read(storage, get_user_storage_offset(), &mut header)
+            .map_err(|_| StorageError::Io)?;
+        let header = LayoutHeader::new_from_bytes(&header);
+        if header.magic != STORAGE_MAGIC || header.layout_version != STORAGE_LAYOUT_VERSION {
+            return Ok(false);
+        }
+
+        let mut image = [0u8; IMAGE_SIZE];
+        flash::read(storage, user_config_staging_offset(), &mut image)
+            .map_err(|_| StorageError::Io)?;
+        let version = u16::from_le_bytes(image[4..6].try_into().unwrap());
+        if image[0..4] != USER_CONFIG_MAGIC || version != USER_CONFIG_VERSION {
+            return Ok(false);
+        }
+
+        Self::install(
+            storage,
+            expected_region_handle(DataRegion::UserConfig),
+            &image,
+        )?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::boot;
+    use crate::storage::ram_flash::{PowerCutFlash, RamFlash};
+    use crate::storage::testing;
+
+    /// Flash with an enrolled verifier, `failed` attempts counted since, and
+    /// every settings slot but the last used up. Returns the key too.
+    fn with_full_slots(failed: u32) -> (RamFlash, DbKey) {
+        let mut flash = testing::fresh_flash();
+        let (mut booted, key) = testing::unlock(&mut flash);
+        let user_config = &mut booted.user_config;
+        for _ in 2..Settings::slot_count(user_config.region) {
+            user_config
+                .save_settings(Settings::default(), &mut flash)
+                .unwrap();
+        }
+        for _ in 0..failed {
+            user_config.record_failed_attempt(&mut flash).unwrap();
+        }
+        (flash, key)
+    }
+
+    #[test]
+    fn unlocking_erases_nothing() {
+        let (mut flash, key) = with_full_slots(0);
+        let mut user_config = boot::open(&mut flash).unwrap().user_config;
+
+        let mut counted = PowerCutFlash::new(flash, usize::MAX);
+        for _ in 0..3 {
+            user_config.record_failed_attempt(&mut counted).unwrap();
+        }
+        assert_eq!(user_config.failed_attempts(), 3);
+        user_config.record_success(&key, &mut counted).unwrap();
+        // One word per attempt and one for the success
+        assert_eq!(counted.ops(), 4);
+
+        let user_config = boot::open(&mut counted.into_inner()).unwrap().user_config;
+        assert_eq!(user_config.failed_attempts(), 0);
+    }
+
+    #[test]
+    fn a_rewrite_keeps_the_failures() {
+        let mut flash = testing::fresh_flash();
+        let (mut booted, key) = testing::unlock(&mut flash);
+        let user_config = &mut booted.user_config;
+
+        // Run the attempt bitmap out a few times over
+        for _ in 0..ATTEMPT_SLOTS * 2 {
+            user_config.record_failed_attempt(&mut flash).unwrap();
+            user_config.record_success(&key, &mut flash).unwrap();
+        }
+        for _ in 0..ATTEMPT_SLOTS {
+            user_config.record_failed_attempt(&mut flash).unwrap();
+            user_config.record_failed_attempt(&mut flash).unwrap();
+            user_config.record_success(&key, &mut flash).unwrap();
+        }
+        user_config.record_failed_attempt(&mut flash).unwrap();
+        user_config.record_failed_attempt(&mut flash).unwrap();
+        assert_eq!(user_config.failed_attempts(), 2);
+
+        let user_config = boot::open(&mut flash).unwrap().user_config;
+        assert_eq!(user_config.failed_attempts(), 2);
+        assert_eq!(user_config.check_verifier(&key), Some(true));
+    }
+
+    #[test]
+    fn a_rewrite_survives_a_power_cut() {
+        let (base, key) = with_full_slots(2);
+        let saved = Settings {
+            auto_lock_secs: 30,
+            ..Settings::default()
+        };
+
+        for budget in 0.. {
+            let mut flash = base.clone();
+            let mut user_config = boot::open(&mut flash).unwrap().user_config;
+            let mut cut_flash = PowerCutFlash::new(flash, budget);
+            // The last slot, then a rewrite
+            let result = user_config
+                .save_settings(saved, &mut cut_flash)
+                .and_then(|()| user_config.save_settings(saved, &mut cut_flash));
+            let cut = cut_flash.is_cut();
+
+            let user_config = boot::open(&mut cut_flash.into_inner()).unwrap().user_config;
+            assert_eq!(user_config.check_verifier(&key), Some(true));
+            assert_eq!(user_config.failed_attempts(), 2);
+            if !cut {
+                result.unwrap();
+                assert_eq!(*user_config.settings(), saved);
+                return;
+            }
+            assert!(
+                [Settings::default(), saved].contains(user_config.settings()),
+                "power cut after {budget} operations left {:?}",
+                user_config.settings()
+            );
+        }
+    }
+}
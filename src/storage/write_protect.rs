@@ -0,0 +1,81 @@
+//! Flash that can be switched to read-only, for running the app on storage
+//! that failed its checks without making things worse.
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+use crate::storage::layout::expected_region_handle;
+use crate::storage::region::DataRegion;
+
+/// Wraps a flash and, once protected, refuses every erase and every write
+/// outside `UserConfig`.
+///
+/// Programming `UserConfig` is still let through: it can only clear bits,
+/// which is how the failed-attempt counter and the wipe marker are recorded,
+/// so PIN attempts keep being counted. Anything that replaces stored data
+/// there needs an erase first and fails. The KeePass record log is only ever
+/// appended to, so it takes no writes at all.
+pub struct WriteProtect<S> {
+    inner: S,
+    protected: bool,
+}
+
+impl<S> WriteProtect<S> {
+    /// Starts out writable.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            protected: false,
+        }
+    }
+
+    pub fn set_protected(&mut self, protected: bool) {
+        self.protected = protected;
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.protected
+    }
+}
+
+impl<S: NorFlash> ErrorType for WriteProtect<S> {
+    type Error = NorFlashErrorKind;
+}
+
+impl<S: NorFlash> ReadNorFlash for WriteProtect<S> {
+    const READ_SIZE: usize = S::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.inner.read(offset, bytes).map_err(|err| err.kind())
+    }
+
+    fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+}
+
+impl<S: NorFlash> NorFlash for WriteProtect<S> {
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+    const ERASE_SIZE: usize = S::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if self.protected {
+            return Err(NorFlashErrorKind::Other);
+        }
+        self.inner.erase(from, to).map_err(|err| err.kind())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        if self.protected {
+            let user_config = expected_region_handle(DataRegion::UserConfig);
+            let in_user_config = offset
+                .checked_sub(user_config.base)
+                .is_some_and(|relative| user_config.contains_range(relative, bytes.len()));
+            if !in_user_config {
+                return Err(NorFlashErrorKind::Other);
+            }
+        }
+        self.inner.write(offset, bytes).map_err(|err| err.kind())
+    }
+}
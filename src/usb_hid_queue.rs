@@ -1,14 +1,28 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use defmt::Format;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
-use heapless::String;
 
-pub const USB_HID_TEXT_CAP: usize = 64;
+use crate::keepass::entry::MAX_PASSWORD_LEN;
+use crate::secret::SecretString;
+use crate::storage::settings::{KeyboardLayout, Settings};
+
+/// Longest text typed in one go: an entry's password.
+pub const USB_HID_TEXT_CAP: usize = MAX_PASSWORD_LEN;
 pub const USB_HID_QUEUE_DEPTH: usize = 4;
 
-#[derive(Clone, Debug, Format)]
+#[derive(Debug, Format)]
 pub enum UsbHidCommand {
-    TypeText(String<USB_HID_TEXT_CAP>),
+    /// Text typed out as keystrokes. Usually a password, so it is zeroized
+    /// once the USB task is done with it.
+    TypeText {
+        text: SecretString<USB_HID_TEXT_CAP>,
+        /// Host layout the characters are mapped for
+        layout: KeyboardLayout,
+        /// Pause after every keystroke
+        delay_ms: u16,
+    },
 }
 
 #[derive(Clone, Copy, Debug, Format, Eq, PartialEq)]
@@ -19,6 +33,8 @@ pub enum UsbHidQueueError {
 
 static CHANNEL: Channel<CriticalSectionRawMutex, UsbHidCommand, USB_HID_QUEUE_DEPTH> =
     Channel::new();
+static USB_DISCONNECTED: AtomicBool = AtomicBool::new(false);
+static TYPING_CANCELLED: AtomicBool = AtomicBool::new(false);
 
 pub fn try_queue(command: UsbHidCommand) -> Result<(), UsbHidQueueError> {
     CHANNEL
@@ -26,15 +42,43 @@ pub fn try_queue(command: UsbHidCommand) -> Result<(), UsbHidQueueError> {
         .map_err(|_| UsbHidQueueError::Full)
 }
 
-pub fn try_queue_type_text(text: &str) -> Result<(), UsbHidQueueError> {
-    let mut buf: String<USB_HID_TEXT_CAP> = String::new();
+/// Queues `text` to be typed with the layout and typing delay in `settings`.
+pub fn try_queue_type_text(text: &str, settings: &Settings) -> Result<(), UsbHidQueueError> {
+    let mut buf: SecretString<USB_HID_TEXT_CAP> = SecretString::new();
     if buf.push_str(text).is_err() {
         return Err(UsbHidQueueError::TooLong);
     }
 
-    try_queue(UsbHidCommand::TypeText(buf))
+    try_queue(UsbHidCommand::TypeText {
+        text: buf,
+        layout: settings.keyboard_layout,
+        delay_ms: settings.typing_delay_ms,
+    })
 }
 
 pub async fn receive() -> UsbHidCommand {
     CHANNEL.receive().await
 }
+
+/// Drops every queued command, zeroizing its text, and stops the one being
+/// typed at its next keystroke. Called when the device locks.
+pub fn clear() {
+    CHANNEL.clear();
+    TYPING_CANCELLED.store(true, Ordering::Release);
+}
+
+/// Returns `true` once per [`clear`] since the last call. The USB task checks
+/// it between keystrokes.
+pub fn take_typing_cancelled() -> bool {
+    TYPING_CANCELLED.swap(false, Ordering::AcqRel)
+}
+
+/// Called by the USB task when the host goes away (unplugged or suspended).
+pub fn notify_usb_disconnected() {
+    USB_DISCONNECTED.store(true, Ordering::Release);
+}
+
+/// Returns `true` once per disconnect reported since the last call.
+pub fn take_usb_disconnected() -> bool {
+    USB_DISCONNECTED.swap(false, Ordering::AcqRel)
+}
@@ -0,0 +1,634 @@
+//! KeePass files to and from the host over the USB serial port.
+//!
+//! The host writes a request: a header line, then the file's password and,
+//! for an import, the file itself.
+//!
+//! ```text
+//! import kdb|kdbx <password length> <file length>
+//! export kdb|kdbx <password length>
+//! ```
+//!
+//! The USB task reads it with a [`RequestReader`] and hands it to the app,
+//! which asks on the screen before replacing or sending the database, then
+//! answers with [`Reply`]s. An export comes first as `data <length>` lines,
+//! each followed by that many bytes of the file. Then `ok <groups> <entries>
+//! <left out>` ends the transfer, followed by a `misfit` line for each thing
+//! left out of a KDBX import, or `error <reason>` does. One request is handled
+//! at a time. Imported files are at most [`kdbx::MAX_FILE_SIZE`] bytes, which
+//! keeps an import within the heap; exports are streamed and have no limit.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use defmt::{Format, info};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embedded_storage::nor_flash::NorFlash;
+use zeroize::Zeroize;
+
+use crate::keepass::kdbx::{self, Field, KdbxError, Misfit};
+use crate::keepass::{KDBError, KeePassDb};
+use crate::secret::SecretVec;
+
+/// Longest password a request may carry.
+pub const MAX_PASSWORD_LEN: usize = 256;
+/// Longest header line, newline included.
+const MAX_HEADER_LEN: usize = 48;
+/// Most bytes of an exported file in one [`Reply::Data`].
+pub const EXPORT_CHUNK_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, Format, PartialEq, Eq)]
+pub enum FileFormat {
+    /// KeePass 1.x, see [`crate::keepass::kdb`]
+    Kdb,
+    /// KDBX 4, see [`crate::keepass::kdbx`]
+    Kdbx,
+}
+
+#[derive(Debug, Format)]
+pub enum Request {
+    Import(ImportRequest),
+    Export(ExportRequest),
+}
+
+impl Request {
+    pub fn format(&self) -> FileFormat {
+        match self {
+            Request::Import(request) => request.format,
+            Request::Export(request) => request.format,
+        }
+    }
+}
+
+/// A file the host wants imported, with its password.
+pub struct ImportRequest {
+    pub format: FileFormat,
+    pub password: SecretVec,
+    /// A `.kdb` file is decrypted in place, so it is zeroized on drop
+    pub file: Vec<u8>,
+}
+
+impl Drop for ImportRequest {
+    fn drop(&mut self) {
+        self.file.zeroize();
+    }
+}
+
+impl fmt::Debug for ImportRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImportRequest")
+            .field("format", &self.format)
+            .field("password", &self.password)
+            .field("file_len", &self.file.len())
+            .finish()
+    }
+}
+
+impl Format for ImportRequest {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "ImportRequest {{ format: {}, password: {}, file_len: {} }}",
+            self.format,
+            self.password,
+            self.file.len(),
+        );
+    }
+}
+
+/// The database, as a file for `password`.
+#[derive(Debug, Format)]
+pub struct ExportRequest {
+    pub format: FileFormat,
+    pub password: SecretVec,
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq, Eq)]
+pub enum TransferError {
+    /// The header line isn't a request
+    Malformed,
+    /// The password or the file is longer than the device takes
+    TooLarge,
+    /// The database is locked, or got locked before the user answered
+    Locked,
+    ReadOnly,
+    /// The user turned the request down on the device
+    Refused,
+    Kdb(KDBError),
+    Kdbx(KdbxError),
+}
+
+impl TransferError {
+    fn reason(&self) -> &'static str {
+        match self {
+            TransferError::Malformed => "malformed request",
+            TransferError::TooLarge
+            | TransferError::Kdbx(KdbxError::TooLarge)
+            | TransferError::Kdbx(KdbxError::KdfTooExpensive(_)) => "file too large",
+            TransferError::Locked | TransferError::Kdb(KDBError::Locked) => "device locked",
+            TransferError::ReadOnly => "device read-only",
+            TransferError::Refused => "refused on the device",
+            TransferError::Kdb(KDBError::InvalidKey)
+            | TransferError::Kdbx(KdbxError::InvalidKey) => "wrong password",
+            TransferError::Kdb(KDBError::FieldTooLong) => "field too long",
+            TransferError::Kdb(KDBError::DatabaseFull | KDBError::StorageFull) => "database full",
+            TransferError::Kdb(KDBError::UnsupportedFormat(_))
+            | TransferError::Kdbx(
+                KdbxError::UnsupportedVersion(_)
+                | KdbxError::UnsupportedCipher
+                | KdbxError::UnsupportedKdf
+                | KdbxError::UnsupportedCompression
+                | KdbxError::UnsupportedInnerStream,
+            ) => "unsupported file",
+            TransferError::Kdb(KDBError::Io) => "storage failed",
+            TransferError::Kdb(_) | TransferError::Kdbx(_) => "damaged file",
+        }
+    }
+}
+
+/// The device's answer to a request. Every answer ends with
+/// [`Reply::Imported`], [`Reply::Exported`] or [`Reply::Failed`].
+#[derive(Debug)]
+pub enum Reply {
+    /// The next piece of an exported file
+    Data(Vec<u8>),
+    Imported {
+        groups: usize,
+        entries: usize,
+        misfits: Vec<Misfit>,
+    },
+    Exported {
+        groups: usize,
+        entries: usize,
+    },
+    Failed(TransferError),
+}
+
+impl Reply {
+    /// Whether this ends the answer to a request.
+    pub fn is_last(&self) -> bool {
+        !matches!(self, Reply::Data(_))
+    }
+
+    /// The reply as the host reads it: a line per fact, and the data after
+    /// its line.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut text = String::new();
+        // Writing to a `String` can't fail
+        let _ = self.write_text(&mut text);
+        let mut bytes = text.into_bytes();
+        if let Reply::Data(data) = self {
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    fn write_text(&self, out: &mut String) -> fmt::Result {
+        match self {
+            Reply::Data(data) => writeln!(out, "data {}", data.len()),
+            Reply::Exported { groups, entries } => writeln!(out, "ok {groups} {entries} 0"),
+            Reply::Imported {
+                groups,
+                entries,
+                misfits,
+            } => {
+                writeln!(out, "ok {groups} {entries} {}", misfits.len())?;
+                // Names stay on the device, like everywhere else secrets go
+                for misfit in misfits {
+                    match misfit {
+                        Misfit::GroupTooDeep { entries, .. } => {
+                            writeln!(out, "misfit group-too-deep {entries}")?
+                        }
+                        Misfit::FieldTooLong {
+                            field,
+                            len,
+                            entries,
+                            ..
+                        } => writeln!(
+                            out,
+                            "misfit {}-too-long {len} {entries}",
+                            field_name(*field)
+                        )?,
+                    }
+                }
+                Ok(())
+            }
+            Reply::Failed(err) => writeln!(out, "error {}", err.reason()),
+        }
+    }
+}
+
+fn field_name(field: Field) -> &'static str {
+    match field {
+        Field::GroupName => "group-name",
+        Field::Title => "title",
+        Field::UserName => "username",
+        Field::Password => "password",
+        Field::Url => "url",
+        Field::Notes => "notes",
+    }
+}
+
+enum ReadState {
+    Header,
+    Body {
+        export: bool,
+        format: FileFormat,
+        password_len: usize,
+        file_len: usize,
+        password: Vec<u8>,
+        file: Vec<u8>,
+    },
+    /// Throwing away the body of a refused request
+    Skip(usize),
+}
+
+/// Puts requests together from what the host writes, however it is cut
+/// into packets. Whatever follows a request before its reply is dropped.
+pub struct RequestReader {
+    header: Vec<u8>,
+    state: ReadState,
+}
+
+impl RequestReader {
+    pub fn new() -> Self {
+        Self {
+            header: Vec::new(),
+            state: ReadState::Header,
+        }
+    }
+
+    /// Takes the next bytes from the host. Returns a request once one is
+    /// complete, or the error to reply with for one the device won't take.
+    pub fn feed(&mut self, mut bytes: &[u8]) -> Option<Result<Request, TransferError>> {
+        loop {
+            match &mut self.state {
+                ReadState::Header => {
+                    if bytes.is_empty() {
+                        return None;
+                    }
+                    let Some(end) = bytes.iter().position(|&byte| byte == b'\n') else {
+                        self.header.extend_from_slice(bytes);
+                        if self.header.len() > MAX_HEADER_LEN {
+                            self.header.clear();
+                            return Some(Err(TransferError::Malformed));
+                        }
+                        return None;
+                    };
+                    self.header.extend_from_slice(&bytes[..end]);
+                    bytes = &bytes[end + 1..];
+                    let header = core::mem::take(&mut self.header);
+                    let (export, format, password_len, file_len) = match parse_header(&header) {
+                        Ok(header) => header,
+                        Err(err) => return Some(Err(err)),
+                    };
+                    if password_len > MAX_PASSWORD_LEN || file_len > kdbx::MAX_FILE_SIZE {
+                        let left = password_len + file_len;
+                        let take = left.min(bytes.len());
+                        self.state = match left - take {
+                            0 => ReadState::Header,
+                            left => ReadState::Skip(left),
+                        };
+                        return Some(Err(TransferError::TooLarge));
+                    }
+                    self.state = ReadState::Body {
+                        export,
+                        format,
+                        password_len,
+                        file_len,
+                        password: Vec::with_capacity(password_len),
+                        file: Vec::with_capacity(file_len),
+                    };
+                }
+                ReadState::Body {
+                    export,
+                    format,
+                    password_len,
+                    file_len,
+                    password,
+                    file,
+                } => {
+                    let take = (*password_len - password.len()).min(bytes.len());
+                    password.extend_from_slice(&bytes[..take]);
+                    bytes = &bytes[take..];
+                    let take = (*file_len - file.len()).min(bytes.len());
+                    file.extend_from_slice(&bytes[..take]);
+                    if password.len() < *password_len || file.len() < *file_len {
+                        return None;
+                    }
+
+                    let format = *format;
+                    let password = SecretVec::from_slice(password);
+                    let request = if *export {
+                        Request::Export(ExportRequest { format, password })
+                    } else {
+                        Request::Import(ImportRequest {
+                            format,
+                            password,
+                            file: core::mem::take(file),
+                        })
+                    };
+                    self.reset();
+                    return Some(Ok(request));
+                }
+                ReadState::Skip(left) => {
+                    let take = (*left).min(bytes.len());
+                    *left -= take;
+                    bytes = &bytes[take..];
+                    if *left > 0 {
+                        return None;
+                    }
+                    self.state = ReadState::Header;
+                }
+            }
+        }
+    }
+
+    /// Drops a half-read request, as when the host goes away.
+    pub fn reset(&mut self) {
+        if let ReadState::Body { password, file, .. } = &mut self.state {
+            password.zeroize();
+            file.zeroize();
+        }
+        self.header.clear();
+        self.state = ReadState::Header;
+    }
+}
+
+impl Default for RequestReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for RequestReader {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+/// `import <format> <password length> <file length>` or `export <format>
+/// <password length>`, with an optional `\r`. Returns whether it is an
+/// export, the format and the lengths of what follows.
+fn parse_header(line: &[u8]) -> Result<(bool, FileFormat, usize, usize), TransferError> {
+    let line = core::str::from_utf8(line).map_err(|_| TransferError::Malformed)?;
+    let words: Vec<&str> = line.trim_end_matches('\r').split(' ').collect();
+    let len = |word: &str| word.parse().map_err(|_| TransferError::Malformed);
+    let (export, format, password_len, file_len) = match words[..] {
+        ["import", format, password_len, file_len] => {
+            (false, format, len(password_len)?, len(file_len)?)
+        }
+        ["export", format, password_len] => (true, format, len(password_len)?, 0),
+        _ => return Err(TransferError::Malformed),
+    };
+    let format = match format {
+        "kdb" => FileFormat::Kdb,
+        "kdbx" => FileFormat::Kdbx,
+        _ => return Err(TransferError::Malformed),
+    };
+    Ok((export, format, password_len, file_len))
+}
+
+/// Replaces the unlocked database with the file in `request`.
+pub fn import<S: NorFlash>(kpdb: &mut KeePassDb, request: ImportRequest, storage: &mut S) -> Reply {
+    let mut request = request;
+    let password = request.password.expose();
+    let result = match request.format {
+        FileFormat::Kdb => kpdb
+            .import_kdb(&mut request.file, password, storage)
+            .map(|()| Vec::new())
+            .map_err(TransferError::Kdb),
+        FileFormat::Kdbx => kdbx::import(&request.file, password, kdbx::MAX_KDF_MEMORY)
+            .map_err(TransferError::Kdbx)
+            .and_then(|import| {
+                kpdb.import_kdbx(&import, storage)
+                    .map(|()| import.misfits)
+                    .map_err(TransferError::Kdb)
+            }),
+    };
+    match result {
+        Ok(misfits) => {
+            info!("Imported a {} file over USB", request.format);
+            Reply::Imported {
+                groups: kpdb.groups.iter().flatten().count(),
+                entries: kpdb.entries.iter().flatten().count(),
+                misfits,
+            }
+        }
+        Err(err) => Reply::Failed(err),
+    }
+}
+
+/// Writes the unlocked database as the file `request` asks for, handing it
+/// to `send` in [`Reply::Data`] pieces of [`EXPORT_CHUNK_SIZE`] bytes.
+/// Returns the reply that ends the transfer.
+pub async fn export(
+    kpdb: &KeePassDb,
+    request: ExportRequest,
+    mut send: impl AsyncFnMut(Reply),
+) -> Reply {
+    let mut chunk = Vec::with_capacity(EXPORT_CHUNK_SIZE);
+    // The file is encrypted, so nothing in it needs zeroizing
+    let mut sink = async |mut bytes: &[u8]| -> Result<(), KDBError> {
+        while !bytes.is_empty() {
+            let take = (EXPORT_CHUNK_SIZE - chunk.len()).min(bytes.len());
+            chunk.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+            if chunk.len() == EXPORT_CHUNK_SIZE {
+                let full = core::mem::replace(&mut chunk, Vec::with_capacity(EXPORT_CHUNK_SIZE));
+                send(Reply::Data(full)).await;
+            }
+        }
+        Ok(())
+    };
+    let password = request.password.expose();
+    let result = match request.format {
+        FileFormat::Kdb => kpdb.export_kdb(password, &mut sink).await,
+        FileFormat::Kdbx => kpdb.export_kdbx(password, &mut sink).await,
+    };
+    if let Err(err) = result {
+        return Reply::Failed(TransferError::Kdb(err));
+    }
+    if !chunk.is_empty() {
+        send(Reply::Data(chunk)).await;
+    }
+
+    info!("Exported a {} file over USB", request.format);
+    Reply::Exported {
+        groups: kpdb.groups.iter().flatten().count(),
+        entries: kpdb.entries.iter().flatten().count(),
+    }
+}
+
+static REQUESTS: Channel<CriticalSectionRawMutex, Request, 1> = Channel::new();
+static REPLIES: Channel<CriticalSectionRawMutex, Reply, 1> = Channel::new();
+
+/// Called by the USB task with a complete request; waits for the app to take it.
+pub async fn send_request(request: Request) {
+    REQUESTS.send(request).await
+}
+
+/// Called by the USB task after [`send_request`], until [`Reply::is_last`].
+pub async fn receive_reply() -> Reply {
+    REPLIES.receive().await
+}
+
+/// The request the host is waiting on an answer to, if there is a new one.
+pub fn try_take_request() -> Option<Request> {
+    REQUESTS.try_receive().ok()
+}
+
+/// Answers the request last taken with [`try_take_request`], when nothing
+/// else was sent for it.
+pub fn reply(reply: Reply) {
+    // There is only ever one request waiting on a reply
+    let _ = REPLIES.try_send(reply);
+}
+
+/// Sends part of the answer, waiting for the USB task to take the last part.
+pub async fn send_reply(reply: Reply) {
+    REPLIES.send(reply).await
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::keepass::kdb::KdbFile;
+    use crate::storage::testing::{self, titles};
+
+    const AES_KDF: &[u8] = include_bytes!("keepass/fixtures/aes_kdf.kdbx");
+
+    fn request_bytes(format: &str, password: &[u8], file: &[u8]) -> Vec<u8> {
+        let mut bytes =
+            alloc::format!("import {format} {} {}\r\n", password.len(), file.len()).into_bytes();
+        bytes.extend_from_slice(password);
+        bytes.extend_from_slice(file);
+        bytes
+    }
+
+    fn read(bytes: &[u8]) -> Request {
+        RequestReader::new().feed(bytes).unwrap().unwrap()
+    }
+
+    fn read_import(bytes: &[u8]) -> ImportRequest {
+        match read(bytes) {
+            Request::Import(request) => request,
+            Request::Export(_) => panic!("read an export"),
+        }
+    }
+
+    fn read_export(bytes: &[u8]) -> ExportRequest {
+        match read(bytes) {
+            Request::Export(request) => request,
+            Request::Import(_) => panic!("read an import"),
+        }
+    }
+
+    /// Runs an export, returning the file and what the host reads after it.
+    fn run_export(kpdb: &KeePassDb, request: ExportRequest) -> (Vec<u8>, Vec<u8>) {
+        let mut file = Vec::new();
+        let last = block_on(export(kpdb, request, async |reply| {
+            let Reply::Data(data) = reply else {
+                panic!("{reply:?} before the end");
+            };
+            assert!(data.len() <= EXPORT_CHUNK_SIZE);
+            file.extend_from_slice(&data);
+        }));
+        assert!(last.is_last());
+        (file, last.to_bytes())
+    }
+
+    #[test]
+    fn reads_a_request_cut_into_packets() {
+        let bytes = request_bytes("kdbx", b"correct horse", AES_KDF);
+        let mut reader = RequestReader::new();
+        let mut packets = bytes.chunks(64);
+        let request = loop {
+            let packet = packets.next().expect("the request never completed");
+            if let Some(result) = reader.feed(packet) {
+                break result.unwrap();
+            }
+        };
+        assert!(packets.next().is_none());
+        let Request::Import(request) = request else {
+            panic!("read an export");
+        };
+        assert_eq!(request.format, FileFormat::Kdbx);
+        assert_eq!(request.password.expose(), b"correct horse");
+        assert_eq!(request.file, AES_KDF);
+
+        let request = read_export(b"export kdb 3\nabc");
+        assert_eq!(request.format, FileFormat::Kdb);
+        assert_eq!(request.password.expose(), b"abc");
+    }
+
+    #[test]
+    fn skips_a_request_too_large() {
+        let mut reader = RequestReader::new();
+        let file = vec![0; kdbx::MAX_FILE_SIZE + 1];
+        let result = reader.feed(&request_bytes("kdb", b"pw", &file));
+        assert_eq!(result.unwrap().err(), Some(TransferError::TooLarge));
+        // The refused body is dropped, and the next request reads fine
+        let result = reader.feed(&request_bytes("kdb", b"pw", b"file"));
+        let Some(Ok(Request::Import(request))) = result else {
+            panic!("the next request wasn't read");
+        };
+        assert_eq!(request.file, b"file");
+
+        let result = reader.feed(b"export everything\n");
+        assert_eq!(result.unwrap().err(), Some(TransferError::Malformed));
+    }
+
+    #[test]
+    fn imports_and_reports_what_was_left_out() {
+        let mut flash = testing::fresh_flash();
+        let (mut booted, _) = testing::unlock(&mut flash);
+        let request = read_import(&request_bytes("kdbx", b"correct horse", AES_KDF));
+
+        let reply = import(&mut booted.kpdb, request, &mut flash);
+        assert_eq!(
+            reply.to_bytes(),
+            b"ok 3 3 2\nmisfit title-too-long 200 1\nmisfit group-too-deep 2\n"
+        );
+        let (mut booted, _) = testing::unlock(&mut flash);
+        assert_eq!(
+            titles(&booted.kpdb),
+            [&b"GitHub"[..], b"Mail", b"After deep"]
+        );
+
+        let request = read_import(&request_bytes("kdbx", b"wrong", AES_KDF));
+        let reply = import(&mut booted.kpdb, request, &mut flash);
+        assert_eq!(reply.to_bytes(), b"error wrong password\n");
+    }
+
+    #[test]
+    fn exports_what_imports_back() {
+        let mut flash = testing::fresh_flash();
+        let (mut booted, _) = testing::unlock(&mut flash);
+        let request = read_import(&request_bytes("kdbx", b"correct horse", AES_KDF));
+        import(&mut booted.kpdb, request, &mut flash);
+
+        let request = read_export(b"export kdbx 12\nnew password");
+        let (file, last) = run_export(&booted.kpdb, request);
+        assert_eq!(last, b"ok 3 3 0\n");
+        let reread = kdbx::import(&file, b"new password", kdbx::MAX_KDF_MEMORY).unwrap();
+        let reread: Vec<_> = reread.entries.iter().map(|entry| entry.title()).collect();
+        assert_eq!(reread, [&b"GitHub"[..], b"Mail", b"After deep"]);
+
+        let request = read_export(b"export kdb 12\nnew password");
+        let (mut file, last) = run_export(&booted.kpdb, request);
+        assert_eq!(last, b"ok 3 3 0\n");
+        let kdb = KdbFile::open(&mut file, b"new password").unwrap();
+        assert_eq!(kdb.header().num_entries, 3);
+
+        // Locked, the database stays on the device
+        booted.kpdb.lock();
+        let request = read_export(b"export kdbx 12\nnew password");
+        let (file, last) = run_export(&booted.kpdb, request);
+        assert!(file.is_empty());
+        assert_eq!(last, b"error device locked\n");
+    }
+}